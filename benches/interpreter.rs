@@ -0,0 +1,97 @@
+//! Throughput of the execution engines on a compute-heavy program; run
+//! it with `cargo bench`.
+//!
+//! For comparison, the interpreter ran the sieve at about 3.1 million
+//! instructions per second when every register and memory access took
+//! a lock, and at about 24.5 million once they didn't.
+
+use std::{io, time::Instant};
+use vlc3::{asm, console::Headless, cpu::register::Register, machine::Engine, Machine};
+
+/// a sieve of Eratosthenes below 4000, repeated 20 times, leaving the
+/// number of primes found in R0
+const SIEVE: &str = "
+        .ORIG x3000
+        LD R6, ROUNDS
+ROUND   LD R1, TABLE        ; clear the table
+        LD R2, SIZE
+        AND R3, R3, #0
+CLEAR   STR R3, R1, #0
+        ADD R1, R1, #1
+        ADD R2, R2, #-1
+        BRp CLEAR
+
+        AND R0, R0, #0      ; primes found
+        AND R2, R2, #0
+        ADD R2, R2, #2      ; candidate
+OUTER   LD R1, TABLE
+        ADD R1, R1, R2
+        LDR R3, R1, #0
+        BRnp NEXT
+        ADD R0, R0, #1
+        ADD R4, R2, R2      ; cross off its multiples
+INNER   LD R5, NSIZE
+        ADD R5, R4, R5
+        BRzp NEXT
+        LD R1, TABLE
+        ADD R1, R1, R4
+        AND R3, R3, #0
+        ADD R3, R3, #1
+        STR R3, R1, #0
+        ADD R4, R4, R2
+        BR INNER
+NEXT    ADD R2, R2, #1
+        LD R5, NSIZE
+        ADD R5, R2, R5
+        BRn OUTER
+
+        ADD R6, R6, #-1
+        BRp ROUND
+        HALT
+
+ROUNDS  .FILL #20
+SIZE    .FILL #4000
+NSIZE   .FILL #-4000
+TABLE   .FILL x4000
+        .END
+";
+
+/// primes below 4000
+const PRIMES: u16 = 550;
+
+/// the fastest of this many runs is reported
+const RUNS: usize = 5;
+
+fn main() {
+	let object = asm::assemble(SIEVE)
+		.expect("the benchmark program assembles")
+		.to_object();
+
+	for (name, engine) in [("interpreter", Engine::Interpreter), ("blocks", Engine::Blocks)] {
+		let mut best = None;
+		let mut instructions = 0;
+		for _ in 0..RUNS {
+			let mut machine = Machine::new();
+			machine.set_engine(engine);
+			machine.set_console(Headless::new(Vec::new(), io::sink()));
+			machine.load(&object).unwrap();
+
+			let begin = Instant::now();
+			machine.run().unwrap();
+			let elapsed = begin.elapsed();
+
+			assert_eq!(machine.reg(Register::R0), PRIMES, "the sieve miscounted");
+			instructions = machine.instructions();
+			best = Some(best.map_or(elapsed, |best: std::time::Duration| best.min(elapsed)));
+		}
+
+		let best = best.unwrap();
+		println!(
+			"sieve, {}: {} instructions in {:.3} s, {:.1} million instructions per second",
+			name,
+			instructions,
+			best.as_secs_f64(),
+			instructions as f64 / best.as_secs_f64() / 1e6,
+		);
+	}
+}
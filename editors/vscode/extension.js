@@ -0,0 +1,15 @@
+// start `vlc3 dap` as the debug adapter of "vlc3" launch configurations
+const vscode = require("vscode");
+
+function activate(context) {
+	context.subscriptions.push(
+		vscode.debug.registerDebugAdapterDescriptorFactory("vlc3", {
+			createDebugAdapterDescriptor(session) {
+				const vlc3 = session.configuration.vlc3Path || "vlc3";
+				return new vscode.DebugAdapterExecutable(vlc3, ["dap"]);
+			},
+		})
+	);
+}
+
+module.exports = { activate };
@@ -0,0 +1,123 @@
+/// A lexical unit of one line of LC-3 assembly.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Token {
+	/// mnemonic, directive, label, register or number
+	Word(String),
+
+	/// a double quoted string with its escapes resolved
+	Str(String),
+}
+
+/// split 'line' into tokens, dropping commas and the trailing comment
+pub fn tokenize(line: &str) -> Result<Vec<Token>, String> {
+	let mut tokens = Vec::new();
+	let mut chars = line.chars().peekable();
+
+	while let Some(&ch) = chars.peek() {
+		match ch {
+			';' => break,
+			',' => {
+				chars.next();
+			}
+			'"' => {
+				chars.next();
+				tokens.push(Token::Str(string_literal(&mut chars)?));
+			}
+			ch if ch.is_whitespace() => {
+				chars.next();
+			}
+			_ => {
+				let mut word = String::new();
+				while let Some(&ch) = chars.peek() {
+					if ch.is_whitespace() || ch == ',' || ch == ';' || ch == '"' {
+						break;
+					}
+					word.push(ch);
+					chars.next();
+				}
+				tokens.push(Token::Word(word));
+			}
+		}
+	}
+
+	Ok(tokens)
+}
+
+/// read the rest of a string literal whose opening quote is consumed
+fn string_literal<I>(chars: &mut I) -> Result<String, String>
+where
+	I: Iterator<Item = char>,
+{
+	let mut s = String::new();
+
+	loop {
+		match chars.next() {
+			None => return Err(String::from("unterminated string")),
+			Some('"') => return Ok(s),
+			Some('\\') => match chars.next() {
+				Some('n') => s.push('\n'),
+				Some('t') => s.push('\t'),
+				Some('r') => s.push('\r'),
+				Some('e') => s.push('\x1b'),
+				Some('0') => s.push('\0'),
+				Some('\\') => s.push('\\'),
+				Some('"') => s.push('"'),
+				Some(other) => return Err(format!("unknown escape '\\{}'", other)),
+				None => return Err(String::from("unterminated string")),
+			},
+			Some(ch) => s.push(ch),
+		}
+	}
+}
+
+/// parse a numeric literal: '#' decimal, 'x' hexadecimal, 'b' binary
+/// or a bare decimal number, as typed at the debugger's prompt
+pub fn number(word: &str) -> Option<i32> {
+	parse_number(word, true)
+}
+
+/// parse a numeric literal of assembly source, which unlike [`number`]
+/// needs its '#', 'x' or 'b', as in other LC-3 assemblers
+pub fn literal(word: &str) -> Option<i32> {
+	parse_number(word, false)
+}
+
+fn parse_number(word: &str, bare_decimal: bool) -> Option<i32> {
+	let (negative, word) = match word.strip_prefix('-') {
+		Some(rest) => (true, rest),
+		None => (false, word),
+	};
+
+	let (radix, digits) = if let Some(digits) = word.strip_prefix('#') {
+		(10, digits)
+	} else if let Some(digits) = word
+		.strip_prefix("0x")
+		.or_else(|| word.strip_prefix("0X"))
+		.or_else(|| word.strip_prefix('x'))
+		.or_else(|| word.strip_prefix('X'))
+	{
+		(16, digits)
+	} else if let Some(digits) = word
+		.strip_prefix('b')
+		.or_else(|| word.strip_prefix('B'))
+	{
+		(2, digits)
+	} else if bare_decimal {
+		(10, word)
+	} else {
+		return None;
+	};
+
+	// allow '#-5' and 'x-1' as well as '-#5'
+	let (negative, digits) = match digits.strip_prefix('-') {
+		Some(rest) if !negative => (true, rest),
+		_ => (negative, digits),
+	};
+
+	if digits.is_empty() || !digits.chars().all(|ch| ch.is_digit(radix)) {
+		return None;
+	}
+
+	let value = i32::from_str_radix(digits, radix).ok()?;
+	Some(if negative { -value } else { value })
+}
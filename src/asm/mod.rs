@@ -0,0 +1,652 @@
+use lexer::Token;
+use std::fmt;
+
+pub mod lexer;
+pub mod symbol;
+
+pub use symbol::SymbolTable;
+
+/// An error found while assembling, with the 1-based source line.
+#[derive(Clone, Debug)]
+pub struct AsmError {
+	line: usize,
+	message: String,
+}
+
+/// The result of assembling one program.
+#[derive(Clone, Debug)]
+pub struct Assembly {
+	origin: u16,
+	words: Vec<u16>,
+	symbols: SymbolTable,
+	/// source line each word was assembled from
+	lines: Vec<usize>,
+}
+
+/// A source line that occupies memory, located by the first pass.
+#[derive(Debug)]
+struct Statement {
+	line: usize,
+	addr: u16,
+	op: String,
+	operands: Vec<Token>,
+}
+
+/// An operand that may be a label or a number.
+enum Operand {
+	/// the address of a label
+	Label(u16),
+	Number(i32),
+}
+
+impl AsmError {
+	fn new(line: usize, message: String) -> Self {
+		Self { line, message }
+	}
+
+	pub fn line(&self) -> usize {
+		self.line
+	}
+
+	pub fn message(&self) -> &str {
+		&self.message
+	}
+}
+
+impl fmt::Display for AsmError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "line {}: {}", self.line, self.message)
+	}
+}
+
+impl std::error::Error for AsmError {}
+
+impl Assembly {
+	pub fn origin(&self) -> u16 {
+		self.origin
+	}
+
+	pub fn words(&self) -> &[u16] {
+		&self.words
+	}
+
+	pub fn symbols(&self) -> &SymbolTable {
+		&self.symbols
+	}
+
+	/// source line the word at 'addr' was assembled from
+	pub fn line_of(&self, addr: u16) -> Option<usize> {
+		let idx = addr.wrapping_sub(self.origin) as usize;
+		self.lines.get(idx).copied()
+	}
+
+	/// first address assembled from source line 'line'
+	pub fn addr_of(&self, line: usize) -> Option<u16> {
+		self.lines
+			.iter()
+			.position(|&l| l == line)
+			.map(|idx| self.origin.wrapping_add(idx as u16))
+	}
+
+	/// the object image: big endian origin followed by big endian words,
+	/// as read by `Machine::load`
+	pub fn to_object(&self) -> Vec<u8> {
+		std::iter::once(self.origin)
+			.chain(self.words.iter().copied())
+			.flat_map(u16::to_be_bytes)
+			.collect()
+	}
+}
+
+const TRAP_ALIASES: [(&str, u16); 6] = [
+	("GETC", 0x20),
+	("OUT", 0x21),
+	("PUTS", 0x22),
+	("IN", 0x23),
+	("PUTSP", 0x24),
+	("HALT", 0x25),
+];
+
+const OPCODES: [&str; 17] = [
+	"ADD", "AND", "NOT", "JMP", "RET", "JSR", "JSRR", "LD", "LDI",
+	"LDR", "LEA", "ST", "STI", "STR", "TRAP", "RTI", "BR",
+];
+
+const DIRECTIVES: [&str; 5] = [".ORIG", ".FILL", ".BLKW", ".STRINGZ", ".END"];
+
+/// whether 'word' (upper case) is a mnemonic, trap alias or directive
+fn is_operation(word: &str) -> bool {
+	OPCODES.contains(&word)
+		|| DIRECTIVES.contains(&word)
+		|| TRAP_ALIASES.iter().any(|&(alias, _)| alias == word)
+		|| branch_flags(word).is_some()
+}
+
+/// nzp bits of a BR mnemonic such as "BRNZ"; plain "BR" is "BRNZP"
+fn branch_flags(word: &str) -> Option<u16> {
+	let flags = word.strip_prefix("BR")?;
+	if flags.is_empty() {
+		return Some(0b111);
+	}
+
+	let mut nzp = 0;
+	let mut last = 3;
+	for ch in flags.chars() {
+		let bit = match ch {
+			'N' => 2,
+			'Z' => 1,
+			'P' => 0,
+			_ => return None,
+		};
+		// flags must appear at most once and in "nzp" order
+		if bit >= last {
+			return None;
+		}
+		last = bit;
+		nzp |= 1 << bit;
+	}
+	Some(nzp)
+}
+
+fn register(word: &str) -> Option<u16> {
+	match word.as_bytes() {
+		[b'R' | b'r', idx @ b'0'..=b'7'] => Some((idx - b'0') as u16),
+		_ => None,
+	}
+}
+
+/// whether 'word' may name a label; in the label column even words
+/// that read as numbers, such as B10 or xAB, are labels
+fn is_label(word: &str) -> bool {
+	let mut chars = word.chars();
+	let starts_well = matches!(chars.next(), Some(ch) if ch.is_ascii_alphabetic() || ch == '_');
+
+	starts_well
+		&& chars.all(|ch| ch.is_ascii_alphanumeric() || ch == '_')
+		&& register(word).is_none()
+		&& !is_operation(&word.to_ascii_uppercase())
+}
+
+/// assemble LC-3 source into an object image and its symbol table
+pub fn assemble(source: &str) -> Result<Assembly, Vec<AsmError>> {
+	let mut errors = Vec::new();
+	let (origin, statements, symbols) = first_pass(source, &mut errors);
+
+	let mut words = Vec::new();
+	let mut lines = Vec::new();
+	for statement in &statements {
+		match encode(statement, &symbols) {
+			Ok(encoded) => {
+				lines.extend(std::iter::repeat_n(statement.line, encoded.len()));
+				words.extend(encoded);
+			}
+			Err(message) => errors.push(AsmError::new(statement.line, message)),
+		}
+	}
+
+	if !errors.is_empty() {
+		return Err(errors);
+	}
+
+	Ok(Assembly {
+		origin: origin.unwrap_or(0),
+		words,
+		symbols,
+		lines,
+	})
+}
+
+/// locate every statement and define every label
+fn first_pass(
+	source: &str,
+	errors: &mut Vec<AsmError>,
+) -> (Option<u16>, Vec<Statement>, SymbolTable) {
+	let mut origin = None;
+	let mut statements = Vec::new();
+	let mut symbols = SymbolTable::new();
+	let mut location: u32 = 0;
+	let mut ended = false;
+
+	for (idx, text) in source.lines().enumerate() {
+		let line = idx + 1;
+
+		let mut tokens = match lexer::tokenize(text) {
+			Ok(tokens) => tokens,
+			Err(message) => {
+				errors.push(AsmError::new(line, message));
+				continue;
+			}
+		};
+		if tokens.is_empty() {
+			continue;
+		}
+		if ended {
+			errors.push(AsmError::new(line, String::from("code after .END")));
+			break;
+		}
+
+		// an optional label comes first
+		let mut label = None;
+		if let Token::Word(word) = &tokens[0] {
+			let word = word.strip_suffix(':').unwrap_or(word);
+			if !is_operation(&word.to_ascii_uppercase()) {
+				if !is_label(word) {
+					errors.push(AsmError::new(
+						line, format!("invalid label or opcode '{}'", word)
+					));
+					continue;
+				}
+				label = Some(String::from(word));
+				tokens.remove(0);
+			}
+		}
+
+		let op = match tokens.first() {
+			Some(Token::Word(word)) => word.to_ascii_uppercase(),
+			Some(Token::Str(_)) => {
+				errors.push(AsmError::new(line, String::from("unexpected string")));
+				continue;
+			}
+			None => String::new(),
+		};
+
+		if op == ".ORIG" {
+			if origin.is_some() {
+				errors.push(AsmError::new(line, String::from("duplicate .ORIG")));
+				continue;
+			}
+			match operand_number(&tokens[1..], 0, 0, 0xffff) {
+				Ok(addr) => {
+					origin = Some(addr as u16);
+					location = addr as u32;
+				}
+				Err(message) => errors.push(AsmError::new(line, message)),
+			}
+			continue;
+		}
+
+		if origin.is_none() {
+			errors.push(AsmError::new(line, String::from("code before .ORIG")));
+			continue;
+		}
+
+		if let Some(label) = label {
+			if location > 0xffff {
+				errors.push(AsmError::new(line, String::from("label beyond end of memory")));
+			} else if !symbols.insert(&label, location as u16) {
+				errors.push(AsmError::new(
+					line, format!("label '{}' defined more than once", label)
+				));
+			}
+		}
+
+		let size = match op.as_str() {
+			"" => continue,
+			".END" => {
+				ended = true;
+				continue;
+			}
+			".BLKW" => match operand_number(&tokens[1..], 0, 1, 0xffff) {
+				Ok(count) => count as u32,
+				Err(message) => {
+					errors.push(AsmError::new(line, message));
+					continue;
+				}
+			},
+			".STRINGZ" => match tokens.get(1) {
+				Some(Token::Str(s)) => s.chars().count() as u32 + 1,
+				_ => {
+					errors.push(AsmError::new(
+						line, String::from(".STRINGZ expects a string")
+					));
+					continue;
+				}
+			},
+			_ => 1,
+		};
+
+		if location + size > 0x10000 {
+			errors.push(AsmError::new(line, String::from("program exceeds end of memory")));
+			continue;
+		}
+		statements.push(Statement {
+			line,
+			addr: location as u16,
+			op,
+			operands: tokens.split_off(1),
+		});
+		location += size;
+	}
+
+	if origin.is_none() && errors.is_empty() {
+		errors.push(AsmError::new(1, String::from("missing .ORIG")));
+	} else if origin.is_some() && !ended {
+		let last = source.lines().count().max(1);
+		errors.push(AsmError::new(last, String::from("missing .END")));
+	}
+
+	(origin, statements, symbols)
+}
+
+/// the numeric operand at 'idx', checked against [min, max]
+fn operand_number(operands: &[Token], idx: usize, min: i32, max: i32) -> Result<i32, String> {
+	let word = operand_word(operands, idx)?;
+	let value = lexer::literal(word).ok_or_else(|| not_a_number(word))?;
+	check_range(value, min, max)
+}
+
+fn not_a_number(word: &str) -> String {
+	if lexer::number(word).is_some() {
+		format!("decimal numbers need a '#', found '{}'", word)
+	} else {
+		format!("expected a number, found '{}'", word)
+	}
+}
+
+/// the label 'word' or, if no label has that name, the number it
+/// reads as
+fn label_or_literal(word: &str, symbols: &SymbolTable) -> Result<Operand, String> {
+	if let Some(addr) = symbols.get(word) {
+		return Ok(Operand::Label(addr));
+	}
+	match lexer::literal(word) {
+		Some(value) => Ok(Operand::Number(value)),
+		None if lexer::number(word).is_some() => Err(not_a_number(word)),
+		None => Err(format!("undefined label '{}'", word)),
+	}
+}
+
+fn operand_word(operands: &[Token], idx: usize) -> Result<&str, String> {
+	match operands.get(idx) {
+		Some(Token::Word(word)) => Ok(word),
+		Some(Token::Str(_)) => Err(String::from("unexpected string")),
+		None => Err(format!("missing operand {}", idx + 1)),
+	}
+}
+
+fn operand_register(operands: &[Token], idx: usize) -> Result<u16, String> {
+	let word = operand_word(operands, idx)?;
+	register(word).ok_or_else(|| format!("expected a register, found '{}'", word))
+}
+
+fn check_range(value: i32, min: i32, max: i32) -> Result<i32, String> {
+	if value < min || value > max {
+		return Err(format!("{} is out of range [{}, {}]", value, min, max));
+	}
+	Ok(value)
+}
+
+/// a signed immediate of 'bits' bits, truncated to those bits
+fn operand_imm(operands: &[Token], idx: usize, bits: u32) -> Result<u16, String> {
+	let limit = 1 << (bits - 1);
+	let value = operand_number(operands, idx, -limit, limit - 1)?;
+	Ok(value as u16 & ((1 << bits) - 1))
+}
+
+/// a PC relative offset of 'bits' bits to a label or literal offset
+fn operand_offset(
+	statement: &Statement,
+	idx: usize,
+	bits: u32,
+	symbols: &SymbolTable,
+) -> Result<u16, String> {
+	let word = operand_word(&statement.operands, idx)?;
+	let limit = 1 << (bits - 1);
+
+	let offset = match label_or_literal(word, symbols)? {
+		Operand::Label(target) => target as i32 - (statement.addr as i32 + 1),
+		Operand::Number(offset) => offset,
+	};
+
+	if offset < -limit || offset >= limit {
+		return Err(format!(
+			"offset to '{}' ({}) doesn't fit in {} bits", word, offset, bits
+		));
+	}
+	Ok(offset as u16 & ((1 << bits) - 1))
+}
+
+fn expect_operands(statement: &Statement, count: usize) -> Result<(), String> {
+	if statement.operands.len() != count {
+		return Err(format!(
+			"{} expects {} operand(s), found {}",
+			statement.op, count, statement.operands.len()
+		));
+	}
+	Ok(())
+}
+
+/// encode one statement into the words it occupies
+fn encode(statement: &Statement, symbols: &SymbolTable) -> Result<Vec<u16>, String> {
+	let ops = &statement.operands;
+	let op = statement.op.as_str();
+
+	if let Some(&(_, vector)) = TRAP_ALIASES.iter().find(|&&(alias, _)| alias == op) {
+		expect_operands(statement, 0)?;
+		return Ok(vec![0xf000 | vector]);
+	}
+
+	if let Some(nzp) = branch_flags(op) {
+		expect_operands(statement, 1)?;
+		return Ok(vec![nzp << 9 | operand_offset(statement, 0, 9, symbols)?]);
+	}
+
+	let word = match op {
+		"ADD" | "AND" => {
+			expect_operands(statement, 3)?;
+			let opcode = if op == "ADD" { 0b0001 } else { 0b0101 };
+			let dr = operand_register(ops, 0)?;
+			let sr1 = operand_register(ops, 1)?;
+			let last = match operand_register(ops, 2) {
+				Ok(sr2) => sr2,
+				Err(_) => 1 << 5 | operand_imm(ops, 2, 5)?,
+			};
+			opcode << 12 | dr << 9 | sr1 << 6 | last
+		}
+		"NOT" => {
+			expect_operands(statement, 2)?;
+			0b1001 << 12
+				| operand_register(ops, 0)? << 9
+				| operand_register(ops, 1)? << 6
+				| 0b111111
+		}
+		"JMP" => {
+			expect_operands(statement, 1)?;
+			0b1100 << 12 | operand_register(ops, 0)? << 6
+		}
+		"RET" => {
+			expect_operands(statement, 0)?;
+			0xc1c0
+		}
+		"JSR" => {
+			expect_operands(statement, 1)?;
+			0b0100 << 12 | 1 << 11 | operand_offset(statement, 0, 11, symbols)?
+		}
+		"JSRR" => {
+			expect_operands(statement, 1)?;
+			0b0100 << 12 | operand_register(ops, 0)? << 6
+		}
+		"LD" | "LDI" | "LEA" | "ST" | "STI" => {
+			expect_operands(statement, 2)?;
+			let opcode = match op {
+				"LD" => 0b0010,
+				"LDI" => 0b1010,
+				"LEA" => 0b1110,
+				"ST" => 0b0011,
+				_ => 0b1011,
+			};
+			opcode << 12
+				| operand_register(ops, 0)? << 9
+				| operand_offset(statement, 1, 9, symbols)?
+		}
+		"LDR" | "STR" => {
+			expect_operands(statement, 3)?;
+			let opcode = if op == "LDR" { 0b0110 } else { 0b0111 };
+			opcode << 12
+				| operand_register(ops, 0)? << 9
+				| operand_register(ops, 1)? << 6
+				| operand_imm(ops, 2, 6)?
+		}
+		"TRAP" => {
+			expect_operands(statement, 1)?;
+			0xf000 | operand_number(ops, 0, 0, 0xff)? as u16
+		}
+		"RTI" => {
+			expect_operands(statement, 0)?;
+			0x8000
+		}
+		".FILL" => {
+			expect_operands(statement, 1)?;
+			match label_or_literal(operand_word(ops, 0)?, symbols)? {
+				Operand::Label(addr) => addr,
+				Operand::Number(value) => check_range(value, -0x8000, 0xffff)? as u16,
+			}
+		}
+		".BLKW" => {
+			let count = operand_number(ops, 0, 1, 0xffff)?;
+			return Ok(vec![0; count as usize]);
+		}
+		".STRINGZ" => {
+			expect_operands(statement, 1)?;
+			let s = match &ops[0] {
+				Token::Str(s) => s,
+				Token::Word(_) => return Err(String::from(".STRINGZ expects a string")),
+			};
+			if let Some(ch) = s.chars().find(|ch| !ch.is_ascii()) {
+				return Err(format!("'{}' isn't an ASCII character", ch));
+			}
+			return Ok(s
+				.chars()
+				.map(|ch| ch as u16)
+				.chain(std::iter::once(0))
+				.collect());
+		}
+		_ => return Err(format!("unknown opcode '{}'", statement.op)),
+	};
+
+	Ok(vec![word])
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::disasm;
+
+	const PROGRAM: &str = "
+        .ORIG x3000
+MAIN    LEA R0, TEXT
+        PUTS
+        LD R1, COUNT
+        AND R2, R2, #0
+LOOP    ADD R2, R2, R1
+        ADD R1, R1, #-1
+        BRp LOOP
+        JSR SUB
+        LDI R3, PTR
+        STR R3, R6, #-2
+        NOT R4, R3
+        HALT
+SUB     JMP R7
+COUNT   .FILL #10
+PTR     .FILL COUNT
+TEXT    .STRINGZ \"hi\"
+        .END
+";
+
+	fn messages(source: &str) -> Vec<String> {
+		assemble(source)
+			.unwrap_err()
+			.iter()
+			.map(|e| e.to_string())
+			.collect()
+	}
+
+	#[test]
+	fn symbols_are_defined_where_their_line_is() {
+		let assembly = assemble(PROGRAM).unwrap();
+		let symbols = assembly.symbols().iter().collect::<Vec<_>>();
+		assert_eq!(symbols, [
+			("MAIN", 0x3000),
+			("LOOP", 0x3004),
+			("SUB", 0x300c),
+			("COUNT", 0x300d),
+			("PTR", 0x300e),
+			("TEXT", 0x300f),
+		]);
+		assert_eq!(assembly.origin(), 0x3000);
+		assert_eq!(assembly.words().len(), 0x12);
+		assert_eq!(assembly.line_of(0x3004), Some(7));
+		assert_eq!(assembly.addr_of(7), Some(0x3004));
+
+		let table = SymbolTable::parse(&assembly.symbols().to_string());
+		assert!(table.iter().eq(assembly.symbols().iter()));
+	}
+
+	#[test]
+	fn disassembly_assembles_to_the_same_words() {
+		let assembly = assemble(PROGRAM).unwrap();
+		let symbols = assembly.symbols();
+		// a listing line is the address, the word, the label if there
+		// is one and the assembly
+		let listing = disasm::listing(0x3000, assembly.words(), symbols);
+		let mut source = String::from(".ORIG x3000\n");
+		for line in listing.lines() {
+			let fields = line.split_whitespace().skip(2).collect::<Vec<_>>();
+			source += &fields.join(" ");
+			source += "\n";
+		}
+		source += ".END\n";
+
+		let reassembled = assemble(&source).unwrap();
+		assert_eq!(reassembled.words(), assembly.words());
+		assert!(reassembled.symbols().iter().eq(symbols.iter()));
+	}
+
+	#[test]
+	fn labels_may_read_as_numbers() {
+		let assembly = assemble("
+        .ORIG x3000
+        LD R0, B10
+        BR xAB
+B10     .FILL x10
+xAB     .FILL B10
+        .END
+").unwrap();
+		assert_eq!(assembly.words(), [0x2001, 0x0e01, 0x0010, 0x3002]);
+	}
+
+	#[test]
+	fn numbers_need_their_prefix() {
+		assert_eq!(messages(".ORIG x3000\nADD R0, R0, 5\n.END"), [
+			"line 2: decimal numbers need a '#', found '5'",
+		]);
+		assert_eq!(messages(".ORIG x3000\n.FILL 12\n.END"), [
+			"line 2: decimal numbers need a '#', found '12'",
+		]);
+		let assembly = assemble(".ORIG x3000\n.FILL #-1\n.FILL b101\n.FILL x1F\n.END").unwrap();
+		assert_eq!(assembly.words(), [0xffff, 0b101, 0x1f]);
+	}
+
+	#[test]
+	fn strings_are_ascii() {
+		assert_eq!(messages(".ORIG x3000\n.STRINGZ \"caf\u{e9}\"\n.END"), [
+			"line 2: '\u{e9}' isn't an ASCII character",
+		]);
+		let assembly = assemble(".ORIG x3000\n.STRINGZ \"a\\n\"\n.END").unwrap();
+		assert_eq!(assembly.words(), [0x61, 0x0a, 0]);
+	}
+
+	#[test]
+	fn programs_end_with_end() {
+		assert_eq!(messages(".ORIG x3000\nHALT\n"), ["line 2: missing .END"]);
+		assert_eq!(messages(".ORIG x3000\nHALT\n.END\nHALT"), ["line 4: code after .END"]);
+	}
+
+	#[test]
+	fn errors_name_their_line() {
+		assert_eq!(messages(".ORIG x3000\nLD R0, NOWHERE\nADD R0, R0, #16\n.END"), [
+			"line 2: undefined label 'NOWHERE'",
+			"line 3: 16 is out of range [-16, 15]",
+		]);
+	}
+}
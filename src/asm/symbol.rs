@@ -0,0 +1,84 @@
+use std::fmt;
+
+/// Labels and the addresses they stand for, in definition order.
+#[derive(Clone, Debug, Default)]
+pub struct SymbolTable {
+	symbols: Vec<(String, u16)>,
+}
+
+impl SymbolTable {
+	pub fn new() -> Self {
+		Self {
+			symbols: Vec::new(),
+		}
+	}
+
+	/// define 'name', returning false if it is already defined
+	pub fn insert(&mut self, name: &str, addr: u16) -> bool {
+		if self.get(name).is_some() {
+			return false;
+		}
+		self.symbols.push((String::from(name), addr));
+		true
+	}
+
+	pub fn get(&self, name: &str) -> Option<u16> {
+		self.symbols
+			.iter()
+			.find(|(symbol, _)| symbol == name)
+			.map(|&(_, addr)| addr)
+	}
+
+	/// first label defined at 'addr'
+	pub fn name_of(&self, addr: u16) -> Option<&str> {
+		self.symbols
+			.iter()
+			.find(|&&(_, symbol_addr)| symbol_addr == addr)
+			.map(|(name, _)| name.as_str())
+	}
+
+	pub fn iter(&self) -> impl Iterator<Item = (&str, u16)> {
+		self.symbols
+			.iter()
+			.map(|(name, addr)| (name.as_str(), *addr))
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.symbols.is_empty()
+	}
+
+	/// parse a symbol file in the format written by lc3as (and by
+	/// this type's Display implementation), skipping its header
+	pub fn parse(text: &str) -> Self {
+		let mut table = Self::new();
+
+		for line in text.lines() {
+			let entry = match line.trim().strip_prefix("//") {
+				Some(entry) => entry,
+				None => continue,
+			};
+			let fields = entry.split_whitespace().collect::<Vec<_>>();
+			if fields.len() != 2 {
+				continue;
+			}
+			if let Ok(addr) = u16::from_str_radix(fields[1], 16) {
+				table.insert(fields[0], addr);
+			}
+		}
+
+		table
+	}
+}
+
+impl fmt::Display for SymbolTable {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		writeln!(f, "// Symbol table")?;
+		writeln!(f, "// Scope level 0:")?;
+		writeln!(f, "//\tSymbol Name       Page Address")?;
+		writeln!(f, "//\t{:<16}  {}", "-".repeat(16), "-".repeat(12))?;
+		for (name, addr) in self.iter() {
+			writeln!(f, "//\t{:<16}  {:04X}", name, addr)?;
+		}
+		Ok(())
+	}
+}
@@ -0,0 +1,755 @@
+use libc::{FD_SET, fd_set, timeval};
+use std::{
+	collections::VecDeque,
+	fmt,
+	io::{self, Write},
+	mem,
+	os::fd::AsRawFd,
+	sync::{
+		atomic::{AtomicU64, Ordering},
+		Arc, Mutex, MutexGuard,
+	},
+	time::Duration,
+};
+use syscalls::{syscall, Sysno};
+
+/// Where a machine's keyboard input comes from and where its display
+/// output goes.
+pub trait Console: Send {
+	/// whether a key can be read without blocking
+	fn key_ready(&mut self) -> io::Result<bool>;
+
+	/// read a key, blocking until there is one; None at end of input
+	fn read_key(&mut self) -> io::Result<Option<u8>>;
+
+	/// wait up to 'timeout' until read_key doesn't block; false if it
+	/// still would
+	fn wait_key(&mut self, _timeout: Duration) -> io::Result<bool> {
+		Ok(true)
+	}
+
+	fn write(&mut self, bytes: &[u8]) -> io::Result<()>;
+
+	/// called with the number of instructions executed since the last
+	/// call, before the console is used
+	fn tick(&mut self, _instructions: u64) {}
+
+	/// the keys the console has yet to deliver, for snapshots; None if
+	/// it can't restore them
+	fn pending_input(&mut self) -> Option<ConsoleInput> {
+		None
+	}
+
+	/// deliver the keys of 'input', as a console of the same kind
+	/// returned them from pending_input, instead of its own; false if
+	/// it can't
+	fn restore_input(&mut self, _input: &ConsoleInput) -> bool {
+		false
+	}
+}
+
+/// The standard input and output of the process.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stdio;
+
+/// A console for unattended runs: keys come from a script and output
+/// goes to a writer, never to the terminal.
+///
+/// Every key becomes ready a fixed number of instructions after the
+/// one before it was read, so runs are reproducible. A program blocking
+/// on a key that isn't ready yet gets it right away.
+pub struct Headless {
+	input: VecDeque<u8>,
+	/// instructions a key takes to become ready
+	delay: u64,
+	/// instructions executed since the last key was read
+	waited: u64,
+	output: Box<dyn Write + Send>,
+}
+
+/// Output collected in memory, shared by all clones.
+#[derive(Clone, Debug, Default)]
+pub struct Capture {
+	inner: Arc<Mutex<Vec<u8>>>,
+}
+
+/// Keys a machine has yet to read, as saved in snapshots.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PendingInput {
+	/// keys reverse execution gave back, delivered before any other,
+	/// oldest first
+	pub unread: Vec<u8>,
+	/// the keys of the console, None if it can't save them
+	pub console: Option<ConsoleInput>,
+}
+
+/// The keys a console has yet to deliver and when it delivers them.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConsoleInput {
+	/// the state of a [`Headless`] console
+	Headless { keys: Vec<u8>, delay: u64, waited: u64 },
+	/// the state of a [`Replay`]
+	Replay { events: Vec<InputEvent>, instructions: u64 },
+}
+
+/// A key read from a console, or the end of its input, together with
+/// the number of instructions executed before it was read.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InputEvent {
+	pub instructions: u64,
+	/// None at the end of input
+	pub key: Option<u8>,
+}
+
+/// A console logging the keys another one delivers, so that [`Replay`]
+/// can deliver them at the same instructions.
+pub struct Recorder {
+	console: Box<dyn Console>,
+	log: Box<dyn Write + Send>,
+	instructions: u64,
+}
+
+/// A console delivering the keys of a recording at the instructions
+/// they were read, writing output to another console.
+pub struct Replay {
+	events: VecDeque<InputEvent>,
+	console: Box<dyn Console>,
+	instructions: u64,
+}
+
+/// A console shared by the CPU and memory of one machine.
+#[derive(Clone)]
+pub struct ConsoleHandle {
+	inner: Arc<Mutex<Box<dyn Console>>>,
+	/// instructions executed the console hasn't been told about yet
+	ticks: Arc<AtomicU64>,
+	keys: Arc<Mutex<Keys>>,
+}
+
+/// Keys a [`ConsoleHandle`] delivered, and was given back, for
+/// reverse execution.
+#[derive(Debug, Default)]
+struct Keys {
+	/// keys given back, the last one is delivered first
+	returned: Vec<u8>,
+	/// keys delivered since the last take, if they are remembered
+	taken: Option<Vec<u8>>,
+}
+
+impl Stdio {
+	/// whether standard input has a key, or its end, to read within
+	/// 'timeout'
+	fn readable_within(timeout: Duration) -> io::Result<bool> {
+		let mut readfds: fd_set;
+		unsafe {
+			readfds = mem::zeroed();
+		}
+		unsafe {
+			FD_SET(io::stdin().as_raw_fd(), &mut readfds as *mut fd_set);
+		}
+
+		let mut timeout = timeval {
+			tv_sec: timeout.as_secs() as _,
+			tv_usec: timeout.subsec_micros() as _,
+		};
+
+		let ret;
+		unsafe {
+			ret = syscall!(
+				Sysno::select,
+				1,
+				&mut readfds as *mut fd_set,
+				0,
+				0,
+				&mut timeout as *mut timeval
+			).map_err(|errno| io::Error::from_raw_os_error(errno.into_raw()))?;
+		}
+		Ok(ret != 0)
+	}
+}
+
+impl Console for Stdio {
+	fn key_ready(&mut self) -> io::Result<bool> {
+		Self::readable_within(Duration::ZERO)
+	}
+
+	fn wait_key(&mut self, timeout: Duration) -> io::Result<bool> {
+		Self::readable_within(timeout)
+	}
+
+	fn read_key(&mut self) -> io::Result<Option<u8>> {
+		// unbuffered, so that a key read ahead can't hide from select
+		let mut ch = 0u8;
+		loop {
+			let ret = unsafe { libc::read(io::stdin().as_raw_fd(), &mut ch as *mut u8 as *mut _, 1) };
+			match ret {
+				1 => return Ok(Some(ch)),
+				0 => return Ok(None),
+				_ => {
+					let e = io::Error::last_os_error();
+					if e.kind() != io::ErrorKind::Interrupted {
+						return Err(e);
+					}
+				}
+			}
+		}
+	}
+
+	fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+		let mut stdout = io::stdout();
+		stdout.write_all(bytes)?;
+		stdout.flush()
+	}
+}
+
+impl Headless {
+	/// type 'input' as fast as the program reads it, writing to 'output'
+	pub fn new<W: Write + Send + 'static>(input: impl Into<Vec<u8>>, output: W) -> Self {
+		Self {
+			input: input.into().into(),
+			delay: 0,
+			waited: 0,
+			output: Box::new(output),
+		}
+	}
+
+	/// make every key wait 'delay' instructions after the previous one
+	/// was read, or after the start for the first
+	pub fn with_delay(mut self, delay: u64) -> Self {
+		self.delay = delay;
+		self
+	}
+}
+
+impl Console for Headless {
+	fn key_ready(&mut self) -> io::Result<bool> {
+		Ok(!self.input.is_empty() && self.waited >= self.delay)
+	}
+
+	fn read_key(&mut self) -> io::Result<Option<u8>> {
+		self.waited = 0;
+		Ok(self.input.pop_front())
+	}
+
+	fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+		self.output.write_all(bytes)?;
+		self.output.flush()
+	}
+
+	fn tick(&mut self, instructions: u64) {
+		self.waited = self.waited.saturating_add(instructions);
+	}
+
+	fn pending_input(&mut self) -> Option<ConsoleInput> {
+		Some(ConsoleInput::Headless {
+			keys: self.input.iter().copied().collect(),
+			delay: self.delay,
+			waited: self.waited,
+		})
+	}
+
+	fn restore_input(&mut self, input: &ConsoleInput) -> bool {
+		match input {
+			ConsoleInput::Headless { keys, delay, waited } => {
+				self.input = keys.iter().copied().collect();
+				self.delay = *delay;
+				self.waited = *waited;
+				true
+			}
+			ConsoleInput::Replay { .. } => false,
+		}
+	}
+}
+
+impl fmt::Debug for Headless {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("Headless")
+			.field("input", &self.input)
+			.field("delay", &self.delay)
+			.field("waited", &self.waited)
+			.finish_non_exhaustive()
+	}
+}
+
+impl Capture {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// everything written so far
+	pub fn contents(&self) -> Vec<u8> {
+		self.inner.lock().unwrap().clone()
+	}
+
+	/// everything written since the last take
+	pub fn take(&self) -> Vec<u8> {
+		std::mem::take(&mut *self.inner.lock().unwrap())
+	}
+}
+
+impl Write for Capture {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		self.inner.lock().unwrap().extend_from_slice(buf);
+		Ok(buf.len())
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		Ok(())
+	}
+}
+
+impl<C: Console + ?Sized> Console for Box<C> {
+	fn key_ready(&mut self) -> io::Result<bool> {
+		(**self).key_ready()
+	}
+
+	fn read_key(&mut self) -> io::Result<Option<u8>> {
+		(**self).read_key()
+	}
+
+	fn wait_key(&mut self, timeout: Duration) -> io::Result<bool> {
+		(**self).wait_key(timeout)
+	}
+
+	fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+		(**self).write(bytes)
+	}
+
+	fn tick(&mut self, instructions: u64) {
+		(**self).tick(instructions)
+	}
+
+	fn pending_input(&mut self) -> Option<ConsoleInput> {
+		(**self).pending_input()
+	}
+
+	fn restore_input(&mut self, input: &ConsoleInput) -> bool {
+		(**self).restore_input(input)
+	}
+}
+
+/// first line of a recording
+const RECORDING_HEADER: &str = "# vlc3 input recording: INSTRUCTIONS KEY, KEY a byte or eof";
+
+impl InputEvent {
+	/// the events of a recording written by [`Recorder`]
+	pub fn parse_recording(text: &str) -> Result<Vec<Self>, String> {
+		text.lines()
+			.enumerate()
+			.filter(|(_, line)| !line.trim().is_empty() && !line.starts_with('#'))
+			.map(|(idx, line)| {
+				let invalid = || format!("line {}: invalid input event '{}'", idx + 1, line);
+				let (instructions, key) = line.trim().split_once(' ').ok_or_else(invalid)?;
+				let key = match key.trim() {
+					"eof" => None,
+					key => Some(key.parse().map_err(|_| invalid())?),
+				};
+				Ok(Self {
+					instructions: instructions.parse().map_err(|_| invalid())?,
+					key,
+				})
+			})
+			.collect()
+	}
+}
+
+impl fmt::Display for InputEvent {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self.key {
+			Some(key) => write!(f, "{} {}", self.instructions, key),
+			None => write!(f, "{} eof", self.instructions),
+		}
+	}
+}
+
+impl Recorder {
+	/// pass the keys of 'console' through, logging them to 'log'
+	pub fn new<C, W>(console: C, mut log: W) -> io::Result<Self>
+	where
+		C: Console + 'static,
+		W: Write + Send + 'static,
+	{
+		writeln!(log, "{}", RECORDING_HEADER)?;
+		log.flush()?;
+		Ok(Self {
+			console: Box::new(console),
+			log: Box::new(log),
+			instructions: 0,
+		})
+	}
+}
+
+impl Console for Recorder {
+	fn key_ready(&mut self) -> io::Result<bool> {
+		self.console.key_ready()
+	}
+
+	fn wait_key(&mut self, timeout: Duration) -> io::Result<bool> {
+		self.console.wait_key(timeout)
+	}
+
+	fn read_key(&mut self) -> io::Result<Option<u8>> {
+		let key = self.console.read_key()?;
+		let event = InputEvent {
+			instructions: self.instructions,
+			key,
+		};
+		// log every key as it comes, vlc3 may exit at any time
+		writeln!(self.log, "{}", event)?;
+		self.log.flush()?;
+		Ok(key)
+	}
+
+	fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+		self.console.write(bytes)
+	}
+
+	fn tick(&mut self, instructions: u64) {
+		self.instructions += instructions;
+		self.console.tick(instructions);
+	}
+
+	fn pending_input(&mut self) -> Option<ConsoleInput> {
+		self.console.pending_input()
+	}
+
+	fn restore_input(&mut self, input: &ConsoleInput) -> bool {
+		self.console.restore_input(input)
+	}
+}
+
+impl Replay {
+	/// deliver 'events' and write output to 'console'
+	pub fn new<C: Console + 'static>(events: Vec<InputEvent>, console: C) -> Self {
+		Self {
+			events: events.into(),
+			console: Box::new(console),
+			instructions: 0,
+		}
+	}
+}
+
+impl Console for Replay {
+	fn key_ready(&mut self) -> io::Result<bool> {
+		Ok(self.events
+			.front()
+			.is_some_and(|event| event.instructions <= self.instructions))
+	}
+
+	/// the next key, even if the program asks for it earlier than it
+	/// did when recorded; None once the recording is used up
+	fn read_key(&mut self) -> io::Result<Option<u8>> {
+		Ok(self.events.pop_front().and_then(|event| event.key))
+	}
+
+	fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+		self.console.write(bytes)
+	}
+
+	fn tick(&mut self, instructions: u64) {
+		self.instructions += instructions;
+		self.console.tick(instructions);
+	}
+
+	fn pending_input(&mut self) -> Option<ConsoleInput> {
+		Some(ConsoleInput::Replay {
+			events: self.events.iter().copied().collect(),
+			instructions: self.instructions,
+		})
+	}
+
+	fn restore_input(&mut self, input: &ConsoleInput) -> bool {
+		match input {
+			ConsoleInput::Replay { events, instructions } => {
+				self.events = events.iter().copied().collect();
+				self.instructions = *instructions;
+				true
+			}
+			ConsoleInput::Headless { .. } => false,
+		}
+	}
+}
+
+impl ConsoleInput {
+	/// whether no keys are left to deliver
+	pub fn is_empty(&self) -> bool {
+		match self {
+			Self::Headless { keys, .. } => keys.is_empty(),
+			Self::Replay { events, .. } => events.is_empty(),
+		}
+	}
+}
+
+impl fmt::Debug for Recorder {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("Recorder")
+			.field("instructions", &self.instructions)
+			.finish_non_exhaustive()
+	}
+}
+
+impl fmt::Debug for Replay {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("Replay")
+			.field("events", &self.events)
+			.field("instructions", &self.instructions)
+			.finish_non_exhaustive()
+	}
+}
+
+impl ConsoleHandle {
+	pub fn new<C: Console + 'static>(console: C) -> Self {
+		Self {
+			inner: Arc::new(Mutex::new(Box::new(console))),
+			ticks: Arc::default(),
+			keys: Arc::default(),
+		}
+	}
+
+	/// replace the console every holder of this handle uses
+	pub fn replace(&self, console: Box<dyn Console>) {
+		*self.lock() = console;
+	}
+
+	/// the console, told about the instructions executed since it was
+	/// last used
+	fn lock(&self) -> MutexGuard<'_, Box<dyn Console>> {
+		let mut console = self.inner.lock().unwrap();
+		let ticks = self.ticks.swap(0, Ordering::Relaxed);
+		if ticks > 0 {
+			console.tick(ticks);
+		}
+		console
+	}
+
+	pub fn key_ready(&self) -> io::Result<bool> {
+		if !self.keys.lock().unwrap().returned.is_empty() {
+			return Ok(true);
+		}
+		self.lock().key_ready()
+	}
+
+	/// wait up to 'timeout' until read_key doesn't block, see
+	/// [`Console::wait_key`]
+	pub fn wait_key(&self, timeout: Duration) -> io::Result<bool> {
+		if !self.keys.lock().unwrap().returned.is_empty() {
+			return Ok(true);
+		}
+		self.lock().wait_key(timeout)
+	}
+
+	pub fn read_key(&self) -> io::Result<Option<u8>> {
+		let mut keys = self.keys.lock().unwrap();
+		let key = match keys.returned.pop() {
+			Some(key) => Some(key),
+			None => self.lock().read_key()?,
+		};
+		if let (Some(taken), Some(key)) = (&mut keys.taken, key) {
+			taken.push(key);
+		}
+		Ok(key)
+	}
+
+	pub fn write(&self, bytes: &[u8]) -> io::Result<()> {
+		self.lock().write(bytes)
+	}
+
+	/// count an executed instruction, the console is told before it's
+	/// next used
+	pub fn tick(&self) {
+		self.advance(1);
+	}
+
+	/// count 'instructions' executed instructions at once
+	pub(crate) fn advance(&self, instructions: u64) {
+		self.ticks.fetch_add(instructions, Ordering::Relaxed);
+	}
+
+	/// remember the keys delivered from now on, see take_keys
+	pub(crate) fn remember_keys(&self, remember: bool) {
+		let mut keys = self.keys.lock().unwrap();
+		keys.taken = remember.then(Vec::new);
+	}
+
+	/// the keys delivered since the last call, oldest first
+	pub(crate) fn take_keys(&self) -> Vec<u8> {
+		self.keys
+			.lock()
+			.unwrap()
+			.taken
+			.as_mut()
+			.map(std::mem::take)
+			.unwrap_or_default()
+	}
+
+	/// deliver 'keys', oldest first, before any the console has
+	pub(crate) fn unread(&self, keys: &[u8]) {
+		self.keys.lock().unwrap().returned.extend(keys.iter().rev());
+	}
+
+	/// the keys yet to be delivered, see [`Machine::snapshot`]
+	///
+	/// [`Machine::snapshot`]: crate::machine::Machine::snapshot
+	pub(crate) fn pending_input(&self) -> PendingInput {
+		let keys = self.keys.lock().unwrap();
+		PendingInput {
+			unread: keys.returned.iter().rev().copied().collect(),
+			console: self.lock().pending_input(),
+		}
+	}
+
+	/// deliver the keys of 'input' instead of those yet to be
+	/// delivered; a console without keys in 'input' keeps its own.
+	/// Fails, changing nothing, if the console can't deliver them.
+	pub(crate) fn restore_input(&self, input: &PendingInput) -> Result<(), String> {
+		let mut keys = self.keys.lock().unwrap();
+		if let Some(console_input) = input.console.as_ref().filter(|input| !input.is_empty()) {
+			if !self.lock().restore_input(console_input) {
+				return Err(String::from("the console can't deliver the keys the snapshot has left"));
+			}
+		}
+		keys.returned = input.unread.iter().rev().copied().collect();
+		Ok(())
+	}
+}
+
+impl Default for ConsoleHandle {
+	fn default() -> Self {
+		Self::new(Stdio)
+	}
+}
+
+impl fmt::Debug for ConsoleHandle {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("ConsoleHandle").finish_non_exhaustive()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::asm;
+	use crate::cpu::register::Register;
+	use crate::machine::Machine;
+	use crate::snapshot::Snapshot;
+
+	/// counts in R1 how often it polls for each of three keys and
+	/// echoes them
+	const POLL: &str = "
+        .ORIG x3000
+        AND R3, R3, #0
+        ADD R3, R3, #3
+WAIT    ADD R1, R1, #1
+        LDI R2, KBSRP
+        BRzp WAIT
+        LDI R0, KBDRP
+        OUT
+        ADD R3, R3, #-1
+        BRp WAIT
+        HALT
+KBSRP   .FILL xFE00
+KBDRP   .FILL xFE02
+        .END
+";
+
+	/// how often POLL polled, and what it wrote, with keys 'delay'
+	/// instructions apart
+	fn poll(delay: u64) -> (u16, Vec<u8>) {
+		let object = asm::assemble(POLL).unwrap().to_object();
+		let output = Capture::new();
+		let mut machine = Machine::new();
+		machine.set_console(Headless::new("abc", output.clone()).with_delay(delay));
+		machine.load(&object).unwrap();
+		machine.run().unwrap();
+		(machine.reg(Register::R1), output.contents())
+	}
+
+	#[test]
+	fn headless_keys_wait_their_delay() {
+		let mut console = Headless::new("ab", Capture::new()).with_delay(3);
+		assert!(!console.key_ready().unwrap());
+		console.tick(2);
+		assert!(!console.key_ready().unwrap());
+		console.tick(1);
+		assert!(console.key_ready().unwrap());
+		assert_eq!(console.read_key().unwrap(), Some(b'a'));
+
+		// the delay starts over with each key read
+		assert!(!console.key_ready().unwrap());
+		console.tick(3);
+		assert!(console.key_ready().unwrap());
+		assert_eq!(console.read_key().unwrap(), Some(b'b'));
+		console.tick(3);
+		assert!(!console.key_ready().unwrap());
+		assert_eq!(console.read_key().unwrap(), None);
+	}
+
+	#[test]
+	fn programs_poll_as_long_as_the_delay() {
+		let (immediate, output) = poll(0);
+		assert_eq!(immediate, 3);
+		assert_eq!(output, b"abcHALT\n");
+
+		// each poll takes three instructions, about a hundred per key
+		let (delayed, output) = poll(300);
+		assert_eq!(output, b"abcHALT\n");
+		assert!((290..=310).contains(&delayed), "{} polls", delayed);
+		assert_eq!(poll(300).0, delayed);
+	}
+
+	#[test]
+	fn replays_deliver_keys_where_they_were_recorded() {
+		let object = asm::assemble(POLL).unwrap().to_object();
+		let run = |console: Box<dyn Console>| {
+			let mut machine = Machine::new();
+			machine.set_console(console);
+			machine.load(&object).unwrap();
+			machine.run().unwrap();
+			// the consoles differ, but not the machines
+			Snapshot { input: PendingInput::default(), ..machine.snapshot() }
+		};
+
+		let log = Capture::new();
+		let output = Capture::new();
+		let headless = Headless::new("xyz", output.clone()).with_delay(100);
+		let recorded = run(Box::new(Recorder::new(headless, log.clone()).unwrap()));
+
+		let text = String::from_utf8(log.contents()).unwrap();
+		let events = InputEvent::parse_recording(&text).unwrap();
+		assert_eq!(events.len(), 3);
+		assert!(events.windows(2).all(|pair| pair[0].instructions < pair[1].instructions));
+
+		let replayed_output = Capture::new();
+		let replay = Replay::new(events, Headless::new("", replayed_output.clone()));
+		let replayed = run(Box::new(replay));
+		assert_eq!(replayed, recorded);
+		assert_eq!(replayed_output.contents(), output.contents());
+	}
+
+	#[test]
+	fn recordings_parse_what_recorders_write() {
+		let events = [
+			InputEvent { instructions: 12, key: Some(b'a') },
+			InputEvent { instructions: 40, key: Some(b'\n') },
+			InputEvent { instructions: 41, key: None },
+		];
+		let text = events.iter().map(|event| format!("{}\n", event)).collect::<String>();
+		assert_eq!(InputEvent::parse_recording(&format!("{}\n{}", RECORDING_HEADER, text)).unwrap(), events);
+		assert_eq!(
+			InputEvent::parse_recording("12 a"),
+			Err(String::from("line 1: invalid input event '12 a'"))
+		);
+	}
+
+	#[test]
+	fn captures_share_their_output() {
+		let capture = Capture::new();
+		let mut console = Headless::new("", capture.clone());
+		console.write(b"hello, ").unwrap();
+		console.write(b"world").unwrap();
+		assert_eq!(capture.contents(), b"hello, world");
+		assert_eq!(capture.take(), b"hello, world");
+		console.write(b"!").unwrap();
+		assert_eq!(capture.contents(), b"!");
+	}
+}
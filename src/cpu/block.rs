@@ -0,0 +1,398 @@
+//! Basic blocks of instructions compiled to closures, for
+//! [`Engine::Blocks`].
+//!
+//! A block starts wherever the program jumps and runs up to, and
+//! including, the next BR, JMP, JSR or JSRR. Each instruction becomes
+//! a closure with its registers, offsets and PC relative addresses
+//! bound, so running a block skips fetching and decoding, and the
+//! machine skips polling devices between its instructions.
+//!
+//! TRAP, RTI and illegal opcodes end a block before them and are left
+//! to [`Cpu::execute`], as are loads and stores that reach the I/O
+//! page. Blocks are compiled from decoded words, so writing a decoded
+//! word throws away the blocks containing it, which keeps
+//! self-modifying code correct.
+//!
+//! [`Engine::Blocks`]: crate::machine::Engine::Blocks
+
+use super::instruction::{Instruction, OpCode};
+use super::register::Register;
+use super::{psr, Cpu};
+use crate::device::IO_PAGE;
+use crate::memory::{Memory, MEMORY_SIZE};
+use std::fmt;
+
+/// most instructions compiled into one block
+const MAX_BLOCK_LEN: usize = 64;
+
+/// What a compiled instruction did.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Flow {
+	/// go on with the next instruction of the block
+	Next,
+	/// the block ends, the program continues at the address
+	Jump(u16),
+	/// the instruction has to be interpreted; it changed nothing
+	Interpret,
+}
+
+type Op = Box<dyn Fn(&mut Cpu, &mut Memory) -> Flow + Send>;
+
+/// The instructions from 'start' on, compiled.
+struct Block {
+	start: u16,
+	ops: Vec<Op>,
+}
+
+/// Compiled blocks by the address of their first instruction.
+#[derive(Default)]
+pub(crate) struct Blocks {
+	/// one slot for every address, allocated once a block is compiled
+	blocks: Vec<Option<Block>>,
+	/// [`Memory::code_writes`] when the blocks were compiled
+	code_writes: u64,
+}
+
+/// whether 'addr' is plain memory, outside of the I/O page
+fn is_plain(addr: u16) -> bool {
+	addr < *IO_PAGE.start()
+}
+
+impl Blocks {
+	/// run the block at the PC, at most 'budget' of its instructions,
+	/// returning how many ran and the address to continue at; none run
+	/// if the first has to be interpreted
+	pub(crate) fn run(&mut self, cpu: &mut Cpu, memory: &mut Memory, budget: u64) -> (u64, u16) {
+		if memory.code_writes() != self.code_writes {
+			self.code_writes = memory.code_writes();
+			match memory.take_rewritten() {
+				Some(rewritten) => rewritten.into_iter().for_each(|addr| self.forget(addr)),
+				None => self.blocks.fill_with(|| None),
+			}
+		}
+		if self.blocks.is_empty() {
+			self.blocks.resize_with(MEMORY_SIZE, || None);
+		}
+
+		// blocks whose first instruction has to be interpreted aren't
+		// kept, as their word may be rewritten without being noticed
+		let pc = cpu.read(Register::PC);
+		let block = match &mut self.blocks[pc as usize] {
+			Some(block) => block,
+			slot => match Block::compile(pc, memory) {
+				block if block.ops.is_empty() => return (0, pc),
+				block => slot.insert(block),
+			},
+		};
+		block.run(cpu, memory, budget)
+	}
+
+	/// throw away the blocks containing 'addr'
+	fn forget(&mut self, addr: u16) {
+		if self.blocks.is_empty() {
+			return;
+		}
+		// blocks don't wrap around, and none is longer than MAX_BLOCK_LEN
+		let first = addr.saturating_sub(MAX_BLOCK_LEN as u16 - 1);
+		for start in first..=addr {
+			let slot = &mut self.blocks[start as usize];
+			if slot.as_ref().is_some_and(|block| start as usize + block.ops.len() > addr as usize) {
+				*slot = None;
+			}
+		}
+	}
+}
+
+impl fmt::Debug for Blocks {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("Blocks")
+			.field("compiled", &self.blocks.iter().flatten().count())
+			.field("code_writes", &self.code_writes)
+			.finish()
+	}
+}
+
+impl Block {
+	/// compile the instructions from 'start' on, remembering their
+	/// decoding in 'memory' so that writing them is noticed
+	fn compile(start: u16, memory: &mut Memory) -> Self {
+		let mut ops = Vec::new();
+		let mut pc = start;
+		while ops.len() < MAX_BLOCK_LEN && is_plain(pc) {
+			let instr = match memory.decoded(pc) {
+				Some(instr) => instr,
+				None => match Cpu::decode_word(memory.peek(pc)) {
+					Some(instr) => instr,
+					None => break,
+				},
+			};
+			let Some(op) = compile_op(pc, instr) else {
+				break;
+			};
+			memory.remember_decoded(pc, instr);
+			ops.push(op);
+
+			let ends = matches!(
+				instr.opcode(),
+				OpCode::BR | OpCode::JMP | OpCode::JSR | OpCode::JSRR
+			);
+			pc = pc.wrapping_add(1);
+			if ends || pc == 0 {
+				break;
+			}
+		}
+		Self { start, ops }
+	}
+
+	fn run(&self, cpu: &mut Cpu, memory: &mut Memory, budget: u64) -> (u64, u16) {
+		let len = self.ops.len().min(budget.try_into().unwrap_or(usize::MAX));
+		for (executed, op) in self.ops[..len].iter().enumerate() {
+			match op(cpu, memory) {
+				Flow::Next => {}
+				Flow::Jump(addr) => return (executed as u64 + 1, addr),
+				Flow::Interpret => {
+					return (executed as u64, self.start.wrapping_add(executed as u16));
+				}
+			}
+		}
+		(len as u64, self.start.wrapping_add(len as u16))
+	}
+}
+
+/// write 'data' to 'addr', ending the block if it was a decoded word,
+/// which may be part of this very block
+fn store(memory: &mut Memory, addr: u16, data: u16, next: u16) -> Flow {
+	let code_writes = memory.code_writes();
+	memory.write(addr, data);
+	if memory.code_writes() == code_writes {
+		Flow::Next
+	} else {
+		Flow::Jump(next)
+	}
+}
+
+/// 'instr' at 'pc' as a closure, None if it has to be interpreted
+fn compile_op(pc: u16, instr: Instruction) -> Option<Op> {
+	let next = pc.wrapping_add(1);
+	let regs = instr.regs();
+	let imm = instr.imm().unwrap_or_default();
+	// PC relative operands are relative to the incremented PC
+	let target = next.wrapping_add(imm);
+
+	let op: Op = match instr.opcode() {
+		OpCode::ADDR => {
+			let (dr, sr1, sr2) = (regs[0]?, regs[1]?, regs[2]?);
+			Box::new(move |cpu, _| {
+				let result = cpu.read(sr1).wrapping_add(cpu.read(sr2));
+				cpu.write(dr, result);
+				cpu.update_condition_reg(result);
+				Flow::Next
+			})
+		}
+		OpCode::ADDI => {
+			let (dr, sr1) = (regs[0]?, regs[1]?);
+			Box::new(move |cpu, _| {
+				let result = cpu.read(sr1).wrapping_add(imm);
+				cpu.write(dr, result);
+				cpu.update_condition_reg(result);
+				Flow::Next
+			})
+		}
+		OpCode::ANDR => {
+			let (dr, sr1, sr2) = (regs[0]?, regs[1]?, regs[2]?);
+			Box::new(move |cpu, _| {
+				let result = cpu.read(sr1) & cpu.read(sr2);
+				cpu.write(dr, result);
+				cpu.update_condition_reg(result);
+				Flow::Next
+			})
+		}
+		OpCode::ANDI => {
+			let (dr, sr1) = (regs[0]?, regs[1]?);
+			Box::new(move |cpu, _| {
+				let result = cpu.read(sr1) & imm;
+				cpu.write(dr, result);
+				cpu.update_condition_reg(result);
+				Flow::Next
+			})
+		}
+		OpCode::NOT => {
+			let (dr, sr) = (regs[0]?, regs[1]?);
+			Box::new(move |cpu, _| {
+				let result = !cpu.read(sr);
+				cpu.write(dr, result);
+				cpu.update_condition_reg(result);
+				Flow::Next
+			})
+		}
+		OpCode::LEA => {
+			let dr = regs[0]?;
+			Box::new(move |cpu, _| {
+				cpu.write(dr, target);
+				cpu.update_condition_reg(target);
+				Flow::Next
+			})
+		}
+		OpCode::LD => {
+			let dr = regs[0]?;
+			if !is_plain(target) {
+				return None;
+			}
+			Box::new(move |cpu, memory| {
+				let data = memory.read(target);
+				cpu.write(dr, data);
+				cpu.update_condition_reg(data);
+				Flow::Next
+			})
+		}
+		OpCode::LDI => {
+			let dr = regs[0]?;
+			if !is_plain(target) {
+				return None;
+			}
+			Box::new(move |cpu, memory| {
+				// reading plain memory has no effect to take back
+				let addr = memory.read(target);
+				if !is_plain(addr) {
+					return Flow::Interpret;
+				}
+				let data = memory.read(addr);
+				cpu.write(dr, data);
+				cpu.update_condition_reg(data);
+				Flow::Next
+			})
+		}
+		OpCode::LDR => {
+			let (dr, base) = (regs[0]?, regs[1]?);
+			Box::new(move |cpu, memory| {
+				let addr = cpu.read(base).wrapping_add(imm);
+				if !is_plain(addr) {
+					return Flow::Interpret;
+				}
+				let data = memory.read(addr);
+				cpu.write(dr, data);
+				cpu.update_condition_reg(data);
+				Flow::Next
+			})
+		}
+		OpCode::ST => {
+			let sr = regs[0]?;
+			if !is_plain(target) {
+				return None;
+			}
+			Box::new(move |cpu, memory| store(memory, target, cpu.read(sr), next))
+		}
+		OpCode::STI => {
+			let sr = regs[0]?;
+			if !is_plain(target) {
+				return None;
+			}
+			Box::new(move |cpu, memory| {
+				// reading plain memory has no effect to take back
+				let addr = memory.read(target);
+				if !is_plain(addr) {
+					return Flow::Interpret;
+				}
+				store(memory, addr, cpu.read(sr), next)
+			})
+		}
+		OpCode::STR => {
+			let (sr, base) = (regs[0]?, regs[1]?);
+			Box::new(move |cpu, memory| {
+				let addr = cpu.read(base).wrapping_add(imm);
+				if !is_plain(addr) {
+					return Flow::Interpret;
+				}
+				store(memory, addr, cpu.read(sr), next)
+			})
+		}
+		OpCode::BR => {
+			let nzp = instr.nzp();
+			let mut mask = 0;
+			for (set, bit) in nzp.iter().zip([psr::NEGATIVE, psr::ZERO, psr::POSITIVE]) {
+				if (*set)? {
+					mask |= bit;
+				}
+			}
+			Box::new(move |cpu, _| {
+				if cpu.read(Register::Psr) & mask != 0 {
+					Flow::Jump(target)
+				} else {
+					Flow::Jump(next)
+				}
+			})
+		}
+		OpCode::JMP => {
+			let base = regs[0]?;
+			Box::new(move |cpu, _| Flow::Jump(cpu.read(base)))
+		}
+		OpCode::JSR => Box::new(move |cpu, _| {
+			cpu.write(Register::R7, next);
+			Flow::Jump(target)
+		}),
+		OpCode::JSRR => {
+			let base = regs[0]?;
+			Box::new(move |cpu, _| {
+				let addr = cpu.read(base);
+				cpu.write(Register::R7, next);
+				Flow::Jump(addr)
+			})
+		}
+		OpCode::TRAP | OpCode::RTI | OpCode::RES | OpCode::RET => return None,
+	};
+	Some(op)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// two blocks, x3000-x3001 and x3002-x3003, jumping to each other
+	fn two_blocks() -> (Cpu, Memory) {
+		let mut memory = Memory::new();
+		for (addr, word) in (0x3000..).zip([0x1261, 0x0e00, 0x14a1, 0x0ffc]) {
+			memory.write(addr, word);
+		}
+		(Cpu::new(), memory)
+	}
+
+	#[test]
+	fn rewriting_a_word_forgets_only_the_blocks_containing_it() {
+		let (mut cpu, mut memory) = two_blocks();
+		let mut blocks = Blocks::default();
+		cpu.write(Register::PC, 0x3000);
+		assert_eq!(blocks.run(&mut cpu, &mut memory, u64::MAX), (2, 0x3002));
+		cpu.write(Register::PC, 0x3002);
+		assert_eq!(blocks.run(&mut cpu, &mut memory, u64::MAX), (2, 0x3000));
+		assert_eq!(cpu.read(Register::R2), 1);
+
+		// ADD R2, R2, #2
+		memory.write(0x3002, 0x14a2);
+		cpu.write(Register::PC, 0x3000);
+		assert_eq!(blocks.run(&mut cpu, &mut memory, u64::MAX), (2, 0x3002));
+		assert!(blocks.blocks[0x3000].is_some());
+		assert!(blocks.blocks[0x3002].is_none());
+
+		cpu.write(Register::PC, 0x3002);
+		blocks.run(&mut cpu, &mut memory, u64::MAX);
+		assert_eq!(cpu.read(Register::R2), 3);
+		assert_eq!(cpu.read(Register::R1), 2);
+	}
+
+	#[test]
+	fn blocks_are_forgotten_when_all_of_memory_is_replaced() {
+		let (mut cpu, mut memory) = two_blocks();
+		let mut blocks = Blocks::default();
+		cpu.write(Register::PC, 0x3000);
+		blocks.run(&mut cpu, &mut memory, u64::MAX);
+
+		let mut contents = memory.contents();
+		// ADD R1, R1, #3
+		contents[0x3000] = 0x1263;
+		memory.set_contents(&contents);
+		cpu.write(Register::PC, 0x3000);
+		blocks.run(&mut cpu, &mut memory, u64::MAX);
+		assert_eq!(cpu.read(Register::R1), 4);
+		assert_eq!(blocks.blocks.len(), MEMORY_SIZE);
+	}
+}
@@ -1,6 +1,8 @@
 use enum_iterator::Sequence;
 use super::register::Register;
+use std::fmt;
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Clone, Copy, Debug, Sequence)]
 pub enum OpCode {
 	ADDR,
@@ -53,8 +55,9 @@ impl From<OpCode> for String {
 }
 
 #[allow(dead_code)]
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 pub struct Instruction {
+	raw: u16,
 	opcode: OpCode,
 	imm_flag: Option<bool>,
 	regs: [Option<Register>; 3],
@@ -65,13 +68,19 @@ pub struct Instruction {
 #[allow(dead_code)]
 impl Instruction {
 	pub fn new(
+		raw: u16,
 		opcode: OpCode,
 		imm_flag: Option<bool>,
 		regs: [Option<Register>; 3],
 		imm: Option<u16>,
 		nzp: [Option<bool>; 3],
 	) -> Self {
-		Self { opcode, imm_flag, regs, imm, nzp }
+		Self { raw, opcode, imm_flag, regs, imm, nzp }
+	}
+
+	/// the instruction word this instruction was decoded from
+	pub fn raw(&self) -> u16 {
+		self.raw
 	}
 
 	pub fn opcode(&self) -> OpCode {
@@ -93,5 +102,69 @@ impl Instruction {
 	pub fn nzp(&self) -> [Option<bool>; 3] {
 		self.nzp
 	}
+
+	/// render as assembly source; 'target' names the destination of a
+	/// PC relative operand given its sign extended offset. A branch on
+	/// none of n, z and p has no mnemonic and becomes a .FILL
+	pub fn to_asm<F>(&self, target: F) -> String
+	where
+		F: Fn(i16) -> String,
+	{
+		let reg = |idx: usize| self.regs[idx].unwrap();
+		let imm = || self.imm.unwrap() as i16;
+
+		match self.opcode {
+			OpCode::ADDR | OpCode::ANDR => format!(
+				"{} {}, {}, {}",
+				&String::from(self.opcode)[..3], reg(0), reg(1), reg(2)
+			),
+			OpCode::ADDI | OpCode::ANDI => format!(
+				"{} {}, {}, #{}",
+				&String::from(self.opcode)[..3], reg(0), reg(1), imm()
+			),
+			OpCode::BR => {
+				let flags = self.nzp
+					.iter()
+					.zip(['n', 'z', 'p'])
+					.filter(|(set, _)| set.unwrap())
+					.map(|(_, flag)| flag)
+					.collect::<String>();
+				if flags.is_empty() {
+					format!(".FILL x{:04X}", self.raw)
+				} else {
+					format!("BR{} {}", flags, target(imm()))
+				}
+			}
+			OpCode::JMP | OpCode::RET => match reg(0) {
+				Register::R7 => String::from("RET"),
+				base => format!("JMP {}", base),
+			},
+			OpCode::JSR => format!("JSR {}", target(imm())),
+			OpCode::JSRR => format!("JSRR {}", reg(0)),
+			OpCode::LD | OpCode::LDI | OpCode::LEA | OpCode::ST | OpCode::STI => {
+				format!("{} {}, {}", String::from(self.opcode), reg(0), target(imm()))
+			}
+			OpCode::LDR | OpCode::STR => format!(
+				"{} {}, {}, #{}",
+				String::from(self.opcode), reg(0), reg(1), imm()
+			),
+			OpCode::NOT => format!("NOT {}, {}", reg(0), reg(1)),
+			OpCode::RTI | OpCode::RES => String::from(self.opcode),
+			OpCode::TRAP => match self.imm.unwrap() {
+				0x20 => String::from("GETC"),
+				0x21 => String::from("OUT"),
+				0x22 => String::from("PUTS"),
+				0x23 => String::from("IN"),
+				0x24 => String::from("PUTSP"),
+				0x25 => String::from("HALT"),
+				vector => format!("TRAP x{:02X}", vector),
+			},
+		}
+	}
 }
 
+impl fmt::Display for Instruction {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}", self.to_asm(|offset| format!("#{}", offset)))
+	}
+}
@@ -1,82 +1,163 @@
-use crate::memory::MEMORY;
-use crate::optional_utils::summary::SUMMARY;
-use crate::parse::ARGS;
+use crate::console::ConsoleHandle;
+use crate::fault::Fault;
+use crate::memory::Memory;
+use crate::trace::Tracer;
+use crate::undo::UndoLog;
+use crate::watch::Watchpoints;
 use instruction::{Instruction, OpCode};
-use lazy_static::*;
-use libc;
 use register::Register;
-use std::{
-	io::{self, Write},
-	sync::{Arc, Mutex},
-	time::Instant,
-};
+use std::io;
 
+pub(crate) mod block;
 pub mod instruction;
+pub mod psr;
 pub mod register;
 
-const REG_COUNT: usize = 11;
+pub(crate) const REG_COUNT: usize = 13;
 
-#[derive(Debug)]
-struct CpuInner {
-	regs: [u16; REG_COUNT],
-	running: bool,
-}
+/// base of the interrupt vector table, which holds the addresses of
+/// interrupt and exception service routines
+pub const INTERRUPT_VECTOR_TABLE: u16 = 0x0100;
 
 #[derive(Debug)]
 pub struct Cpu {
-	inner: Arc<Mutex<CpuInner>>,
+	regs: [u16; REG_COUNT],
+	running: bool,
+	/// whether TRAP runs the service routines built into vlc3 rather
+	/// than those of an operating system in memory
+	native_traps: bool,
+	/// whether register writes are reported, see set_observed
+	observed: bool,
+	watchpoints: Watchpoints,
+	tracer: Tracer,
+	undo: UndoLog,
+	console: ConsoleHandle,
 }
 
-lazy_static! {
-	pub static ref CPU: Cpu = Cpu::new();
+impl Default for Cpu {
+	fn default() -> Self {
+		Self::new()
+	}
 }
 
-impl CpuInner {
-	fn new() -> Self {
+impl Cpu {
+	pub fn new() -> Self {
 		Self {
-			regs: [0, 0, 0, 0, 0, 0, 0, 0, 0x3000, 0, 0],
+			// programs start in user mode with the supervisor stack
+			// growing down from x3000
+			regs: [0, 0, 0, 0, 0, 0, 0, 0, 0x3000, psr::INITIAL, 0x3000, 0, 0],
 			running: true,
+			native_traps: true,
+			observed: true,
+			watchpoints: Watchpoints::new(),
+			tracer: Tracer::new(),
+			undo: UndoLog::new(),
+			console: ConsoleHandle::default(),
 		}
 	}
-}
 
-impl Cpu {
-	fn new() -> Self {
-		Self {
-			inner: Arc::new(Mutex::new(CpuInner::new())),
+	/// report register writes to 'watchpoints'
+	pub fn set_watchpoints(&mut self, watchpoints: Watchpoints) {
+		self.watchpoints = watchpoints;
+	}
+
+	/// report register changes to 'tracer'
+	pub fn set_tracer(&mut self, tracer: Tracer) {
+		self.tracer = tracer;
+	}
+
+	/// log register writes to 'undo'
+	pub fn set_undo_log(&mut self, undo: UndoLog) {
+		self.undo = undo;
+	}
+
+	/// report register writes only if 'observed', for the machine to
+	/// skip the watchpoints, the tracer and the undo log when none of
+	/// them is in use
+	pub(crate) fn set_observed(&mut self, observed: bool) {
+		self.observed = observed;
+	}
+
+	/// do the I/O of trap routines on 'console'
+	pub fn set_console(&mut self, console: ConsoleHandle) {
+		self.console = console;
+	}
+
+	pub fn read(&self, which: Register) -> u16 {
+		self.regs[which as usize]
+	}
+
+	pub fn write(&mut self, which: Register, data: u16) {
+		let old = std::mem::replace(&mut self.regs[which as usize], data);
+		if !self.observed {
+			return;
 		}
+		self.watchpoints.register_write(which, data);
+		self.tracer.register_write(which, old, data);
+		self.undo.register_write(which, old, data);
+	}
+
+	/// set a register back to 'data' without reporting the write
+	pub(crate) fn restore_register(&mut self, which: Register, data: u16) {
+		self.regs[which as usize] = data;
 	}
 
-	fn read(&self, which: Register) -> u16 {
-		self.inner
-			.lock()
-			.unwrap()
-			.regs[which as usize]
+	/// every register, in the order of [`Register`]
+	pub(crate) fn registers(&self) -> [u16; REG_COUNT] {
+		self.regs
 	}
 
-	fn write(&self, which: Register, data: u16) {
-		self.inner
-			.lock()
-			.unwrap()
-			.regs[which as usize] = data;
+	pub(crate) fn set_registers(&mut self, regs: [u16; REG_COUNT]) {
+		self.regs = regs;
+	}
+
+	pub(crate) fn set_running(&mut self, running: bool) {
+		self.running = running;
 	}
 
 	pub fn is_running(&self) -> bool {
-		self.inner
-			.lock()
-			.unwrap()
-			.running
+		self.running
 	}
 
-	pub fn fetch(&self) -> u16 {
-		let raw_instr = MEMORY.read(self.read(Register::PC));
-		self.write(
-			Register::PC,
-			self.read(Register::PC).wrapping_add(1)
-		);
+	pub fn native_traps(&self) -> bool {
+		self.native_traps
+	}
+
+	/// run TRAP through the built-in service routines or, if 'native'
+	/// is false, through the trap vector table at x0000-x00FF
+	pub fn set_native_traps(&mut self, native: bool) {
+		self.native_traps = native;
+	}
+
+	/// whether the instruction at PC is a built-in GETC or IN, which
+	/// blocks until the console has a key
+	pub fn reads_key(&self, memory: &Memory) -> bool {
+		let raw_instr = memory.peek(self.read(Register::PC));
+		self.native_traps
+			&& raw_instr >> 12 == 0b1111
+			&& matches!(raw_instr & 0xff, 0x20 | 0x23)
+	}
+
+	pub fn fetch(&mut self, memory: &Memory) -> u16 {
+		// fetching isn't a data access, so bypass watchpoints
+		let pc = self.read(Register::PC);
+		let raw_instr = memory.peek(pc);
+		self.advance_pc(pc.wrapping_add(1));
 		raw_instr
 	}
 
+	/// write the PC of the next instruction in sequence; unlike a
+	/// control transfer it only triggers watchpoints on its value
+	fn advance_pc(&mut self, data: u16) {
+		let old = std::mem::replace(&mut self.regs[Register::PC as usize], data);
+		if !self.observed {
+			return;
+		}
+		self.watchpoints.pc_advance(data);
+		self.tracer.register_write(Register::PC, old, data);
+		self.undo.register_write(Register::PC, old, data);
+	}
+
 	/// sign extend 'low' bits of 'data' to 16-bit integer
 	fn sign_extend_16(data: u16, low: u16) -> u16 {
 		let mut mask = 0_u16;
@@ -100,23 +181,80 @@ impl Cpu {
 		data & mask
 	}
 
-	fn update_condition_reg(&self, result: u16) {
-		if result == 0 {
-			self.write(Register::Cond, 0b010);	/* zero */
-			return;
+	fn update_condition_reg(&mut self, result: u16) {
+		let condition = if result == 0 {
+			psr::ZERO
+		} else if (result >> 15) & 1 == 1 {
+			psr::NEGATIVE
+		} else {
+			psr::POSITIVE
+		};
+		let psr = self.read(Register::Psr);
+		self.write(Register::Psr, psr::with_condition(psr, condition));
+	}
+
+	/// enter the service routine of 'vector' in supervisor mode,
+	/// saving PSR and PC on the supervisor stack; an interrupt raises
+	/// the priority level to its 'priority', an exception keeps it
+	pub fn interrupt(&mut self, vector: u8, priority: Option<u16>, memory: &mut Memory) {
+		let routine = memory.read(INTERRUPT_VECTOR_TABLE + vector as u16);
+		self.enter_supervisor(routine, priority, memory);
+	}
+
+	/// switch to the supervisor stack, push PSR and PC and continue at
+	/// 'routine' in supervisor mode, as interrupts and TRAPs do
+	fn enter_supervisor(&mut self, routine: u16, priority: Option<u16>, memory: &mut Memory) {
+		let psr = self.read(Register::Psr);
+		if psr::is_user_mode(psr) {
+			self.write(Register::SavedUsp, self.read(Register::R6));
+			self.write(Register::R6, self.read(Register::SavedSsp));
 		}
 
-		match (result >> 15) & 1 {
-			0 => self.write(Register::Cond, 0b001),	/* positive */
-			1 => self.write(Register::Cond, 0b100),	/* negative */
-			_ => unreachable!(),
+		// push PSR, then PC
+		let sp = self.read(Register::R6).wrapping_sub(2);
+		memory.write(sp.wrapping_add(1), psr);
+		memory.write(sp, self.read(Register::PC));
+		self.write(Register::R6, sp);
+
+		let priority = match priority {
+			Some(priority) => (priority << 8) & psr::PRIORITY,
+			None => psr & psr::PRIORITY,
+		};
+		self.write(Register::Psr, priority | (psr & psr::CONDITION));
+		self.write(Register::PC, routine);
+	}
+
+	/// address of the instruction being executed, i.e. the one just
+	/// fetched
+	fn current_pc(&self) -> u16 {
+		self.read(Register::PC).wrapping_sub(1)
+	}
+
+	pub fn decode(&self, raw_instr: u16) -> Result<Instruction, Fault> {
+		Cpu::decode_word(raw_instr).ok_or(Fault::IllegalOpcode {
+			pc: self.current_pc(),
+			instr: raw_instr,
+		})
+	}
+
+	/// decode 'raw_instr', fetched from 'pc', reusing the instruction
+	/// 'memory' remembers if the word wasn't written since it was last
+	/// decoded
+	pub fn decode_at(&self, pc: u16, raw_instr: u16, memory: &mut Memory) -> Result<Instruction, Fault> {
+		if let Some(instr) = memory.decoded(pc) {
+			return Ok(instr);
 		}
+		let instr = self.decode(raw_instr)?;
+		memory.remember_decoded(pc, instr);
+		Ok(instr)
 	}
 
-	pub fn decode(&self, raw_instr: u16) -> Instruction {
+	/// decode 'raw_instr' without a machine, e.g. to disassemble it;
+	/// None if its opcode is reserved
+	pub fn decode_word(raw_instr: u16) -> Option<Instruction> {
 		let opcode = raw_instr >> 12;
 
-		match opcode {
+		let instr = match opcode {
 			0b0000 => Cpu::decode_br(raw_instr),
 			0b0001 => Cpu::decode_add(raw_instr),
 			0b0010 => Cpu::decode_ld(raw_instr),
@@ -130,11 +268,12 @@ impl Cpu {
 			0b1010 => Cpu::decode_ldi(raw_instr),
 			0b1011 => Cpu::decode_sti(raw_instr),
 			0b1100 => Cpu::decode_jmp(raw_instr),
-			0b1101 => Cpu::decode_res(raw_instr),
 			0b1110 => Cpu::decode_lea(raw_instr),
 			0b1111 => Cpu::decode_trap(raw_instr),
-			_ => unreachable!(),
-		}
+			/* 0b1101 is reserved */
+			_ => return None,
+		};
+		Some(instr)
 	}
 
 	fn decode_br(raw_instr: u16) -> Instruction {
@@ -144,6 +283,7 @@ impl Cpu {
 		let offset = Self::sign_extend_16(raw_instr, 9);
 
 		Instruction::new(
+			raw_instr,
 			OpCode::BR,
 			None,
 			[None, None, None],
@@ -153,14 +293,15 @@ impl Cpu {
 	}
 
 	fn decode_add(raw_instr: u16) -> Instruction {
-		let dest_reg = Register::from((raw_instr >> 9) & 0b111);
-		let src_reg1 = Register::from((raw_instr >> 6) & 0b111);
+		let dest_reg = Register::gpr(raw_instr >> 9);
+		let src_reg1 = Register::gpr(raw_instr >> 6);
 		let imm_flag = ((raw_instr >> 5) & 1) == 1;
 
-		if imm_flag == false {
-			let src_reg2 = Register::from(raw_instr & 0b111);
+		if !imm_flag {
+			let src_reg2 = Register::gpr(raw_instr);
 
 			Instruction::new(
+				raw_instr,
 				OpCode::ADDR,
 				Some(imm_flag),
 				[Some(dest_reg), Some(src_reg1), Some(src_reg2)],
@@ -171,6 +312,7 @@ impl Cpu {
 			let imm = Self::sign_extend_16(raw_instr, 5);
 
 			Instruction::new(
+				raw_instr,
 				OpCode::ADDI,
 				Some(imm_flag),
 				[Some(dest_reg), Some(src_reg1), None],
@@ -181,10 +323,11 @@ impl Cpu {
 	}
 
 	fn decode_ld(raw_instr: u16) -> Instruction {
-		let dr = Register::from((raw_instr >> 9) & 0b111);
+		let dr = Register::gpr(raw_instr >> 9);
 		let offset = Self::sign_extend_16(raw_instr, 9);
 
 		Instruction::new(
+			raw_instr,
 			OpCode::LD,
 			None,
 			[Some(dr), None, None],
@@ -194,10 +337,11 @@ impl Cpu {
 	}
 
 	fn decode_st(raw_instr: u16) -> Instruction {
-		let sr = Register::from((raw_instr >> 9) & 0b111);
+		let sr = Register::gpr(raw_instr >> 9);
 		let offset = Self::sign_extend_16(raw_instr, 9);
 
 		Instruction::new(
+			raw_instr,
 			OpCode::ST,
 			None,
 			[Some(sr), None, None],
@@ -209,9 +353,10 @@ impl Cpu {
 	fn decode_jsr(raw_instr: u16) -> Instruction {
 		let imm_flag: bool = ((raw_instr >> 11) & 1) == 1;
 
-		if imm_flag == true {
+		if imm_flag {
 			let offset = Self::sign_extend_16(raw_instr, 11);
 			Instruction::new(
+				raw_instr,
 				OpCode::JSR,
 				Some(imm_flag),
 				[None, None, None],
@@ -219,8 +364,9 @@ impl Cpu {
 				[None, None, None],
 			)
 		} else {
-			let base_reg = Register::from((raw_instr >> 6) & 0b111);
+			let base_reg = Register::gpr(raw_instr >> 6);
 			Instruction::new(
+				raw_instr,
 				OpCode::JSRR,
 				Some(imm_flag),
 				[Some(base_reg), None, None],
@@ -231,13 +377,14 @@ impl Cpu {
 	}
 
 	fn decode_and(raw_instr: u16) -> Instruction {
-		let dr = Register::from((raw_instr >> 9) & 0b111);
-		let sr1 = Register::from((raw_instr >> 6) & 0b111);
+		let dr = Register::gpr(raw_instr >> 9);
+		let sr1 = Register::gpr(raw_instr >> 6);
 		let imm_flag = ((raw_instr >> 5) & 1) == 1;
 
-		if imm_flag == false {
-			let sr2 = Register::from(raw_instr & 0b111);
+		if !imm_flag {
+			let sr2 = Register::gpr(raw_instr);
 			Instruction::new(
+				raw_instr,
 				OpCode::ANDR,
 				Some(imm_flag),
 				[Some(dr), Some(sr1), Some(sr2)],
@@ -247,6 +394,7 @@ impl Cpu {
 		} else {
 			let imm = Self::sign_extend_16(raw_instr, 5);
 			Instruction::new(
+				raw_instr,
 				OpCode::ANDI,
 				Some(imm_flag),
 				[Some(dr), Some(sr1), None],
@@ -257,11 +405,12 @@ impl Cpu {
 	}
 
 	fn decode_ldr(raw_instr: u16) -> Instruction {
-		let dr = Register::from((raw_instr >> 9) & 0b111);
-		let base_reg = Register::from((raw_instr >> 6) & 0b111);
+		let dr = Register::gpr(raw_instr >> 9);
+		let base_reg = Register::gpr(raw_instr >> 6);
 		let offset = Self::sign_extend_16(raw_instr, 6);
 
 		Instruction::new(
+			raw_instr,
 			OpCode::LDR,
 			None,
 			[Some(dr), Some(base_reg), None],
@@ -271,11 +420,12 @@ impl Cpu {
 	}
 
 	fn decode_str(raw_instr: u16) -> Instruction {
-		let sr = Register::from((raw_instr >> 9) & 0b111);
-		let base_reg = Register::from((raw_instr >> 6) & 0b111);
+		let sr = Register::gpr(raw_instr >> 9);
+		let base_reg = Register::gpr(raw_instr >> 6);
 		let offset = Self::sign_extend_16(raw_instr, 6);
 
 		Instruction::new(
+			raw_instr,
 			OpCode::STR,
 			None,
 			[Some(sr), Some(base_reg), None],
@@ -284,8 +434,9 @@ impl Cpu {
 		)
 	}
 
-	fn decode_rti(_raw_instr: u16) -> Instruction {
+	fn decode_rti(raw_instr: u16) -> Instruction {
 		Instruction::new(
+			raw_instr,
 			OpCode::RTI,
 			None,
 			[None, None, None],
@@ -295,10 +446,11 @@ impl Cpu {
 	}
 
 	fn decode_not(raw_instr: u16) -> Instruction {
-		let dr = Register::from((raw_instr >> 9) & 0b111);
-		let sr = Register::from((raw_instr >> 6) & 0b111);
+		let dr = Register::gpr(raw_instr >> 9);
+		let sr = Register::gpr(raw_instr >> 6);
 
 		Instruction::new(
+			raw_instr,
 			OpCode::NOT,
 			None,
 			[Some(dr), Some(sr), None],
@@ -308,10 +460,11 @@ impl Cpu {
 	}
 
 	fn decode_ldi(raw_instr: u16) -> Instruction {
-		let dr = Register::from((raw_instr >> 9) & 0b111);
+		let dr = Register::gpr(raw_instr >> 9);
 		let offset = Self::sign_extend_16(raw_instr, 9);
 
 		Instruction::new(
+			raw_instr,
 			OpCode::LDI,
 			None,
 			[Some(dr), None, None],
@@ -321,10 +474,11 @@ impl Cpu {
 	}
 
 	fn decode_sti(raw_instr: u16) -> Instruction {
-		let sr = Register::from((raw_instr >> 9) & 0b111);
+		let sr = Register::gpr(raw_instr >> 9);
 		let offset = Self::sign_extend_16(raw_instr, 9);
 
 		Instruction::new(
+			raw_instr,
 			OpCode::STI,
 			None,
 			[Some(sr), None, None],
@@ -334,9 +488,10 @@ impl Cpu {
 	}
 
 	fn decode_jmp(raw_instr: u16) -> Instruction {
-		let base_reg = Register::from((raw_instr >> 6) & 0b111);
+		let base_reg = Register::gpr(raw_instr >> 6);
 
 		Instruction::new(
+			raw_instr,
 			OpCode::JMP,
 			None,
 			[Some(base_reg), None, None],
@@ -345,21 +500,12 @@ impl Cpu {
 		)
 	}
 
-	fn decode_res(_raw_instr: u16) -> Instruction {
-		Instruction::new(
-			OpCode::RTI,
-			None,
-			[None, None, None],
-			None,
-			[None, None, None],
-		)
-	}
-
 	fn decode_lea(raw_instr: u16) -> Instruction {
-		let dr = Register::from((raw_instr >> 9) & 0b111);
+		let dr = Register::gpr(raw_instr >> 9);
 		let offset = Self::sign_extend_16(raw_instr, 9);
 
 		Instruction::new(
+			raw_instr,
 			OpCode::LEA,
 			None,
 			[Some(dr), None, None],
@@ -372,6 +518,7 @@ impl Cpu {
 		let trapvect = Self::zero_extend_16(raw_instr, 8);
 
 		Instruction::new(
+			raw_instr,
 			OpCode::TRAP,
 			None,
 			[None, None, None],
@@ -380,14 +527,12 @@ impl Cpu {
 		)
 	}
 
-	pub fn execute(&self, instr: Instruction) {
-		let mut begin = None;
-		if ARGS.summary() == true {
-			begin = Some(Instant::now());
-		}
-
-		let opcode = instr.opcode();
-		match opcode {
+	pub fn execute(
+		&mut self,
+		instr: Instruction,
+		memory: &mut Memory,
+	) -> Result<(), Fault> {
+		match instr.opcode() {
 			OpCode::ADDR => self.execute_addr(instr),
 			OpCode::ADDI => self.execute_addi(instr),
 			OpCode::ANDR => self.execute_andr(instr),
@@ -396,33 +541,26 @@ impl Cpu {
 			OpCode::JMP => self.execute_jmp(instr),
 			OpCode::JSR => self.execute_jsr(instr),
 			OpCode::JSRR => self.execute_jsrr(instr),
-			OpCode::LD => self.execute_ld(instr),
-			OpCode::LDI => self.execute_ldi(instr),
-			OpCode::LDR => self.execute_ldr(instr),
+			OpCode::LD => self.execute_ld(instr, memory),
+			OpCode::LDI => self.execute_ldi(instr, memory),
+			OpCode::LDR => self.execute_ldr(instr, memory),
 			OpCode::LEA => self.execute_lea(instr),
 			OpCode::NOT => self.execute_not(instr),
-			OpCode::RES => self.execute_res(instr),
-			OpCode::RTI => self.execute_rti(instr),
-			OpCode::ST => self.execute_st(instr),
-			OpCode::STI => self.execute_sti(instr),
-			OpCode::STR => self.execute_str(instr),
-			OpCode::TRAP => self.handle_trap(instr),
-			_ => unimplemented!(),
-		}
-
-		let mut end = None;
-		if ARGS.summary() == true {
-			end = Some(Instant::now());
-		}
-
-		if ARGS.summary() == true {
-			SUMMARY.add_record(
-				opcode, 1, end.unwrap() - begin.unwrap()
-			);
+			OpCode::RTI => return self.execute_rti(instr, memory),
+			OpCode::ST => self.execute_st(instr, memory),
+			OpCode::STI => self.execute_sti(instr, memory),
+			OpCode::STR => self.execute_str(instr, memory),
+			OpCode::TRAP => return self.handle_trap(instr, memory),
+			/* never produced by decode */
+			OpCode::RES | OpCode::RET => return Err(Fault::IllegalOpcode {
+				pc: self.current_pc(),
+				instr: instr.raw(),
+			}),
 		}
+		Ok(())
 	}
 
-	fn execute_addr(&self, instr: Instruction) {
+	fn execute_addr(&mut self, instr: Instruction) {
 		let regs = instr.regs();
 		let (dr, sr1, sr2) = (
 			regs[0].unwrap(),
@@ -435,7 +573,7 @@ impl Cpu {
 		self.update_condition_reg(result);
 	}
 
-	fn execute_addi(&self, instr: Instruction) {
+	fn execute_addi(&mut self, instr: Instruction) {
 		let regs = instr.regs();
 		let (dr, sr1, imm) = (
 			regs[0].unwrap(),
@@ -448,7 +586,7 @@ impl Cpu {
 		self.update_condition_reg(result);
 	}
 
-	fn execute_andr(&self, instr: Instruction) {
+	fn execute_andr(&mut self, instr: Instruction) {
 		let regs = instr.regs();
 		let (dr, sr1, sr2) = (
 			regs[0].unwrap(),
@@ -461,7 +599,7 @@ impl Cpu {
 		self.update_condition_reg(result);
 	}
 
-	fn execute_andi(&self, instr: Instruction) {
+	fn execute_andi(&mut self, instr: Instruction) {
 		let regs = instr.regs();
 		let (dr, sr1) = (regs[0].unwrap(), regs[1].unwrap());
 		let imm = instr.imm().unwrap();
@@ -471,8 +609,8 @@ impl Cpu {
 		self.update_condition_reg(result);
 	}
 
-	fn execute_br(&self, instr: Instruction) {
-		let cond = self.read(Register::Cond);
+	fn execute_br(&mut self, instr: Instruction) {
+		let cond = self.read(Register::Psr) & psr::CONDITION;
 		let nzp = instr.nzp();
 		let (n, z, p) = (
 			nzp[0].unwrap(),
@@ -481,7 +619,7 @@ impl Cpu {
 		);
 		let offset = instr.imm().unwrap();
 
-		if (n && (cond == 1 << 2)) || (z && (cond == 1 << 1)) || (p && (cond == 1 << 0)) {
+		if (n && cond & psr::NEGATIVE != 0) || (z && cond & psr::ZERO != 0) || (p && cond & psr::POSITIVE != 0) {
 			self.write(
 				Register::PC,
 				self.read(Register::PC).wrapping_add(offset)
@@ -489,7 +627,7 @@ impl Cpu {
 		}
 	}
 
-	fn execute_jmp(&self, instr: Instruction) {
+	fn execute_jmp(&mut self, instr: Instruction) {
 		let target_addr = self.read(
 			instr.regs()[0].unwrap()
 		);
@@ -497,7 +635,7 @@ impl Cpu {
 		self.write(Register::PC, target_addr);
 	}
 
-	fn execute_jsr(&self, instr: Instruction) {
+	fn execute_jsr(&mut self, instr: Instruction) {
 		self.write(Register::R7, self.read(Register::PC));
 		let offset = instr.imm().unwrap();
 		self.write(
@@ -506,28 +644,28 @@ impl Cpu {
 		);
 	}
 
-	fn execute_jsrr(&self, instr: Instruction) {
+	fn execute_jsrr(&mut self, instr: Instruction) {
 		let base_reg = instr.regs()[0].unwrap();
 		let target = self.read(base_reg);
 		self.write(Register::R7, self.read(Register::PC));
 		self.write(Register::PC, target);
 	}
 
-	fn execute_ld(&self, instr: Instruction) {
+	fn execute_ld(&mut self, instr: Instruction, memory: &mut Memory) {
 		let dr = instr.regs()[0].unwrap();
 		let offset = instr.imm().unwrap();
 		let target_addr = self.read(Register::PC).wrapping_add(offset);
-		let data = MEMORY.read(target_addr);
+		let data = memory.read(target_addr);
 		self.write(dr, data);
 		self.update_condition_reg(data);
 	}
 
-	fn execute_ldi(&self, instr: Instruction) {
-		let target_addr = MEMORY.read(
+	fn execute_ldi(&mut self, instr: Instruction, memory: &mut Memory) {
+		let target_addr = memory.read(
 			self.read(Register::PC).wrapping_add(instr.imm().unwrap())
 		);
 
-		let result = MEMORY.read(target_addr);
+		let result = memory.read(target_addr);
 		
 		let dr = instr.regs()[0].unwrap();
 		self.write(dr, result);
@@ -535,21 +673,21 @@ impl Cpu {
 		self.update_condition_reg(result);
 	}
 
-	fn execute_ldr(&self, instr: Instruction) {
+	fn execute_ldr(&mut self, instr: Instruction, memory: &mut Memory) {
 		let regs = instr.regs();
 		let (dr, base_reg) = (
 			regs[0].unwrap(),
 			regs[1].unwrap()
 		);
 		let offset = instr.imm().unwrap();
-		let data = MEMORY.read(
+		let data = memory.read(
 			self.read(base_reg).wrapping_add(offset)
 		);
 		self.write(dr, data);
 		self.update_condition_reg(data);
 	}
 
-	fn execute_lea(&self, instr: Instruction) {
+	fn execute_lea(&mut self, instr: Instruction) {
 		let dr = instr.regs()[0].unwrap();
 		let offset = instr.imm().unwrap();
 		let result = self.read(Register::PC).wrapping_add(offset);
@@ -557,7 +695,7 @@ impl Cpu {
 		self.update_condition_reg(result);
 	}
 
-	fn execute_not(&self, instr: Instruction) {
+	fn execute_not(&mut self, instr: Instruction) {
 		let regs = instr.regs();
 		let (dr, sr) = (regs[0].unwrap(), regs[1].unwrap());
 		let result = !self.read(sr);
@@ -565,33 +703,48 @@ impl Cpu {
 		self.update_condition_reg(result);
 	}
 
-	fn execute_res(&self, _instr: Instruction) {
-		unimplemented!("This operation isn't allowed in vlc3");
-	}
+	fn execute_rti(&mut self, instr: Instruction, memory: &mut Memory) -> Result<(), Fault> {
+		let psr = self.read(Register::Psr);
+		if psr::is_user_mode(psr) {
+			return Err(Fault::PrivilegeViolation {
+				pc: self.current_pc(),
+				instr: instr.raw(),
+			});
+		}
+
+		// pop PC and PSR off the supervisor stack
+		let sp = self.read(Register::R6);
+		self.write(Register::PC, memory.read(sp));
+		let psr = memory.read(sp.wrapping_add(1));
+		self.write(Register::Psr, psr);
+		self.write(Register::R6, sp.wrapping_add(2));
 
-	fn execute_rti(&self, _instr: Instruction) {
-		unimplemented!("This operation isn't allowed in vlc3");
+		if psr::is_user_mode(psr) {
+			self.write(Register::SavedSsp, self.read(Register::R6));
+			self.write(Register::R6, self.read(Register::SavedUsp));
+		}
+		Ok(())
 	}
 
-	fn execute_st(&self, instr: Instruction) {
+	fn execute_st(&mut self, instr: Instruction, memory: &mut Memory) {
 		let sr = instr.regs()[0].unwrap();
 		let offset = instr.imm().unwrap();
 		let addr = self.read(Register::PC).wrapping_add(offset);
-		MEMORY.write(addr, self.read(sr));
+		memory.write(addr, self.read(sr));
 	}
 
-	fn execute_sti(&self, instr: Instruction) {
+	fn execute_sti(&mut self, instr: Instruction, memory: &mut Memory) {
 		let sr = instr.regs()[0].unwrap();
 		let offset = instr.imm().unwrap();
 
-		let target_addr = MEMORY
-			.read(self.read(Register::PC))
-			.wrapping_add(offset);
+		let target_addr = memory.read(
+			self.read(Register::PC).wrapping_add(offset)
+		);
 
-		MEMORY.write(target_addr, self.read(sr));
+		memory.write(target_addr, self.read(sr));
 	}
 
-	fn execute_str(&self, instr: Instruction) {
+	fn execute_str(&mut self, instr: Instruction, memory: &mut Memory) {
 		let regs = instr.regs();
 		let (sr, base_reg) = (
 			regs[0].unwrap(),
@@ -600,80 +753,298 @@ impl Cpu {
 		let offset = instr.imm().unwrap();
 
 		let addr = self.read(base_reg).wrapping_add(offset);
-		MEMORY.write(addr, self.read(sr));
+		memory.write(addr, self.read(sr));
 	}
 
-	fn handle_trap(&self, instr: Instruction) {
-		self.write(Register::R7, self.read(Register::PC));
+	fn handle_trap(
+		&mut self,
+		instr: Instruction,
+		memory: &mut Memory,
+	) -> Result<(), Fault> {
 		let trapvect = instr.imm().unwrap();
+		let return_addr = self.read(Register::PC);
+
+		if !self.native_traps() {
+			// the routine returns with RTI or, as R7 holds the return
+			// address too, with RET; an empty entry leads to x0000
+			let routine = memory.read(trapvect);
+			self.enter_supervisor(routine, None, memory);
+			self.write(Register::R7, return_addr);
+			return Ok(());
+		}
 
 		match trapvect {
-			0x20 => self.handle_trap_getc(),	/* get character but not echo it */
-			0x21 => self.handle_trap_out(),		/* output a character */
-			0x22 => self.handle_trap_puts(),	/* output a word string */
-			0x23 => self.handle_trap_in(),		/* get character and echo it */
-			0x24 => self.handle_trap_putsp(),	/* output a byte string */
-			0x25 => self.halt(),				/* halt the vm */
-			_ => unreachable!(),
+			0x20 => self.handle_trap_getc()?,	/* get character but not echo it */
+			0x21 => self.handle_trap_out()?,	/* output a character */
+			0x22 => self.handle_trap_puts(memory)?,	/* output a word string */
+			0x23 => self.handle_trap_in()?,		/* get character and echo it */
+			0x24 => self.handle_trap_putsp(memory)?,	/* output a byte string */
+			0x25 => self.halt()?,				/* halt the vm */
+			_ => return Err(Fault::UnknownTrap {
+				pc: self.current_pc(),
+				instr: instr.raw(),
+			}),
 		}
+
+		self.write(Register::R7, return_addr);
+		Ok(())
 	}
 
-	fn handle_trap_getc(&self) {
-		let ch;
-		unsafe {
-			ch = libc::getchar() as u16;
-		}
+	fn handle_trap_getc(&mut self) -> Result<(), Fault> {
+		let ch = match self.console.read_key()? {
+			Some(ch) => ch as u16,
+			None => return Err(Fault::Io(io::Error::new(
+				io::ErrorKind::UnexpectedEof,
+				"no more input to read",
+			))),
+		};
 		self.write(Register::R0, ch);
 		self.update_condition_reg(ch);
+		Ok(())
 	}
 
-	fn handle_trap_out(&self) {
+	fn handle_trap_out(&mut self) -> Result<(), Fault> {
 		let ch = self.read(Register::R0) & 0xff;
-		print!("{}", char::from(ch as u8));
-		let _ = io::stdout().flush();
+		self.console.write(char::from(ch as u8).to_string().as_bytes())?;
+		Ok(())
 	}
 
-	fn handle_trap_puts(&self) {
+	fn handle_trap_puts(&mut self, memory: &mut Memory) -> Result<(), Fault> {
 		let start_addr = self.read(Register::R0);
 		let s = (start_addr..)
-			.take_while(|&addr| {
-				0 != MEMORY.read(addr)
-			})
-			.map(|addr| {
-				let ch = MEMORY.read(addr);
-				char::from(ch as u8)
-			})
+			.map(|addr| memory.read(addr))
+			.take_while(|&ch| ch != 0)
+			.map(|ch| char::from(ch as u8))
 			.collect::<String>();
-		print!("{s}");
-		let _ = io::stdout().flush();
+		self.console.write(s.as_bytes())?;
+		Ok(())
 	}
 
-	fn handle_trap_in(&self) {
-		self.handle_trap_getc();
-		self.handle_trap_out();
+	fn handle_trap_in(&mut self) -> Result<(), Fault> {
+		self.handle_trap_getc()?;
+		self.handle_trap_out()
 	}
 
-	fn handle_trap_putsp(&self) {
+	fn handle_trap_putsp(&mut self, memory: &mut Memory) -> Result<(), Fault> {
 		let start_addr = self.read(Register::R0);
 		let s = (start_addr..)
-			.take_while(|&addr| 0 != {
-				MEMORY.read(addr)
-			})
-			.map(|num| {
-				let c1 = char::from((num & 0xff) as u8);
-				let c2 = char::from((num >> 8) as u8);
-				format!("{}{}", c1.to_string(), c2.to_string())
-			})
+			.map(|addr| memory.read(addr))
+			.take_while(|&num| num != 0)
+			.flat_map(|num| [num & 0xff, num >> 8])
+			.take_while(|&ch| ch != 0)
+			.map(|ch| char::from(ch as u8))
 			.collect::<String>();
-		print!("{s}");
-		let _ = io::stdout().flush();
+		self.console.write(s.as_bytes())?;
+		Ok(())
+	}
+
+	fn halt(&mut self) -> Result<(), Fault> {
+		self.console.write(b"HALT\n")?;
+		self.running = false;
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::asm;
+	use crate::console::{Capture, Headless};
+	use crate::machine::Machine;
+
+	/// a machine with 'source' loaded, about to run it
+	fn load(source: &str) -> Machine {
+		let object = asm::assemble(source).unwrap().to_object();
+		let mut machine = Machine::new();
+		machine.set_console(Headless::new(Vec::new(), Capture::new()));
+		machine.load(&object).unwrap();
+		machine
+	}
+
+	/// run 'source' to its end, returning the machine and its output
+	fn run(source: &str) -> (Machine, Vec<u8>) {
+		let output = Capture::new();
+		let mut machine = load(source);
+		machine.set_console(Headless::new(Vec::new(), output.clone()));
+		machine.run().unwrap();
+		(machine, output.contents())
+	}
+
+	#[test]
+	fn sti_stores_through_the_pointer_at_pc_offset() {
+		let (machine, _) = run("
+        .ORIG x3000
+        ADD R0, R0, #7
+        STI R0, PTR
+        HALT
+PTR     .FILL x4000
+        .END
+		");
+		assert_eq!(machine.peek(0x4000), 7);
+		// the pointer and the word after the PC are left alone
+		assert_eq!(machine.peek(0x3003), 0x4000);
+		assert_eq!(machine.peek(0x3002), 0xf025);
+	}
+
+	#[test]
+	fn ldi_and_sti_use_the_same_pointer() {
+		let (machine, _) = run("
+        .ORIG x3000
+        ADD R0, R0, #5
+        STI R0, PTR
+        LDI R1, PTR
+        HALT
+PTR     .FILL x4000
+        .END
+		");
+		assert_eq!(machine.reg(Register::R1), 5);
+	}
+
+	#[test]
+	fn putsp_prints_the_bytes_of_each_word() {
+		let (_, output) = run("
+        .ORIG x3000
+        LEA R0, TEXT
+        PUTSP
+        LEA R0, ODD
+        PUTSP
+        HALT
+TEXT    .FILL x6568         ; 'he'
+        .FILL x6C6C         ; 'll'
+        .FILL x0000
+ODD     .FILL x6261         ; 'ab'
+        .FILL x0063         ; 'c', the high byte ends the string
+        .FILL x0000
+        .END
+		");
+		assert_eq!(output, b"hellabcHALT\n");
+	}
+
+	#[test]
+	fn condition_codes_are_kept_in_the_psr() {
+		let mut machine = load("
+        .ORIG x3000
+        ADD R0, R0, #-1
+        AND R0, R0, #0
+        ADD R0, R0, #1
+        HALT
+        .END
+		");
+		for condition in [psr::NEGATIVE, psr::ZERO, psr::POSITIVE] {
+			machine.step().unwrap();
+			let psr = machine.reg(Register::Psr);
+			assert_eq!(psr & psr::CONDITION, condition);
+			// the mode and the priority are left alone
+			assert_eq!(psr & !psr::CONDITION, psr::USER_MODE);
+		}
 	}
 
-	fn halt(&self) {
-		println!("HALT");
-		self.inner
-			.lock()
-			.unwrap()
-			.running = false;
+	#[test]
+	fn supervisor_mode_runs_on_its_own_stack() {
+		let mut cpu = Cpu::new();
+		let mut memory = Memory::new();
+		memory.write(INTERRUPT_VECTOR_TABLE + 0x80, 0x1000);
+		memory.write(0x1000, 0x8000);	/* RTI */
+		cpu.write(Register::PC, 0x3000);
+		cpu.write(Register::R6, 0x5000);
+
+		// entering supervisor mode saves the user stack pointer
+		cpu.interrupt(0x80, Some(4), &mut memory);
+		assert!(!psr::is_user_mode(cpu.read(Register::Psr)));
+		assert_eq!(cpu.read(Register::R6), 0x3000 - 2);
+		assert_eq!(cpu.read(Register::SavedUsp), 0x5000);
+
+		// and leaving it saves the supervisor stack pointer
+		let rti = cpu.fetch(&memory);
+		cpu.execute(cpu.decode(rti).unwrap(), &mut memory).unwrap();
+		assert!(psr::is_user_mode(cpu.read(Register::Psr)));
+		assert_eq!(cpu.read(Register::R6), 0x5000);
+		assert_eq!(cpu.read(Register::SavedSsp), 0x3000);
+		assert_eq!(cpu.read(Register::PC), 0x3000);
+	}
+
+	#[test]
+	fn rti_in_user_mode_is_a_privilege_violation() {
+		let mut machine = load(".ORIG x3000\nRTI\n.END");
+		assert!(matches!(
+			machine.step(),
+			Err(Fault::PrivilegeViolation { pc: 0x3000, instr: 0x8000 })
+		));
+		// nothing was popped
+		assert_eq!(machine.reg(Register::R6), 0);
+		assert_eq!(machine.reg(Register::Psr), psr::INITIAL);
+	}
+
+	/// enables keyboard interrupts and waits for one
+	const WAITING: &str = "
+        .ORIG x3000
+        LD R0, IE
+        STI R0, KBSRP
+LOOP    BRnzp LOOP
+IE      .FILL x4000
+KBSRP   .FILL xFE00
+        .END
+";
+
+	/// a machine running WAITING with a key typed and its interrupt
+	/// routine at x1000
+	fn waiting() -> Machine {
+		let mut machine = load(WAITING);
+		machine.set_console(Headless::new("a", Capture::new()));
+		machine.poke(INTERRUPT_VECTOR_TABLE + 0x80, 0x1000);
+		machine
+	}
+
+	#[test]
+	fn interrupts_push_psr_and_pc_on_the_supervisor_stack() {
+		let mut machine = waiting();
+		for _ in 0..3 {
+			machine.step().unwrap();
+		}
+		// interrupted at LOOP, after LD set P
+		assert_eq!(machine.reg(Register::PC), 0x1000);
+		assert_eq!(machine.reg(Register::R6), 0x2ffe);
+		assert_eq!(machine.peek(0x2ffe), 0x3002);
+		assert_eq!(machine.peek(0x2fff), psr::USER_MODE | psr::POSITIVE);
+		// in supervisor mode at the keyboard's priority
+		assert_eq!(machine.reg(Register::Psr), 4 << 8 | psr::POSITIVE);
+		assert_eq!(machine.reg(Register::SavedUsp), 0);
+	}
+
+	#[test]
+	fn interrupts_wait_for_a_lower_priority() {
+		let mut machine = waiting();
+		machine.set_reg(Register::Psr, psr::USER_MODE | 4 << 8);
+		for _ in 0..10 {
+			machine.step().unwrap();
+		}
+		assert_eq!(machine.reg(Register::PC), 0x3002);
+
+		let psr = machine.reg(Register::Psr);
+		machine.set_reg(Register::Psr, psr - (1 << 8));
+		machine.step().unwrap();
+		assert_eq!(machine.reg(Register::PC), 0x1000);
+		assert_eq!(psr::priority(machine.reg(Register::Psr)), 4);
+	}
+
+	#[test]
+	fn exceptions_vector_only_to_routines_that_exist() {
+		// RTI in user mode and a reserved opcode
+		let programs = [(".ORIG x3000\nRTI\n.END", 0x00), (".ORIG x3000\n.FILL xD000\n.END", 0x01)];
+		for (source, vector) in programs {
+			let mut machine = load(source);
+			let fault = machine.step().unwrap_err();
+			assert_eq!((fault.exception_vector(), fault.pc()), (Some(vector), Some(0x3000)));
+
+			let mut machine = load(source);
+			machine.poke(INTERRUPT_VECTOR_TABLE + vector as u16, 0x1000);
+			machine.step().unwrap();
+			assert_eq!(machine.reg(Register::PC), 0x1000);
+			assert_eq!(machine.reg(Register::R6), 0x2ffe);
+			assert_eq!(machine.peek(0x2ffe), 0x3001);
+			assert_eq!(machine.peek(0x2fff), psr::INITIAL);
+			// exceptions keep the priority
+			assert_eq!(machine.reg(Register::Psr), psr::ZERO);
+		}
 	}
 }
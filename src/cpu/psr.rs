@@ -0,0 +1,73 @@
+//! Fields of the processor status register: the privilege mode in bit
+//! 15, the priority level in bits 10-8 and the condition codes in
+//! bits 2-0.
+
+/// set while the processor runs in user mode
+pub const USER_MODE: u16 = 1 << 15;
+pub const PRIORITY: u16 = 0b111 << 8;
+pub const CONDITION: u16 = 0b111;
+
+pub const NEGATIVE: u16 = 0b100;
+pub const ZERO: u16 = 0b010;
+pub const POSITIVE: u16 = 0b001;
+
+/// user mode, priority 0, Z set
+pub const INITIAL: u16 = USER_MODE | ZERO;
+
+pub fn is_user_mode(psr: u16) -> bool {
+	psr & USER_MODE != 0
+}
+
+pub fn priority(psr: u16) -> u16 {
+	(psr & PRIORITY) >> 8
+}
+
+/// 'psr' with its condition codes replaced by 'condition'
+pub fn with_condition(psr: u16, condition: u16) -> u16 {
+	(psr & !CONDITION) | (condition & CONDITION)
+}
+
+/// the condition codes that are set, e.g. "NZ"
+pub fn flags(psr: u16) -> String {
+	[(NEGATIVE, 'N'), (ZERO, 'Z'), (POSITIVE, 'P')]
+		.iter()
+		.filter(|&&(bit, _)| psr & bit != 0)
+		.map(|&(_, flag)| flag)
+		.collect()
+}
+
+/// e.g. "user, priority 0, Z"
+pub fn describe(psr: u16) -> String {
+	format!(
+		"{}, priority {}, {}",
+		if is_user_mode(psr) { "user" } else { "supervisor" },
+		priority(psr),
+		flags(psr),
+	)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn fields_are_read_from_their_bits() {
+		let psr = USER_MODE | 5 << 8 | NEGATIVE;
+		assert!(is_user_mode(psr));
+		assert_eq!(priority(psr), 5);
+		assert_eq!(flags(psr), "N");
+		assert_eq!(describe(psr), "user, priority 5, N");
+
+		let psr = 4 << 8 | ZERO | POSITIVE;
+		assert!(!is_user_mode(psr));
+		assert_eq!(describe(psr), "supervisor, priority 4, ZP");
+	}
+
+	#[test]
+	fn conditions_replace_only_the_condition_codes() {
+		let psr = USER_MODE | 3 << 8 | POSITIVE;
+		assert_eq!(with_condition(psr, ZERO), USER_MODE | 3 << 8 | ZERO);
+		// other bits of 'condition' are ignored
+		assert_eq!(with_condition(psr, !CONDITION | NEGATIVE), USER_MODE | 3 << 8 | NEGATIVE);
+	}
+}
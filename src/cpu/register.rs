@@ -1,6 +1,6 @@
-use std::convert::From;
+use std::{convert::TryFrom, fmt};
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Register {
 	R0,
 	R1,
@@ -11,25 +11,65 @@ pub enum Register {
 	R6,
 	R7,
 	PC,
-	Cond,
+	/// processor status register, see [`psr`](super::psr)
+	Psr,
+	/// R6 of supervisor mode while in user mode
+	SavedSsp,
+	/// R6 of user mode while in supervisor mode
+	SavedUsp,
 	Count,
 }
 
-impl From<u16> for Register {
-	fn from(value: u16) -> Self {
+impl Register {
+	const GENERAL_PURPOSE: [Register; 8] = [
+		Self::R0,
+		Self::R1,
+		Self::R2,
+		Self::R3,
+		Self::R4,
+		Self::R5,
+		Self::R6,
+		Self::R7,
+	];
+
+	/// general purpose register encoded in the low 3 bits of 'field'
+	pub fn gpr(field: u16) -> Self {
+		Self::GENERAL_PURPOSE[(field & 0b111) as usize]
+	}
+}
+
+impl TryFrom<u16> for Register {
+	type Error = u16;
+
+	fn try_from(value: u16) -> Result<Self, Self::Error> {
 		match value {
-			0 => Self::R0,
-			1 => Self::R1,
-			2 => Self::R2,
-			3 => Self::R3,
-			4 => Self::R4,
-			5 => Self::R5,
-			6 => Self::R6,
-			7 => Self::R7,
-			8 => Self::PC,
-			9 => Self::Cond,
-			10 => Self::Count,
-			_ => panic!("Invalid register number"),
+			0 => Ok(Self::R0),
+			1 => Ok(Self::R1),
+			2 => Ok(Self::R2),
+			3 => Ok(Self::R3),
+			4 => Ok(Self::R4),
+			5 => Ok(Self::R5),
+			6 => Ok(Self::R6),
+			7 => Ok(Self::R7),
+			8 => Ok(Self::PC),
+			9 => Ok(Self::Psr),
+			10 => Ok(Self::SavedSsp),
+			11 => Ok(Self::SavedUsp),
+			12 => Ok(Self::Count),
+			_ => Err(value),
+		}
+	}
+}
+
+impl fmt::Display for Register {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::PC => write!(f, "PC"),
+			Self::Psr => write!(f, "PSR"),
+			Self::SavedSsp => write!(f, "SAVED_SSP"),
+			Self::SavedUsp => write!(f, "SAVED_USP"),
+			Self::Count => write!(f, "COUNT"),
+			general => write!(f, "{:?}", general),
 		}
 	}
 }
@@ -0,0 +1,1047 @@
+//! A Debug Adapter Protocol server.
+//!
+//! The client launches a program with `{"program": "prog.obj"}` and
+//! optionally `"source"` (default: the `.asm` next to the program),
+//! `"stopOnEntry"` and `"input"`, a string the program reads as its
+//! keyboard input. Lines are mapped to addresses by assembling the
+//! source again; labels come from it or from the program's `.sym`.
+//!
+//! There is a single thread with a single stack frame. As in the GDB
+//! stub, memory references are word addresses while offsets and counts
+//! in `readMemory` and `writeMemory` are bytes, two per word, low byte
+//! first.
+
+use crate::asm::{self, lexer, Assembly, SymbolTable};
+use crate::console::{Capture, Headless};
+use crate::cpu::{psr, register::Register};
+use crate::debugger::{self, Debugger, Resume};
+use crate::disasm;
+use crate::fault::Fault;
+use crate::machine::Machine;
+use serde_json::{json, Value};
+use std::{
+	collections::{BTreeSet, VecDeque},
+	fs,
+	io::{self, BufRead, BufReader, Read, Write},
+	path::{Path, PathBuf},
+	sync::mpsc::{self, Receiver, TryRecvError},
+	thread,
+};
+
+/// instructions executed between checks for requests, e.g. pause
+const POLL_INTERVAL: usize = 1024;
+
+/// longest message a client may send, in bytes
+const MAX_MESSAGE: usize = 1 << 20;
+
+/// most bytes a readMemory and instructions a disassemble request
+/// answer with: all of memory
+const MAX_COUNT: u64 = 0x10000;
+
+const THREAD_ID: u64 = 1;
+const FRAME_ID: u64 = 1;
+const REGISTERS_REF: u64 = 1;
+
+/// registers shown in the registers scope, followed by COND, the
+/// condition codes of the PSR
+const REGISTERS: [Register; 10] = [
+	Register::R0,
+	Register::R1,
+	Register::R2,
+	Register::R3,
+	Register::R4,
+	Register::R5,
+	Register::R6,
+	Register::R7,
+	Register::PC,
+	Register::Psr,
+];
+
+/// Why the program stopped running.
+enum Stop {
+	Done,
+	Breakpoint,
+	Paused,
+	Halted,
+	Fault(Fault),
+	/// the client went away or asked to end the session
+	Disconnected,
+}
+
+/// A launched program.
+struct Session {
+	machine: Machine,
+	/// the source file and its assembly, if the program has one
+	source: Option<(PathBuf, Assembly)>,
+	symbols: SymbolTable,
+	/// breakpoints set on source lines
+	line_breakpoints: BTreeSet<u16>,
+	/// breakpoints set on instructions, e.g. in a disassembly view
+	instruction_breakpoints: BTreeSet<u16>,
+	stop_on_entry: bool,
+	/// what the program wrote, sent to the client as output events
+	output: Capture,
+}
+
+/// Serves one client, driving a [`Machine`] on its behalf.
+pub struct DapServer<W: Write> {
+	writer: W,
+	requests: Receiver<Value>,
+	/// requests that arrived while the program was running
+	pending: VecDeque<Value>,
+	seq: u64,
+	/// number of the first line in the client's convention, 0 or 1
+	line_base: usize,
+	session: Option<Session>,
+}
+
+impl Session {
+	fn is_breakpoint(&self, addr: u16) -> bool {
+		self.line_breakpoints.contains(&addr) || self.instruction_breakpoints.contains(&addr)
+	}
+
+	/// an address given as a number or a label
+	fn location(&self, loc: &str) -> Option<u16> {
+		match lexer::number(loc) {
+			Some(addr) if (-0x8000..=0xffff).contains(&addr) => Some(addr as u16),
+			Some(_) => None,
+			None => self.symbols.get(loc),
+		}
+	}
+
+	/// the source line of 'addr', if it was assembled from one
+	fn line_of(&self, addr: u16) -> Option<usize> {
+		self.source.as_ref()?.1.line_of(addr)
+	}
+
+	/// the first address assembled from 'line' or, for a line without
+	/// code, from the nearest line after it
+	fn addr_of(&self, line: usize) -> Option<(u16, usize)> {
+		let (_, assembly) = self.source.as_ref()?;
+		(0..assembly.words().len())
+			.map(|idx| assembly.origin().wrapping_add(idx as u16))
+			.filter_map(|addr| Some((addr, assembly.line_of(addr)?)))
+			.filter(|&(_, l)| l >= line)
+			.min_by_key(|&(_, l)| l)
+	}
+
+	/// whether the client's 'path' names this session's source
+	fn is_source(&self, path: &str) -> bool {
+		let source = match &self.source {
+			Some((source, _)) => source,
+			None => return false,
+		};
+		match (fs::canonicalize(source), fs::canonicalize(path)) {
+			(Ok(source), Ok(path)) => source == path,
+			_ => source == Path::new(path),
+		}
+	}
+
+	/// the nearest label at or before 'addr'
+	fn frame_name(&self, addr: u16) -> String {
+		self.symbols
+			.iter()
+			.filter(|&(_, label_addr)| label_addr <= addr)
+			.max_by_key(|&(_, label_addr)| label_addr)
+			.map(|(label, _)| String::from(label))
+			.unwrap_or_else(|| format!("x{:04X}", addr))
+	}
+}
+
+/// read one message framed by a Content-Length header, or None at the
+/// end of input; a message longer than [`MAX_MESSAGE`] is skipped and
+/// reported as invalid data
+fn read_message<R: BufRead>(reader: &mut R) -> io::Result<Option<Value>> {
+	let mut length = None;
+	loop {
+		let mut line = String::new();
+		if reader.read_line(&mut line)? == 0 {
+			return Ok(None);
+		}
+
+		let line = line.trim_end();
+		if line.is_empty() {
+			if length.is_some() {
+				break;
+			}
+		} else if let Some(value) = line.strip_prefix("Content-Length:") {
+			length = value.trim().parse::<usize>().ok();
+		}
+	}
+
+	let length = length.unwrap();
+	if length > MAX_MESSAGE {
+		io::copy(&mut reader.take(length as u64), &mut io::sink())?;
+		return Err(io::Error::new(
+			io::ErrorKind::InvalidData,
+			format!("a message of {} bytes is longer than {}", length, MAX_MESSAGE),
+		));
+	}
+
+	let mut body = vec![0; length];
+	reader.read_exact(&mut body)?;
+	serde_json::from_slice(&body)
+		.map(Some)
+		.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+impl<W: Write> DapServer<W> {
+	/// serve the client reading from 'reader' and writing to 'writer';
+	/// requests are read on a thread of their own so that they can
+	/// arrive while the program runs
+	pub fn new<R: Read + Send + 'static>(reader: R, writer: W) -> Self {
+		let (sender, requests) = mpsc::channel();
+		thread::spawn(move || {
+			let mut reader = BufReader::new(reader);
+			loop {
+				match read_message(&mut reader) {
+					Ok(Some(message)) => {
+						if sender.send(message).is_err() {
+							break;
+						}
+					}
+					Err(e) if e.kind() == io::ErrorKind::InvalidData => continue,
+					_ => break,
+				}
+			}
+		});
+
+		Self {
+			writer,
+			requests,
+			pending: VecDeque::new(),
+			seq: 0,
+			line_base: 1,
+			session: None,
+		}
+	}
+
+	/// answer requests until the client disconnects
+	pub fn serve(&mut self) -> io::Result<()> {
+		loop {
+			let request = match self.pending.pop_front() {
+				Some(request) => request,
+				None => match self.requests.recv() {
+					Ok(request) => request,
+					Err(_) => return Ok(()),
+				},
+			};
+
+			if request["type"] == "request" && !self.handle(&request)? {
+				return Ok(());
+			}
+		}
+	}
+
+	/// answer 'request', returning false once the session is over
+	fn handle(&mut self, request: &Value) -> io::Result<bool> {
+		let args = &request["arguments"];
+		let command = request["command"].as_str().unwrap_or("");
+
+		let mode = match command {
+			"continue" => Some(Resume::Continue),
+			"next" => Some(Resume::Next),
+			"stepIn" => Some(Resume::Step(1)),
+			"stepOut" => Some(Resume::Finish),
+			_ => None,
+		};
+		if let Some(mode) = mode {
+			if self.session.is_none() {
+				self.respond(request, Err(String::from("no program is running")))?;
+				return Ok(true);
+			}
+			let body = match mode {
+				Resume::Continue => json!({ "allThreadsContinued": true }),
+				_ => json!({}),
+			};
+			self.respond(request, Ok(body))?;
+			return self.resume(mode);
+		}
+
+		match command {
+			"initialize" => {
+				self.line_base = match args["linesStartAt1"].as_bool() {
+					Some(false) => 0,
+					_ => 1,
+				};
+				let capabilities = json!({
+					"supportsConfigurationDoneRequest": true,
+					"supportsSetVariable": true,
+					"supportsReadMemoryRequest": true,
+					"supportsWriteMemoryRequest": true,
+					"supportsDisassembleRequest": true,
+					"supportsInstructionBreakpoints": true,
+					"supportsTerminateRequest": true,
+					"supportsStepBack": true,
+				});
+				self.respond(request, Ok(capabilities))?;
+			}
+			"launch" => {
+				let result = self.launch(args);
+				let launched = result.is_ok();
+				self.respond(request, result)?;
+				if launched {
+					self.event("initialized", json!({}))?;
+				}
+			}
+			"configurationDone" => {
+				self.respond(request, Ok(json!({})))?;
+				let session = match &self.session {
+					Some(session) => session,
+					None => return Ok(true),
+				};
+				let pc = session.machine.reg(Register::PC);
+				if session.stop_on_entry {
+					self.stopped("entry", None)?;
+				} else if session.is_breakpoint(pc) {
+					self.stopped("breakpoint", None)?;
+				} else {
+					return self.resume(Resume::Continue);
+				}
+			}
+			"stepBack" | "reverseContinue" => {
+				if self.session.is_none() {
+					self.respond(request, Err(String::from("no program is running")))?;
+					return Ok(true);
+				}
+				self.respond(request, Ok(json!({})))?;
+				self.reverse(command == "stepBack")?;
+			}
+			"pause" => {
+				// only a running program can be paused
+				self.respond(request, Ok(json!({})))?;
+			}
+			"disconnect" | "terminate" => {
+				self.respond(request, Ok(json!({})))?;
+				if command == "terminate" {
+					self.event("terminated", json!({}))?;
+				}
+				return Ok(false);
+			}
+			_ => {
+				let result = self.query(command, args);
+				self.respond(request, result)?;
+			}
+		}
+		Ok(true)
+	}
+
+	/// answer a request which doesn't run the program
+	fn query(&mut self, command: &str, args: &Value) -> Result<Value, String> {
+		if command == "threads" {
+			return Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "LC-3" }] }));
+		}
+
+		let line_base = self.line_base;
+		let session = match &mut self.session {
+			Some(session) => session,
+			None => return Err(format!("'{}' needs a launched program", command)),
+		};
+		let machine = &session.machine;
+
+		match command {
+			"setBreakpoints" => {
+				let path = args["source"]["path"].as_str().unwrap_or("");
+				let lines = args["breakpoints"]
+					.as_array()
+					.map(|breakpoints| {
+						breakpoints
+							.iter()
+							.filter_map(|breakpoint| breakpoint["line"].as_u64())
+							.map(|line| (line as usize).saturating_add(1).saturating_sub(line_base))
+							.collect::<Vec<_>>()
+					})
+					.unwrap_or_default();
+
+				if !session.is_source(path) {
+					let breakpoints = lines
+						.iter()
+						.map(|_| json!({ "verified": false, "message": "not the program's source" }))
+						.collect::<Vec<_>>();
+					return Ok(json!({ "breakpoints": breakpoints }));
+				}
+
+				session.line_breakpoints.clear();
+				let breakpoints = lines
+					.iter()
+					.map(|&line| match session.addr_of(line) {
+						Some((addr, line)) => {
+							session.line_breakpoints.insert(addr);
+							json!({ "verified": true, "line": line - 1 + line_base })
+						}
+						None => json!({ "verified": false, "message": "no code at or after this line" }),
+					})
+					.collect::<Vec<_>>();
+				Ok(json!({ "breakpoints": breakpoints }))
+			}
+			"setInstructionBreakpoints" => {
+				session.instruction_breakpoints.clear();
+				let breakpoints = args["breakpoints"]
+					.as_array()
+					.cloned()
+					.unwrap_or_default()
+					.iter()
+					.map(|breakpoint| {
+						let addr = breakpoint["instructionReference"]
+							.as_str()
+							.and_then(|reference| session.location(reference))
+							.map(|addr| {
+								let offset = breakpoint["offset"].as_i64().unwrap_or(0);
+								addr.wrapping_add(offset as u16)
+							});
+						match addr {
+							Some(addr) => {
+								session.instruction_breakpoints.insert(addr);
+								json!({ "verified": true, "instructionReference": format!("0x{:04X}", addr) })
+							}
+							None => json!({ "verified": false, "message": "unknown instruction" }),
+						}
+					})
+					.collect::<Vec<_>>();
+				Ok(json!({ "breakpoints": breakpoints }))
+			}
+			"stackTrace" => {
+				let pc = machine.reg(Register::PC);
+				let mut frame = json!({
+					"id": FRAME_ID,
+					"name": session.frame_name(pc),
+					"line": 0,
+					"column": 0,
+					"instructionPointerReference": format!("0x{:04X}", pc),
+				});
+				if let (Some((path, _)), Some(line)) = (&session.source, session.line_of(pc)) {
+					frame["source"] = source(path);
+					frame["line"] = json!(line - 1 + line_base);
+					frame["column"] = json!(line_base);
+				}
+				Ok(json!({ "stackFrames": [frame], "totalFrames": 1 }))
+			}
+			"scopes" => Ok(json!({
+				"scopes": [{
+					"name": "Registers",
+					"presentationHint": "registers",
+					"variablesReference": REGISTERS_REF,
+					"expensive": false,
+				}],
+			})),
+			"variables" => {
+				if args["variablesReference"].as_u64() != Some(REGISTERS_REF) {
+					return Ok(json!({ "variables": [] }));
+				}
+				let variables = REGISTERS
+					.iter()
+					.map(|&register| register_variable(machine, register))
+					.chain(std::iter::once(condition_variable(machine)))
+					.collect::<Vec<_>>();
+				Ok(json!({ "variables": variables }))
+			}
+			"setVariable" => {
+				let name = args["name"].as_str().unwrap_or("");
+				let text = args["value"].as_str().unwrap_or("").trim();
+				if args["variablesReference"].as_u64() != Some(REGISTERS_REF) {
+					return Err(format!("unknown register '{}'", name));
+				}
+				if name == "COND" {
+					let codes = condition_codes(text)
+						.ok_or_else(|| format!("invalid condition codes '{}'", text))?;
+					let value = psr::with_condition(machine.reg(Register::Psr), codes);
+					session.machine.set_reg(Register::Psr, value);
+					return Ok(condition_variable(&session.machine));
+				}
+
+				let register = Debugger::register(name)
+					.ok_or_else(|| format!("unknown register '{}'", name))?;
+				match session.location(text) {
+					Some(value) => {
+						session.machine.set_reg(register, value);
+						Ok(register_variable(&session.machine, register))
+					}
+					None => Err(format!("invalid value '{}'", text)),
+				}
+			}
+			"readMemory" => {
+				let addr = memory_reference(session, args)?;
+				let offset = args["offset"].as_i64().unwrap_or(0);
+				let count = args["count"].as_u64().unwrap_or(0).min(MAX_COUNT) as i64;
+
+				// the byte address space is 0 to 2 * x10000
+				let start = (addr as i64 * 2).saturating_add(offset).clamp(0, 0x20000);
+				let end = (start + count).min(0x20000);
+				let bytes = (start..end)
+					.map(|byte| {
+						let word = machine.peek((byte / 2) as u16);
+						word.to_le_bytes()[(byte % 2) as usize]
+					})
+					.collect::<Vec<_>>();
+				Ok(json!({
+					"address": format!("0x{:04X}", start / 2),
+					"data": base64_encode(&bytes),
+					"unreadableBytes": count - (end - start),
+				}))
+			}
+			"writeMemory" => {
+				let addr = memory_reference(session, args)?;
+				let offset = args["offset"].as_i64().unwrap_or(0);
+				let bytes = base64_decode(args["data"].as_str().unwrap_or(""))
+					.ok_or_else(|| String::from("data isn't base64"))?;
+
+				let start = (addr as i64 * 2)
+					.checked_add(offset)
+					.filter(|&start| start >= 0 && start + bytes.len() as i64 <= 0x20000)
+					.ok_or_else(|| String::from("write outside of memory"))?;
+				for (idx, &byte) in bytes.iter().enumerate() {
+					let byte_addr = start + idx as i64;
+					let word_addr = (byte_addr / 2) as u16;
+					let mut word = session.machine.peek(word_addr).to_le_bytes();
+					word[(byte_addr % 2) as usize] = byte;
+					session.machine.poke(word_addr, u16::from_le_bytes(word));
+				}
+				Ok(json!({ "bytesWritten": bytes.len() }))
+			}
+			"disassemble" => {
+				let addr = memory_reference(session, args)?;
+				// addresses wrap around as the PC does, so offsets only
+				// matter modulo x10000
+				let start = addr
+					.wrapping_add((args["offset"].as_i64().unwrap_or(0) / 2) as u16)
+					.wrapping_add(args["instructionOffset"].as_i64().unwrap_or(0) as u16);
+				let count = args["instructionCount"].as_u64().unwrap_or(0).min(MAX_COUNT);
+
+				let instructions = (0..count)
+					.map(|idx| {
+						let addr = start.wrapping_add(idx as u16);
+						let word = machine.peek(addr);
+						let mut instruction = json!({
+							"address": format!("0x{:04X}", addr),
+							"instructionBytes": format!("{:04X}", word),
+							"instruction": disasm::disassemble(addr, word, &session.symbols),
+						});
+						if let Some(label) = session.symbols.name_of(addr) {
+							instruction["symbol"] = json!(label);
+						}
+						if let (Some((path, _)), Some(line)) = (&session.source, session.line_of(addr)) {
+							instruction["location"] = source(path);
+							instruction["line"] = json!(line - 1 + line_base);
+						}
+						instruction
+					})
+					.collect::<Vec<_>>();
+				Ok(json!({ "instructions": instructions }))
+			}
+			_ => Err(format!("unsupported request '{}'", command)),
+		}
+	}
+
+	/// load the program and its source as 'args' ask
+	fn launch(&mut self, args: &Value) -> Result<Value, String> {
+		let program = match args["program"].as_str() {
+			Some(program) => PathBuf::from(program),
+			None => return Err(String::from("'program' is missing")),
+		};
+
+		let mut machine = Machine::new();
+		let image = fs::read(&program).map_err(|e| format!("{}: {}", program.display(), e))?;
+		machine.load(&image).map_err(|fault| fault.to_string())?;
+		machine.enable_undo(debugger::UNDO_LIMIT);
+
+		let source = match args["source"].as_str() {
+			Some(path) => Some(PathBuf::from(path)),
+			None => Some(program.with_extension("asm")).filter(|path| path.exists()),
+		};
+		let source = match source {
+			Some(path) => {
+				let text = fs::read_to_string(&path)
+					.map_err(|e| format!("{}: {}", path.display(), e))?;
+				let assembly = asm::assemble(&text).map_err(|errors| {
+					errors
+						.iter()
+						.map(|error| format!("{}:{}: {}", path.display(), error.line(), error.message()))
+						.collect::<Vec<_>>()
+						.join("\n")
+				})?;
+				if assembly.to_object() == image {
+					Some((path, assembly))
+				} else {
+					let warning = format!(
+						"{} doesn't assemble to {}, debugging without source\n",
+						path.display(), program.display()
+					);
+					self.event("output", json!({ "category": "console", "output": warning }))
+						.map_err(|e| e.to_string())?;
+					None
+				}
+			}
+			None => None,
+		};
+
+		let symbols = match &source {
+			Some((_, assembly)) => assembly.symbols().clone(),
+			None => fs::read_to_string(program.with_extension("sym"))
+				.map(|text| SymbolTable::parse(&text))
+				.unwrap_or_default(),
+		};
+
+		let output = Capture::new();
+		let input = args["input"].as_str().unwrap_or("");
+		machine.set_console(Headless::new(input, output.clone()));
+
+		self.session = Some(Session {
+			machine,
+			source,
+			symbols,
+			line_breakpoints: BTreeSet::new(),
+			instruction_breakpoints: BTreeSet::new(),
+			stop_on_entry: args["stopOnEntry"].as_bool().unwrap_or(false),
+			output,
+		});
+		Ok(json!({}))
+	}
+
+	/// run the program as 'mode' asks and tell the client where it
+	/// stopped, returning false once the session is over
+	fn resume(&mut self, mode: Resume) -> io::Result<bool> {
+		let stop = self.run(mode)?;
+		self.flush_output()?;
+
+		match stop {
+			Stop::Done => self.stopped("step", None)?,
+			Stop::Breakpoint => self.stopped("breakpoint", None)?,
+			Stop::Paused => self.stopped("pause", None)?,
+			Stop::Fault(fault) => {
+				let text = fault.to_string();
+				self.event("output", json!({ "category": "stderr", "output": format!("{}\n", text) }))?;
+				self.stopped("exception", Some(text))?;
+			}
+			Stop::Halted => {
+				self.event("exited", json!({ "exitCode": 0 }))?;
+				self.event("terminated", json!({}))?;
+			}
+			Stop::Disconnected => return Ok(!self.pending.is_empty()),
+		}
+		Ok(true)
+	}
+
+	/// take back one step or, unless 'single_step', every step back to
+	/// the last breakpoint, and tell the client where the program is
+	fn reverse(&mut self, single_step: bool) -> io::Result<()> {
+		let session = self.session.as_mut().unwrap();
+		let mut reason = "step";
+		while let Some(step) = session.machine.step_back() {
+			if single_step {
+				break;
+			}
+			if session.is_breakpoint(step.pc) {
+				reason = "breakpoint";
+				break;
+			}
+		}
+		self.stopped(reason, None)
+	}
+
+	/// step until 'mode' is complete, a breakpoint is reached, the
+	/// client pauses or the program stops
+	fn run(&mut self, mode: Resume) -> io::Result<Stop> {
+		let mut done = mode.until(&self.session.as_ref().unwrap().machine);
+
+		loop {
+			let session = self.session.as_mut().unwrap();
+			for _ in 0..POLL_INTERVAL {
+				let machine = &mut session.machine;
+				if !machine.is_running() {
+					return Ok(Stop::Halted);
+				}
+
+				let word = machine.peek(machine.reg(Register::PC));
+				if let Err(fault) = machine.step() {
+					return Ok(Stop::Fault(fault));
+				}
+
+				if !machine.is_running() {
+					return Ok(Stop::Halted);
+				}
+				if done(machine, word) {
+					return Ok(Stop::Done);
+				}
+				let pc = machine.reg(Register::PC);
+				if session.is_breakpoint(pc) {
+					return Ok(Stop::Breakpoint);
+				}
+			}
+
+			self.flush_output()?;
+			if let Some(stop) = self.poll()? {
+				return Ok(stop);
+			}
+		}
+	}
+
+	/// handle requests that arrived while the program runs, returning
+	/// why it should stop if it should
+	fn poll(&mut self) -> io::Result<Option<Stop>> {
+		loop {
+			let request = match self.requests.try_recv() {
+				Ok(request) => request,
+				Err(TryRecvError::Empty) => return Ok(None),
+				Err(TryRecvError::Disconnected) => return Ok(Some(Stop::Disconnected)),
+			};
+
+			match request["command"].as_str().unwrap_or("") {
+				"pause" => {
+					self.respond(&request, Ok(json!({})))?;
+					return Ok(Some(Stop::Paused));
+				}
+				"disconnect" | "terminate" => {
+					self.pending.push_front(request);
+					return Ok(Some(Stop::Disconnected));
+				}
+				"setBreakpoints" | "setInstructionBreakpoints" | "threads" => {
+					self.handle(&request)?;
+				}
+				_ => self.pending.push_back(request),
+			}
+		}
+	}
+
+	/// send what the program wrote since the last call
+	fn flush_output(&mut self) -> io::Result<()> {
+		let output = match &self.session {
+			Some(session) => session.output.take(),
+			None => return Ok(()),
+		};
+		if output.is_empty() {
+			return Ok(());
+		}
+
+		let output = String::from_utf8_lossy(&output);
+		self.event("output", json!({ "category": "stdout", "output": output }))
+	}
+
+	fn stopped(&mut self, reason: &str, text: Option<String>) -> io::Result<()> {
+		let mut body = json!({
+			"reason": reason,
+			"threadId": THREAD_ID,
+			"allThreadsStopped": true,
+		});
+		if let Some(text) = text {
+			body["text"] = json!(text);
+		}
+		self.event("stopped", body)
+	}
+
+	fn respond(&mut self, request: &Value, result: Result<Value, String>) -> io::Result<()> {
+		let mut response = json!({
+			"type": "response",
+			"request_seq": request["seq"],
+			"command": request["command"],
+		});
+		match result {
+			Ok(body) => {
+				response["success"] = json!(true);
+				response["body"] = body;
+			}
+			Err(message) => {
+				response["success"] = json!(false);
+				response["message"] = json!(message);
+			}
+		}
+		self.send(response)
+	}
+
+	fn event(&mut self, event: &str, body: Value) -> io::Result<()> {
+		self.send(json!({ "type": "event", "event": event, "body": body }))
+	}
+
+	fn send(&mut self, mut message: Value) -> io::Result<()> {
+		self.seq += 1;
+		message["seq"] = json!(self.seq);
+
+		let body = message.to_string();
+		write!(self.writer, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+		self.writer.flush()
+	}
+}
+
+fn source(path: &Path) -> Value {
+	json!({
+		"name": path.file_name().map(|name| name.to_string_lossy()),
+		"path": path.display().to_string(),
+	})
+}
+
+fn register_variable(machine: &Machine, register: Register) -> Value {
+	let value = machine.reg(register);
+	let mut variable = json!({
+		"name": register.to_string(),
+		"variablesReference": 0,
+	});
+
+	if register == Register::Psr {
+		variable["value"] = json!(format!("x{:04X} ({})", value, psr::describe(value)));
+	} else {
+		variable["value"] = json!(format!("x{:04X} ({})", value, value as i16));
+		variable["memoryReference"] = json!(format!("0x{:04X}", value));
+	}
+	variable
+}
+
+fn condition_variable(machine: &Machine) -> Value {
+	json!({
+		"name": "COND",
+		"value": psr::flags(machine.reg(Register::Psr)),
+		"variablesReference": 0,
+	})
+}
+
+/// condition codes given as flags, e.g. "Z" or "nz"
+fn condition_codes(text: &str) -> Option<u16> {
+	if text.is_empty() {
+		return None;
+	}
+	text.chars().try_fold(0, |codes, flag| match flag.to_ascii_uppercase() {
+		'N' => Some(codes | psr::NEGATIVE),
+		'Z' => Some(codes | psr::ZERO),
+		'P' => Some(codes | psr::POSITIVE),
+		_ => None,
+	})
+}
+
+fn memory_reference(session: &Session, args: &Value) -> Result<u16, String> {
+	let reference = args["memoryReference"].as_str().unwrap_or("");
+	session
+		.location(reference)
+		.ok_or_else(|| format!("invalid memory reference '{}'", reference))
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(bytes: &[u8]) -> String {
+	let mut text = String::new();
+	for chunk in bytes.chunks(3) {
+		let bits = chunk
+			.iter()
+			.enumerate()
+			.fold(0_u32, |bits, (idx, &byte)| bits | (byte as u32) << (16 - 8 * idx));
+		for idx in 0..4 {
+			if idx <= chunk.len() {
+				text.push(BASE64[(bits >> (18 - 6 * idx) & 0x3f) as usize] as char);
+			} else {
+				text.push('=');
+			}
+		}
+	}
+	text
+}
+
+fn base64_decode(text: &str) -> Option<Vec<u8>> {
+	let digits = text
+		.trim_end_matches('=')
+		.bytes()
+		.map(|ch| BASE64.iter().position(|&digit| digit == ch).map(|digit| digit as u32))
+		.collect::<Option<Vec<_>>>()?;
+	if digits.len() % 4 == 1 {
+		return None;
+	}
+
+	let mut bytes = Vec::new();
+	for chunk in digits.chunks(4) {
+		let bits = chunk
+			.iter()
+			.enumerate()
+			.fold(0_u32, |bits, (idx, &digit)| bits | digit << (18 - 6 * idx));
+		for idx in 0..chunk.len() - 1 {
+			bytes.push((bits >> (16 - 8 * idx)) as u8);
+		}
+	}
+	Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::io::Cursor;
+
+	const COUNTDOWN: &str = "
+        .ORIG x3000
+        AND R0, R0, #0
+        ADD R0, R0, #5
+LOOP    ADD R0, R0, #-1
+        BRp LOOP
+        HALT
+        .END
+";
+
+	/// COUNTDOWN's source and object in a directory of their own
+	fn program(name: &str) -> (PathBuf, PathBuf) {
+		let dir = std::env::temp_dir().join(format!("vlc3-dap-{}-{}", std::process::id(), name));
+		fs::create_dir_all(&dir).unwrap();
+		let (source, object) = (dir.join("countdown.asm"), dir.join("countdown.obj"));
+		fs::write(&source, COUNTDOWN).unwrap();
+		fs::write(&object, asm::assemble(COUNTDOWN).unwrap().to_object()).unwrap();
+		(source, object)
+	}
+
+	fn frame(message: &Value) -> Vec<u8> {
+		let body = message.to_string();
+		format!("Content-Length: {}\r\n\r\n{}", body.len(), body).into_bytes()
+	}
+
+	/// the messages a server sends when a client sends 'requests', given
+	/// as commands and their arguments
+	fn session(requests: &[(&str, Value)]) -> Vec<Value> {
+		let input = requests
+			.iter()
+			.enumerate()
+			.flat_map(|(seq, (command, args))| frame(&json!({
+				"seq": seq + 1,
+				"type": "request",
+				"command": command,
+				"arguments": args,
+			})))
+			.collect::<Vec<_>>();
+
+		let mut server = DapServer::new(Cursor::new(input), Vec::new());
+		server.serve().unwrap();
+
+		// responses may be longer than requests can
+		let mut output = server.writer.as_slice();
+		let mut messages = Vec::new();
+		while !output.is_empty() {
+			let header = output.windows(4).position(|end| end == b"\r\n\r\n").unwrap();
+			let length = std::str::from_utf8(&output[..header])
+				.unwrap()
+				.trim_start_matches("Content-Length: ")
+				.parse::<usize>()
+				.unwrap();
+			let (body, rest) = output[header + 4..].split_at(length);
+			messages.push(serde_json::from_slice(body).unwrap());
+			output = rest;
+		}
+		messages
+	}
+
+	/// the body of the response to the request numbered 'seq', which
+	/// has to have succeeded
+	fn body(messages: &[Value], seq: u64) -> &Value {
+		let response = messages
+			.iter()
+			.find(|message| message["type"] == "response" && message["request_seq"] == seq)
+			.unwrap_or_else(|| panic!("no response to request {}", seq));
+		assert_eq!(response["success"], true, "{}", response);
+		&response["body"]
+	}
+
+	fn events<'a>(messages: &'a [Value], event: &str) -> Vec<&'a Value> {
+		messages
+			.iter()
+			.filter(|message| message["event"] == event)
+			.map(|message| &message["body"])
+			.collect()
+	}
+
+	#[test]
+	fn a_session_stops_at_breakpoints_and_exits() {
+		let (source, object) = program("session");
+		let messages = session(&[
+			("initialize", json!({ "linesStartAt1": true })),
+			("launch", json!({ "program": object, "stopOnEntry": true })),
+			("setBreakpoints", json!({
+				"source": { "path": source },
+				"breakpoints": [{ "line": 5 }, { "line": 1 }],
+			})),
+			("configurationDone", json!({})),
+			("continue", json!({ "threadId": THREAD_ID })),
+			("variables", json!({ "variablesReference": REGISTERS_REF })),
+			("stackTrace", json!({ "threadId": THREAD_ID })),
+			("setBreakpoints", json!({ "source": { "path": source }, "breakpoints": [] })),
+			("continue", json!({ "threadId": THREAD_ID })),
+			("disconnect", json!({})),
+		]);
+
+		assert_eq!(body(&messages, 2), &json!({}));
+		assert_eq!(
+			body(&messages, 3)["breakpoints"],
+			json!([{ "verified": true, "line": 5 }, { "verified": true, "line": 3 }])
+		);
+		let reasons = events(&messages, "stopped")
+			.iter()
+			.map(|stopped| stopped["reason"].clone())
+			.collect::<Vec<_>>();
+		assert_eq!(reasons, [json!("entry"), json!("breakpoint")]);
+
+		assert_eq!(body(&messages, 6)["variables"][0]["value"], "x0005 (5)");
+		let frame = &body(&messages, 7)["stackFrames"][0];
+		assert_eq!(frame["name"], "LOOP");
+		assert_eq!(frame["line"], 5);
+		assert_eq!(frame["instructionPointerReference"], "0x3002");
+
+		assert_eq!(events(&messages, "exited"), [&json!({ "exitCode": 0 })]);
+		assert_eq!(events(&messages, "terminated").len(), 1);
+	}
+
+	#[test]
+	fn memory_requests_stay_within_memory() {
+		let (_, object) = program("memory");
+		let messages = session(&[
+			("launch", json!({ "program": object })),
+			("readMemory", json!({ "memoryReference": "0x3000", "count": u64::MAX })),
+			("readMemory", json!({ "memoryReference": "0x3000", "offset": i64::MIN, "count": 2 })),
+			("readMemory", json!({ "memoryReference": "0xFFFF", "offset": i64::MAX, "count": 4 })),
+			("disassemble", json!({
+				"memoryReference": "0x0000",
+				"instructionOffset": -2,
+				"instructionCount": 4,
+			})),
+			("disassemble", json!({
+				"memoryReference": "0xFFFF",
+				"offset": i64::MIN,
+				"instructionOffset": i64::MAX,
+				"instructionCount": u64::MAX,
+			})),
+			("writeMemory", json!({ "memoryReference": "0xFFFF", "offset": i64::MAX, "data": "AAAA" })),
+			("setBreakpoints", json!({ "source": { "path": "" }, "breakpoints": [{ "line": u64::MAX }] })),
+		]);
+
+		let read = body(&messages, 2);
+		assert_eq!(read["address"], "0x3000");
+		assert_eq!(base64_decode(read["data"].as_str().unwrap()).unwrap().len(), 0x10000);
+		assert_eq!(read["unreadableBytes"], 0);
+		assert_eq!(body(&messages, 3)["address"], "0x0000");
+		assert_eq!(body(&messages, 4)["unreadableBytes"], 4);
+
+		let addresses = body(&messages, 5)["instructions"]
+			.as_array()
+			.unwrap()
+			.iter()
+			.map(|instruction| instruction["address"].clone())
+			.collect::<Vec<_>>();
+		assert_eq!(addresses, [json!("0xFFFE"), json!("0xFFFF"), json!("0x0000"), json!("0x0001")]);
+		assert_eq!(body(&messages, 6)["instructions"].as_array().unwrap().len(), 0x10000);
+
+		let write = messages.iter().find(|message| message["request_seq"] == 7).unwrap();
+		assert_eq!(write["success"], false);
+		assert_eq!(body(&messages, 8)["breakpoints"][0]["verified"], false);
+	}
+
+	#[test]
+	fn long_messages_are_skipped() {
+		let mut input = format!("Content-Length: {}\r\n\r\n", MAX_MESSAGE + 1).into_bytes();
+		input.extend(std::iter::repeat_n(b' ', MAX_MESSAGE + 1));
+		input.extend(frame(&json!({ "seq": 1 })));
+
+		let mut reader = Cursor::new(input);
+		let error = read_message(&mut reader).unwrap_err();
+		assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+		assert_eq!(read_message(&mut reader).unwrap(), Some(json!({ "seq": 1 })));
+		assert_eq!(read_message(&mut reader).unwrap(), None);
+	}
+
+	#[test]
+	fn base64_round_trips() {
+		for length in 0..8 {
+			let bytes = (0..length).map(|byte: u8| byte.wrapping_mul(37).wrapping_add(200)).collect::<Vec<_>>();
+			assert_eq!(base64_decode(&base64_encode(&bytes)), Some(bytes));
+		}
+		assert_eq!(base64_encode(b"LC-3"), "TEMtMw==");
+		assert_eq!(base64_decode("A"), None);
+	}
+}
@@ -0,0 +1,726 @@
+use crate::asm::{lexer, SymbolTable};
+use crate::cpu::{instruction::OpCode, psr, register::Register, Cpu};
+use crate::disasm;
+use crate::fault::Fault;
+use crate::machine::Machine;
+use crate::watch::{Access, WatchEvent, WatchHit, Watchpoint};
+use std::{
+	collections::BTreeSet,
+	io::{self, Write},
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		Arc,
+	},
+};
+
+const HELP: &str = "\
+commands:
+  s, step [N]             execute N instructions (default 1)
+  n, next                 step, treating JSR/JSRR/TRAP as one instruction
+  c, continue             run until a breakpoint or the program halts
+  finish                  run until the current subroutine returns
+  rs, reverse-step [N]    take back N instructions (default 1)
+  rc, reverse-continue    run backwards to a breakpoint or watchpoint
+  b, break LOC            set a breakpoint at an address or label
+  d, delete [LOC]         delete a breakpoint, or all of them
+  info break              list breakpoints
+  watch LOC[:END] [VALUE] stop when LOC (to END) is written (with VALUE)
+  rwatch LOC[:END] [VALUE]
+                          stop when LOC (to END) is read (as VALUE)
+  awatch LOC[:END] [VALUE]
+                          stop when LOC (to END) is read or written
+  watch REG [VALUE]       stop when REG is written (with VALUE)
+  unwatch [ID]            delete a watchpoint, or all of them
+  info watch              list watchpoints
+  r, regs                 show registers
+  x LOC [N]               show N words of memory (default 8)
+  set REG|LOC VALUE       change a register or a word of memory
+  l, list [LOC]           disassemble around LOC (default PC)
+  snapshot FILE           save the whole machine to FILE
+  restore FILE            return to the machine saved in FILE
+  q, quit                 stop debugging
+  h, help                 show this message
+an empty line repeats the previous command; LOC and VALUE are numbers
+(x3000, #12, b101) or labels; the last 100000 instructions can be taken
+back, but not their output";
+
+/// steps the debugger can take back, see [`Machine::enable_undo`]
+pub const UNDO_LIMIT: usize = 100_000;
+
+/// How the debugger should let the program run.
+#[derive(Clone, Copy, Debug)]
+pub enum Resume {
+	Step(usize),
+	Next,
+	Continue,
+	Finish,
+}
+
+impl Resume {
+	/// a predicate telling, after each instruction of a program
+	/// resumed on 'machine', whether 'self' is complete; it is given
+	/// the word just executed
+	pub fn until(self, machine: &Machine) -> Until {
+		match self {
+			Resume::Step(count) => {
+				let mut steps = 0;
+				Box::new(move |_, _| {
+					steps += 1;
+					steps >= count
+				})
+			}
+			Resume::Next => {
+				let pc = machine.reg(Register::PC);
+				if !Debugger::is_call(machine.peek(pc)) {
+					return Box::new(|_, _| true);
+				}
+
+				// a recursive call may come back to the same address
+				// deeper down, only the return of this call ends the step
+				let return_addr = pc.wrapping_add(1);
+				let native_traps = machine.cpu().native_traps();
+				let mut depth = 0_usize;
+				Box::new(move |machine, word| {
+					let is_trap = word >> 12 == 0b1111;
+					if Debugger::is_call(word) && !(is_trap && native_traps) {
+						depth += 1;
+					} else if Debugger::is_return(word) {
+						depth = depth.saturating_sub(1);
+					}
+					depth == 0 && machine.reg(Register::PC) == return_addr
+				})
+			}
+			Resume::Continue => Box::new(|_, _| false),
+			Resume::Finish => {
+				// built-in trap routines return without RTI
+				let native_traps = machine.cpu().native_traps();
+				let mut depth = 0_usize;
+				Box::new(move |_, word| {
+					let is_trap = word >> 12 == 0b1111;
+					if Debugger::is_call(word) && !(is_trap && native_traps) {
+						depth += 1;
+					} else if Debugger::is_return(word) {
+						if depth == 0 {
+							return true;
+						}
+						depth -= 1;
+					}
+					false
+				})
+			}
+		}
+	}
+}
+
+/// Tells, after each instruction, whether a [`Resume`] is complete.
+pub type Until = Box<dyn FnMut(&Machine, u16) -> bool>;
+
+/// What the front end should do after a command.
+#[derive(Clone, Copy, Debug)]
+pub enum Action {
+	/// ask for the next command
+	Prompt,
+	/// let the program run (see [`Debugger::resume`])
+	Resume(Resume),
+	Quit,
+}
+
+/// Why the program stopped running.
+#[derive(Debug)]
+enum Stop {
+	Done,
+	Breakpoint(u16),
+	Watchpoint(Vec<WatchHit>),
+	Halted,
+	Interrupted,
+	Fault(Fault),
+	/// no step is left to take back
+	Beginning,
+}
+
+/// An interactive debugger for programs running on a [`Machine`].
+///
+/// The front end reads command lines, hands them to
+/// [`command`](Debugger::command) and, when asked to, calls
+/// [`resume`](Debugger::resume) to run the program.
+#[derive(Debug)]
+pub struct Debugger {
+	symbols: SymbolTable,
+	breakpoints: BTreeSet<u16>,
+	last_command: String,
+	interrupted: Arc<AtomicBool>,
+}
+
+impl Debugger {
+	pub fn new(symbols: SymbolTable) -> Self {
+		Self {
+			symbols,
+			breakpoints: BTreeSet::new(),
+			last_command: String::new(),
+			interrupted: Arc::new(AtomicBool::new(false)),
+		}
+	}
+
+	/// set this flag (e.g. from a Ctrl-C handler) to pause a running
+	/// program
+	pub fn interrupt_flag(&self) -> Arc<AtomicBool> {
+		Arc::clone(&self.interrupted)
+	}
+
+	/// execute one command line, writing its output to 'out'
+	pub fn command<W: Write>(
+		&mut self,
+		machine: &mut Machine,
+		line: &str,
+		out: &mut W,
+	) -> io::Result<Action> {
+		let line = match line.trim() {
+			"" => self.last_command.clone(),
+			line => String::from(line),
+		};
+		self.last_command = line.clone();
+
+		let words = line.split_whitespace().collect::<Vec<_>>();
+		let (name, args) = match words.split_first() {
+			Some((name, args)) => (*name, args),
+			None => return Ok(Action::Prompt),
+		};
+
+		match (name, args) {
+			("s" | "step", []) => return Ok(Action::Resume(Resume::Step(1))),
+			("s" | "step", [count]) => match count.parse::<usize>() {
+				Ok(count) if count > 0 => return Ok(Action::Resume(Resume::Step(count))),
+				_ => writeln!(out, "invalid step count '{}'", count)?,
+			},
+			("n" | "next", []) => return Ok(Action::Resume(Resume::Next)),
+			("c" | "continue", []) => return Ok(Action::Resume(Resume::Continue)),
+			("finish", []) => return Ok(Action::Resume(Resume::Finish)),
+			("rs" | "reverse-step", []) => self.reverse(machine, Some(1), out)?,
+			("rs" | "reverse-step", [count]) => match count.parse::<usize>() {
+				Ok(count) if count > 0 => self.reverse(machine, Some(count), out)?,
+				_ => writeln!(out, "invalid step count '{}'", count)?,
+			},
+			("rc" | "reverse-continue", []) => self.reverse(machine, None, out)?,
+			("b" | "break", [loc]) => match self.location(loc) {
+				Some(addr) => {
+					self.breakpoints.insert(addr);
+					writeln!(out, "breakpoint at {}", self.describe(addr))?;
+				}
+				None => writeln!(out, "unknown location '{}'", loc)?,
+			},
+			("d" | "delete", []) => self.breakpoints.clear(),
+			("d" | "delete", [loc]) => match self.location(loc) {
+				Some(addr) if self.breakpoints.remove(&addr) => {}
+				_ => writeln!(out, "no breakpoint at '{}'", loc)?,
+			},
+			("info", ["break"]) => {
+				if self.breakpoints.is_empty() {
+					writeln!(out, "no breakpoints")?;
+				}
+				for &addr in &self.breakpoints {
+					writeln!(out, "{}", self.describe(addr))?;
+				}
+			}
+			("watch", [target]) if Debugger::register(target).is_some() => {
+				self.watch_register(machine, target, None, out)?
+			}
+			("watch", [target, value]) if Debugger::register(target).is_some() => {
+				self.watch_register(machine, target, Some(value), out)?
+			}
+			("watch" | "rwatch" | "awatch", [range]) => {
+				self.watch_memory(machine, name, range, None, out)?
+			}
+			("watch" | "rwatch" | "awatch", [range, value]) => {
+				self.watch_memory(machine, name, range, Some(value), out)?
+			}
+			("unwatch", []) => {
+				for (id, _) in machine.watchpoints() {
+					machine.remove_watchpoint(id);
+				}
+			}
+			("unwatch", [id]) => match id.parse::<usize>() {
+				Ok(id) if machine.remove_watchpoint(id) => {}
+				_ => writeln!(out, "no watchpoint '{}'", id)?,
+			},
+			("info", ["watch"]) => {
+				let watchpoints = machine.watchpoints();
+				if watchpoints.is_empty() {
+					writeln!(out, "no watchpoints")?;
+				}
+				for (id, watchpoint) in watchpoints {
+					writeln!(out, "{}: {}", id, self.describe_watchpoint(&watchpoint))?;
+				}
+			}
+			("r" | "regs", []) | ("info", ["registers"]) => self.print_registers(machine, out)?,
+			("x", [loc]) => self.print_memory(machine, loc, "8", out)?,
+			("x", [loc, count]) => self.print_memory(machine, loc, count, out)?,
+			("set", [target, value]) => self.set(machine, target, value, out)?,
+			("l" | "list", []) => self.list(machine, machine.reg(Register::PC), out)?,
+			("l" | "list", [loc]) => match self.location(loc) {
+				Some(addr) => self.list(machine, addr, out)?,
+				None => writeln!(out, "unknown location '{}'", loc)?,
+			},
+			("snapshot", [path]) => match machine.save_snapshot(path) {
+				Ok(()) => writeln!(out, "saved snapshot to {}", path)?,
+				Err(e) => writeln!(out, "{}: {}", path, e)?,
+			},
+			("restore", [path]) => match machine.load_snapshot(path) {
+				Ok(()) => {
+					writeln!(out, "restored snapshot from {}", path)?;
+					self.print_current(machine, out)?;
+				}
+				Err(fault) => writeln!(out, "{}", fault)?,
+			},
+			("q" | "quit", []) => return Ok(Action::Quit),
+			("h" | "help", []) => writeln!(out, "{}", HELP)?,
+			_ => writeln!(out, "unknown command '{}', try 'help'", line)?,
+		}
+
+		Ok(Action::Prompt)
+	}
+
+	/// run the program as 'mode' asks and report where it stopped
+	pub fn resume<W: Write>(
+		&mut self,
+		machine: &mut Machine,
+		mode: Resume,
+		out: &mut W,
+	) -> io::Result<()> {
+		self.interrupted.store(false, Ordering::SeqCst);
+
+		let stop = self.run_until(machine, mode.until(machine));
+
+		match stop {
+			Stop::Done => {}
+			Stop::Breakpoint(addr) => writeln!(out, "\nbreakpoint at {}", self.describe(addr))?,
+			Stop::Watchpoint(hits) => {
+				writeln!(out)?;
+				for hit in hits {
+					self.print_hit(&hit, out)?;
+				}
+			}
+			Stop::Interrupted => writeln!(out, "\ninterrupted")?,
+			Stop::Halted => {
+				writeln!(out, "program halted")?;
+				return Ok(());
+			}
+			Stop::Fault(fault) => writeln!(out, "\n{}", fault)?,
+			Stop::Beginning => {}
+		}
+		self.print_current(machine, out)
+	}
+
+	/// run the program backwards, 'count' steps or, if None, until a
+	/// breakpoint or a watchpoint is reached, and report where it stopped
+	fn reverse<W: Write>(
+		&mut self,
+		machine: &mut Machine,
+		count: Option<usize>,
+		out: &mut W,
+	) -> io::Result<()> {
+		self.interrupted.store(false, Ordering::SeqCst);
+
+		match self.run_back(machine, count) {
+			Stop::Breakpoint(addr) => writeln!(out, "breakpoint at {}", self.describe(addr))?,
+			Stop::Watchpoint(hits) => {
+				for hit in hits {
+					self.print_hit(&hit, out)?;
+				}
+			}
+			Stop::Interrupted => writeln!(out, "interrupted")?,
+			Stop::Beginning => writeln!(out, "no earlier instructions were recorded")?,
+			_ => {}
+		}
+		self.print_current(machine, out)
+	}
+
+	/// take back steps until 'count' of them are, a breakpoint is
+	/// reached, a taken back access triggers a watchpoint or no step is
+	/// left
+	fn run_back(&self, machine: &mut Machine, count: Option<usize>) -> Stop {
+		let mut steps = 0;
+		loop {
+			let Some(step) = machine.step_back() else {
+				return Stop::Beginning;
+			};
+			steps += 1;
+
+			let hits = machine.take_watch_hits();
+			if !hits.is_empty() {
+				return Stop::Watchpoint(hits);
+			}
+			if count.is_some_and(|count| steps >= count) {
+				return Stop::Done;
+			}
+			if self.breakpoints.contains(&step.pc) {
+				return Stop::Breakpoint(step.pc);
+			}
+			if self.interrupted.load(Ordering::SeqCst) {
+				return Stop::Interrupted;
+			}
+		}
+	}
+
+	/// step until 'done' (told the word just executed) says so, a
+	/// breakpoint is reached or the program stops
+	fn run_until<F>(&self, machine: &mut Machine, mut done: F) -> Stop
+	where
+		F: FnMut(&Machine, u16) -> bool,
+	{
+		loop {
+			if !machine.is_running() {
+				return Stop::Halted;
+			}
+
+			let word = machine.peek(machine.reg(Register::PC));
+			if let Err(fault) = machine.step() {
+				return Stop::Fault(fault);
+			}
+
+			let pc = machine.reg(Register::PC);
+			let hits = machine.take_watch_hits();
+			if !hits.is_empty() {
+				return Stop::Watchpoint(hits);
+			}
+			if !machine.is_running() {
+				return Stop::Halted;
+			}
+			if done(machine, word) {
+				return Stop::Done;
+			}
+			if self.breakpoints.contains(&pc) {
+				return Stop::Breakpoint(pc);
+			}
+			if self.interrupted.load(Ordering::SeqCst) {
+				return Stop::Interrupted;
+			}
+		}
+	}
+
+	fn is_call(word: u16) -> bool {
+		matches!(
+			Cpu::decode_word(word).map(|instr| instr.opcode()),
+			Some(OpCode::JSR | OpCode::JSRR | OpCode::TRAP)
+		)
+	}
+
+	fn is_return(word: u16) -> bool {
+		word == 0xc1c0 || word == 0x8000
+	}
+
+	/// an address given as a number or a label
+	fn location(&self, loc: &str) -> Option<u16> {
+		match lexer::number(loc) {
+			Some(addr) if (-0x8000..=0xffff).contains(&addr) => Some(addr as u16),
+			Some(_) => None,
+			None => self.symbols.get(loc),
+		}
+	}
+
+	/// 'addr' with its label, if it has one
+	fn describe(&self, addr: u16) -> String {
+		match self.symbols.name_of(addr) {
+			Some(label) => format!("x{:04X} ({})", addr, label),
+			None => format!("x{:04X}", addr),
+		}
+	}
+
+	fn print_current<W: Write>(&self, machine: &Machine, out: &mut W) -> io::Result<()> {
+		let pc = machine.reg(Register::PC);
+		let word = machine.peek(pc);
+		writeln!(
+			out,
+			"=> x{:04X}  x{:04X}  {}",
+			pc, word, disasm::disassemble(pc, word, &self.symbols)
+		)
+	}
+
+	fn print_registers<W: Write>(&self, machine: &Machine, out: &mut W) -> io::Result<()> {
+		for row in 0..2 {
+			let line = (0..4)
+				.map(|col| {
+					let reg = Register::gpr(row * 4 + col);
+					let value = machine.reg(reg);
+					format!("{} x{:04X} {:>6}", reg, value, value as i16)
+				})
+				.collect::<Vec<_>>()
+				.join("   ");
+			writeln!(out, "{}", line)?;
+		}
+
+		let psr = machine.reg(Register::Psr);
+		writeln!(
+			out,
+			"PC x{:04X}   PSR x{:04X} ({})   SAVED_SSP x{:04X}   SAVED_USP x{:04X}",
+			machine.reg(Register::PC),
+			psr,
+			psr::describe(psr),
+			machine.reg(Register::SavedSsp),
+			machine.reg(Register::SavedUsp),
+		)
+	}
+
+	fn print_memory<W: Write>(
+		&self,
+		machine: &Machine,
+		loc: &str,
+		count: &str,
+		out: &mut W,
+	) -> io::Result<()> {
+		let addr = match self.location(loc) {
+			Some(addr) => addr,
+			None => return writeln!(out, "unknown location '{}'", loc),
+		};
+		let count = match count.parse::<u16>() {
+			Ok(count) if count > 0 => count,
+			_ => return writeln!(out, "invalid word count '{}'", count),
+		};
+
+		for row in (0..count).step_by(8) {
+			let start = addr.wrapping_add(row);
+			let words = (row..count.min(row + 8))
+				.map(|idx| format!("x{:04X}", machine.peek(addr.wrapping_add(idx))))
+				.collect::<Vec<_>>()
+				.join(" ");
+			writeln!(out, "x{:04X}: {}", start, words)?;
+		}
+		Ok(())
+	}
+
+	fn set<W: Write>(
+		&self,
+		machine: &mut Machine,
+		target: &str,
+		value: &str,
+		out: &mut W,
+	) -> io::Result<()> {
+		let value = match self.location(value) {
+			Some(value) => value,
+			None => return writeln!(out, "invalid value '{}'", value),
+		};
+
+		match (Debugger::register(target), self.location(target)) {
+			(Some(register), _) => machine.set_reg(register, value),
+			(None, Some(addr)) => machine.poke(addr, value),
+			(None, None) => writeln!(out, "unknown register or location '{}'", target)?,
+		}
+		Ok(())
+	}
+
+	/// the register called 'name', e.g. r0 or PC
+	pub(crate) fn register(name: &str) -> Option<Register> {
+		match name.to_ascii_uppercase().as_str() {
+			"PC" => Some(Register::PC),
+			"PSR" => Some(Register::Psr),
+			"SAVED_SSP" => Some(Register::SavedSsp),
+			"SAVED_USP" => Some(Register::SavedUsp),
+			name => match name.as_bytes() {
+				[b'R', idx @ b'0'..=b'7'] => Some(Register::gpr((idx - b'0') as u16)),
+				_ => None,
+			},
+		}
+	}
+
+	fn watch_register<W: Write>(
+		&self,
+		machine: &Machine,
+		name: &str,
+		value: Option<&str>,
+		out: &mut W,
+	) -> io::Result<()> {
+		let value = match value.map(|value| self.location(value).ok_or(value)) {
+			None => None,
+			Some(Ok(value)) => Some(value),
+			Some(Err(value)) => return writeln!(out, "invalid value '{}'", value),
+		};
+
+		let register = Debugger::register(name).unwrap();
+		let watchpoint = Watchpoint::Register { register, value };
+		let id = machine.add_watchpoint(watchpoint);
+		writeln!(out, "watchpoint {}: {}", id, self.describe_watchpoint(&watchpoint))
+	}
+
+	fn watch_memory<W: Write>(
+		&self,
+		machine: &Machine,
+		command: &str,
+		range: &str,
+		value: Option<&str>,
+		out: &mut W,
+	) -> io::Result<()> {
+		let (start, end) = match range.split_once(':') {
+			Some((start, end)) => (self.location(start), self.location(end)),
+			None => (self.location(range), self.location(range)),
+		};
+		let (start, end) = match (start, end) {
+			(Some(start), Some(end)) if start <= end => (start, end),
+			_ => return writeln!(out, "invalid location or range '{}'", range),
+		};
+		let value = match value.map(|value| self.location(value).ok_or(value)) {
+			None => None,
+			Some(Ok(value)) => Some(value),
+			Some(Err(value)) => return writeln!(out, "invalid value '{}'", value),
+		};
+
+		let access = match command {
+			"rwatch" => Access::Read,
+			"awatch" => Access::ReadWrite,
+			_ => Access::Write,
+		};
+		let watchpoint = Watchpoint::Memory { start, end, access, value };
+		let id = machine.add_watchpoint(watchpoint);
+		writeln!(out, "watchpoint {}: {}", id, self.describe_watchpoint(&watchpoint))
+	}
+
+	fn describe_watchpoint(&self, watchpoint: &Watchpoint) -> String {
+		let value = |value: &Option<u16>| match value {
+			Some(value) => format!(" of x{:04X}", value),
+			None => String::new(),
+		};
+
+		match watchpoint {
+			Watchpoint::Memory { start, end, access, value: expected } => {
+				let access = match access {
+					Access::Read => "read",
+					Access::Write => "write",
+					Access::ReadWrite => "access",
+				};
+				let range = if start == end {
+					self.describe(*start)
+				} else {
+					format!("{} to {}", self.describe(*start), self.describe(*end))
+				};
+				format!("{}{} at {}", access, value(expected), range)
+			}
+			Watchpoint::Register { register, value: expected } => {
+				format!("write{} to {}", value(expected), register)
+			}
+		}
+	}
+
+	fn print_hit<W: Write>(&self, hit: &WatchHit, out: &mut W) -> io::Result<()> {
+		let event = match hit.event {
+			WatchEvent::Read { addr, value } => {
+				format!("read x{:04X} from {}", value, self.describe(addr))
+			}
+			WatchEvent::Write { addr, value } => {
+				format!("wrote x{:04X} to {}", value, self.describe(addr))
+			}
+			WatchEvent::Register { register, value } => {
+				format!("wrote x{:04X} to {}", value, register)
+			}
+		};
+		writeln!(
+			out,
+			"watchpoint {}: x{:04X}  {} {}",
+			hit.id,
+			hit.pc,
+			disasm::disassemble(hit.pc, hit.instr, &self.symbols),
+			event,
+		)
+	}
+
+	fn list<W: Write>(&self, machine: &Machine, addr: u16, out: &mut W) -> io::Result<()> {
+		let pc = machine.reg(Register::PC);
+
+		for offset in -3_i16..7 {
+			let addr = addr.wrapping_add(offset as u16);
+			let word = machine.peek(addr);
+			let marker = if addr == pc { "=>" } else { "  " };
+			let breakpoint = if self.breakpoints.contains(&addr) { '*' } else { ' ' };
+			writeln!(
+				out,
+				"{}{} x{:04X}  x{:04X}  {:<8}  {}",
+				marker,
+				breakpoint,
+				addr,
+				word,
+				self.symbols.name_of(addr).unwrap_or(""),
+				disasm::disassemble(addr, word, &self.symbols),
+			)?;
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::asm;
+	use crate::console::{Capture, Headless};
+
+	/// counts in R1 how deep it recursed, R0 times
+	const RECURSIVE: &str = "
+        .ORIG x3000
+        LD R6, STACK
+        ADD R0, R0, #3
+        JSR REC
+        HALT
+REC     ADD R6, R6, #-1
+        STR R7, R6, #0
+        ADD R1, R1, #1
+        ADD R0, R0, #-1
+        BRz DONE
+INNER   JSR REC
+DONE    LDR R7, R6, #0
+        ADD R6, R6, #1
+        RET
+STACK   .FILL x5000
+        .END
+";
+
+	fn debug(source: &str) -> (Debugger, Machine) {
+		let assembly = asm::assemble(source).unwrap();
+		let mut machine = Machine::new();
+		machine.set_console(Headless::new(Vec::new(), Capture::new()));
+		machine.load(&assembly.to_object()).unwrap();
+		(Debugger::new(assembly.symbols().clone()), machine)
+	}
+
+	fn run(debugger: &mut Debugger, machine: &mut Machine, line: &str) -> String {
+		let mut out = Vec::new();
+		if let Action::Resume(mode) = debugger.command(machine, line, &mut out).unwrap() {
+			debugger.resume(machine, mode, &mut out).unwrap();
+		}
+		String::from_utf8(out).unwrap()
+	}
+
+	#[test]
+	fn next_steps_over_a_whole_recursive_call() {
+		let (mut debugger, mut machine) = debug(RECURSIVE);
+		run(&mut debugger, &mut machine, "break INNER");
+		run(&mut debugger, &mut machine, "continue");
+		assert_eq!(machine.reg(Register::PC), 0x3009);
+		run(&mut debugger, &mut machine, "delete");
+		let sp = machine.reg(Register::R6);
+
+		// the call returns to DONE twice, first from the deepest level
+		run(&mut debugger, &mut machine, "next");
+		assert_eq!(machine.reg(Register::PC), 0x300a);
+		assert_eq!(machine.reg(Register::R6), sp);
+		assert_eq!(machine.reg(Register::R1), 3);
+	}
+
+	#[test]
+	fn next_steps_over_a_call_and_a_trap() {
+		let (mut debugger, mut machine) = debug(RECURSIVE);
+		run(&mut debugger, &mut machine, "step 2");
+		run(&mut debugger, &mut machine, "next");
+		assert_eq!(machine.reg(Register::PC), 0x3003);
+		assert_eq!(machine.reg(Register::R1), 3);
+
+		let out = run(&mut debugger, &mut machine, "next");
+		assert!(out.contains("program halted"), "{}", out);
+	}
+
+	#[test]
+	fn finish_returns_from_the_current_level() {
+		let (mut debugger, mut machine) = debug(RECURSIVE);
+		run(&mut debugger, &mut machine, "break DONE");
+		run(&mut debugger, &mut machine, "continue");
+		run(&mut debugger, &mut machine, "delete");
+		run(&mut debugger, &mut machine, "finish");
+		assert_eq!(machine.reg(Register::PC), 0x300a);
+		assert_eq!(machine.reg(Register::R6), 0x5000 - 2);
+	}
+}
@@ -0,0 +1,620 @@
+//! Memory mapped devices of the I/O page, xFE00-xFFFF.
+//!
+//! A [`Device`] answers accesses to a range of addresses in the page
+//! once attached to a machine's memory, see [`Memory::attach`]. The
+//! keyboard, the display, the timer and the machine control register
+//! are attached to every machine.
+//!
+//! The keyboard interrupts through x80 at priority 4, as the ISA has
+//! it. The ISA has no timer, so it takes the next vector, x81, at
+//! priority 6: above the keyboard and the display, so that a
+//! scheduler's tick isn't held back while their service routines run,
+//! and below 7, which masks every interrupt. TMVR moves it elsewhere.
+//!
+//! [`Memory::attach`]: crate::memory::Memory::attach
+
+use crate::console::ConsoleHandle;
+use std::{
+	fmt,
+	ops::RangeInclusive,
+	time::{Duration, Instant},
+};
+
+/// addresses reserved for memory mapped devices
+pub const IO_PAGE: RangeInclusive<u16> = 0xfe00..=0xffff;
+
+/// keyboard status register: bit 15 is set while KBDR holds a key,
+/// bit 14 enables keyboard interrupts
+pub const KBSR: u16 = 0xfe00;
+/// keyboard data register
+pub const KBDR: u16 = 0xfe02;
+/// display status register: bit 15 is set while the display is ready
+/// to take a character, bit 14 enables display interrupts
+pub const DSR: u16 = 0xfe04;
+/// display data register
+pub const DDR: u16 = 0xfe06;
+/// timer control register: bit 15 is set once the interval elapsed,
+/// bit 14 enables timer interrupts, bit 1 counts milliseconds instead
+/// of instructions and bit 0 starts the timer
+pub const TMCR: u16 = 0xfe08;
+/// timer interval register, in instructions or milliseconds
+pub const TMIR: u16 = 0xfe0a;
+/// timer vector register: the interrupt vector the timer requests
+pub const TMVR: u16 = 0xfe0c;
+/// machine control register: the clock runs while bit 15 is set
+pub const MCR: u16 = 0xfffe;
+
+/// interrupt vector and priority level of the keyboard
+pub const KEYBOARD_VECTOR: u8 = 0x80;
+pub const KEYBOARD_PRIORITY: u16 = 4;
+/// interrupt vector and priority level of the display; the ISA leaves
+/// its vector unassigned, so it follows the keyboard's
+pub const DISPLAY_VECTOR: u8 = 0x82;
+pub const DISPLAY_PRIORITY: u16 = 4;
+/// interrupt vector the timer requests until TMVR is written, and its
+/// priority level
+pub const TIMER_VECTOR: u8 = 0x81;
+pub const TIMER_PRIORITY: u16 = 6;
+
+const READY: u16 = 1 << 15;
+const INTERRUPT_ENABLE: u16 = 1 << 14;
+/// bits of TMCR
+pub const TIMER_ELAPSED: u16 = 1 << 15;
+pub const TIMER_INTERRUPT_ENABLE: u16 = 1 << 14;
+pub const TIMER_WALL_CLOCK: u16 = 1 << 1;
+pub const TIMER_START: u16 = 1 << 0;
+/// bit of MCR that lets the clock run
+pub const CLOCK_ENABLE: u16 = 1 << 15;
+
+/// A request for the CPU to run the service routine at 'vector' of the
+/// interrupt vector table; it is taken once the program runs at a
+/// lower priority level than 'priority'.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Interrupt {
+	pub vector: u8,
+	pub priority: u16,
+}
+
+/// A memory mapped device.
+pub trait Device: Send + fmt::Debug {
+	/// the addresses of the device's registers, within [`IO_PAGE`]
+	fn range(&self) -> RangeInclusive<u16>;
+
+	/// a register as seen by a debugger, without side effects
+	fn peek(&self, addr: u16) -> u16;
+
+	/// a register as read by the program
+	fn read(&mut self, addr: u16) -> u16 {
+		self.peek(addr)
+	}
+
+	fn write(&mut self, addr: u16, data: u16);
+
+	/// called after every instruction
+	fn tick(&mut self) {}
+
+	/// the interrupt the device requests, if any
+	fn interrupt(&mut self) -> Option<Interrupt> {
+		None
+	}
+
+	/// whether ticks change nothing and no interrupt is requested
+	/// until the program accesses the device, which lets the machine
+	/// skip the device while it runs compiled code
+	fn is_idle(&self) -> bool {
+		false
+	}
+
+	/// the device's state, for snapshots of the machine
+	fn save(&self) -> Vec<u16> {
+		Vec::new()
+	}
+
+	/// return to a state 'save' returned
+	fn restore(&mut self, _state: &[u16]) {}
+}
+
+/// Why a device couldn't be attached.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AttachError {
+	/// its range reaches outside of [`IO_PAGE`]
+	OutsideIoPage(RangeInclusive<u16>),
+	/// its range overlaps that of an attached device
+	Overlap(RangeInclusive<u16>),
+}
+
+/// The keyboard, reading keys from a console.
+#[derive(Debug)]
+pub struct Keyboard {
+	status: u16,
+	data: u16,
+	console: ConsoleHandle,
+}
+
+/// The display, writing characters to a console.
+#[derive(Debug)]
+pub struct Display {
+	status: u16,
+	console: ConsoleHandle,
+}
+
+/// A periodic timer, counting executed instructions or wall-clock
+/// milliseconds.
+///
+/// Once started, TMCR's elapsed bit is set every TMIR instructions or
+/// milliseconds and stays set until the program reads TMCR. With
+/// interrupts enabled the timer requests the interrupt at TMVR while
+/// the bit is set, so service routines acknowledge it by reading TMCR.
+#[derive(Debug)]
+pub struct Timer {
+	control: u16,
+	interval: u16,
+	vector: u8,
+	/// instructions left until the interval elapses
+	remaining: u16,
+	/// whether the instruction executing started the interval, which
+	/// doesn't count towards it
+	starting: bool,
+	/// when the running wall-clock interval began
+	started: Instant,
+}
+
+/// The machine control register.
+#[derive(Debug)]
+pub struct MachineControl {
+	mcr: u16,
+}
+
+impl Keyboard {
+	pub fn new(console: ConsoleHandle) -> Self {
+		Self {
+			status: 0,
+			data: 0,
+			console,
+		}
+	}
+
+	/// move a pending key into KBDR unless KBDR still holds one
+	fn poll(&mut self) {
+		if self.status & READY != 0 {
+			return;
+		}
+
+		// a console that fails to deliver a key just has none
+		let key = match self.console.key_ready() {
+			Ok(true) => self.console.read_key().unwrap_or(None),
+			_ => None,
+		};
+		if let Some(key) = key {
+			self.data = key as u16;
+			self.status |= READY;
+		}
+	}
+}
+
+impl Device for Keyboard {
+	fn range(&self) -> RangeInclusive<u16> {
+		KBSR..=KBDR
+	}
+
+	/// reading KBSR polls the console, reading KBDR takes the key
+	fn read(&mut self, addr: u16) -> u16 {
+		match addr {
+			KBSR => {
+				self.poll();
+				self.status
+			}
+			KBDR => {
+				self.status &= !READY;
+				self.data
+			}
+			_ => 0,
+		}
+	}
+
+	fn peek(&self, addr: u16) -> u16 {
+		match addr {
+			KBSR => self.status,
+			KBDR => self.data,
+			_ => 0,
+		}
+	}
+
+	/// only the interrupt enable bit of KBSR is writable
+	fn write(&mut self, addr: u16, data: u16) {
+		if addr == KBSR {
+			self.status = (self.status & READY) | (data & INTERRUPT_ENABLE);
+		}
+	}
+
+	/// a key is pending and interrupts are enabled
+	fn interrupt(&mut self) -> Option<Interrupt> {
+		if self.status & INTERRUPT_ENABLE == 0 {
+			return None;
+		}
+		self.poll();
+		(self.status & READY != 0).then_some(Interrupt {
+			vector: KEYBOARD_VECTOR,
+			priority: KEYBOARD_PRIORITY,
+		})
+	}
+
+	/// keys are only polled for interrupts if they are enabled
+	fn is_idle(&self) -> bool {
+		self.status & INTERRUPT_ENABLE == 0
+	}
+
+	/// KBSR and the key in KBDR
+	fn save(&self) -> Vec<u16> {
+		vec![self.status, self.data]
+	}
+
+	fn restore(&mut self, state: &[u16]) {
+		if let &[status, data] = state {
+			self.status = status;
+			self.data = data;
+		}
+	}
+}
+
+impl Display {
+	pub fn new(console: ConsoleHandle) -> Self {
+		Self {
+			status: READY,
+			console,
+		}
+	}
+}
+
+impl Device for Display {
+	fn range(&self) -> RangeInclusive<u16> {
+		DSR..=DDR
+	}
+
+	fn peek(&self, addr: u16) -> u16 {
+		match addr {
+			DSR => self.status,
+			_ => 0,
+		}
+	}
+
+	/// writing DDR prints a character and keeps the display busy for
+	/// the rest of the instruction; only the interrupt enable bit of
+	/// DSR is writable
+	fn write(&mut self, addr: u16, data: u16) {
+		match addr {
+			DSR => self.status = (self.status & READY) | (data & INTERRUPT_ENABLE),
+			DDR => {
+				// stores can't fail, so output the console rejects is lost
+				let _ = self.console.write(&[data as u8]);
+				self.status &= !READY;
+			}
+			_ => {}
+		}
+	}
+
+	/// an instruction passed, the display is ready again
+	fn tick(&mut self) {
+		self.status |= READY;
+	}
+
+	/// the display is ready and interrupts are enabled
+	fn interrupt(&mut self) -> Option<Interrupt> {
+		let requested = READY | INTERRUPT_ENABLE;
+		(self.status & requested == requested).then_some(Interrupt {
+			vector: DISPLAY_VECTOR,
+			priority: DISPLAY_PRIORITY,
+		})
+	}
+
+	/// ready, with interrupts disabled
+	fn is_idle(&self) -> bool {
+		self.status & (READY | INTERRUPT_ENABLE) == READY
+	}
+
+	fn save(&self) -> Vec<u16> {
+		vec![self.status]
+	}
+
+	fn restore(&mut self, state: &[u16]) {
+		if let &[status] = state {
+			self.status = status;
+		}
+	}
+}
+
+impl Default for Timer {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl Timer {
+	/// a stopped timer, requesting TIMER_VECTOR
+	pub fn new() -> Self {
+		Self {
+			control: 0,
+			interval: 0,
+			vector: TIMER_VECTOR,
+			remaining: 0,
+			starting: false,
+			started: Instant::now(),
+		}
+	}
+
+	fn running(&self) -> bool {
+		self.control & TIMER_START != 0 && self.interval != 0
+	}
+
+	/// begin a new interval
+	fn restart(&mut self) {
+		self.remaining = self.interval;
+		self.started = Instant::now();
+	}
+}
+
+impl Device for Timer {
+	fn range(&self) -> RangeInclusive<u16> {
+		TMCR..=TMVR
+	}
+
+	/// reading TMCR acknowledges an elapsed interval
+	fn read(&mut self, addr: u16) -> u16 {
+		let data = self.peek(addr);
+		if addr == TMCR {
+			self.control &= !TIMER_ELAPSED;
+		}
+		data
+	}
+
+	fn peek(&self, addr: u16) -> u16 {
+		match addr {
+			TMCR => self.control,
+			TMIR => self.interval,
+			TMVR => self.vector as u16,
+			_ => 0,
+		}
+	}
+
+	/// writing TMCR or TMIR starts the interval over after the writing
+	/// instruction, the elapsed bit is read only
+	fn write(&mut self, addr: u16, data: u16) {
+		match addr {
+			TMCR => {
+				self.control = (self.control & TIMER_ELAPSED) | (data & !TIMER_ELAPSED);
+				self.restart();
+				self.starting = true;
+			}
+			TMIR => {
+				self.interval = data;
+				self.restart();
+				self.starting = true;
+			}
+			TMVR => self.vector = data as u8,
+			_ => {}
+		}
+	}
+
+	/// count the instruction, or check the clock
+	fn tick(&mut self) {
+		let starting = std::mem::take(&mut self.starting);
+		if !self.running() || starting {
+			return;
+		}
+
+		let elapsed = if self.control & TIMER_WALL_CLOCK != 0 {
+			self.started.elapsed() >= Duration::from_millis(self.interval as u64)
+		} else {
+			self.remaining = self.remaining.saturating_sub(1);
+			self.remaining == 0
+		};
+		if elapsed {
+			self.control |= TIMER_ELAPSED;
+			self.restart();
+		}
+	}
+
+	/// an interval elapsed and interrupts are enabled
+	fn interrupt(&mut self) -> Option<Interrupt> {
+		let requested = TIMER_ELAPSED | TIMER_INTERRUPT_ENABLE;
+		(self.control & requested == requested).then_some(Interrupt {
+			vector: self.vector,
+			priority: TIMER_PRIORITY,
+		})
+	}
+
+	/// stopped, with no elapsed interval to interrupt for
+	fn is_idle(&self) -> bool {
+		let requested = TIMER_ELAPSED | TIMER_INTERRUPT_ENABLE;
+		!self.running() && self.control & requested != requested
+	}
+
+	/// the registers and the instructions left; a wall-clock interval
+	/// starts over on restore
+	fn save(&self) -> Vec<u16> {
+		vec![self.control, self.interval, self.vector as u16, self.remaining]
+	}
+
+	fn restore(&mut self, state: &[u16]) {
+		if let &[control, interval, vector, remaining] = state {
+			self.control = control;
+			self.interval = interval;
+			self.vector = vector as u8;
+			self.remaining = remaining;
+			self.started = Instant::now();
+		}
+	}
+}
+
+impl Default for MachineControl {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl MachineControl {
+	pub fn new() -> Self {
+		Self { mcr: CLOCK_ENABLE }
+	}
+}
+
+impl Device for MachineControl {
+	fn range(&self) -> RangeInclusive<u16> {
+		MCR..=MCR
+	}
+
+	fn peek(&self, _addr: u16) -> u16 {
+		self.mcr
+	}
+
+	fn write(&mut self, _addr: u16, data: u16) {
+		self.mcr = data;
+	}
+
+	fn is_idle(&self) -> bool {
+		true
+	}
+
+	fn save(&self) -> Vec<u16> {
+		vec![self.mcr]
+	}
+
+	fn restore(&mut self, state: &[u16]) {
+		if let &[mcr] = state {
+			self.mcr = mcr;
+		}
+	}
+}
+
+impl fmt::Display for AttachError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::OutsideIoPage(range) => write!(
+				f, "x{:04X}-x{:04X} is outside of the I/O page",
+				range.start(), range.end()
+			),
+			Self::Overlap(range) => write!(
+				f, "x{:04X}-x{:04X} overlaps an attached device",
+				range.start(), range.end()
+			),
+		}
+	}
+}
+
+impl std::error::Error for AttachError {}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::asm;
+	use crate::console::{Capture, Headless};
+	use crate::cpu::{psr, register::Register, INTERRUPT_VECTOR_TABLE};
+	use crate::machine::Machine;
+
+	fn display() -> (Display, Capture) {
+		let output = Capture::new();
+		let console = ConsoleHandle::new(Headless::new(Vec::new(), output.clone()));
+		(Display::new(console), output)
+	}
+
+	#[test]
+	fn the_display_is_ready_but_for_the_instruction_writing_ddr() {
+		let (mut display, _) = display();
+		assert_eq!(display.read(DSR), READY);
+		display.write(DDR, b'a' as u16);
+		assert_eq!(display.read(DSR), 0);
+		display.tick();
+		assert_eq!(display.read(DSR), READY);
+	}
+
+	#[test]
+	fn ddr_writes_reach_the_console() {
+		let (mut display, output) = display();
+		for &ch in b"hi\n" {
+			display.write(DDR, ch as u16);
+			display.tick();
+		}
+		assert_eq!(output.contents(), b"hi\n");
+		// DDR isn't read back
+		assert_eq!(display.read(DDR), 0);
+	}
+
+	#[test]
+	fn the_display_interrupts_only_when_enabled() {
+		let (mut display, _) = display();
+		assert_eq!(display.interrupt(), None);
+		assert!(display.is_idle());
+
+		display.write(DSR, INTERRUPT_ENABLE | 0x1234);
+		assert_eq!(display.read(DSR), READY | INTERRUPT_ENABLE);
+		let interrupt = Interrupt { vector: DISPLAY_VECTOR, priority: DISPLAY_PRIORITY };
+		assert_eq!(display.interrupt(), Some(interrupt));
+		assert!(!display.is_idle());
+
+		// not while busy
+		display.write(DDR, b'a' as u16);
+		assert_eq!(display.interrupt(), None);
+		display.tick();
+		assert_eq!(display.interrupt(), Some(interrupt));
+
+		display.write(DSR, 0);
+		assert_eq!(display.interrupt(), None);
+	}
+
+	#[test]
+	fn timer_registers_read_back_but_for_the_elapsed_bit() {
+		let mut timer = Timer::new();
+		assert_eq!(timer.read(TMVR), TIMER_VECTOR as u16);
+		timer.write(TMIR, 2);
+		timer.write(TMVR, 0x90);
+		timer.write(TMCR, TIMER_ELAPSED | TIMER_INTERRUPT_ENABLE | TIMER_START);
+		assert_eq!(timer.read(TMIR), 2);
+		assert_eq!(timer.read(TMVR), 0x90);
+		assert_eq!(timer.read(TMCR), TIMER_INTERRUPT_ENABLE | TIMER_START);
+		assert_eq!(timer.interrupt(), None);
+
+		for _ in 0..3 {
+			timer.tick();
+		}
+		let interrupt = Interrupt { vector: 0x90, priority: TIMER_PRIORITY };
+		assert_eq!(timer.interrupt(), Some(interrupt));
+		// peeking leaves it set, reading TMCR acknowledges it
+		assert_ne!(timer.peek(TMCR) & TIMER_ELAPSED, 0);
+		assert_ne!(timer.read(TMCR) & TIMER_ELAPSED, 0);
+		assert_eq!(timer.read(TMCR) & TIMER_ELAPSED, 0);
+		assert_eq!(timer.interrupt(), None);
+	}
+
+	#[test]
+	fn timer_interrupts_come_the_interval_after_it_starts() {
+		let object = asm::assemble("
+        .ORIG x3000
+        LD R0, INTERVAL
+        STI R0, TMIRP
+        LD R0, CONTROL
+        STI R0, TMCRP       ; starts the interval
+        ADD R1, R1, #1
+        ADD R1, R1, #1
+        ADD R1, R1, #1
+        ADD R1, R1, #1
+        HALT
+INTERVAL .FILL #3
+CONTROL .FILL x4001         ; interrupts enabled, started
+TMIRP   .FILL xFE0A
+TMCRP   .FILL xFE08
+        .END
+		").unwrap().to_object();
+		let mut machine = Machine::new();
+		machine.set_console(Headless::new(Vec::new(), Capture::new()));
+		machine.load(&object).unwrap();
+		machine.poke(INTERRUPT_VECTOR_TABLE + TIMER_VECTOR as u16, 0x1000);
+
+		while machine.reg(Register::PC) != 0x1000 {
+			machine.step().unwrap();
+		}
+		// three instructions after the STI, at the timer's priority
+		assert_eq!(machine.reg(Register::R1), 3);
+		assert_eq!(machine.peek(machine.reg(Register::R6)), 0x3007);
+		assert_eq!(psr::priority(machine.reg(Register::Psr)), TIMER_PRIORITY);
+	}
+}
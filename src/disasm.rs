@@ -0,0 +1,127 @@
+use crate::asm::SymbolTable;
+use crate::cpu::{instruction::OpCode, Cpu};
+use std::collections::HashSet;
+
+/// whether 'word' looks like an instruction rather than data, i.e. it
+/// decodes and its unused bits are clear
+pub fn is_code(word: u16) -> bool {
+	let field = |high: u16, low: u16| (word >> low) & ((1 << (high - low + 1)) - 1);
+
+	match word >> 12 {
+		0b0000 => field(11, 9) != 0,	/* a branch that never branches is data */
+		0b0001 | 0b0101 => field(5, 5) == 1 || field(4, 3) == 0,
+		0b0100 => field(11, 11) == 1 || (field(10, 9) == 0 && field(5, 0) == 0),
+		0b1000 => field(11, 0) == 0,
+		0b1001 => field(5, 0) == 0b111111,
+		0b1100 => field(11, 9) == 0 && field(5, 0) == 0,
+		0b1101 => false,
+		0b1111 => field(11, 8) == 0,
+		_ => true,
+	}
+}
+
+/// disassemble the word at 'addr', naming PC relative targets after
+/// labels in 'symbols' where possible; data becomes a .FILL
+pub fn disassemble(addr: u16, word: u16, symbols: &SymbolTable) -> String {
+	let instr = match Cpu::decode_word(word) {
+		Some(instr) if is_code(word) => instr,
+		_ => return fill(word),
+	};
+
+	instr.to_asm(|offset| {
+		let target = addr.wrapping_add(1).wrapping_add(offset as u16);
+		match symbols.name_of(target) {
+			Some(label) => String::from(label),
+			None => format!("x{:04X}", target),
+		}
+	})
+}
+
+fn fill(word: u16) -> String {
+	match char::from_u32(word as u32) {
+		Some(ch) if ch.is_ascii_graphic() || ch == ' ' => {
+			format!(".FILL x{:04X}\t; '{}'", word, ch)
+		}
+		_ => format!(".FILL x{:04X}", word),
+	}
+}
+
+/// addresses the code in an image loads from, stores to or takes the
+/// address of, which therefore hold data
+fn data_refs(origin: u16, words: &[u16]) -> HashSet<u16> {
+	words
+		.iter()
+		.enumerate()
+		.filter(|&(_, &word)| is_code(word))
+		.filter_map(|(idx, &word)| {
+			let instr = Cpu::decode_word(word)?;
+			match instr.opcode() {
+				OpCode::LD | OpCode::LDI | OpCode::LEA | OpCode::ST | OpCode::STI => {
+					let addr = origin.wrapping_add(idx as u16);
+					Some(addr.wrapping_add(1).wrapping_add(instr.imm()?))
+				}
+				_ => None,
+			}
+		})
+		.collect()
+}
+
+/// disassemble a whole image, one line per word: address, raw word,
+/// label and assembly
+pub fn listing(origin: u16, words: &[u16], symbols: &SymbolTable) -> String {
+	let data = data_refs(origin, words);
+	let label_width = symbols
+		.iter()
+		.map(|(name, _)| name.len())
+		.max()
+		.unwrap_or(0);
+
+	words
+		.iter()
+		.enumerate()
+		.map(|(idx, &word)| {
+			let addr = origin.wrapping_add(idx as u16);
+			format!(
+				"x{:04X}  x{:04X}  {:<width$}  {}\n",
+				addr,
+				word,
+				symbols.name_of(addr).unwrap_or(""),
+				if data.contains(&addr) {
+					fill(word)
+				} else {
+					disassemble(addr, word, symbols)
+				},
+				width = label_width,
+			)
+		})
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn branches_on_no_condition_are_data() {
+		let symbols = SymbolTable::new();
+		assert!(!is_code(0x0000));
+		assert!(!is_code(0x0041));
+		assert_eq!(disassemble(0x3000, 0x0041, &symbols), ".FILL x0041\t; 'A'");
+		assert_eq!(Cpu::decode_word(0x0041).unwrap().to_string(), ".FILL x0041");
+		assert_eq!(disassemble(0x3000, 0x0e02, &symbols), "BRnzp x3003");
+	}
+
+	#[test]
+	fn listings_name_labels_and_data() {
+		let mut symbols = SymbolTable::new();
+		symbols.insert("START", 0x3000);
+		symbols.insert("VALUE", 0x3002);
+		// LD R0, VALUE; HALT; .FILL x1234
+		let listing = listing(0x3000, &[0x2001, 0xf025, 0x1234], &symbols);
+		assert_eq!(listing, "\
+x3000  x2001  START  LD R0, VALUE
+x3001  xF025         HALT
+x3002  x1234  VALUE  .FILL x1234
+");
+	}
+}
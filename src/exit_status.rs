@@ -0,0 +1,52 @@
+//! Exit statuses of the vlc3 binary, so that scripts can tell how a
+//! program ended without scraping its output.
+
+use crate::fault::Fault;
+use crate::machine::Stop;
+
+/// the program halted
+pub const HALTED: i32 = 0;
+/// vlc3 couldn't run the program, e.g. it failed to load
+pub const ERROR: i32 = 1;
+/// the command line is invalid
+pub const USAGE: i32 = 2;
+/// the program faulted
+pub const FAULT: i32 = 3;
+/// the program exceeded --max-instructions or --timeout, as with
+/// timeout(1)
+pub const TIMEOUT: i32 = 124;
+/// the program was interrupted with Ctrl-C, as shells report SIGINT
+pub const INTERRUPTED: i32 = 130;
+
+/// the status vlc3 exits with when a run ends with 'result'
+pub fn of(result: &Result<Stop, Fault>) -> i32 {
+	match result {
+		Ok(Stop::Halted) => HALTED,
+		Ok(Stop::InstructionLimit | Stop::Timeout) => TIMEOUT,
+		Ok(Stop::Watchpoint | Stop::Interrupted) => INTERRUPTED,
+		Err(Fault::Load(_)) => ERROR,
+		Err(_) => FAULT,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn every_way_a_run_ends_has_its_status() {
+		let fault = Fault::IllegalOpcode { pc: 0x3000, instr: 0xd000 };
+		let statuses = [
+			(Ok(Stop::Halted), HALTED),
+			(Ok(Stop::InstructionLimit), TIMEOUT),
+			(Ok(Stop::Timeout), TIMEOUT),
+			(Ok(Stop::Watchpoint), INTERRUPTED),
+			(Ok(Stop::Interrupted), INTERRUPTED),
+			(Err(Fault::Load(String::from("missing"))), ERROR),
+			(Err(fault), FAULT),
+		];
+		for (result, status) in statuses {
+			assert_eq!(of(&result), status, "{:?}", result);
+		}
+	}
+}
@@ -0,0 +1,86 @@
+use std::{error::Error, fmt, io};
+
+/// Reasons why a machine had to stop executing a program.
+#[derive(Debug)]
+pub enum Fault {
+	/// the instruction word has an opcode vlc3 can't execute
+	IllegalOpcode { pc: u16, instr: u16 },
+
+	/// TRAP with a vector no service routine is installed for
+	UnknownTrap { pc: u16, instr: u16 },
+
+	/// a privileged instruction was executed in user mode
+	PrivilegeViolation { pc: u16, instr: u16 },
+
+	/// the program image couldn't be loaded
+	Load(String),
+
+	/// reading from or writing to the console failed
+	Io(io::Error),
+}
+
+impl Fault {
+	/// address of the faulting instruction, if an instruction caused it
+	pub fn pc(&self) -> Option<u16> {
+		match self {
+			Self::IllegalOpcode { pc, .. }
+			| Self::UnknownTrap { pc, .. }
+			| Self::PrivilegeViolation { pc, .. } => Some(*pc),
+			Self::Load(_) | Self::Io(_) => None,
+		}
+	}
+
+	/// raw word of the faulting instruction, if an instruction caused it
+	pub fn instr(&self) -> Option<u16> {
+		match self {
+			Self::IllegalOpcode { instr, .. }
+			| Self::UnknownTrap { instr, .. }
+			| Self::PrivilegeViolation { instr, .. } => Some(*instr),
+			Self::Load(_) | Self::Io(_) => None,
+		}
+	}
+
+	/// entry of the interrupt vector table for the exception the
+	/// ISA raises on this fault, if it raises one
+	pub fn exception_vector(&self) -> Option<u8> {
+		match self {
+			Self::PrivilegeViolation { .. } => Some(0x00),
+			Self::IllegalOpcode { .. } => Some(0x01),
+			_ => None,
+		}
+	}
+}
+
+impl fmt::Display for Fault {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::IllegalOpcode { pc, instr } => write!(
+				f, "illegal opcode x{:04X} at x{:04X}", instr, pc
+			),
+			Self::UnknownTrap { pc, instr } => write!(
+				f, "unknown trap vector x{:02X} (x{:04X}) at x{:04X}",
+				instr & 0xff, instr, pc
+			),
+			Self::PrivilegeViolation { pc, instr } => write!(
+				f, "privilege mode violation x{:04X} at x{:04X}", instr, pc
+			),
+			Self::Load(reason) => write!(f, "failed to load program: {}", reason),
+			Self::Io(e) => write!(f, "I/O error: {}", e),
+		}
+	}
+}
+
+impl Error for Fault {
+	fn source(&self) -> Option<&(dyn Error + 'static)> {
+		match self {
+			Self::Io(e) => Some(e),
+			_ => None,
+		}
+	}
+}
+
+impl From<io::Error> for Fault {
+	fn from(value: io::Error) -> Self {
+		Self::Io(value)
+	}
+}
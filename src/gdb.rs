@@ -0,0 +1,836 @@
+//! A GDB remote serial protocol stub.
+//!
+//! LC-3 memory is word addressed, so addresses in `m`, `M` and `Z`
+//! packets are word addresses while lengths count bytes: each word is
+//! sent as two bytes, low byte first. Registers are R0-R7, PC and PSR,
+//! 16 bits each and also little endian.
+
+use crate::cpu::register::Register;
+use crate::exit_status;
+use crate::fault::Fault;
+use crate::machine::{Machine, Stop};
+use crate::watch::{Access, WatchEvent, Watchpoint};
+use std::{
+	collections::{BTreeSet, HashMap, VecDeque},
+	io::{self, Read, Write},
+	net::{TcpListener, TcpStream},
+	os::unix::net::{UnixListener, UnixStream},
+};
+
+/// instructions executed between checks for an interrupt request
+const POLL_INTERVAL: usize = 1024;
+
+/// largest packet the front end may send or ask for, in bytes
+const PACKET_SIZE: usize = 0x4000;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.vlc3.lc3.core">
+    <reg name="r0" bitsize="16" type="int"/>
+    <reg name="r1" bitsize="16" type="int"/>
+    <reg name="r2" bitsize="16" type="int"/>
+    <reg name="r3" bitsize="16" type="int"/>
+    <reg name="r4" bitsize="16" type="int"/>
+    <reg name="r5" bitsize="16" type="int"/>
+    <reg name="r6" bitsize="16" type="data_ptr"/>
+    <reg name="r7" bitsize="16" type="code_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+    <reg name="psr" bitsize="16" type="int"/>
+  </feature>
+</target>
+"#;
+
+/// A connection to a debugger front end.
+pub trait Connection: Read + Write {
+	/// read a byte the front end already sent without blocking, None
+	/// if there is none
+	fn poll_byte(&mut self) -> io::Result<Option<u8>>;
+}
+
+/// A front end connected over TCP or a Unix socket.
+#[derive(Debug)]
+pub enum Stream {
+	Tcp(TcpStream),
+	Unix(UnixStream),
+}
+
+/// How a session with the front end ended.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Detach {
+	/// the front end detached or hung up, the program may keep running
+	Detached,
+	/// the front end asked to kill the program
+	Killed,
+}
+
+/// Why the program stopped, as reported to the front end.
+enum StopReason {
+	Trap,
+	Interrupted,
+	Watch(WatchEvent, Access),
+	Fault,
+	/// the program ended, vlc3 would exit with this status
+	Exited(i32),
+}
+
+/// Serves one front end, driving a [`Machine`] on its behalf.
+pub struct GdbStub<'a, C: Connection> {
+	machine: &'a mut Machine,
+	conn: C,
+	no_ack: bool,
+	breakpoints: BTreeSet<u16>,
+	/// ids of watchpoints set by Z2-Z4, by packet type, address and length
+	watchpoints: HashMap<(u8, u16, u16), usize>,
+	last_stop: String,
+	/// bytes read while looking for interrupt requests
+	pending: VecDeque<u8>,
+}
+
+/// wait for a front end to connect to 'target': a TCP port on the
+/// loopback interface or a Unix socket path
+pub fn accept(target: &str) -> io::Result<Stream> {
+	match target.parse::<u16>() {
+		Ok(port) => {
+			let listener = TcpListener::bind(("127.0.0.1", port))?;
+			let (stream, _) = listener.accept()?;
+			stream.set_nodelay(true)?;
+			Ok(Stream::Tcp(stream))
+		}
+		Err(_) => {
+			let listener = UnixListener::bind(target)?;
+			let (stream, _) = listener.accept()?;
+			Ok(Stream::Unix(stream))
+		}
+	}
+}
+
+impl Read for Stream {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		match self {
+			Self::Tcp(stream) => stream.read(buf),
+			Self::Unix(stream) => stream.read(buf),
+		}
+	}
+}
+
+impl Write for Stream {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		match self {
+			Self::Tcp(stream) => stream.write(buf),
+			Self::Unix(stream) => stream.write(buf),
+		}
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		match self {
+			Self::Tcp(stream) => stream.flush(),
+			Self::Unix(stream) => stream.flush(),
+		}
+	}
+}
+
+impl Connection for Stream {
+	fn poll_byte(&mut self) -> io::Result<Option<u8>> {
+		let mut byte = [0_u8];
+		let read = match self {
+			Self::Tcp(stream) => {
+				stream.set_nonblocking(true)?;
+				let read = stream.read(&mut byte);
+				stream.set_nonblocking(false)?;
+				read
+			}
+			Self::Unix(stream) => {
+				stream.set_nonblocking(true)?;
+				let read = stream.read(&mut byte);
+				stream.set_nonblocking(false)?;
+				read
+			}
+		};
+
+		match read {
+			Ok(1) => Ok(Some(byte[0])),
+			Ok(_) => Ok(None),
+			Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+			Err(e) => Err(e),
+		}
+	}
+}
+
+impl<'a, C: Connection> GdbStub<'a, C> {
+	pub fn new(machine: &'a mut Machine, conn: C) -> Self {
+		Self {
+			machine,
+			conn,
+			no_ack: false,
+			breakpoints: BTreeSet::new(),
+			watchpoints: HashMap::new(),
+			last_stop: String::from("S05"),
+			pending: VecDeque::new(),
+		}
+	}
+
+	/// answer packets until the front end detaches, kills the program
+	/// or hangs up
+	pub fn serve(&mut self) -> io::Result<Detach> {
+		loop {
+			let packet = match self.receive()? {
+				Some(packet) => packet,
+				None => return Ok(Detach::Detached),
+			};
+
+			match packet.as_slice() {
+				b"D" => {
+					self.send("OK")?;
+					return Ok(Detach::Detached);
+				}
+				b"k" | b"vKill;1" => return Ok(Detach::Killed),
+				_ => {
+					let reply = self.handle(&packet)?;
+					self.send(&reply)?;
+				}
+			}
+		}
+	}
+
+	/// the next packet's payload, or None if the front end hung up;
+	/// interrupt requests outside of execution are ignored
+	fn receive(&mut self) -> io::Result<Option<Vec<u8>>> {
+		loop {
+			loop {
+				match self.read_byte()? {
+					None => return Ok(None),
+					Some(b'$') => break,
+					Some(_) => continue,	/* acks and stray interrupts */
+				}
+			}
+
+			// the checksum covers the bytes as sent, escapes included
+			let mut payload = Vec::new();
+			let mut actual = 0_u8;
+			loop {
+				let byte = match self.read_byte()? {
+					None => return Ok(None),
+					Some(b'#') => break,
+					Some(byte) => byte,
+				};
+				actual = actual.wrapping_add(byte);
+				if byte == b'}' {
+					match self.read_byte()? {
+						Some(escaped) => {
+							actual = actual.wrapping_add(escaped);
+							payload.push(escaped ^ 0x20);
+						}
+						None => return Ok(None),
+					}
+				} else {
+					payload.push(byte);
+				}
+			}
+
+			let mut checksum = [0_u8; 2];
+			for digit in &mut checksum {
+				match self.read_byte()? {
+					Some(byte) => *digit = byte,
+					None => return Ok(None),
+				}
+			}
+			let expected = hex_bytes(&checksum).map(|bytes| bytes[0]);
+
+			if !self.no_ack {
+				// a damaged packet is sent again
+				if expected != Some(actual) {
+					self.conn.write_all(b"-")?;
+					continue;
+				}
+				self.conn.write_all(b"+")?;
+			}
+			return Ok(Some(payload));
+		}
+	}
+
+	fn read_byte(&mut self) -> io::Result<Option<u8>> {
+		if let Some(byte) = self.pending.pop_front() {
+			return Ok(Some(byte));
+		}
+		let mut byte = [0_u8];
+		match self.conn.read(&mut byte)? {
+			0 => Ok(None),
+			_ => Ok(Some(byte[0])),
+		}
+	}
+
+	fn send(&mut self, payload: &str) -> io::Result<()> {
+		let escaped = payload
+			.bytes()
+			.flat_map(|byte| match byte {
+				b'#' | b'$' | b'}' | b'*' => vec![b'}', byte ^ 0x20],
+				byte => vec![byte],
+			})
+			.collect::<Vec<_>>();
+		let checksum = escaped.iter().fold(0_u8, |sum, &byte| sum.wrapping_add(byte));
+
+		self.conn.write_all(b"$")?;
+		self.conn.write_all(&escaped)?;
+		write!(self.conn, "#{:02x}", checksum)?;
+		self.conn.flush()
+	}
+
+	/// the reply to one packet
+	fn handle(&mut self, packet: &[u8]) -> io::Result<String> {
+		let (command, args) = match packet.split_first() {
+			Some((&command, args)) => (command, args),
+			None => return Ok(String::new()),
+		};
+
+		let reply = match command {
+			b'?' => self.last_stop.clone(),
+			b'g' => (0..10)
+				.map(|idx| hex_word(self.read_register(idx)))
+				.collect(),
+			b'G' => self.write_registers(args),
+			b'p' => match parse_hex(args) {
+				Some(idx) if idx < 10 => hex_word(self.read_register(idx as usize)),
+				_ => String::from("E01"),
+			},
+			b'P' => self.write_register(args),
+			b'm' => self.read_memory(args),
+			b'M' => self.write_memory(args),
+			b's' | b'c' => {
+				if !args.is_empty() {
+					match parse_hex(args) {
+						Some(addr) => self.machine.set_reg(Register::PC, addr),
+						None => return Ok(String::from("E01")),
+					}
+				}
+				let reason = self.resume(command == b's')?;
+				self.last_stop = stop_reply(&reason);
+				self.last_stop.clone()
+			}
+			b'Z' | b'z' => self.set_breakpoint(command == b'Z', args),
+			b'H' => String::from("OK"),
+			b'T' => String::from("OK"),
+			b'q' | b'Q' => self.query(packet),
+			_ => String::new(),
+		};
+		Ok(reply)
+	}
+
+	fn query(&mut self, packet: &[u8]) -> String {
+		if packet.starts_with(b"qSupported") {
+			return format!("PacketSize={:x};QStartNoAckMode+;qXfer:features:read+", PACKET_SIZE);
+		}
+		if let Some(range) = packet.strip_prefix(b"qXfer:features:read:target.xml:") {
+			return xfer(TARGET_XML, range);
+		}
+
+		match packet {
+			b"QStartNoAckMode" => {
+				self.no_ack = true;
+				String::from("OK")
+			}
+			b"qAttached" => String::from("1"),
+			b"qC" => String::from("QC1"),
+			b"qfThreadInfo" => String::from("m1"),
+			b"qsThreadInfo" => String::from("l"),
+			_ => String::new(),
+		}
+	}
+
+	fn read_register(&self, idx: usize) -> u16 {
+		self.machine.reg(register(idx))
+	}
+
+	fn write_register(&mut self, args: &[u8]) -> String {
+		let parsed = split_once(args, b'=').and_then(|(idx, value)| {
+			Some((parse_hex(idx)?, parse_word(value)?))
+		});
+
+		match parsed {
+			Some((idx, value)) if idx < 10 => {
+				self.machine.set_reg(register(idx as usize), value);
+				String::from("OK")
+			}
+			_ => String::from("E01"),
+		}
+	}
+
+	fn write_registers(&mut self, args: &[u8]) -> String {
+		if args.len() != 40 {
+			return String::from("E01");
+		}
+
+		let values = args.chunks(4).map(parse_word).collect::<Option<Vec<_>>>();
+		match values {
+			Some(values) => {
+				for (idx, value) in values.into_iter().enumerate() {
+					self.machine.set_reg(register(idx), value);
+				}
+				String::from("OK")
+			}
+			None => String::from("E01"),
+		}
+	}
+
+	fn read_memory(&self, args: &[u8]) -> String {
+		// the reply sends each byte as two hex digits
+		let (addr, length) = match parse_range(args) {
+			Some((addr, length)) if length * 2 <= PACKET_SIZE => (addr, length),
+			_ => return String::from("E01"),
+		};
+
+		(0..length)
+			.map(|byte| {
+				let word = self.machine.peek(addr.wrapping_add((byte / 2) as u16));
+				let byte = if byte % 2 == 0 { word & 0xff } else { word >> 8 };
+				format!("{:02x}", byte)
+			})
+			.collect()
+	}
+
+	fn write_memory(&mut self, args: &[u8]) -> String {
+		let parsed = split_once(args, b':').and_then(|(range, data)| {
+			let (addr, length) = parse_range(range)?;
+			let bytes = hex_bytes(data)?;
+			(bytes.len() == length).then_some((addr, bytes))
+		});
+		let (addr, bytes) = match parsed {
+			Some(parsed) => parsed,
+			None => return String::from("E01"),
+		};
+
+		for (idx, &byte) in bytes.iter().enumerate() {
+			let word_addr = addr.wrapping_add((idx / 2) as u16);
+			let word = self.machine.peek(word_addr);
+			let word = if idx % 2 == 0 {
+				(word & 0xff00) | byte as u16
+			} else {
+				(word & 0x00ff) | (byte as u16) << 8
+			};
+			self.machine.poke(word_addr, word);
+		}
+		String::from("OK")
+	}
+
+	/// "type,addr,kind" for Z and z packets
+	fn set_breakpoint(&mut self, insert: bool, args: &[u8]) -> String {
+		let fields = args.split(|&byte| byte == b',').collect::<Vec<_>>();
+		let parsed = match fields.as_slice() {
+			[kind, addr, length] => (|| Some((
+				u8::try_from(parse_hex(kind)?).ok()?,
+				parse_hex(addr)?,
+				parse_hex(length)?,
+			)))(),
+			_ => None,
+		};
+		let (kind, addr, length) = match parsed {
+			Some(parsed) => parsed,
+			None => return String::from("E01"),
+		};
+
+		let access = match kind {
+			0 | 1 => {
+				if insert {
+					self.breakpoints.insert(addr);
+				} else {
+					self.breakpoints.remove(&addr);
+				}
+				return String::from("OK");
+			}
+			2 => Access::Write,
+			3 => Access::Read,
+			4 => Access::ReadWrite,
+			_ => return String::new(),
+		};
+
+		let key = (kind, addr, length);
+		if insert {
+			let words = length.div_ceil(2).max(1);
+			let id = self.machine.add_watchpoint(Watchpoint::Memory {
+				start: addr,
+				end: addr.saturating_add(words - 1),
+				access,
+				value: None,
+			});
+			self.watchpoints.insert(key, id);
+		} else if let Some(id) = self.watchpoints.remove(&key) {
+			self.machine.remove_watchpoint(id);
+		}
+		String::from("OK")
+	}
+
+	/// run one instruction or until something stops the program
+	fn resume(&mut self, single_step: bool) -> io::Result<StopReason> {
+		// hits of watchpoints the front end didn't set aren't ours
+		self.machine.take_watch_hits();
+
+		let halted = StopReason::Exited(exit_status::of(&Ok(Stop::Halted)));
+		let mut steps = 0;
+		loop {
+			if !self.machine.is_running() {
+				return Ok(halted);
+			}
+			match self.machine.step() {
+				Ok(()) => {}
+				// the console is gone, so the program can't go on
+				Err(fault @ Fault::Io(_)) => {
+					return Ok(StopReason::Exited(exit_status::of(&Err(fault))));
+				}
+				Err(_) => return Ok(StopReason::Fault),
+			}
+			steps += 1;
+
+			if let Some(hit) = self.machine.take_watch_hits().into_iter().next() {
+				let access = self.watchpoints
+					.iter()
+					.find(|(_, &id)| id == hit.id)
+					.map(|(&(kind, _, _), _)| match kind {
+						3 => Access::Read,
+						4 => Access::ReadWrite,
+						_ => Access::Write,
+					})
+					.unwrap_or(Access::Write);
+				return Ok(StopReason::Watch(hit.event, access));
+			}
+			if !self.machine.is_running() {
+				return Ok(halted);
+			}
+			if single_step || self.breakpoints.contains(&self.machine.reg(Register::PC)) {
+				return Ok(StopReason::Trap);
+			}
+			if steps % POLL_INTERVAL == 0 && self.poll_interrupt()? {
+				return Ok(StopReason::Interrupted);
+			}
+		}
+	}
+
+	/// whether the front end sent an interrupt request (0x03); other
+	/// bytes it sent are kept for the next packet
+	fn poll_interrupt(&mut self) -> io::Result<bool> {
+		while let Some(byte) = self.conn.poll_byte()? {
+			if byte == 0x03 {
+				return Ok(true);
+			}
+			self.pending.push_back(byte);
+		}
+		Ok(false)
+	}
+}
+
+/// the part of 'document' an "offset,length" qXfer request asks for
+fn xfer(document: &str, range: &[u8]) -> String {
+	let (offset, length) = match parse_range(range) {
+		Some((offset, length)) => (offset as usize, length.min(PACKET_SIZE)),
+		None => return String::from("E01"),
+	};
+
+	let end = offset.saturating_add(length);
+	let chunk = document.get(offset..end.min(document.len())).unwrap_or("");
+	let more = end < document.len();
+	format!("{}{}", if more { 'm' } else { 'l' }, chunk)
+}
+
+fn register(idx: usize) -> Register {
+	match idx {
+		8 => Register::PC,
+		9 => Register::Psr,
+		idx => Register::gpr(idx as u16),
+	}
+}
+
+/// "addr,length" with both in hex
+fn parse_range(args: &[u8]) -> Option<(u16, usize)> {
+	let (addr, length) = split_once(args, b',')?;
+	Some((parse_hex(addr)?, parse_hex(length)? as usize))
+}
+
+fn split_once(bytes: &[u8], separator: u8) -> Option<(&[u8], &[u8])> {
+	let idx = bytes.iter().position(|&byte| byte == separator)?;
+	Some((&bytes[..idx], &bytes[idx + 1..]))
+}
+
+fn stop_reply(reason: &StopReason) -> String {
+	match reason {
+		StopReason::Trap => String::from("S05"),
+		StopReason::Interrupted => String::from("S02"),
+		StopReason::Watch(event, access) => {
+			let name = match access {
+				Access::Write => "watch",
+				Access::Read => "rwatch",
+				Access::ReadWrite => "awatch",
+			};
+			let addr = match event {
+				WatchEvent::Read { addr, .. } | WatchEvent::Write { addr, .. } => *addr,
+				WatchEvent::Register { .. } => 0,
+			};
+			format!("T05{}:{:04x};", name, addr)
+		}
+		StopReason::Fault => String::from("S04"),
+		StopReason::Exited(status) => format!("W{:02x}", status),
+	}
+}
+
+fn hex_word(word: u16) -> String {
+	format!("{:02x}{:02x}", word & 0xff, word >> 8)
+}
+
+/// a little endian word sent as 4 hex digits
+fn parse_word(hex: &[u8]) -> Option<u16> {
+	match hex_bytes(hex)?.as_slice() {
+		&[low, high] => Some((high as u16) << 8 | low as u16),
+		_ => None,
+	}
+}
+
+/// bytes sent as pairs of hex digits
+fn hex_bytes(hex: &[u8]) -> Option<Vec<u8>> {
+	if !hex.len().is_multiple_of(2) {
+		return None;
+	}
+	hex.chunks(2)
+		.map(|pair| Some(hex_digit(pair[0])? << 4 | hex_digit(pair[1])?))
+		.collect()
+}
+
+/// a number in hex that fits a word
+fn parse_hex(hex: &[u8]) -> Option<u16> {
+	if hex.is_empty() {
+		return None;
+	}
+	hex.iter().try_fold(0_u16, |number, &digit| {
+		number.checked_mul(16)?.checked_add(hex_digit(digit)? as u16)
+	})
+}
+
+fn hex_digit(digit: u8) -> Option<u8> {
+	(digit as char).to_digit(16).map(|value| value as u8)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::asm;
+	use crate::console::{Capture, Headless};
+	use std::{thread, time::Duration};
+
+	const COUNTER: &str = "
+        .ORIG x3000
+        AND R0, R0, #0
+        LD R1, COUNT
+LOOP    ADD R0, R0, #1
+        ADD R1, R1, #-1
+        BRp LOOP
+DONE    HALT
+COUNT   .FILL #3000
+        .END
+";
+
+	const SPIN: &str = "
+        .ORIG x3000
+SPIN    BRnzp SPIN
+        .END
+";
+
+	/// A scripted front end.
+	struct Client(UnixStream);
+
+	impl Client {
+		fn send(&mut self, payload: &[u8]) {
+			let checksum = payload.iter().fold(0_u8, |sum, &byte| sum.wrapping_add(byte));
+			self.0.write_all(b"$").unwrap();
+			self.0.write_all(payload).unwrap();
+			write!(self.0, "#{:02x}", checksum).unwrap();
+		}
+
+		fn reply(&mut self) -> String {
+			let mut bytes = Vec::new();
+			let mut byte = [0_u8];
+			loop {
+				self.0.read_exact(&mut byte).unwrap();
+				match byte[0] {
+					b'+' if bytes.is_empty() => continue,
+					b'#' => break,
+					byte => bytes.push(byte),
+				}
+			}
+			let mut checksum = [0_u8; 2];
+			self.0.read_exact(&mut checksum).unwrap();
+			assert_eq!(bytes[0], b'$');
+			String::from_utf8(bytes[1..].to_vec()).unwrap()
+		}
+
+		fn ask(&mut self, payload: &[u8]) -> String {
+			self.send(payload);
+			self.reply()
+		}
+	}
+
+	/// serve 'script' with 'source' loaded, returning how the session
+	/// ended and the machine it left
+	fn session<F>(source: &str, script: F) -> (Detach, Machine)
+	where
+		F: FnOnce(&mut Client) + Send + 'static,
+	{
+		let object = asm::assemble(source).unwrap().to_object();
+		let mut machine = Machine::new();
+		machine.set_console(Headless::new(Vec::new(), Capture::new()));
+		machine.load(&object).unwrap();
+
+		let (server, client) = UnixStream::pair().unwrap();
+		client.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+		let front_end = thread::spawn(move || {
+			let mut client = Client(client);
+			assert_eq!(client.ask(b"QStartNoAckMode"), "OK");
+			script(&mut client);
+		});
+
+		let detach = GdbStub::new(&mut machine, Stream::Unix(server)).serve().unwrap();
+		if let Err(panic) = front_end.join() {
+			std::panic::resume_unwind(panic);
+		}
+		(detach, machine)
+	}
+
+	#[test]
+	fn packets_with_bad_checksums_are_sent_again() {
+		let mut machine = Machine::new();
+		let (server, client) = UnixStream::pair().unwrap();
+		client.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+		let front_end = thread::spawn(move || {
+			let mut client = Client(client);
+			// many in a row, as a noisy line may damage them
+			for _ in 0..1000 {
+				client.0.write_all(b"$?#00").unwrap();
+				let mut nak = [0_u8];
+				client.0.read_exact(&mut nak).unwrap();
+				assert_eq!(&nak, b"-");
+			}
+			assert_eq!(client.ask(b"?"), "S05");
+			// the checksum covers escapes as sent: "}\x12" stands for '2'
+			client.0.write_all(b"$m3000,}\x12#eb").unwrap();
+			assert_eq!(client.reply(), "0000");
+			assert_eq!(client.ask(b"QStartNoAckMode"), "OK");
+			client.send(b"k");
+		});
+
+		let detach = GdbStub::new(&mut machine, Stream::Unix(server)).serve().unwrap();
+		if let Err(panic) = front_end.join() {
+			std::panic::resume_unwind(panic);
+		}
+		assert_eq!(detach, Detach::Killed);
+	}
+
+	#[test]
+	fn registers_and_memory_are_read_and_written() {
+		let (detach, machine) = session(COUNTER, |client| {
+			assert_eq!(client.ask(b"?"), "S05");
+			let registers = client.ask(b"g");
+			assert_eq!(registers.len(), 40);
+			assert_eq!(&registers[32..36], "0030");
+
+			let written = "0100020003000400050006000700080000300280";
+			assert_eq!(client.ask(format!("G{}", written).as_bytes()), "OK");
+			assert_eq!(client.ask(b"g"), written);
+			assert_eq!(client.ask(b"p3"), "0400");
+			assert_eq!(client.ask(b"P7=3412"), "OK");
+			assert_eq!(client.ask(b"p7"), "3412");
+
+			assert_eq!(client.ask(b"m3006,2"), "b80b");
+			assert_eq!(client.ask(b"M3006,2:0a00"), "OK");
+			assert_eq!(client.ask(b"m3005,3"), "25f00a");
+			client.send(b"k");
+		});
+		assert_eq!(detach, Detach::Killed);
+		assert_eq!(machine.reg(Register::gpr(7)), 0x1234);
+		assert_eq!(machine.peek(0x3006), 10);
+	}
+
+	#[test]
+	fn breakpoints_steps_and_exits_are_reported() {
+		let (detach, machine) = session(COUNTER, |client| {
+			assert_eq!(client.ask(b"Z0,3005,2"), "OK");
+			assert_eq!(client.ask(b"c"), "S05");
+			assert_eq!(client.ask(b"p8"), "0530");
+			assert_eq!(client.ask(b"p0"), "b80b");
+			assert_eq!(client.ask(b"z0,3005,2"), "OK");
+
+			assert_eq!(client.ask(b"c3000"), "W00");
+			assert_eq!(client.ask(b"?"), "W00");
+			client.send(b"D");
+			assert_eq!(client.reply(), "OK");
+		});
+		assert_eq!(detach, Detach::Detached);
+		assert!(!machine.is_running());
+	}
+
+	#[test]
+	fn single_steps_execute_one_instruction() {
+		session(COUNTER, |client| {
+			assert_eq!(client.ask(b"s"), "S05");
+			assert_eq!(client.ask(b"p8"), "0130");
+			assert_eq!(client.ask(b"s"), "S05");
+			assert_eq!(client.ask(b"p1"), "b80b");
+			client.send(b"k");
+		});
+	}
+
+	#[test]
+	fn ctrl_c_interrupts_a_running_program() {
+		let (_, machine) = session(SPIN, |client| {
+			client.send(b"c");
+			thread::sleep(Duration::from_millis(50));
+			client.0.write_all(&[0x03]).unwrap();
+			assert_eq!(client.reply(), "S02");
+			assert_eq!(client.ask(b"?"), "S02");
+			client.send(b"k");
+		});
+		assert!(machine.is_running());
+	}
+
+	#[test]
+	fn packets_sent_while_running_are_kept() {
+		session(COUNTER, |client| {
+			assert_eq!(client.ask(b"Z0,3005,2"), "OK");
+			// the stub polls for interrupts while the loop runs
+			client.send(b"c");
+			client.send(b"p0");
+			assert_eq!(client.reply(), "S05");
+			assert_eq!(client.reply(), "b80b");
+			client.send(b"k");
+		});
+	}
+
+	#[test]
+	fn malformed_packets_are_errors() {
+		session(COUNTER, |client| {
+			for packet in [
+				&b"m"[..],
+				b"m3000",
+				b"mzz,2",
+				b"m3000,\xc3\xa9",
+				b"m3000,ffff",
+				b"m10000,2",
+				b"M3000,2:abc",
+				b"M3000,2:ab",
+				b"M3000,1:\xc3\xa9",
+				b"M\xc3\xa9",
+				b"G0000",
+				b"G\xc3\xa90000000000000000000000000000000000000",
+				b"p",
+				b"p\xc3\xa9",
+				b"pa",
+				b"P1=12",
+				b"P1=12\xc3\xa9",
+				b"Z0,\xc3\xa9,2",
+				b"Z0,3000",
+				b"c\xc3\xa9",
+			] {
+				assert_eq!(client.ask(packet), "E01", "{}", String::from_utf8_lossy(packet));
+			}
+			assert_eq!(client.ask(b"\xc3\xa9"), "");
+			assert_eq!(client.ask(b""), "");
+			assert_eq!(client.ask(b"m3000,1"), "20");
+			client.send(b"k");
+		});
+	}
+}
@@ -0,0 +1,23 @@
+pub mod asm;
+pub mod console;
+pub mod cpu;
+pub mod dap;
+pub mod debugger;
+pub mod device;
+pub mod disasm;
+pub mod exit_status;
+pub mod fault;
+pub mod gdb;
+pub mod machine;
+pub mod memory;
+pub mod optional_utils;
+pub mod os;
+pub mod snapshot;
+pub mod testing;
+pub mod trace;
+pub mod undo;
+pub mod vm;
+pub mod watch;
+
+pub use fault::Fault;
+pub use machine::Machine;
@@ -0,0 +1,693 @@
+use crate::console::{Console, ConsoleHandle};
+use crate::cpu::{block::Blocks, psr, register::Register, Cpu, INTERRUPT_VECTOR_TABLE};
+use crate::device::{AttachError, Device, Interrupt};
+use crate::fault::Fault;
+use crate::memory::{Memory, MEMORY_SIZE};
+use crate::optional_utils::summary::Summary;
+use crate::os;
+use crate::snapshot::Snapshot;
+use crate::trace::{Record, Tracer};
+use crate::undo::{Change, Step, UndoLog};
+use crate::watch::{Watchpoint, WatchHit, Watchpoints};
+use std::{
+	fs, io,
+	path::Path,
+	str::FromStr,
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		Arc,
+	},
+	time::{Duration, Instant},
+};
+
+/// instructions [`Machine::run_limited`] executes between looks at the
+/// clock
+const CLOCK_CHECK_INTERVAL: u64 = 1024;
+
+/// longest [`Machine::run_limited`] waits for a key before it looks at
+/// the stop flag again
+const KEY_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// number of instructions [`Machine::recent_pcs`] remembers
+pub const HISTORY_LEN: usize = 16;
+
+/// Why [`Machine::run`] returned without a fault.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stop {
+	/// the program halted
+	Halted,
+	/// a watchpoint triggered, see [`Machine::take_watch_hits`]
+	Watchpoint,
+	/// the program executed as many instructions as [`Limits`] allow
+	InstructionLimit,
+	/// the program ran for as long as [`Limits`] allow
+	Timeout,
+	/// the flag of [`Machine::stop_flag`] was set
+	Interrupted,
+}
+
+/// Bounds on a run of [`Machine::run_limited`]; None is unbounded.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Limits {
+	pub max_instructions: Option<u64>,
+	pub timeout: Option<Duration>,
+}
+
+/// How [`Machine::run_limited`] executes instructions.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Engine {
+	/// fetch, decode and execute one instruction at a time
+	#[default]
+	Interpreter,
+	/// run basic blocks compiled to closures, interpreting what they
+	/// can't do; the results are those of the interpreter
+	Blocks,
+}
+
+impl FromStr for Engine {
+	type Err = String;
+
+	fn from_str(name: &str) -> Result<Self, Self::Err> {
+		match name {
+			"interpreter" => Ok(Self::Interpreter),
+			"blocks" => Ok(Self::Blocks),
+			_ => Err(format!("unknown engine '{}'", name)),
+		}
+	}
+}
+
+/// An LC-3 machine which owns its registers, memory and devices.
+///
+/// Every machine is independent of the others, so a process may drive
+/// as many of them as it likes, e.g. one per thread.
+#[derive(Debug)]
+pub struct Machine {
+	cpu: Cpu,
+	memory: Memory,
+	summary: Option<Summary>,
+	engine: Engine,
+	/// blocks compiled for [`Engine::Blocks`]
+	blocks: Blocks,
+	watchpoints: Watchpoints,
+	tracer: Tracer,
+	undo: UndoLog,
+	console: ConsoleHandle,
+	/// instructions executed so far
+	instructions: u64,
+	/// addresses of the last HISTORY_LEN instructions, the one executed
+	/// as instruction n at n % HISTORY_LEN
+	recent: [u16; HISTORY_LEN],
+	/// set to stop a run from another thread
+	stop_requested: Arc<AtomicBool>,
+}
+
+impl Default for Machine {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl Machine {
+	pub fn new() -> Self {
+		let watchpoints = Watchpoints::new();
+		let tracer = Tracer::new();
+		let undo = UndoLog::new();
+		let mut memory = Memory::new();
+		memory.set_watchpoints(watchpoints.clone());
+		memory.set_tracer(tracer.clone());
+		memory.set_undo_log(undo.clone());
+		let console = memory.console();
+		let mut cpu = Cpu::new();
+		cpu.set_watchpoints(watchpoints.clone());
+		cpu.set_tracer(tracer.clone());
+		cpu.set_undo_log(undo.clone());
+		cpu.set_console(console.clone());
+
+		Self {
+			cpu,
+			memory,
+			summary: None,
+			engine: Engine::default(),
+			blocks: Blocks::default(),
+			watchpoints,
+			tracer,
+			undo,
+			console,
+			instructions: 0,
+			recent: [0; HISTORY_LEN],
+			stop_requested: Arc::new(AtomicBool::new(false)),
+		}
+	}
+
+	/// do keyboard and display I/O on 'console' instead of the
+	/// standard input and output
+	pub fn set_console<C: Console + 'static>(&self, console: C) {
+		self.console.replace(Box::new(console));
+	}
+
+	/// connect 'device' to its addresses of the I/O page, xFE00-xFFFF
+	pub fn attach_device<D: Device + 'static>(&mut self, device: D) -> Result<(), AttachError> {
+		self.memory.attach(device)
+	}
+
+	/// record execution times of every opcode from now on
+	pub fn enable_summary(&mut self) {
+		self.summary = Some(Summary::new());
+	}
+
+	pub fn summary(&self) -> Option<&Summary> {
+		self.summary.as_ref()
+	}
+
+	/// execute with 'engine' from now on
+	pub fn set_engine(&mut self, engine: Engine) {
+		self.engine = engine;
+	}
+
+	pub fn engine(&self) -> Engine {
+		self.engine
+	}
+
+	pub fn cpu(&self) -> &Cpu {
+		&self.cpu
+	}
+
+	pub fn memory(&self) -> &Memory {
+		&self.memory
+	}
+
+	/// the tracer every executed instruction is reported to, see
+	/// [`Tracer::start`]
+	pub fn tracer(&self) -> &Tracer {
+		&self.tracer
+	}
+
+	/// the steps [`Machine::step_back`] can take back, see
+	/// [`Machine::enable_undo`]
+	pub fn undo_log(&self) -> &UndoLog {
+		&self.undo
+	}
+
+	/// log the last 'limit' steps from now on, so that they can be
+	/// taken back with [`Machine::step_back`]
+	pub fn enable_undo(&self, limit: usize) {
+		self.undo.enable(limit);
+		self.console.remember_keys(self.undo.is_enabled());
+	}
+
+	pub fn disable_undo(&self) {
+		self.undo.disable();
+		self.console.remember_keys(false);
+	}
+
+	/// number of instructions executed so far, not counting the entries
+	/// into interrupt service routines
+	pub fn instructions(&self) -> u64 {
+		self.instructions
+	}
+
+	/// a flag that makes a run return [`Stop::Interrupted`] soon after
+	/// it's set, e.g. by a signal handler; the run clears it
+	pub fn stop_flag(&self) -> Arc<AtomicBool> {
+		Arc::clone(&self.stop_requested)
+	}
+
+	/// addresses of the last instructions executed, at most
+	/// HISTORY_LEN of them, oldest first
+	pub fn recent_pcs(&self) -> Vec<u16> {
+		let executed = self.instructions();
+		(executed.saturating_sub(HISTORY_LEN as u64)..executed)
+			.map(|n| self.recent[(n % HISTORY_LEN as u64) as usize])
+			.collect()
+	}
+
+	/// split an object image into its origin and the words to place
+	/// there; both are stored big endian
+	pub fn parse_object(byte_stream: &[u8]) -> Result<(u16, Vec<u16>), Fault> {
+		if byte_stream.len() < 2 {
+			return Err(Fault::Load(String::from("missing origin")));
+		}
+		if !byte_stream.len().is_multiple_of(2) {
+			return Err(Fault::Load(String::from(
+				"image doesn't consist of whole 16-bit words"
+			)));
+		}
+
+		// to little endian
+		let mut stream_u16 = byte_stream
+			.chunks(2)
+			.map(|two_bytes| {
+				let big_endian_low = two_bytes[1] as u16;
+				let big_endian_high = two_bytes[0] as u16;
+				(big_endian_high << 8) | big_endian_low
+			})
+			.collect::<Vec<_>>();
+
+		let start = stream_u16.remove(0);
+		if stream_u16.len() > (1 << 16) - start as usize {
+			return Err(Fault::Load(format!(
+				"image starting at x{:04X} doesn't fit in memory", start
+			)));
+		}
+		Ok((start, stream_u16))
+	}
+
+	/// load an object image: a big endian origin followed by the
+	/// big endian words to place there
+	pub fn load(&mut self, byte_stream: &[u8]) -> Result<(), Fault> {
+		let (start, words) = Machine::parse_object(byte_stream)?;
+
+		// copy u16s from [start, end) into memory
+		for (idx, &data) in words.iter().enumerate() {
+			self.memory.write(start + idx as u16, data);
+		}
+		Ok(())
+	}
+
+	/// load the object image stored at 'path'
+	pub fn load_file<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Fault> {
+		let path = path.as_ref();
+		match fs::read(path) {
+			Ok(byte_stream) => self.load(&byte_stream),
+			Err(e) => Err(Fault::Load(format!("{}: {}", path.display(), e))),
+		}
+	}
+
+	/// boot the operating system 'os_image', e.g. [`os::image`]: TRAP
+	/// runs its service routines from now on and execution starts at
+	/// [`os::START`] in supervisor mode, with the PSR and PC of the
+	/// loaded program pushed on the supervisor stack for the system to
+	/// return to
+	pub fn boot(&mut self, os_image: &[u8]) -> Result<(), Fault> {
+		self.load(os_image)?;
+		self.cpu.set_native_traps(false);
+
+		let psr = self.cpu.read(Register::Psr);
+		let sp = self.cpu.read(Register::SavedSsp).wrapping_sub(2);
+		self.memory.write(sp, self.cpu.read(Register::PC));
+		self.memory.write(sp.wrapping_add(1), psr);
+
+		self.cpu.write(Register::SavedUsp, self.cpu.read(Register::R6));
+		self.cpu.write(Register::R6, sp);
+		self.cpu.write(Register::Psr, psr & !psr::USER_MODE);
+		self.cpu.write(Register::PC, os::START);
+		Ok(())
+	}
+
+	/// whether the program neither halted nor stopped the clock
+	pub fn is_running(&self) -> bool {
+		self.cpu.is_running() && self.memory.clock_enabled()
+	}
+
+	/// take a pending interrupt or fetch, decode and execute a single
+	/// instruction
+	pub fn step(&mut self) -> Result<(), Fault> {
+		let pc = self.cpu.read(Register::PC);
+		let observed = self.is_observed();
+		self.cpu.set_observed(observed);
+		self.memory.set_observed(observed);
+		let undo = if observed {
+			self.watchpoints.begin_instruction();
+			self.tracer.begin_instruction();
+			self.begin_undo(pc)
+		} else {
+			None
+		};
+
+		// interrupts are taken between instructions, if their priority
+		// is above the running program's
+		let priority = psr::priority(self.cpu.read(Register::Psr));
+		if let Some(interrupt) = self.memory.pending_interrupt(priority) {
+			self.take_interrupt(pc, interrupt, undo);
+			return Ok(());
+		}
+
+		let raw_instr = self.cpu.fetch(&self.memory);
+		let decoded = self.cpu.decode_at(pc, raw_instr, &mut self.memory);
+		let result = decoded.and_then(|instr| {
+			let opcode = instr.opcode();
+			let begin = self.summary.as_ref().map(|_| Instant::now());
+			self.cpu.execute(instr, &mut self.memory)?;
+
+			if let (Some(summary), Some(begin)) = (&mut self.summary, begin) {
+				summary.add_record(opcode, 1, begin.elapsed());
+			}
+			Ok(())
+		});
+		let result = result.or_else(|fault| self.raise(fault));
+		let executed = self.instructions;
+		self.instructions += 1;
+		if observed {
+			self.watchpoints.end_instruction(pc, raw_instr);
+			self.tracer.end_instruction(Record {
+				index: executed,
+				pc,
+				instr: raw_instr,
+				interrupt: None,
+				psr: self.cpu.read(Register::Psr),
+				events: Vec::new(),
+			});
+		}
+		self.recent[(executed % HISTORY_LEN as u64) as usize] = pc;
+		self.memory.tick();
+		self.console.tick();
+		self.end_undo(undo, raw_instr, None);
+		result
+	}
+
+	/// whether a watchpoint, the tracer or the undo log wants to know
+	/// about every access
+	fn is_observed(&self) -> bool {
+		self.watchpoints.is_armed() || self.tracer.is_enabled() || self.undo.is_enabled()
+	}
+
+	/// run the compiled block at the PC, at most 'budget' of its
+	/// instructions, returning how many ran; none run if the next
+	/// instruction has to be stepped, e.g. because a device needs to
+	/// see every instruction
+	fn run_block(&mut self, budget: u64) -> u64 {
+		if self.engine != Engine::Blocks
+			|| self.summary.is_some()
+			|| self.is_observed()
+			|| !self.memory.devices_idle()
+		{
+			return 0;
+		}
+
+		self.cpu.set_observed(false);
+		self.memory.set_observed(false);
+		let start = self.cpu.read(Register::PC);
+		let (executed, next) = self.blocks.run(&mut self.cpu, &mut self.memory, budget);
+		if executed == 0 {
+			return 0;
+		}
+		self.cpu.write(Register::PC, next);
+
+		// a block's instructions follow each other in memory
+		let first = self.instructions;
+		for n in executed.saturating_sub(HISTORY_LEN as u64)..executed {
+			self.recent[((first + n) % HISTORY_LEN as u64) as usize] = start.wrapping_add(n as u16);
+		}
+		self.instructions += executed;
+		self.console.advance(executed);
+		executed
+	}
+
+	/// the step entering the service routine of 'interrupt' instead
+	/// of executing the instruction at 'pc'
+	#[cold]
+	fn take_interrupt(&mut self, pc: u16, interrupt: Interrupt, undo: Option<Step>) {
+		self.cpu.interrupt(interrupt.vector, Some(interrupt.priority), &mut self.memory);
+		self.watchpoints.end_instruction(pc, self.memory.peek(pc));
+		self.tracer.end_instruction(Record {
+			index: self.instructions(),
+			pc,
+			instr: 0,
+			interrupt: Some(interrupt.vector),
+			psr: self.cpu.read(Register::Psr),
+			events: Vec::new(),
+		});
+		self.end_undo(undo, 0, Some(interrupt.vector));
+	}
+
+	/// the part of a step to log that isn't logged as changes, if the
+	/// undo log is enabled
+	fn begin_undo(&self, pc: u16) -> Option<Step> {
+		if !self.undo.is_enabled() {
+			return None;
+		}
+		self.undo.begin_instruction();
+		self.console.take_keys();
+
+		let index = self.instructions();
+		Some(Step {
+			index,
+			pc,
+			instr: 0,
+			interrupt: None,
+			running: self.cpu.is_running(),
+			changes: Vec::new(),
+			devices: self.memory.device_states(),
+			keys: Vec::new(),
+			forgotten_pc: self.recent[(index % HISTORY_LEN as u64) as usize],
+		})
+	}
+
+	/// log 'step', begun by begin_undo, keeping only the devices it
+	/// changed
+	fn end_undo(&self, step: Option<Step>, instr: u16, interrupt: Option<u8>) {
+		let Some(mut step) = step else {
+			return;
+		};
+		let after = self.memory.device_states();
+		step.devices.retain(|device| !after.contains(device));
+		step.keys = self.console.take_keys();
+		step.instr = instr;
+		step.interrupt = interrupt;
+		self.undo.end_instruction(step);
+	}
+
+	/// take back the last step logged by the undo log, returning it, or
+	/// None if there is none; watchpoints its accesses trigger are
+	/// reported as if it executed
+	pub fn step_back(&mut self) -> Option<Step> {
+		let step = self.undo.pop()?;
+		for change in step.changes.iter().rev() {
+			match *change {
+				Change::Register { register, old, .. } => self.cpu.restore_register(register, old),
+				Change::Write { addr, old, .. } => self.memory.restore_word(addr, old),
+				Change::Read { .. } => {}
+			}
+		}
+		for (range, state) in &step.devices {
+			self.memory.restore_device(range, state);
+		}
+		self.cpu.set_running(step.running);
+		self.console.unread(&step.keys);
+
+		self.instructions = step.index;
+		if step.interrupt.is_none() {
+			self.recent[(step.index % HISTORY_LEN as u64) as usize] = step.forgotten_pc;
+		}
+
+		self.watchpoints.begin_instruction();
+		// an instruction's first change is the PC advanced by its fetch
+		let mut fetched = step.interrupt.is_none();
+		for change in &step.changes {
+			match *change {
+				Change::Register { register: Register::PC, new, .. } if fetched => {
+					fetched = false;
+					self.watchpoints.pc_advance(new);
+				}
+				Change::Register { register, new, .. } => {
+					self.watchpoints.register_write(register, new);
+				}
+				Change::Read { addr, value } => self.watchpoints.memory_read(addr, value),
+				Change::Write { addr, new, .. } => self.watchpoints.memory_write(addr, new),
+			}
+		}
+		let instr = if step.interrupt.is_some() { self.memory.peek(step.pc) } else { step.instr };
+		self.watchpoints.end_instruction(step.pc, instr);
+		Some(step)
+	}
+
+	/// dispatch the exception 'fault' raises to its service routine,
+	/// or return 'fault' if there is no such exception or the vector
+	/// table has no routine for it
+	fn raise(&mut self, fault: Fault) -> Result<(), Fault> {
+		match fault.exception_vector() {
+			Some(vector) if self.memory.peek(INTERRUPT_VECTOR_TABLE + vector as u16) != 0 => {
+				self.cpu.interrupt(vector, None, &mut self.memory);
+				Ok(())
+			}
+			_ => Err(fault),
+		}
+	}
+
+	/// run until the program halts, faults or triggers a watchpoint, or
+	/// until [`Machine::stop_flag`] is set
+	pub fn run(&mut self) -> Result<Stop, Fault> {
+		self.run_limited(Limits::default())
+	}
+
+	/// run like [`Machine::run`], but stop once the program exceeds
+	/// 'limits'
+	pub fn run_limited(&mut self, limits: Limits) -> Result<Stop, Fault> {
+		let begin = Instant::now();
+		let last = limits
+			.max_instructions
+			.map(|max| self.instructions().saturating_add(max));
+
+		let mut steps = 0u64;
+		let mut next_check = CLOCK_CHECK_INTERVAL;
+		while self.is_running() {
+			if last.is_some_and(|last| self.instructions() >= last) {
+				return Ok(Stop::InstructionLimit);
+			}
+			// a step waiting for a key may take any time, so look at
+			// the clock before it and while it waits
+			let reads_key = self.cpu.reads_key(&self.memory);
+			if steps >= next_check || reads_key {
+				next_check = steps + CLOCK_CHECK_INTERVAL;
+				if self.stop_requested.swap(false, Ordering::Relaxed) {
+					return Ok(Stop::Interrupted);
+				}
+				if limits.timeout.is_some_and(|timeout| begin.elapsed() >= timeout) {
+					return Ok(Stop::Timeout);
+				}
+			}
+			if reads_key {
+				if let Some(stop) = self.wait_key(begin, limits.timeout)? {
+					return Ok(stop);
+				}
+			}
+
+			let budget = last.map_or(u64::MAX, |last| last - self.instructions());
+			let executed = self.run_block(budget);
+			if executed > 0 {
+				steps += executed;
+				continue;
+			}
+
+			steps += 1;
+			self.step()?;
+			if self.watchpoints.has_hits() {
+				return Ok(Stop::Watchpoint);
+			}
+		}
+		Ok(Stop::Halted)
+	}
+
+	/// wait until the console has a key to read, or return why the run
+	/// started at 'begin' stops first
+	fn wait_key(&mut self, begin: Instant, timeout: Option<Duration>) -> Result<Option<Stop>, Fault> {
+		loop {
+			let left = timeout.map_or(KEY_POLL_INTERVAL, |timeout| {
+				timeout.saturating_sub(begin.elapsed()).min(KEY_POLL_INTERVAL)
+			});
+			if self.console.wait_key(left)? {
+				return Ok(None);
+			}
+			if self.stop_requested.swap(false, Ordering::Relaxed) {
+				return Ok(Some(Stop::Interrupted));
+			}
+			if timeout.is_some_and(|timeout| begin.elapsed() >= timeout) {
+				return Ok(Some(Stop::Timeout));
+			}
+		}
+	}
+
+	/// stop execution when 'watchpoint' triggers, returning its id
+	pub fn add_watchpoint(&self, watchpoint: Watchpoint) -> usize {
+		self.watchpoints.add(watchpoint)
+	}
+
+	/// remove the watchpoint 'id', returning false if there is none
+	pub fn remove_watchpoint(&self, id: usize) -> bool {
+		self.watchpoints.remove(id)
+	}
+
+	/// watchpoints with their ids
+	pub fn watchpoints(&self) -> Vec<(usize, Watchpoint)> {
+		self.watchpoints.list()
+	}
+
+	/// watchpoints triggered since the last call, oldest first
+	pub fn take_watch_hits(&self) -> Vec<WatchHit> {
+		self.watchpoints.take_hits()
+	}
+
+	pub fn reg(&self, which: Register) -> u16 {
+		self.cpu.read(which)
+	}
+
+	pub fn set_reg(&mut self, which: Register, data: u16) {
+		self.cpu.write(which, data);
+	}
+
+	/// read memory without triggering memory mapped devices
+	pub fn peek(&self, addr: u16) -> u16 {
+		self.memory.peek(addr)
+	}
+
+	pub fn poke(&mut self, addr: u16, data: u16) {
+		self.memory.write(addr, data);
+	}
+
+	/// the state of the machine, to be restored with [`Machine::restore`]
+	pub fn snapshot(&self) -> Snapshot {
+		Snapshot {
+			instructions: self.instructions(),
+			running: self.cpu.is_running(),
+			native_traps: self.cpu.native_traps(),
+			registers: self.cpu.registers(),
+			recent_pcs: self.recent_pcs(),
+			memory: self.memory.contents(),
+			devices: self.memory.device_states(),
+			input: self.console.pending_input(),
+		}
+	}
+
+	/// return to the state of 'snapshot'; fails, leaving the machine
+	/// as it was, if a device of the snapshot isn't attached or saves
+	/// a different state, or if the console can't deliver the keys the
+	/// snapshot has left
+	pub fn restore(&mut self, snapshot: &Snapshot) -> Result<(), Fault> {
+		if snapshot.memory.len() != MEMORY_SIZE {
+			return Err(Fault::Load(format!(
+				"snapshot has {} words of memory instead of {}",
+				snapshot.memory.len(), MEMORY_SIZE
+			)));
+		}
+		let attached = self.memory.device_states();
+		for (range, state) in &snapshot.devices {
+			let device = attached.iter().find(|(attached, _)| attached == range);
+			let (start, end) = (range.start(), range.end());
+			match device {
+				None => return Err(Fault::Load(format!(
+					"snapshot has a device at x{:04X}-x{:04X}, which isn't attached",
+					start, end
+				))),
+				Some((_, attached)) if attached.len() != state.len() => {
+					return Err(Fault::Load(format!(
+						"snapshot has {} words of state for the device at x{:04X}-x{:04X}, \
+						which saves {}",
+						state.len(), start, end, attached.len()
+					)));
+				}
+				Some(_) => {}
+			}
+		}
+		self.console.restore_input(&snapshot.input).map_err(Fault::Load)?;
+
+		for (range, state) in &snapshot.devices {
+			self.memory.restore_device(range, state);
+		}
+		self.memory.set_contents(&snapshot.memory);
+		self.cpu.set_registers(snapshot.registers);
+		self.cpu.set_running(snapshot.running);
+		self.cpu.set_native_traps(snapshot.native_traps);
+		self.undo.clear();
+
+		let executed = snapshot.instructions;
+		self.instructions = executed;
+		let first = executed.saturating_sub(snapshot.recent_pcs.len() as u64);
+		for (n, &pc) in (first..executed).zip(&snapshot.recent_pcs) {
+			self.recent[(n % HISTORY_LEN as u64) as usize] = pc;
+		}
+		Ok(())
+	}
+
+	/// write a snapshot of the machine to 'path'
+	pub fn save_snapshot<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+		fs::write(path, self.snapshot().to_bytes())
+	}
+
+	/// restore the snapshot stored at 'path'
+	pub fn load_snapshot<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Fault> {
+		let path = path.as_ref();
+		let bytes = fs::read(path)
+			.map_err(|e| Fault::Load(format!("{}: {}", path.display(), e)))?;
+		self.restore(&Snapshot::from_bytes(&bytes)?)
+	}
+}
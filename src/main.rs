@@ -1,32 +1,445 @@
-use optional_utils::summary::SUMMARY;
-use parse::ARGS;
-use std::fs;
-use vm::VM;
-
-mod cpu;
-mod memory;
-mod optional_utils;
+use parse::{Argument, AsmArgument, Command, DisasmArgument, TestArgument, TraceArgument};
+use serde_json::{json, Map, Value};
+use std::{
+	fs,
+	io::{self, BufReader, Write},
+	ops::RangeInclusive,
+	path::Path,
+	process::exit,
+};
+use vlc3::{
+	asm::{self, lexer, SymbolTable},
+	console::{Console, Headless, InputEvent, Recorder, Replay, Stdio},
+	cpu::{psr, register::Register},
+	dap::DapServer,
+	debugger::Debugger,
+	disasm,
+	exit_status,
+	gdb,
+	machine::{Limits, Stop},
+	os,
+	testing,
+	trace::Records,
+	vm::Vm,
+	Fault,
+	Machine,
+};
+
 mod parse;
-mod vm;
 
 fn main() {
-	// parse
-	ARGS.parse();
-	let path = ARGS.path().unwrap();
+	match parse::parse() {
+		Command::Run(args) => run(*args),
+		Command::Asm(args) => assemble(args),
+		Command::Disasm(args) => disassemble(args),
+		Command::Dap => dap(),
+		Command::Test(args) => test(args),
+		Command::Trace(args) => print_trace(args),
+	}
+}
+
+fn run(args: Argument) {
+	// only a resumed snapshot comes without a program
+	let path = args.path().unwrap_or_default();
 
 	// vm, run!
-	match fs::read(&path) {
-		Ok(byte_stream) => VM.init(byte_stream),
-		Err(e) => panic!(
-			"An error occured when opening file: {}({})",
-			path,
-			e,
-		),
+	let mut machine = Machine::new();
+	machine.set_engine(args.engine());
+	if args.summary() {
+		machine.enable_summary();
 	}
-	VM.run();
+	// set before a snapshot is loaded, which may leave it keys to type
+	machine.set_console(console(&args));
+	let loaded = match args.load_snapshot() {
+		Some(snapshot_path) => machine.load_snapshot(snapshot_path),
+		None => machine.load_file(&path),
+	};
+	if let Err(fault) = loaded {
+		eprintln!("vlc3: {}", fault);
+		finish(&machine, Err(fault), &args);
+	}
+	if args.os() || args.os_image().is_some() {
+		let image = match args.os_image() {
+			Some(image_path) => fs::read(image_path).unwrap_or_else(|e| {
+				eprintln!("vlc3: {}: {}", image_path, e);
+				exit(exit_status::ERROR);
+			}),
+			None => os::image(),
+		};
+		if let Err(fault) = machine.boot(&image) {
+			eprintln!("vlc3: {}", fault);
+			finish(&machine, Err(fault), &args);
+		}
+	}
+	if let Some(trace_path) = args.trace() {
+		start_trace(&machine, trace_path, &args, symbols_for(Path::new(&path), None));
+	}
+	let mut vm = if args.headless() {
+		Vm::headless(machine)
+	} else {
+		Vm::new(machine)
+	};
+	let result = if args.debug() {
+		let mut debugger = Debugger::new(symbols_for(Path::new(&path), None));
+		// quitting before the program halted counts as interrupting it
+		vm.debug(&mut debugger).map(|_| {
+			if vm.machine().is_running() { Stop::Interrupted } else { Stop::Halted }
+		})
+	} else if let Some(target) = args.gdb() {
+		eprintln!("vlc3: waiting for a debugger on {}", target);
+		match gdb::accept(target) {
+			Ok(conn) => vm.serve_gdb(conn),
+			Err(e) => {
+				eprintln!("vlc3: {}: {}", target, e);
+				exit(exit_status::ERROR);
+			}
+		}
+	} else {
+		vm.run(Limits {
+			max_instructions: args.max_instructions(),
+			timeout: args.timeout(),
+		})
+	};
 
 	// print summary if relative option is specified
-	if ARGS.summary() {
-		SUMMARY.print_summary();
+	if let Some(summary) = vm.machine().summary() {
+		summary.print_summary();
+	}
+
+	match &result {
+		Ok(stop @ (Stop::InstructionLimit | Stop::Timeout)) => {
+			let symbols = symbols_for(Path::new(&path), None);
+			eprint!("{}", describe_limit(vm.machine(), *stop, &args, &symbols));
+		}
+		Ok(Stop::Interrupted) => eprintln!("\nvlc3: interrupted"),
+		Ok(_) => {}
+		Err(fault) => eprintln!("\nvlc3: {}", fault),
+	}
+	if let Some(snapshot_path) = args.save_snapshot() {
+		if let Err(e) = vm.machine().save_snapshot(snapshot_path) {
+			eprintln!("vlc3: {}: {}", snapshot_path, e);
+			exit(exit_status::ERROR);
+		}
+	}
+	finish(vm.machine(), result, &args);
+}
+
+/// exit with the status telling how the program ended, writing the
+/// --result-json file first
+fn finish(machine: &Machine, result: Result<Stop, Fault>, args: &Argument) -> ! {
+	let status = exit_status::of(&result);
+
+	if let Err(e) = machine.tracer().finish() {
+		eprintln!("vlc3: {}: {}", args.trace().unwrap_or_default(), e);
+	}
+	if let Some(json_path) = args.result_json() {
+		let json = result_json(machine, &result, status);
+		if let Err(e) = fs::write(json_path, format!("{:#}\n", json)) {
+			eprintln!("vlc3: {}: {}", json_path, e);
+			exit(exit_status::ERROR);
+		}
+	}
+	exit(status)
+}
+
+/// how the program ended and the final state of the machine
+fn result_json(machine: &Machine, result: &Result<Stop, Fault>, status: i32) -> Value {
+	let reason = match result {
+		Ok(Stop::Halted) => "halted",
+		Ok(Stop::Watchpoint) => "watchpoint",
+		Ok(Stop::InstructionLimit) => "instruction_limit",
+		Ok(Stop::Timeout) => "timeout",
+		Ok(Stop::Interrupted) => "interrupted",
+		Err(Fault::Load(_)) => "load_error",
+		Err(_) => "fault",
+	};
+	let fault = result.as_ref().err();
+
+	let registers = (0..Register::Count as u16)
+		.filter_map(|idx| Register::try_from(idx).ok())
+		.map(|reg| (reg.to_string(), json!(machine.reg(reg))))
+		.collect::<Map<_, _>>();
+	let psr = machine.reg(Register::Psr);
+
+	json!({
+		"reason": reason,
+		"exit_code": status,
+		"instructions": machine.instructions(),
+		"fault": fault.map(Fault::to_string),
+		"fault_pc": fault.and_then(Fault::pc),
+		"registers": registers,
+		"condition": psr::flags(psr),
+		"supervisor": !psr::is_user_mode(psr),
+	})
+}
+
+/// which limit 'stop' hit, where the program was and what it executed
+/// last
+fn describe_limit(machine: &Machine, stop: Stop, args: &Argument, symbols: &SymbolTable) -> String {
+	let mut text = match (stop, args.timeout()) {
+		(Stop::Timeout, Some(timeout)) => format!(
+			"\nvlc3: stopped after {} seconds (--timeout)\n",
+			timeout.as_secs_f64()
+		),
+		_ => format!(
+			"\nvlc3: stopped after {} instructions (--max-instructions)\n",
+			machine.instructions()
+		),
+	};
+
+	let line = |addr: u16| {
+		let word = machine.peek(addr);
+		format!(
+			"x{:04X}  x{:04X}  {:<8}  {}\n",
+			addr,
+			word,
+			symbols.name_of(addr).unwrap_or(""),
+			disasm::disassemble(addr, word, symbols)
+		)
+	};
+	text.push_str("last instructions:\n");
+	for addr in machine.recent_pcs() {
+		text.push_str("   ");
+		text.push_str(&line(addr));
+	}
+	text.push_str("=> ");
+	text.push_str(&line(machine.reg(Register::PC)));
+	text
+}
+
+/// trace the program to 'trace_path' as the trace options ask
+fn start_trace(machine: &Machine, trace_path: &str, args: &Argument, symbols: SymbolTable) {
+	let ranges = args.trace_ranges()
+		.iter()
+		.map(|range| address_range(range).unwrap_or_else(|| {
+			eprintln!("vlc3: invalid address range '{}'", range);
+			exit(exit_status::USAGE);
+		}))
+		.collect();
+	machine.tracer().set_ranges(ranges);
+
+	let started = fs::File::create(trace_path)
+		.and_then(|file| machine.tracer().start(file, args.trace_format(), symbols));
+	if let Err(e) = started {
+		eprintln!("vlc3: {}: {}", trace_path, e);
+		exit(exit_status::ERROR);
+	}
+}
+
+/// the addresses of 'range', given as START-END or as a single address
+fn address_range(range: &str) -> Option<RangeInclusive<u16>> {
+	let address = |text: &str| match lexer::number(text.trim()) {
+		Some(addr) if (0..=0xffff).contains(&addr) => Some(addr as u16),
+		_ => None,
+	};
+	match range.split_once('-') {
+		Some((start, end)) => {
+			let (start, end) = (address(start)?, address(end)?);
+			(start <= end).then_some(start..=end)
+		}
+		None => address(range).map(|addr| addr..=addr),
+	}
+}
+
+/// the console the I/O options ask for: the terminal or a headless one,
+/// possibly recording its input or replaying a recording instead
+fn console(args: &Argument) -> Box<dyn Console> {
+	let console: Box<dyn Console> = if args.headless() {
+		Box::new(headless_console(args))
+	} else {
+		Box::new(Stdio)
+	};
+
+	match (args.record(), args.replay()) {
+		(Some(_), Some(_)) => {
+			eprintln!("vlc3: --record and --replay can't be used together");
+			exit(exit_status::USAGE);
+		}
+		(Some(record_path), None) => {
+			let recorder = fs::File::create(record_path)
+				.and_then(|file| Recorder::new(console, file));
+			match recorder {
+				Ok(recorder) => Box::new(recorder),
+				Err(e) => {
+					eprintln!("vlc3: {}: {}", record_path, e);
+					exit(exit_status::ERROR);
+				}
+			}
+		}
+		(None, Some(replay_path)) => {
+			let events = fs::read_to_string(replay_path)
+				.map_err(|e| e.to_string())
+				.and_then(|text| InputEvent::parse_recording(&text));
+			match events {
+				Ok(events) => Box::new(Replay::new(events, console)),
+				Err(e) => {
+					eprintln!("vlc3: {}: {}", replay_path, e);
+					exit(exit_status::ERROR);
+				}
+			}
+		}
+		(None, None) => console,
+	}
+}
+
+/// the console of a headless run, typing the input the options give
+fn headless_console(args: &Argument) -> Headless {
+	let input = match (args.input(), args.input_text()) {
+		(Some(_), Some(_)) => {
+			eprintln!("vlc3: --input and --input-text can't be used together");
+			exit(exit_status::USAGE);
+		}
+		(Some(input_path), None) => fs::read(input_path).unwrap_or_else(|e| {
+			eprintln!("vlc3: {}: {}", input_path, e);
+			exit(exit_status::ERROR);
+		}),
+		(None, text) => text.unwrap_or_default().as_bytes().to_vec(),
+	};
+
+	let output: Box<dyn Write + Send> = match args.output() {
+		Some(output_path) => match fs::File::create(output_path) {
+			Ok(file) => Box::new(file),
+			Err(e) => {
+				eprintln!("vlc3: {}: {}", output_path, e);
+				exit(exit_status::ERROR);
+			}
+		},
+		None => Box::new(io::stdout()),
+	};
+
+	Headless::new(input, output).with_delay(args.input_delay())
+}
+
+fn assemble(args: AsmArgument) {
+	let source_path = Path::new(args.source());
+	let source = fs::read_to_string(source_path).unwrap_or_else(|e| {
+		eprintln!("vlc3: {}: {}", source_path.display(), e);
+		exit(exit_status::ERROR);
+	});
+
+	let assembly = asm::assemble(&source).unwrap_or_else(|errors| {
+		for error in errors {
+			eprintln!("{}:{}: {}", source_path.display(), error.line(), error.message());
+		}
+		exit(exit_status::ERROR);
+	});
+
+	let obj_path = match args.output() {
+		Some(output) => Path::new(output).to_path_buf(),
+		None => source_path.with_extension("obj"),
+	};
+	let sym_path = obj_path.with_extension("sym");
+
+	for (path, contents) in [
+		(&obj_path, assembly.to_object()),
+		(&sym_path, assembly.symbols().to_string().into_bytes()),
+	] {
+		if let Err(e) = fs::write(path, contents) {
+			eprintln!("vlc3: {}: {}", path.display(), e);
+			exit(exit_status::ERROR);
+		}
+	}
+}
+
+fn disassemble(args: DisasmArgument) {
+	let obj_path = Path::new(args.object());
+	let image = fs::read(obj_path)
+		.map_err(|e| format!("{}: {}", obj_path.display(), e))
+		.and_then(|bytes| Machine::parse_object(&bytes).map_err(|fault| fault.to_string()));
+	let (origin, words) = image.unwrap_or_else(|e| {
+		eprintln!("vlc3: {}", e);
+		exit(exit_status::ERROR);
+	});
+
+	let symbols = symbols_for(obj_path, args.symbols());
+	print!("{}", disasm::listing(origin, &words, &symbols));
+}
+
+fn print_trace(args: TraceArgument) {
+	let fail = |e: io::Error| -> ! {
+		eprintln!("vlc3: {}: {}", args.trace(), e);
+		exit(exit_status::ERROR);
+	};
+
+	let symbols = args.symbols()
+		.map(|sym_path| symbols_for(Path::new(args.trace()), Some(sym_path)))
+		.unwrap_or_default();
+	let file = fs::File::open(args.trace()).unwrap_or_else(|e| fail(e));
+	let records = Records::new(BufReader::new(file)).unwrap_or_else(|e| fail(e));
+
+	let mut stdout = io::BufWriter::new(io::stdout().lock());
+	for record in records {
+		let record = record.unwrap_or_else(|e| fail(e));
+		if writeln!(stdout, "{}", record.to_text(&symbols)).is_err() {
+			// e.g. a closed pipe
+			exit(exit_status::ERROR);
+		}
+	}
+	let _ = stdout.flush();
+}
+
+fn dap() {
+	if let Err(e) = DapServer::new(io::stdin(), io::stdout()).serve() {
+		eprintln!("vlc3: {}", e);
+		exit(exit_status::ERROR);
+	}
+}
+
+fn test(args: TestArgument) {
+	let read = |path: &str| fs::read(path).unwrap_or_else(|e| {
+		eprintln!("vlc3: {}: {}", path, e);
+		exit(exit_status::ERROR);
+	});
+
+	let program = read(args.program());
+	let cases = testing::load_cases(Path::new(args.cases())).unwrap_or_else(|e| {
+		eprintln!("vlc3: {}: {}", args.cases(), e);
+		exit(exit_status::ERROR);
+	});
+	let os_image = match args.os_image() {
+		Some(image_path) => Some(read(image_path)),
+		None => args.os().then(os::image),
+	};
+	let config = testing::Config {
+		os_image,
+		input_delay: args.input_delay(),
+		limits: Limits {
+			max_instructions: args.max_instructions(),
+			timeout: args.timeout(),
+		},
+		engine: args.engine(),
+	};
+
+	let name = Path::new(args.program())
+		.file_stem()
+		.map_or_else(|| args.program().to_string(), |stem| stem.to_string_lossy().into_owned());
+	let report = testing::run(&name, &program, &cases, &config);
+	print!("{}", report.text());
+
+	if let Some(junit_path) = args.junit() {
+		if let Err(e) = fs::write(junit_path, report.junit()) {
+			eprintln!("vlc3: {}: {}", junit_path, e);
+			exit(exit_status::ERROR);
+		}
+	}
+	if !report.all_passed() {
+		exit(exit_status::ERROR);
+	}
+}
+
+/// symbols of the program at 'obj_path': an explicitly given symbol
+/// file must exist, the default one next to the program needn't
+fn symbols_for(obj_path: &Path, sym_path: Option<&str>) -> SymbolTable {
+	match sym_path {
+		Some(sym_path) => match fs::read_to_string(sym_path) {
+			Ok(text) => SymbolTable::parse(&text),
+			Err(e) => {
+				eprintln!("vlc3: {}: {}", sym_path, e);
+				exit(exit_status::ERROR);
+			}
+		},
+		None => fs::read_to_string(obj_path.with_extension("sym"))
+			.map(|text| SymbolTable::parse(&text))
+			.unwrap_or_default(),
 	}
 }
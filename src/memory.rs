@@ -1,99 +1,456 @@
-use lazy_static::*;
-use libc::{getchar, FD_SET, fd_set, timeval};
-use std::{
-	io,
-	mem,
-	os::fd::AsRawFd,
-	sync::{Arc, Mutex},
+use std::ops::RangeInclusive;
+use crate::console::ConsoleHandle;
+use crate::cpu::instruction::Instruction;
+use crate::device::{
+	self, AttachError, Device, Display, Interrupt, Keyboard, MachineControl, Timer, IO_PAGE,
 };
-use syscalls::{syscall, Sysno};
+use crate::trace::Tracer;
+use crate::undo::UndoLog;
+use crate::watch::Watchpoints;
 
-const MEMORY_SIZE: usize = 1 << 16;
+pub(crate) const MEMORY_SIZE: usize = 1 << 16;
+/// number of addresses in the I/O page
+const IO_PAGE_SIZE: usize = MEMORY_SIZE - *IO_PAGE.start() as usize;
 
-#[derive(Debug)]
-struct MemoryInner {
-	mem: [u16; MEMORY_SIZE],
-}
+/// most rewritten addresses [`Memory::take_rewritten`] lists, past
+/// which it tells that any word may have been rewritten
+const MAX_REWRITTEN: usize = 1024;
 
 #[derive(Debug)]
 pub struct Memory {
-	inner: Arc<Mutex<MemoryInner>>,
+	mem: Box<[u16; MEMORY_SIZE]>,
+	/// devices of the I/O page, which answer accesses to their
+	/// ranges instead of 'mem'
+	devices: Vec<Box<dyn Device>>,
+	/// for every address of the I/O page, the index in 'devices' of
+	/// the device answering it
+	io_map: Box<[Option<u16>; IO_PAGE_SIZE]>,
+	/// instructions decoded from 'mem' by address, forgotten when
+	/// their word is written
+	decoded: Box<[Option<Instruction>]>,
+	/// number of decoded words written since memory was created
+	code_writes: u64,
+	/// addresses of the decoded words written since the last
+	/// take_rewritten, None if there were too many to list
+	rewritten: Option<Vec<u16>>,
+	/// whether accesses are reported, see set_observed
+	observed: bool,
+	watchpoints: Watchpoints,
+	tracer: Tracer,
+	undo: UndoLog,
+	console: ConsoleHandle,
 }
 
-lazy_static! {
-	pub static ref MEMORY: Memory = Memory::new();
+impl Default for Memory {
+	fn default() -> Self {
+		Self::new()
+	}
 }
 
-impl MemoryInner {
-	fn new() -> Self {
-		Self {
-			mem: [0; MEMORY_SIZE],
+impl Memory {
+	/// memory with the keyboard, the display, the timer and the machine
+	/// control register attached
+	pub fn new() -> Self {
+		let mut memory = Self {
+			mem: Box::new([0; MEMORY_SIZE]),
+			devices: Vec::new(),
+			io_map: Box::new([None; IO_PAGE_SIZE]),
+			decoded: vec![None; MEMORY_SIZE].into_boxed_slice(),
+			code_writes: 0,
+			rewritten: Some(Vec::new()),
+			observed: true,
+			watchpoints: Watchpoints::new(),
+			tracer: Tracer::new(),
+			undo: UndoLog::new(),
+			console: ConsoleHandle::default(),
+		};
+
+		let console = memory.console();
+		for device in [
+			Box::new(Keyboard::new(console.clone())) as Box<dyn Device>,
+			Box::new(Display::new(console)),
+			Box::new(Timer::new()),
+			Box::new(MachineControl::new()),
+		] {
+			memory.attach_boxed(device).unwrap();
 		}
+		memory
 	}
-}
 
-impl Memory {
-	fn new() -> Self {
-		Self {
-			inner: Arc::new(Mutex::new(MemoryInner::new())),
+	/// report reads and writes to 'watchpoints'
+	pub fn set_watchpoints(&mut self, watchpoints: Watchpoints) {
+		self.watchpoints = watchpoints;
+	}
+
+	/// report reads and writes to 'tracer'
+	pub fn set_tracer(&mut self, tracer: Tracer) {
+		self.tracer = tracer;
+	}
+
+	/// log reads and writes to 'undo'
+	pub fn set_undo_log(&mut self, undo: UndoLog) {
+		self.undo = undo;
+	}
+
+	/// report reads and writes only if 'observed', see
+	/// [`Cpu::set_observed`](crate::cpu::Cpu::set_observed)
+	pub(crate) fn set_observed(&mut self, observed: bool) {
+		self.observed = observed;
+	}
+
+	/// the console the keyboard and the display are connected to
+	pub fn console(&self) -> ConsoleHandle {
+		self.console.clone()
+	}
+
+	/// let 'device' answer accesses to its range of the I/O page
+	pub fn attach<D: Device + 'static>(&mut self, device: D) -> Result<(), AttachError> {
+		self.attach_boxed(Box::new(device))
+	}
+
+	fn attach_boxed(&mut self, device: Box<dyn Device>) -> Result<(), AttachError> {
+		let range = device.range();
+		if !IO_PAGE.contains(range.start()) || !IO_PAGE.contains(range.end()) {
+			return Err(AttachError::OutsideIoPage(range));
+		}
+
+		let overlaps = self.devices.iter().any(|attached| {
+			let attached = attached.range();
+			range.start() <= attached.end() && attached.start() <= range.end()
+		});
+		if overlaps {
+			return Err(AttachError::Overlap(range));
+		}
+		for pos in range {
+			self.io_map[(pos - IO_PAGE.start()) as usize] = Some(self.devices.len() as u16);
 		}
+		self.devices.push(device);
+		Ok(())
 	}
 
-	fn check_key() -> bool {
-		let mut readfds: fd_set;
-		unsafe {
-			readfds = mem::zeroed();
+	/// the index of the device answering accesses to 'pos', if any
+	fn device_index(&self, pos: u16) -> Option<usize> {
+		let offset = pos.checked_sub(*IO_PAGE.start())?;
+		self.io_map[offset as usize].map(usize::from)
+	}
+
+	/// the device answering accesses to 'pos', if any
+	fn device(&mut self, pos: u16) -> Option<&mut Box<dyn Device>> {
+		let index = self.device_index(pos)?;
+		Some(&mut self.devices[index])
+	}
+
+	/// read 'pos' as the program does, triggering memory mapped devices
+	pub fn read(&mut self, pos: u16) -> u16 {
+		let data = match self.device(pos) {
+			Some(device) => device.read(pos),
+			None => self.mem[pos as usize],
+		};
+		if !self.observed {
+			return data;
+		}
+		self.watchpoints.memory_read(pos, data);
+		self.tracer.memory_read(pos, data);
+		self.undo.memory_read(pos, data);
+		data
+	}
+
+	/// read 'pos' without triggering any memory mapped device
+	pub fn peek(&self, pos: u16) -> u16 {
+		match self.device_index(pos) {
+			Some(index) => self.devices[index].peek(pos),
+			None => self.mem[pos as usize],
+		}
+	}
+
+	pub fn write(&mut self, pos: u16, data: u16) {
+		let old = match self.device(pos) {
+			Some(device) => {
+				let old = device.peek(pos);
+				device.write(pos, data);
+				old
+			}
+			None => {
+				self.forget_decoded(pos);
+				std::mem::replace(&mut self.mem[pos as usize], data)
+			}
+		};
+		if !self.observed {
+			return;
 		}
-		unsafe {
-			FD_SET(io::stdin().as_raw_fd(), &mut readfds as *mut fd_set);
+		self.watchpoints.memory_write(pos, data);
+		self.tracer.memory_write(pos, data);
+		self.undo.memory_write(pos, old, data);
+	}
+
+	/// set a word back to 'data' without reporting the write; the
+	/// registers of devices are left alone, see [`Device::restore`]
+	pub(crate) fn restore_word(&mut self, pos: u16, data: u16) {
+		if self.device(pos).is_none() {
+			self.forget_decoded(pos);
+			self.mem[pos as usize] = data;
 		}
+	}
+
+	/// the instruction the word at 'pos' was decoded to since it was
+	/// last written, if any
+	pub(crate) fn decoded(&self, pos: u16) -> Option<Instruction> {
+		self.decoded[pos as usize]
+	}
 
-		let mut timeout: timeval;
-		unsafe {
-			timeout = mem::zeroed();
+	fn forget_decoded(&mut self, pos: u16) {
+		if self.decoded[pos as usize].take().is_some() {
+			self.code_writes += 1;
+			if let Some(rewritten) = &mut self.rewritten {
+				rewritten.push(pos);
+				if rewritten.len() > MAX_REWRITTEN {
+					self.rewritten = None;
+				}
+			}
 		}
-		timeout.tv_sec = 0;
-		timeout.tv_usec = 0;
+	}
+
+	/// number of times a word was written after it was decoded, so
+	/// that code compiled from memory can tell it's out of date
+	pub(crate) fn code_writes(&self) -> u64 {
+		self.code_writes
+	}
+
+	/// the addresses of the decoded words written since the last call,
+	/// None if any word may have been
+	pub(crate) fn take_rewritten(&mut self) -> Option<Vec<u16>> {
+		self.rewritten.replace(Vec::new())
+	}
 
-		let ret;
-		unsafe {
-			ret = syscall!(
-				Sysno::select,
-				1,
-				&mut readfds as *mut fd_set,
-				0,
-				0,
-				&mut timeout as *mut timeval
-			).expect("failed to execute syscall");
+	/// remember that the word at 'pos' decodes to 'instr'; registers of
+	/// devices change without being written, so they are never
+	/// remembered
+	pub(crate) fn remember_decoded(&mut self, pos: u16, instr: Instruction) {
+		if self.device_index(pos).is_none() {
+			self.decoded[pos as usize] = Some(instr);
 		}
-		ret != 0
 	}
 
-	pub fn read(&self, pos: u16) -> u16 {
-		// check if is reading keyboard status register(a memory
-		// mapped register)
-		const MR_KBSR: u16 = 0xfe00;	// address of keyboard status register
-		const MR_KBDR: u16 = 0xfe02;	// address of keyboard data register
+	/// let the devices know an instruction was executed
+	pub fn tick(&mut self) {
+		self.devices
+			.iter_mut()
+			.for_each(|device| device.tick());
+	}
+
+	/// whether every device is idle, see [`Device::is_idle`]
+	pub(crate) fn devices_idle(&self) -> bool {
+		self.devices.iter().all(|device| device.is_idle())
+	}
+
+	/// the most urgent interrupt requested above 'priority', if any
+	pub fn pending_interrupt(&mut self, priority: u16) -> Option<Interrupt> {
+		self.devices
+			.iter_mut()
+			.filter_map(|device| device.interrupt())
+			.filter(|interrupt| interrupt.priority > priority)
+			.max_by_key(|interrupt| interrupt.priority)
+	}
+
+	/// all of memory, without the registers of devices
+	pub(crate) fn contents(&self) -> Vec<u16> {
+		self.mem.to_vec()
+	}
+
+	/// replace all of memory, but not the registers of devices
+	pub(crate) fn set_contents(&mut self, contents: &[u16]) {
+		self.mem.copy_from_slice(contents);
+		self.decoded.fill(None);
+		self.code_writes += 1;
+		self.rewritten = None;
+	}
+
+	/// the range and the state of every attached device
+	pub(crate) fn device_states(&self) -> Vec<(RangeInclusive<u16>, Vec<u16>)> {
+		self.devices
+			.iter()
+			.map(|device| (device.range(), device.save()))
+			.collect()
+	}
 
-		if pos == MR_KBSR {
-			if Memory::check_key() {
-				self.write(MR_KBSR, 1 << 15);
-				self.write(MR_KBDR, unsafe { getchar() } as u16);
-			} else {
-				self.write(MR_KBSR, 0);
+	/// restore the device attached at 'range' to 'state', returning
+	/// false if there is none
+	pub(crate) fn restore_device(&mut self, range: &RangeInclusive<u16>, state: &[u16]) -> bool {
+		match self.devices.iter_mut().find(|device| device.range() == *range) {
+			Some(device) => {
+				device.restore(state);
+				true
 			}
+			None => false,
+		}
+	}
+
+	/// whether the machine control register lets the clock run
+	pub fn clock_enabled(&self) -> bool {
+		self.peek(device::MCR) & device::CLOCK_ENABLE != 0
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::asm;
+	use crate::console::{Capture, Headless};
+	use crate::cpu::Cpu;
+	use crate::cpu::register::Register;
+	use crate::machine::{Engine, Machine, Stop};
+
+	/// A register the program clears by reading it, as a peripheral
+	/// attached by a user might.
+	#[derive(Debug)]
+	struct Latch {
+		addr: u16,
+		data: u16,
+	}
+
+	impl Device for Latch {
+		fn range(&self) -> RangeInclusive<u16> {
+			self.addr..=self.addr
+		}
+
+		fn peek(&self, _addr: u16) -> u16 {
+			self.data
+		}
+
+		fn read(&mut self, _addr: u16) -> u16 {
+			std::mem::take(&mut self.data)
 		}
 
-		self.inner
-			.lock()
-			.unwrap()
-			.mem[pos as usize]
+		fn write(&mut self, _addr: u16, data: u16) {
+			self.data = data;
+		}
+	}
+
+	#[test]
+	fn attached_devices_answer_their_addresses() {
+		let mut memory = Memory::new();
+		memory.attach(Latch { addr: 0xfe20, data: 0 }).unwrap();
+		memory.write(0xfe20, 7);
+		assert_eq!(memory.peek(0xfe20), 7);
+		assert_eq!(memory.read(0xfe20), 7);
+		assert_eq!(memory.peek(0xfe20), 0);
+		// memory behind the device is left alone
+		assert_eq!(memory.contents()[0xfe20], 0);
+
+		// the I/O page without a device is plain memory
+		memory.write(0xfe21, 7);
+		assert_eq!(memory.read(0xfe21), 7);
+		assert_eq!(memory.read(0xfe21), 7);
+	}
+
+	#[test]
+	fn devices_must_fit_the_free_part_of_the_io_page() {
+		let mut memory = Memory::new();
+		assert_eq!(
+			memory.attach(Latch { addr: 0xfdff, data: 0 }),
+			Err(AttachError::OutsideIoPage(0xfdff..=0xfdff))
+		);
+		assert_eq!(
+			memory.attach(Latch { addr: device::KBDR, data: 0 }),
+			Err(AttachError::Overlap(device::KBDR..=device::KBDR))
+		);
+		memory.attach(Latch { addr: 0xfe20, data: 0 }).unwrap();
+		assert_eq!(
+			memory.attach(Latch { addr: 0xfe20, data: 0 }),
+			Err(AttachError::Overlap(0xfe20..=0xfe20))
+		);
+		// a device that failed to attach answers nothing
+		memory.write(0xfdff, 1);
+		assert_eq!(memory.read(0xfdff), 1);
+	}
+
+	#[test]
+	fn clearing_mcr_stops_the_clock() {
+		let object = asm::assemble("
+        .ORIG x3000
+        AND R0, R0, #0
+        STI R0, MCRP
+        ADD R1, R1, #1
+MCRP    .FILL xFFFE
+        .END
+		").unwrap().to_object();
+		let mut machine = Machine::new();
+		machine.set_console(Headless::new(Vec::new(), Capture::new()));
+		machine.load(&object).unwrap();
+		assert!(machine.is_running());
+		assert_eq!(machine.run().unwrap(), Stop::Halted);
+		assert!(!machine.is_running());
+		assert_eq!(machine.reg(Register::PC), 0x3002);
+		assert_eq!(machine.reg(Register::R1), 0);
 	}
 
-	pub fn write(&self, pos: u16, data: u16) {
-		self.inner
-			.lock()
-			.unwrap()
-			.mem[pos as usize] = data;
+	#[test]
+	fn writes_forget_the_instructions_decoded() {
+		let mut memory = Memory::new();
+		let add = Cpu::decode_word(0x1261).unwrap();
+		memory.remember_decoded(0x3000, add);
+		assert!(memory.decoded(0x3000).is_some());
+
+		// words that weren't decoded aren't code being rewritten
+		memory.write(0x3001, 0x1261);
+		assert_eq!(memory.code_writes(), 0);
+
+		memory.write(0x3000, 0x1265);
+		assert!(memory.decoded(0x3000).is_none());
+		assert_eq!(memory.code_writes(), 1);
+
+		memory.remember_decoded(0x3000, add);
+		memory.restore_word(0x3000, 0x1261);
+		assert!(memory.decoded(0x3000).is_none());
+		assert_eq!(memory.code_writes(), 2);
+	}
+
+	#[test]
+	fn rewritten_words_are_listed_until_taken() {
+		let mut memory = Memory::new();
+		let add = Cpu::decode_word(0x1261).unwrap();
+		memory.remember_decoded(0x3000, add);
+		memory.remember_decoded(0x3001, add);
+		memory.write(0x3001, 0x1265);
+		memory.write(0x3002, 0x1265);
+		assert_eq!(memory.take_rewritten(), Some(vec![0x3001]));
+		assert_eq!(memory.take_rewritten(), Some(vec![]));
+
+		// replacing all of memory may rewrite any word
+		memory.set_contents(&memory.contents());
+		assert_eq!(memory.take_rewritten(), None);
+		assert_eq!(memory.take_rewritten(), Some(vec![]));
+	}
+
+	#[test]
+	fn device_registers_are_never_remembered() {
+		let mut memory = Memory::new();
+		memory.remember_decoded(0xfe02, Cpu::decode_word(0x1261).unwrap());
+		assert!(memory.decoded(0xfe02).is_none());
+	}
+
+	#[test]
+	fn rewritten_instructions_run_as_rewritten() {
+		let object = asm::assemble("
+        .ORIG x3000
+        AND R2, R2, #0
+        ADD R2, R2, #3
+LOOP    ADD R1, R1, #1      ; rewritten after it first ran
+        LD R0, PATCH
+        ST R0, LOOP
+        ADD R2, R2, #-1
+        BRp LOOP
+        HALT
+PATCH   ADD R1, R1, #5
+        .END
+		").unwrap().to_object();
+		for engine in [Engine::Interpreter, Engine::Blocks] {
+			let mut machine = Machine::new();
+			machine.set_engine(engine);
+			machine.set_console(Headless::new(Vec::new(), Capture::new()));
+			machine.load(&object).unwrap();
+			machine.run().unwrap();
+			assert_eq!(machine.reg(Register::R1), 11, "with {:?}", engine);
+		}
 	}
 }
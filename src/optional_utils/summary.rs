@@ -1,10 +1,6 @@
 use crate::cpu::instruction::OpCode;
 use enum_iterator::all;
-use lazy_static::*;
-use std::{
-	sync::{Arc, Mutex},
-	time::Duration,
-};
+use std::time::Duration;
 
 #[derive(Debug)]
 struct ExecuteInfo {
@@ -13,18 +9,9 @@ struct ExecuteInfo {
 	cost: Duration,
 }
 
-#[derive(Debug)]
-struct SummaryInner {
-	record: Vec<ExecuteInfo>,
-}
-
 #[derive(Debug)]
 pub struct Summary {
-	inner: Arc<Mutex<SummaryInner>>,
-}
-
-lazy_static! {
-	pub static ref SUMMARY: Summary = Summary::new();
+	record: Vec<ExecuteInfo>,
 }
 
 impl ExecuteInfo {
@@ -35,8 +22,14 @@ impl ExecuteInfo {
 	}
 }
 
-impl SummaryInner {
-	fn new() -> Self {
+impl Default for Summary {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl Summary {
+	pub fn new() -> Self {
 		Self {
 			record: all::<OpCode>()
 				.map(|opcode| {
@@ -45,21 +38,9 @@ impl SummaryInner {
 				.collect::<Vec<_>>()
 		}
 	}
-}
-
-impl Summary {
-	fn new() -> Self {
-		Self {
-			inner: Arc::new(Mutex::new(SummaryInner::new())),
-		}
-	}
 
-	pub fn add_record(&self, opcode: OpCode, times: usize, cost: Duration) {
-		let info = &mut self
-			.inner
-			.lock()
-			.unwrap()
-			.record[opcode as usize];
+	pub fn add_record(&mut self, opcode: OpCode, times: usize, cost: Duration) {
+		let info = &mut self.record[opcode as usize];
 		info.times += times;
 		info.cost += cost;
 	}
@@ -68,7 +49,7 @@ impl Summary {
 		println!("{:>20}{:>15}{:>15}", "Operation Type", "Calls", "Time");
 		println!("{}", "-".repeat(60));
 
-		let records = &self.inner.lock().unwrap().record;
+		let records = &self.record;
 
 		for record in records {
 			println!(
@@ -0,0 +1,162 @@
+; the operating system vlc3 boots with --os
+;
+; TRAP and interrupts save PSR and PC on the supervisor stack, so every
+; service routine returns with RTI and preserves all registers but the
+; R0 it returns; TRAP also leaves the return address in R7. vlc3
+; starts the system at x0200 in supervisor mode with the program's PSR
+; and PC already on the supervisor stack.
+
+        .ORIG x0000
+
+; trap vector table, x0000-x00FF; TRAPs to empty entries jump to x0000
+        .BLKW x20
+        .FILL TRAP_GETC         ; x20
+        .FILL TRAP_OUT          ; x21
+        .FILL TRAP_PUTS         ; x22
+        .FILL TRAP_IN           ; x23
+        .FILL TRAP_PUTSP        ; x24
+        .FILL TRAP_HALT         ; x25
+        .BLKW xDA
+
+; interrupt vector table, x0100-x01FF
+        .FILL EXC_PRIVILEGE     ; x00
+        .FILL EXC_ILLEGAL       ; x01
+        .BLKW xFE
+
+; x0200: start the program by returning to it
+OS_START
+        RTI
+
+; read a character into R0 without echoing it
+TRAP_GETC
+        LDI R0, OS_KBSR
+        BRzp TRAP_GETC
+        LDI R0, OS_KBDR
+        RTI
+
+; write the character in R0
+TRAP_OUT
+        ADD R6, R6, #-1
+        STR R1, R6, #0
+OUT_WAIT
+        LDI R1, OS_DSR
+        BRzp OUT_WAIT
+        STI R0, OS_DDR
+        LDR R1, R6, #0
+        ADD R6, R6, #1
+        RTI
+
+; write the string of one character per word at R0
+TRAP_PUTS
+        ADD R6, R6, #-2
+        STR R0, R6, #0
+        STR R1, R6, #1
+        ADD R1, R0, #0
+PUTS_LOOP
+        LDR R0, R1, #0
+        BRz PUTS_DONE
+        TRAP x21
+        ADD R1, R1, #1
+        BRnzp PUTS_LOOP
+PUTS_DONE
+        LDR R0, R6, #0
+        LDR R1, R6, #1
+        ADD R6, R6, #2
+        RTI
+
+; prompt for a character, echo it and return it in R0
+TRAP_IN
+        ADD R6, R6, #-1
+        STR R1, R6, #0
+        LEA R0, IN_PROMPT
+        TRAP x22
+        TRAP x20
+        TRAP x21
+        ADD R1, R0, #0
+        LD R0, OS_NEWLINE
+        TRAP x21
+        ADD R0, R1, #0
+        LDR R1, R6, #0
+        ADD R6, R6, #1
+        RTI
+
+; write the string of two characters per word at R0, low byte first
+TRAP_PUTSP
+        ADD R6, R6, #-6
+        STR R0, R6, #0
+        STR R1, R6, #1
+        STR R2, R6, #2
+        STR R3, R6, #3
+        STR R4, R6, #4
+        STR R5, R6, #5
+        ADD R1, R0, #0
+PUTSP_LOOP
+        LDR R2, R1, #0
+        LD R3, OS_LOW_BYTE
+        AND R0, R2, R3
+        BRz PUTSP_DONE
+        TRAP x21
+        ; shift the high byte down, one bit at a time
+        AND R0, R0, #0
+        ADD R3, R0, #1
+        LD R4, OS_HIGH_BIT
+PUTSP_SHIFT
+        AND R5, R2, R4
+        BRz PUTSP_NEXT_BIT
+        ADD R0, R0, R3
+PUTSP_NEXT_BIT
+        ADD R3, R3, R3
+        ADD R4, R4, R4
+        BRnp PUTSP_SHIFT
+        ADD R0, R0, #0
+        BRz PUTSP_DONE
+        TRAP x21
+        ADD R1, R1, #1
+        BRnzp PUTSP_LOOP
+PUTSP_DONE
+        LDR R0, R6, #0
+        LDR R1, R6, #1
+        LDR R2, R6, #2
+        LDR R3, R6, #3
+        LDR R4, R6, #4
+        LDR R5, R6, #5
+        ADD R6, R6, #6
+        RTI
+
+; stop the clock by clearing bit 15 of the machine control register
+TRAP_HALT
+        LEA R0, HALT_MESSAGE
+        TRAP x22
+HALT_AGAIN
+        LDI R0, OS_MCR
+        LD R1, OS_CLOCK_OFF
+        AND R0, R0, R1
+        STI R0, OS_MCR
+        BRnzp HALT_AGAIN
+
+EXC_PRIVILEGE
+        LEA R0, PRIVILEGE_MESSAGE
+        TRAP x22
+        TRAP x25
+
+EXC_ILLEGAL
+        LEA R0, ILLEGAL_MESSAGE
+        TRAP x22
+        TRAP x25
+
+OS_KBSR         .FILL xFE00
+OS_KBDR         .FILL xFE02
+OS_DSR          .FILL xFE04
+OS_DDR          .FILL xFE06
+OS_MCR          .FILL xFFFE
+OS_CLOCK_OFF    .FILL x7FFF
+OS_LOW_BYTE     .FILL x00FF
+OS_HIGH_BIT     .FILL x0100
+OS_NEWLINE      .FILL x000A
+
+IN_PROMPT           .STRINGZ "\nInput a character> "
+HALT_MESSAGE        .STRINGZ "\n\n--- Halting the LC-3 ---\n\n"
+PRIVILEGE_MESSAGE   .STRINGZ "\n\n--- Privilege mode violation ---\n\n"
+ILLEGAL_MESSAGE     .STRINGZ "\n\n--- Illegal opcode ---\n\n"
+
+        .END
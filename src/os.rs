@@ -0,0 +1,128 @@
+//! The LC-3 operating system bundled with vlc3, see [`Machine::boot`].
+//!
+//! [`Machine::boot`]: crate::machine::Machine::boot
+
+use crate::asm;
+
+pub const SOURCE: &str = include_str!("os.asm");
+
+/// address operating systems start at
+pub const START: u16 = 0x0200;
+
+/// the object image of the bundled operating system
+pub fn image() -> Vec<u8> {
+	asm::assemble(SOURCE)
+		.expect("the bundled operating system assembles")
+		.to_object()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::console::{Capture, Headless};
+	use crate::cpu::register::Register;
+	use crate::machine::{Machine, Stop};
+
+	/// the output of 'source' run on the bundled system with 'input'
+	fn run(source: &str, input: &str) -> String {
+		run_on(&image(), source, input).1
+	}
+
+	/// the machine that ran 'source' on 'system' with 'input' and its
+	/// output
+	fn run_on(system: &[u8], source: &str, input: &str) -> (Machine, String) {
+		let object = asm::assemble(source).unwrap().to_object();
+		let output = Capture::new();
+		let mut machine = Machine::new();
+		machine.set_console(Headless::new(input, output.clone()));
+		machine.load(&object).unwrap();
+		machine.boot(system).unwrap();
+		assert!(matches!(machine.run(), Ok(Stop::Halted)));
+		(machine, String::from_utf8(output.contents()).unwrap())
+	}
+
+	#[test]
+	fn the_bundled_system_assembles() {
+		let assembly = asm::assemble(SOURCE).unwrap_or_else(|errors| {
+			panic!("os.asm doesn't assemble: {:?}", errors)
+		});
+		assert_eq!(assembly.origin(), 0x0000);
+		assert_eq!(assembly.symbols().get("OS_START"), Some(START));
+		for vector in 0x20..=0x25 {
+			assert_ne!(assembly.words()[vector], 0, "no routine for TRAP x{:02X}", vector);
+		}
+	}
+
+	#[test]
+	fn programs_use_its_service_routines() {
+		let output = run("
+        .ORIG x3000
+        LEA R0, HELLO
+        PUTS
+        GETC
+        ADD R0, R0, #1
+        OUT
+        LEA R0, PACKED
+        PUTSP
+        HALT
+HELLO   .STRINGZ \"hi \"
+PACKED  .FILL x4241
+        .FILL x0043
+        .END
+", "a");
+		assert_eq!(output, "hi bABC\n\n--- Halting the LC-3 ---\n\n");
+	}
+
+	#[test]
+	fn exceptions_halt_with_a_message() {
+		let output = run("
+        .ORIG x3000
+        ADD R0, R0, #1
+        .FILL xD000
+        .END
+", "");
+		assert_eq!(output, "\n\n--- Illegal opcode ---\n\n\n\n--- Halting the LC-3 ---\n\n");
+	}
+
+	#[test]
+	fn service_routines_may_return_with_ret() {
+		let system = asm::assemble("
+        .ORIG x0021
+        .FILL TRAP_OUT          ; x21
+        .BLKW #3
+        .FILL TRAP_HALT         ; x25
+        .BLKW x1DA
+OS_START
+        RTI
+TRAP_OUT
+        ST R1, SAVED_R1
+OUT_WAIT
+        LDI R1, OS_DSR
+        BRzp OUT_WAIT
+        STI R0, OS_DDR
+        LD R1, SAVED_R1
+        RET
+TRAP_HALT
+        AND R0, R0, #0
+        STI R0, OS_MCR
+OS_DSR  .FILL xFE04
+OS_DDR  .FILL xFE06
+OS_MCR  .FILL xFFFE
+SAVED_R1 .BLKW #1
+        .END
+").unwrap().to_object();
+
+		let (machine, output) = run_on(&system, "
+        .ORIG x3000
+        LD R0, CHAR
+        OUT
+        ADD R0, R0, #1
+        OUT
+        HALT
+CHAR    .FILL x61
+        .END
+", "");
+		assert_eq!(output, "ab");
+		assert_eq!(machine.reg(Register::R7), 0x3005);
+	}
+}
@@ -1,79 +1,437 @@
 use argparse::{
 	ArgumentParser,
+	Collect,
+	Store,
 	StoreTrue,
 	StoreOption,
 };
-use lazy_static::*;
-use std::sync::{Arc, Mutex};
+use std::{env, io, process::exit, time::Duration};
+use vlc3::{exit_status, machine::Engine, trace::Format};
 
+/// What the user asked vlc3 to do.
 #[derive(Debug)]
-struct ArgumentInner {
+pub enum Command {
+	Run(Box<Argument>),
+	Asm(AsmArgument),
+	Disasm(DisasmArgument),
+	Dap,
+	Test(TestArgument),
+	Trace(TraceArgument),
+}
+
+/// Arguments of `vlc3 [options] PROGRAM`.
+#[derive(Debug)]
+pub struct Argument {
 	// path
 	path: Option<String>,
 
 	// options
 	summary: bool,
+	debug: bool,
+	gdb: Option<String>,
+	os: bool,
+	os_image: Option<String>,
+	headless: bool,
+	input: Option<String>,
+	input_text: Option<String>,
+	input_delay: u64,
+	output: Option<String>,
+	max_instructions: Option<u64>,
+	timeout: Option<Duration>,
+	engine: Engine,
+	result_json: Option<String>,
+	trace: Option<String>,
+	trace_format: Format,
+	trace_ranges: Vec<String>,
+	record: Option<String>,
+	replay: Option<String>,
+	load_snapshot: Option<String>,
+	save_snapshot: Option<String>,
 }
 
+/// Arguments of `vlc3 asm`.
 #[derive(Debug)]
-pub struct Argument {
-	inner: Arc<Mutex<ArgumentInner>>,
+pub struct AsmArgument {
+	source: String,
+	output: Option<String>,
 }
 
-lazy_static! {
-	pub static ref ARGS: Argument = Argument::new();
+/// Arguments of `vlc3 disasm`.
+#[derive(Debug)]
+pub struct DisasmArgument {
+	object: String,
+	symbols: Option<String>,
 }
 
-impl ArgumentInner {
-	fn new() -> Self {
-		Self {
-			path: None,
-			summary: false,
+/// Arguments of `vlc3 test`.
+#[derive(Debug)]
+pub struct TestArgument {
+	program: String,
+	cases: String,
+	os: bool,
+	os_image: Option<String>,
+	input_delay: u64,
+	max_instructions: Option<u64>,
+	timeout: Option<Duration>,
+	engine: Engine,
+	junit: Option<String>,
+}
+
+/// Arguments of `vlc3 trace`.
+#[derive(Debug)]
+pub struct TraceArgument {
+	trace: String,
+	symbols: Option<String>,
+}
+
+/// parse the command line, dispatching on an optional subcommand
+pub fn parse() -> Command {
+	let args = env::args().collect::<Vec<_>>();
+
+	match args.get(1).map(String::as_str) {
+		Some("asm") => Command::Asm(AsmArgument::parse(subcommand_args(&args))),
+		Some("disasm") => Command::Disasm(DisasmArgument::parse(subcommand_args(&args))),
+		Some("test") => Command::Test(TestArgument::parse(subcommand_args(&args))),
+		Some("trace") => Command::Trace(TraceArgument::parse(subcommand_args(&args))),
+		Some("dap") => {
+			parse_dap(subcommand_args(&args));
+			Command::Dap
 		}
+		_ => Command::Run(Box::new(Argument::parse())),
 	}
 }
 
-impl Argument {
-	fn new() -> Self {
-		Self {
-			inner: Arc::new(Mutex::new(ArgumentInner::new())),
-		}
+/// command line of a subcommand, named after it for the usage message
+fn subcommand_args(args: &[String]) -> Vec<String> {
+	let mut sub_args = vec![format!("{} {}", args[0], args[1])];
+	sub_args.extend_from_slice(&args[2..]);
+	sub_args
+}
+
+/// check the command line of `vlc3 dap`, which takes no arguments
+fn parse_dap(args: Vec<String>) {
+	let mut parser = ArgumentParser::new();
+	parser.set_description(
+		"Serve the Debug Adapter Protocol on standard input and output, \
+		so that editors such as VS Code can debug lc-3 programs."
+	);
+	parse_or_exit(&parser, args);
+}
+
+/// run 'parser' over 'args', exiting on --help or bad arguments
+fn parse_or_exit(parser: &ArgumentParser, args: Vec<String>) {
+	if let Err(code) = parser.parse(args, &mut io::stdout(), &mut io::stderr()) {
+		exit(code);
+	}
+}
+
+/// 'secs' of --timeout as a time limit, None for 0; exits unless it's
+/// a number of seconds
+fn time_limit(secs: f64) -> Option<Duration> {
+	if !secs.is_finite() || secs < 0.0 {
+		eprintln!("vlc3: --timeout must be a number of seconds");
+		exit(exit_status::USAGE);
 	}
+	(secs > 0.0).then(|| Duration::from_secs_f64(secs))
+}
 
+impl Argument {
 	pub fn path(&self) -> Option<String> {
-		self
-			.inner
-			.lock()
-			.unwrap()
-			.path
-			.clone()
+		self.path.clone()
 	}
 
 	pub fn summary(&self) -> bool {
-		self
-			.inner
-			.lock()
-			.unwrap()
-			.summary
+		self.summary
+	}
+
+	pub fn debug(&self) -> bool {
+		self.debug
+	}
+
+	pub fn gdb(&self) -> Option<&str> {
+		self.gdb.as_deref()
+	}
+
+	pub fn os(&self) -> bool {
+		self.os
+	}
+
+	pub fn os_image(&self) -> Option<&str> {
+		self.os_image.as_deref()
+	}
+
+	/// whether the program's I/O is scripted rather than the terminal's;
+	/// any of the input and output options implies it
+	pub fn headless(&self) -> bool {
+		self.headless
+			|| self.input.is_some()
+			|| self.input_text.is_some()
+			|| self.output.is_some()
+	}
+
+	pub fn input(&self) -> Option<&str> {
+		self.input.as_deref()
+	}
+
+	pub fn input_text(&self) -> Option<&str> {
+		self.input_text.as_deref()
+	}
+
+	pub fn input_delay(&self) -> u64 {
+		self.input_delay
+	}
+
+	pub fn output(&self) -> Option<&str> {
+		self.output.as_deref()
+	}
+
+	pub fn max_instructions(&self) -> Option<u64> {
+		self.max_instructions
 	}
 
-	pub(super) fn parse(&self) {
+	/// how long the program may run, None if unlimited
+	pub fn timeout(&self) -> Option<Duration> {
+		self.timeout
+	}
+
+	pub fn engine(&self) -> Engine {
+		self.engine
+	}
+
+	pub fn result_json(&self) -> Option<&str> {
+		self.result_json.as_deref()
+	}
+
+	pub fn trace(&self) -> Option<&str> {
+		self.trace.as_deref()
+	}
+
+	pub fn trace_format(&self) -> Format {
+		self.trace_format
+	}
+
+	/// address ranges given as START-END or ADDR, unparsed
+	pub fn trace_ranges(&self) -> &[String] {
+		&self.trace_ranges
+	}
+
+	pub fn record(&self) -> Option<&str> {
+		self.record.as_deref()
+	}
+
+	pub fn replay(&self) -> Option<&str> {
+		self.replay.as_deref()
+	}
+
+	/// snapshot to start from instead of loading PROGRAM, which then
+	/// only provides symbols
+	pub fn load_snapshot(&self) -> Option<&str> {
+		self.load_snapshot.as_deref()
+	}
+
+	pub fn save_snapshot(&self) -> Option<&str> {
+		self.save_snapshot.as_deref()
+	}
+
+	pub fn parse() -> Self {
 		let mut summary = false;
-		let mut path = Some(String::new());
+		let mut debug = false;
+		let mut gdb = None;
+		let mut os = false;
+		let mut os_image = None;
+		let mut headless = false;
+		let mut input = None;
+		let mut input_text = None;
+		let mut input_delay = 0;
+		let mut output = None;
+		let mut max_instructions = None;
+		let mut timeout = 0.0f64;
+		let mut engine = Engine::default();
+		let mut result_json = None;
+		let mut trace = None;
+		let mut trace_format = Format::Text;
+		let mut trace_ranges = Vec::new();
+		let mut record = None;
+		let mut replay = None;
+		let mut load_snapshot = None;
+		let mut save_snapshot = None;
+		let mut path = None;
 
 		// nmd, use braces to limit ArgumentParser's scope to
 		// ensure that &mut summary and &mut path live long enouth!
 		// RIDICULOUS!!!
 		{
 			let mut parser = ArgumentParser::new();
-			parser.set_description("Emulate lc-3 environment.");
+			parser.set_description(
+				"Emulate lc-3 environment. \
+				Run `vlc3 asm --help` to assemble programs and \
+				`vlc3 disasm --help` to disassemble them, \
+				`vlc3 test --help` to check their output, \
+				`vlc3 trace --help` to read binary traces and \
+				`vlc3 dap --help` to debug them from an editor."
+			);
 			parser.refer(&mut summary)
 				.add_option(
 					&["-s", "--summary"],
 					StoreTrue,
 					"Print summary after program exited"
 				);
-			
+			parser.refer(&mut debug)
+				.add_option(
+					&["-d", "--debug"],
+					StoreTrue,
+					"Run program under the interactive debugger"
+				);
+			parser.refer(&mut gdb)
+				.add_option(
+					&["--gdb"],
+					StoreOption,
+					"Wait for a GDB remote protocol client on a local TCP PORT \
+					or a Unix socket PATH"
+				)
+				.metavar("PORT|PATH");
+			parser.refer(&mut os)
+				.add_option(
+					&["--os"],
+					StoreTrue,
+					"Boot the bundled lc-3 operating system, whose trap \
+					routines run as lc-3 code"
+				);
+			parser.refer(&mut os_image)
+				.add_option(
+					&["--os-image"],
+					StoreOption,
+					"Boot the operating system in object file IMAGE instead \
+					of the bundled one"
+				)
+				.metavar("IMAGE");
+			parser.refer(&mut headless)
+				.add_option(
+					&["--headless"],
+					StoreTrue,
+					"Leave the terminal alone: read keyboard input from --input \
+					or --input-text and write display output to --output or \
+					standard output; GETC or IN past the end of the input \
+					stop the program with exit status 3"
+				);
+			parser.refer(&mut input)
+				.add_option(
+					&["--input"],
+					StoreOption,
+					"Type the contents of FILE on the keyboard (implies --headless)"
+				)
+				.metavar("FILE");
+			parser.refer(&mut input_text)
+				.add_option(
+					&["--input-text"],
+					StoreOption,
+					"Type TEXT on the keyboard (implies --headless)"
+				)
+				.metavar("TEXT");
+			parser.refer(&mut input_delay)
+				.add_option(
+					&["--input-delay"],
+					Store,
+					"Make each key wait N instructions after the previous one \
+					was read (default: 0)"
+				)
+				.metavar("N");
+			parser.refer(&mut output)
+				.add_option(
+					&["--output"],
+					StoreOption,
+					"Write display output to FILE (implies --headless)"
+				)
+				.metavar("FILE");
+			parser.refer(&mut max_instructions)
+				.add_option(
+					&["--max-instructions"],
+					StoreOption,
+					"Stop the program once it executed N instructions"
+				)
+				.metavar("N");
+			parser.refer(&mut timeout)
+				.add_option(
+					&["--timeout"],
+					Store,
+					"Stop the program once it ran for SECS seconds, 0 for no \
+					limit (default: 0)"
+				)
+				.metavar("SECS");
+			parser.refer(&mut engine)
+				.add_option(
+					&["--engine"],
+					Store,
+					"Execute with the 'interpreter' or with 'blocks', basic \
+					blocks compiled to closures, which is faster for long \
+					computations (default: interpreter)"
+				)
+				.metavar("ENGINE");
+			parser.refer(&mut result_json)
+				.add_option(
+					&["--result-json"],
+					StoreOption,
+					"Write how the program ended and the final registers to \
+					FILE as JSON"
+				)
+				.metavar("FILE");
+			parser.refer(&mut trace)
+				.add_option(
+					&["--trace"],
+					StoreOption,
+					"Write every executed instruction with the registers and \
+					memory it changed to FILE"
+				)
+				.metavar("FILE");
+			parser.refer(&mut trace_format)
+				.add_option(
+					&["--trace-format"],
+					Store,
+					"Write the trace as 'text' or as compact 'binary' records \
+					that `vlc3 trace` reads (default: text)"
+				)
+				.metavar("FORMAT");
+			parser.refer(&mut trace_ranges)
+				.add_option(
+					&["--trace-range"],
+					Collect,
+					"Only trace instructions at or accessing addresses in \
+					RANGE, e.g. x3000-x30FF; may be given more than once"
+				)
+				.metavar("RANGE");
+			parser.refer(&mut record)
+				.add_option(
+					&["--record"],
+					StoreOption,
+					"Log every key the program reads, and when it reads it, \
+					to FILE"
+				)
+				.metavar("FILE");
+			parser.refer(&mut replay)
+				.add_option(
+					&["--replay"],
+					StoreOption,
+					"Type the keys logged by --record to FILE, each once the \
+					program executed as many instructions as when recorded"
+				)
+				.metavar("FILE");
+			parser.refer(&mut load_snapshot)
+				.add_option(
+					&["--load-snapshot"],
+					StoreOption,
+					"Resume the machine saved by --save-snapshot to FILE; \
+					PROGRAM, if given, only provides symbols"
+				)
+				.metavar("FILE");
+			parser.refer(&mut save_snapshot)
+				.add_option(
+					&["--save-snapshot"],
+					StoreOption,
+					"Save the whole machine to FILE when the run stops, e.g. \
+					at --max-instructions"
+				)
+				.metavar("FILE");
+
 			parser.refer(&mut path).add_argument(
 					"PROGRAM",
 					StoreOption,
@@ -82,7 +440,268 @@ impl Argument {
 			parser.parse_args_or_exit();
 		}
 
-		(*ARGS.inner.lock().unwrap()).summary = summary;
-		(*ARGS.inner.lock().unwrap()).path = path;
+		if path.is_none() && load_snapshot.is_none() {
+			eprintln!("vlc3: PROGRAM is required unless --load-snapshot is given");
+			exit(exit_status::USAGE);
+		}
+		if load_snapshot.is_some() && (os || os_image.is_some()) {
+			eprintln!("vlc3: --load-snapshot can't be combined with --os or --os-image");
+			exit(exit_status::USAGE);
+		}
+
+		Self {
+			path,
+			summary,
+			debug,
+			gdb,
+			os,
+			os_image,
+			headless,
+			input,
+			input_text,
+			input_delay,
+			output,
+			max_instructions,
+			timeout: time_limit(timeout),
+			engine,
+			result_json,
+			trace,
+			trace_format,
+			trace_ranges,
+			record,
+			replay,
+			load_snapshot,
+			save_snapshot,
+		}
+	}
+}
+
+impl AsmArgument {
+	pub fn source(&self) -> &str {
+		&self.source
+	}
+
+	pub fn output(&self) -> Option<&str> {
+		self.output.as_deref()
+	}
+
+	fn parse(args: Vec<String>) -> Self {
+		let mut source = String::new();
+		let mut output = None;
+
+		{
+			let mut parser = ArgumentParser::new();
+			parser.set_description(
+				"Assemble an lc-3 program into an object file and a symbol file."
+			);
+			parser.refer(&mut output)
+				.add_option(
+					&["-o", "--output"],
+					StoreOption,
+					"Path to object file (default: SOURCE with .obj extension)"
+				);
+			parser.refer(&mut source)
+				.add_argument("SOURCE", Store, "Path to assembly source")
+				.required();
+			parse_or_exit(&parser, args);
+		}
+
+		Self { source, output }
+	}
+}
+
+impl DisasmArgument {
+	pub fn object(&self) -> &str {
+		&self.object
+	}
+
+	pub fn symbols(&self) -> Option<&str> {
+		self.symbols.as_deref()
+	}
+
+	fn parse(args: Vec<String>) -> Self {
+		let mut object = String::new();
+		let mut symbols = None;
+
+		{
+			let mut parser = ArgumentParser::new();
+			parser.set_description("Disassemble an lc-3 object file.");
+			parser.refer(&mut symbols)
+				.add_option(
+					&["--sym"],
+					StoreOption,
+					"Path to symbol file (default: OBJECT with .sym extension, if present)"
+				);
+			parser.refer(&mut object)
+				.add_argument("OBJECT", Store, "Path to object file")
+				.required();
+			parse_or_exit(&parser, args);
+		}
+
+		Self { object, symbols }
+	}
+}
+
+impl TestArgument {
+	pub fn program(&self) -> &str {
+		&self.program
+	}
+
+	pub fn cases(&self) -> &str {
+		&self.cases
+	}
+
+	pub fn os(&self) -> bool {
+		self.os
+	}
+
+	pub fn os_image(&self) -> Option<&str> {
+		self.os_image.as_deref()
+	}
+
+	pub fn input_delay(&self) -> u64 {
+		self.input_delay
+	}
+
+	pub fn max_instructions(&self) -> Option<u64> {
+		self.max_instructions
+	}
+
+	/// the time limit of every case, None if unlimited
+	pub fn timeout(&self) -> Option<Duration> {
+		self.timeout
+	}
+
+	pub fn engine(&self) -> Engine {
+		self.engine
+	}
+
+	pub fn junit(&self) -> Option<&str> {
+		self.junit.as_deref()
+	}
+
+	fn parse(args: Vec<String>) -> Self {
+		let mut program = String::new();
+		let mut cases = String::new();
+		let mut os = false;
+		let mut os_image = None;
+		let mut input_delay = 0;
+		let mut max_instructions = None;
+		let mut timeout = 10.0f64;
+		let mut engine = Engine::default();
+		let mut junit = None;
+
+		{
+			let mut parser = ArgumentParser::new();
+			parser.set_description(
+				"Run an lc-3 program against a directory of cases: every \
+				NAME.out holds the output expected of the program when it \
+				reads the optional NAME.in as its keyboard input."
+			);
+			parser.refer(&mut os)
+				.add_option(
+					&["--os"],
+					StoreTrue,
+					"Boot the bundled lc-3 operating system for every case"
+				);
+			parser.refer(&mut os_image)
+				.add_option(
+					&["--os-image"],
+					StoreOption,
+					"Boot the operating system in object file IMAGE instead \
+					of the bundled one"
+				)
+				.metavar("IMAGE");
+			parser.refer(&mut input_delay)
+				.add_option(
+					&["--input-delay"],
+					Store,
+					"Make each key wait N instructions after the previous one \
+					was read (default: 0)"
+				)
+				.metavar("N");
+			parser.refer(&mut max_instructions)
+				.add_option(
+					&["--max-instructions"],
+					StoreOption,
+					"Fail cases that execute more than N instructions"
+				)
+				.metavar("N");
+			parser.refer(&mut timeout)
+				.add_option(
+					&["--timeout"],
+					Store,
+					"Fail cases that run longer than SECS seconds, 0 for no \
+					limit (default: 10)"
+				)
+				.metavar("SECS");
+			parser.refer(&mut engine)
+				.add_option(
+					&["--engine"],
+					Store,
+					"Execute with the 'interpreter' or with 'blocks', basic \
+					blocks compiled to closures (default: interpreter)"
+				)
+				.metavar("ENGINE");
+			parser.refer(&mut junit)
+				.add_option(
+					&["--junit"],
+					StoreOption,
+					"Also write the report as JUnit XML to FILE"
+				)
+				.metavar("FILE");
+			parser.refer(&mut program)
+				.add_argument("PROGRAM", Store, "Path to program")
+				.required();
+			parser.refer(&mut cases)
+				.add_argument("CASES", Store, "Path to directory of cases")
+				.required();
+			parse_or_exit(&parser, args);
+		}
+
+		Self {
+			program,
+			cases,
+			os,
+			os_image,
+			input_delay,
+			max_instructions,
+			timeout: time_limit(timeout),
+			engine,
+			junit,
+		}
+	}
+}
+
+impl TraceArgument {
+	pub fn trace(&self) -> &str {
+		&self.trace
+	}
+
+	pub fn symbols(&self) -> Option<&str> {
+		self.symbols.as_deref()
+	}
+
+	fn parse(args: Vec<String>) -> Self {
+		let mut trace = String::new();
+		let mut symbols = None;
+
+		{
+			let mut parser = ArgumentParser::new();
+			parser.set_description("Print a binary trace written by --trace as text.");
+			parser.refer(&mut symbols)
+				.add_option(
+					&["--sym"],
+					StoreOption,
+					"Path to symbol file of the traced program"
+				)
+				.metavar("SYMBOLS");
+			parser.refer(&mut trace)
+				.add_argument("TRACE", Store, "Path to binary trace")
+				.required();
+			parse_or_exit(&parser, args);
+		}
+
+		Self { trace, symbols }
 	}
 }
@@ -0,0 +1,422 @@
+//! Snapshots of the whole state of a machine, as saved by
+//! `--save-snapshot` and restored by `--load-snapshot`.
+//!
+//! A snapshot file starts with [`MAGIC`] and the format's version, a
+//! u16, followed by
+//!
+//! | field                             | encoding                      |
+//! |-----------------------------------|-------------------------------|
+//! | instructions executed             | u64                           |
+//! | flags                             | u8, 1 running, 2 native traps |
+//! | registers                         | u16 count, u16 each           |
+//! | addresses of the last instructions| u16 count, u16 each           |
+//! | memory                            | 65536 u16                     |
+//! | devices                           | u16 count, then each device   |
+//! | keys given back by reverse steps  | u32 count, u8 each            |
+//! | console input                     | u8 kind, then its input       |
+//!
+//! where a device is the first and the last address of its registers
+//! and its state, a u16 count of words and the words. The console's
+//! input is kind 0 if it has none to save; kind 1, a headless console,
+//! has its delay and the instructions waited for the next key, u64
+//! each, and its keys, a u32 count and a u8 each; kind 2, a replay, has
+//! the instructions it ran, a u64, and its events, a u32 count and for
+//! each its instructions, a u64, and its key, a u16 with x0100 for the
+//! end of input. Integers are little endian. Version 2 snapshots have
+//! no keys given back and version 1 snapshots end after the devices,
+//! without any keys left to read.
+
+use crate::console::{ConsoleInput, InputEvent, PendingInput};
+use crate::cpu::REG_COUNT;
+use crate::fault::Fault;
+use crate::memory::MEMORY_SIZE;
+use std::ops::RangeInclusive;
+
+/// first bytes of a snapshot
+pub const MAGIC: &[u8; 8] = b"VLC3SNAP";
+/// version of the format written
+pub const VERSION: u16 = 3;
+
+const RUNNING: u8 = 1 << 0;
+const NATIVE_TRAPS: u8 = 1 << 1;
+
+const NO_INPUT: u8 = 0;
+const HEADLESS_INPUT: u8 = 1;
+const REPLAY_INPUT: u8 = 2;
+/// key of a replayed end of input
+const END_OF_INPUT: u16 = 0x100;
+
+/// The state of a machine at some instruction.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Snapshot {
+	pub instructions: u64,
+	pub running: bool,
+	pub native_traps: bool,
+	/// every register, in the order of [`Register`]
+	///
+	/// [`Register`]: crate::cpu::register::Register
+	pub registers: [u16; REG_COUNT],
+	/// addresses of the last instructions executed, oldest first
+	pub recent_pcs: Vec<u16>,
+	/// all of memory, without the registers of devices
+	pub memory: Vec<u16>,
+	/// the range and the state of every device
+	pub devices: Vec<(RangeInclusive<u16>, Vec<u16>)>,
+	/// keys the program has yet to read
+	pub input: PendingInput,
+}
+
+/// Reads the fields of a snapshot in order.
+struct Reader<'a> {
+	bytes: &'a [u8],
+}
+
+fn invalid(reason: &str) -> Fault {
+	Fault::Load(format!("invalid snapshot: {}", reason))
+}
+
+impl Reader<'_> {
+	fn take(&mut self, len: usize) -> Result<&[u8], Fault> {
+		if self.bytes.len() < len {
+			return Err(invalid("truncated"));
+		}
+		let (taken, rest) = self.bytes.split_at(len);
+		self.bytes = rest;
+		Ok(taken)
+	}
+
+	fn u8(&mut self) -> Result<u8, Fault> {
+		Ok(self.take(1)?[0])
+	}
+
+	fn u16(&mut self) -> Result<u16, Fault> {
+		let bytes = self.take(2)?;
+		Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+	}
+
+	fn u64(&mut self) -> Result<u64, Fault> {
+		let mut bytes = [0; 8];
+		bytes.copy_from_slice(self.take(8)?);
+		Ok(u64::from_le_bytes(bytes))
+	}
+
+	/// a u16 count followed by as many words
+	fn words(&mut self) -> Result<Vec<u16>, Fault> {
+		let count = self.u16()?;
+		(0..count).map(|_| self.u16()).collect()
+	}
+
+	fn u32(&mut self) -> Result<u32, Fault> {
+		let mut bytes = [0; 4];
+		bytes.copy_from_slice(self.take(4)?);
+		Ok(u32::from_le_bytes(bytes))
+	}
+
+	/// a u32 count followed by as many bytes
+	fn keys(&mut self) -> Result<Vec<u8>, Fault> {
+		let count = self.u32()? as usize;
+		Ok(self.take(count)?.to_vec())
+	}
+
+	/// the keys left to read, those given back only if 'unread'
+	fn input(&mut self, unread: bool) -> Result<PendingInput, Fault> {
+		let unread = if unread { self.keys()? } else { Vec::new() };
+		let console = match self.u8()? {
+			NO_INPUT => None,
+			HEADLESS_INPUT => {
+				let delay = self.u64()?;
+				let waited = self.u64()?;
+				Some(ConsoleInput::Headless { keys: self.keys()?, delay, waited })
+			}
+			REPLAY_INPUT => {
+				let instructions = self.u64()?;
+				let count = self.u32()?;
+				let events = (0..count)
+					.map(|_| {
+						let instructions = self.u64()?;
+						let key = match self.u16()? {
+							END_OF_INPUT => None,
+							key => Some(u8::try_from(key).map_err(|_| invalid("invalid key"))?),
+						};
+						Ok(InputEvent { instructions, key })
+					})
+					.collect::<Result<Vec<_>, Fault>>()?;
+				Some(ConsoleInput::Replay { events, instructions })
+			}
+			_ => return Err(invalid("unknown console input")),
+		};
+		Ok(PendingInput { unread, console })
+	}
+}
+
+fn push_words(out: &mut Vec<u8>, words: &[u16]) {
+	out.extend_from_slice(&(words.len() as u16).to_le_bytes());
+	for word in words {
+		out.extend_from_slice(&word.to_le_bytes());
+	}
+}
+
+fn push_keys(out: &mut Vec<u8>, keys: &[u8]) {
+	out.extend_from_slice(&(keys.len() as u32).to_le_bytes());
+	out.extend_from_slice(keys);
+}
+
+fn push_input(out: &mut Vec<u8>, input: &PendingInput) {
+	push_keys(out, &input.unread);
+	match &input.console {
+		None => out.push(NO_INPUT),
+		Some(ConsoleInput::Headless { keys, delay, waited }) => {
+			out.push(HEADLESS_INPUT);
+			out.extend_from_slice(&delay.to_le_bytes());
+			out.extend_from_slice(&waited.to_le_bytes());
+			push_keys(out, keys);
+		}
+		Some(ConsoleInput::Replay { events, instructions }) => {
+			out.push(REPLAY_INPUT);
+			out.extend_from_slice(&instructions.to_le_bytes());
+			out.extend_from_slice(&(events.len() as u32).to_le_bytes());
+			for event in events {
+				out.extend_from_slice(&event.instructions.to_le_bytes());
+				let key = event.key.map_or(END_OF_INPUT, u16::from);
+				out.extend_from_slice(&key.to_le_bytes());
+			}
+		}
+	}
+}
+
+impl Snapshot {
+	pub fn to_bytes(&self) -> Vec<u8> {
+		let mut out = Vec::with_capacity(2 * MEMORY_SIZE + 256);
+		out.extend_from_slice(MAGIC);
+		out.extend_from_slice(&VERSION.to_le_bytes());
+		out.extend_from_slice(&self.instructions.to_le_bytes());
+
+		let mut flags = 0;
+		if self.running {
+			flags |= RUNNING;
+		}
+		if self.native_traps {
+			flags |= NATIVE_TRAPS;
+		}
+		out.push(flags);
+
+		push_words(&mut out, &self.registers);
+		push_words(&mut out, &self.recent_pcs);
+		for word in &self.memory {
+			out.extend_from_slice(&word.to_le_bytes());
+		}
+
+		out.extend_from_slice(&(self.devices.len() as u16).to_le_bytes());
+		for (range, state) in &self.devices {
+			out.extend_from_slice(&range.start().to_le_bytes());
+			out.extend_from_slice(&range.end().to_le_bytes());
+			push_words(&mut out, state);
+		}
+		push_input(&mut out, &self.input);
+		out
+	}
+
+	pub fn from_bytes(bytes: &[u8]) -> Result<Self, Fault> {
+		let mut reader = Reader { bytes };
+		if reader.take(MAGIC.len()).ok() != Some(&MAGIC[..]) {
+			return Err(invalid("not a vlc3 snapshot"));
+		}
+		let version = reader.u16()?;
+		if !(1..=VERSION).contains(&version) {
+			return Err(invalid(&format!("unsupported version {}", version)));
+		}
+
+		let instructions = reader.u64()?;
+		let flags = reader.u8()?;
+		let registers = reader.words()?
+			.try_into()
+			.map_err(|_| invalid("wrong number of registers"))?;
+		let recent_pcs = reader.words()?;
+		let memory = (0..MEMORY_SIZE)
+			.map(|_| reader.u16())
+			.collect::<Result<Vec<_>, _>>()?;
+
+		let device_count = reader.u16()?;
+		let mut devices = Vec::new();
+		for _ in 0..device_count {
+			let start = reader.u16()?;
+			let end = reader.u16()?;
+			devices.push((start..=end, reader.words()?));
+		}
+		let input = match version {
+			1 => PendingInput::default(),
+			2 => reader.input(false)?,
+			_ => reader.input(true)?,
+		};
+		if !reader.bytes.is_empty() {
+			return Err(invalid("trailing data"));
+		}
+
+		Ok(Self {
+			instructions,
+			running: flags & RUNNING != 0,
+			native_traps: flags & NATIVE_TRAPS != 0,
+			registers,
+			recent_pcs,
+			memory,
+			devices,
+			input,
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::asm;
+	use crate::console::{Capture, Headless, Replay};
+	use crate::cpu::register::Register;
+	use crate::machine::{Limits, Machine};
+
+	/// echoes three keys it polls for
+	const ECHO: &str = "
+        .ORIG x3000
+        AND R3, R3, #0
+        ADD R3, R3, #3
+WAIT    ADD R1, R1, #1
+        LDI R2, KBSRP
+        BRzp WAIT
+        LDI R0, KBDRP
+        OUT
+        ADD R3, R3, #-1
+        BRp WAIT
+        HALT
+KBSRP   .FILL xFE00
+KBDRP   .FILL xFE02
+        .END
+";
+
+	/// a machine with ECHO loaded, typing 'input' 50 instructions apart
+	fn machine(input: &str) -> (Machine, Capture) {
+		let output = Capture::new();
+		let mut machine = Machine::new();
+		machine.set_console(Headless::new(input, output.clone()).with_delay(50));
+		machine.load(&asm::assemble(ECHO).unwrap().to_object()).unwrap();
+		(machine, output)
+	}
+
+	fn run_for(machine: &mut Machine, instructions: u64) {
+		machine.run_limited(Limits { max_instructions: Some(instructions), timeout: None }).unwrap();
+	}
+
+	#[test]
+	fn restored_snapshots_type_the_keys_left() {
+		let (mut whole, whole_output) = machine("xyz");
+		whole.run().unwrap();
+
+		let (mut saved, saved_output) = machine("xyz");
+		run_for(&mut saved, 100);
+		let snapshot = saved.snapshot();
+		assert_eq!(
+			snapshot.input.console,
+			Some(ConsoleInput::Headless { keys: b"yz".to_vec(), delay: 50, waited: 49 })
+		);
+		let bytes = snapshot.to_bytes();
+		assert_eq!(Snapshot::from_bytes(&bytes).unwrap(), snapshot);
+
+		// the keys of the resumed run's console are replaced
+		let (mut resumed, resumed_output) = machine("other keys");
+		resumed.restore(&Snapshot::from_bytes(&bytes).unwrap()).unwrap();
+		resumed.run().unwrap();
+		assert_eq!(resumed.snapshot(), whole.snapshot());
+		let mut output = saved_output.contents();
+		output.extend(resumed_output.contents());
+		assert_eq!(output, whole_output.contents());
+	}
+
+	#[test]
+	fn replayed_input_round_trips() {
+		let events = vec![
+			InputEvent { instructions: 10, key: Some(b'a') },
+			InputEvent { instructions: 300, key: Some(0xff) },
+			InputEvent { instructions: 301, key: None },
+		];
+		let machine = Machine::new();
+		machine.set_console(Replay::new(events.clone(), Headless::new("", Capture::new())));
+		let snapshot = machine.snapshot();
+		assert_eq!(snapshot.input.console, Some(ConsoleInput::Replay { events, instructions: 0 }));
+		assert_eq!(Snapshot::from_bytes(&snapshot.to_bytes()).unwrap(), snapshot);
+
+		// a console which can't type them fails to restore them
+		let mut terminal = Machine::new();
+		let before = terminal.snapshot();
+		assert!(matches!(terminal.restore(&snapshot), Err(Fault::Load(_))));
+		assert_eq!(terminal.snapshot(), before);
+	}
+
+	#[test]
+	fn device_state_of_the_wrong_length_is_a_fault() {
+		let (mut machine, _) = machine("xyz");
+		run_for(&mut machine, 10);
+		let mut snapshot = machine.snapshot();
+		snapshot.registers[Register::R0 as usize] = 1234;
+		snapshot.devices[0].1.push(0);
+
+		let before = machine.snapshot();
+		match machine.restore(&snapshot) {
+			Err(Fault::Load(message)) => assert!(message.contains("words of state"), "{}", message),
+			result => panic!("restored: {:?}", result),
+		}
+		assert_eq!(machine.snapshot(), before);
+
+		snapshot.devices[0].1.pop();
+		snapshot.memory.pop();
+		assert!(matches!(machine.restore(&snapshot), Err(Fault::Load(_))));
+	}
+
+	#[test]
+	fn version_1_snapshots_have_no_keys_left() {
+		let mut snapshot = Machine::new().snapshot();
+		snapshot.input = PendingInput::default();
+		let mut bytes = snapshot.to_bytes();
+		// no keys given back and no console input
+		assert!(bytes.ends_with(&[0, 0, 0, 0, NO_INPUT]));
+		bytes.truncate(bytes.len() - 5);
+		bytes[MAGIC.len()..MAGIC.len() + 2].copy_from_slice(&1_u16.to_le_bytes());
+		assert_eq!(Snapshot::from_bytes(&bytes).unwrap(), snapshot);
+	}
+
+	#[test]
+	fn version_2_snapshots_have_no_keys_given_back() {
+		let (machine, _) = machine("xyz");
+		let snapshot = machine.snapshot();
+		let mut bytes = snapshot.to_bytes();
+		// the count of keys given back, before the headless input
+		let count = bytes.len() - 8 - 8 - 4 - 3 - 1 - 4;
+		assert_eq!(bytes[count..count + 4], [0; 4]);
+		bytes.drain(count..count + 4);
+		bytes[MAGIC.len()..MAGIC.len() + 2].copy_from_slice(&2_u16.to_le_bytes());
+		assert_eq!(Snapshot::from_bytes(&bytes).unwrap(), snapshot);
+	}
+
+	#[test]
+	fn keys_given_back_are_saved() {
+		let mut snapshot = Machine::new().snapshot();
+		snapshot.input.unread = b"ab".to_vec();
+		assert_eq!(Snapshot::from_bytes(&snapshot.to_bytes()).unwrap(), snapshot);
+	}
+
+	#[test]
+	fn damaged_snapshots_are_faults() {
+		let bytes = machine("xyz").0.snapshot().to_bytes();
+		let mut future = bytes.clone();
+		future[MAGIC.len()..MAGIC.len() + 2].copy_from_slice(&(VERSION + 1).to_le_bytes());
+		let mut unknown = bytes.clone();
+		// before the delay, the wait and the three keys
+		let kind = unknown.len() - 8 - 8 - 4 - 3 - 1;
+		assert_eq!(unknown[kind], HEADLESS_INPUT);
+		unknown[kind] = 9;
+
+		for damaged in [&bytes[..bytes.len() - 1], &bytes[1..], &future, &unknown] {
+			assert!(matches!(Snapshot::from_bytes(damaged), Err(Fault::Load(_))));
+		}
+		let mut trailing = bytes;
+		trailing.push(0);
+		assert!(Snapshot::from_bytes(&trailing).is_err());
+	}
+}
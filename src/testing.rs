@@ -0,0 +1,533 @@
+//! Expected-output tests of lc-3 programs, as run by `vlc3 test`.
+//!
+//! A directory of cases holds a `NAME.out` file for every case, the
+//! output the program is expected to write, and optionally a `NAME.in`
+//! file, the input typed on its keyboard. Each case runs the program on
+//! a fresh headless [`Machine`] and passes if the output matches byte
+//! for byte.
+
+use crate::console::{Capture, Headless};
+use crate::machine::{Engine, Limits, Machine, Stop};
+use std::{
+	ffi::OsStr,
+	fmt::Write as _,
+	fs, io,
+	path::Path,
+	time::{Duration, Instant},
+};
+
+/// lines of unchanged output shown around differences
+const DIFF_CONTEXT: usize = 2;
+
+/// most pairs of lines [`diff`] compares; longer outputs only get their
+/// first difference shown
+const DIFF_BUDGET: usize = 1 << 20;
+
+/// A program's input and the output expected of it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Case {
+	pub name: String,
+	pub input: Vec<u8>,
+	pub expected: Vec<u8>,
+}
+
+/// How the program runs for every case.
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+	/// operating system to boot, see [`Machine::boot`]
+	pub os_image: Option<Vec<u8>>,
+	/// instructions each key waits, see [`Headless::with_delay`]
+	pub input_delay: u64,
+	pub limits: Limits,
+	pub engine: Engine,
+}
+
+/// How a case ended.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Outcome {
+	Passed,
+	/// the output differs from the expected one, as 'diff' shows
+	Failed { diff: String },
+	/// the program didn't halt within the instruction limit
+	InstructionLimit(u64),
+	/// the program didn't halt within the time limit
+	Timeout(Duration),
+	/// the program faulted or couldn't be loaded
+	Error(String),
+}
+
+/// A case that ran.
+#[derive(Clone, Debug)]
+pub struct CaseResult {
+	pub name: String,
+	pub outcome: Outcome,
+	pub instructions: u64,
+	pub time: Duration,
+	/// what the program wrote
+	pub output: Vec<u8>,
+}
+
+/// Results of running a program against a directory of cases.
+#[derive(Clone, Debug)]
+pub struct Report {
+	/// name of the suite, e.g. the program's file name
+	pub name: String,
+	pub results: Vec<CaseResult>,
+}
+
+/// the cases in 'dir', sorted by name
+pub fn load_cases(dir: &Path) -> io::Result<Vec<Case>> {
+	let mut cases = Vec::new();
+	for entry in fs::read_dir(dir)? {
+		let path = entry?.path();
+		if path.extension() != Some(OsStr::new("out")) {
+			continue;
+		}
+		let Some(name) = path.file_stem().and_then(OsStr::to_str) else {
+			continue;
+		};
+
+		let expected = fs::read(&path)?;
+		let input = match fs::read(path.with_extension("in")) {
+			Ok(input) => input,
+			Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+			Err(e) => return Err(e),
+		};
+		cases.push(Case {
+			name: name.to_string(),
+			input,
+			expected,
+		});
+	}
+
+	cases.sort_by(|a, b| a.name.cmp(&b.name));
+	Ok(cases)
+}
+
+/// run the object image 'program' against 'case'
+pub fn run_case(program: &[u8], case: &Case, config: &Config) -> CaseResult {
+	let begin = Instant::now();
+	let output = Capture::new();
+	let mut machine = Machine::new();
+	machine.set_engine(config.engine);
+	machine.set_console(
+		Headless::new(case.input.clone(), output.clone()).with_delay(config.input_delay)
+	);
+
+	let result = machine.load(program).and_then(|_| match &config.os_image {
+		Some(os_image) => machine.boot(os_image),
+		None => Ok(()),
+	});
+	let result = result.and_then(|_| machine.run_limited(config.limits));
+
+	let output = output.contents();
+	let outcome = match result {
+		Ok(Stop::InstructionLimit) => {
+			Outcome::InstructionLimit(config.limits.max_instructions.unwrap_or_default())
+		}
+		Ok(Stop::Timeout) => Outcome::Timeout(config.limits.timeout.unwrap_or_default()),
+		Ok(Stop::Halted | Stop::Watchpoint) if output == case.expected => Outcome::Passed,
+		Ok(Stop::Halted | Stop::Watchpoint) => Outcome::Failed {
+			diff: diff(&case.expected, &output),
+		},
+		Ok(Stop::Interrupted) => Outcome::Error(String::from("interrupted")),
+		Err(fault) => Outcome::Error(fault.to_string()),
+	};
+
+	CaseResult {
+		name: case.name.clone(),
+		outcome,
+		instructions: machine.instructions(),
+		time: begin.elapsed(),
+		output,
+	}
+}
+
+/// run 'program' against every case of 'cases'
+pub fn run(name: &str, program: &[u8], cases: &[Case], config: &Config) -> Report {
+	Report {
+		name: name.to_string(),
+		results: cases.iter().map(|case| run_case(program, case, config)).collect(),
+	}
+}
+
+/// the lines of 'expected' missing from 'actual', marked with '-', and
+/// those it gained, marked with '+', around a little unchanged context;
+/// outputs too long to compare only show their first difference
+pub fn diff(expected: &[u8], actual: &[u8]) -> String {
+	let expected = String::from_utf8_lossy(expected);
+	let actual = String::from_utf8_lossy(actual);
+	let old = expected.split_inclusive('\n').collect::<Vec<_>>();
+	let new = actual.split_inclusive('\n').collect::<Vec<_>>();
+	let compared = old.len().saturating_mul(new.len()) <= DIFF_BUDGET;
+	let lines = if compared {
+		align(&old, &new)
+	} else {
+		first_difference(&old, &new)
+	};
+
+	let changed = lines
+		.iter()
+		.enumerate()
+		.filter(|(_, (mark, _))| *mark != ' ')
+		.map(|(idx, _)| idx)
+		.collect::<Vec<_>>();
+	let near_change = |idx: usize| {
+		changed
+			.iter()
+			.any(|&changed| changed.abs_diff(idx) <= DIFF_CONTEXT)
+	};
+
+	let mut text = String::new();
+	let mut skipped = false;
+	for (idx, (mark, line)) in lines.iter().enumerate() {
+		if !near_change(idx) {
+			skipped = true;
+			continue;
+		}
+		if skipped {
+			text.push_str("  ...\n");
+			skipped = false;
+		}
+		let _ = match line.strip_suffix('\n') {
+			Some(line) => writeln!(text, "{} {}", mark, line.escape_debug()),
+			None => writeln!(text, "{} {}  (no newline at end)", mark, line.escape_debug()),
+		};
+	}
+	if skipped {
+		text.push_str("  ...\n");
+	}
+	if !compared {
+		text.push_str("  ... (too long to compare any further)\n");
+	}
+	text
+}
+
+/// the lines of 'old' and 'new' marked as in [`diff`], along their
+/// longest common subsequence
+fn align<'a>(old: &[&'a str], new: &[&'a str]) -> Vec<(char, &'a str)> {
+	// longest common subsequences of the suffixes
+	let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+	for i in (0..old.len()).rev() {
+		for j in (0..new.len()).rev() {
+			lcs[i][j] = if old[i] == new[j] {
+				lcs[i + 1][j + 1] + 1
+			} else {
+				lcs[i + 1][j].max(lcs[i][j + 1])
+			};
+		}
+	}
+
+	let mut lines = Vec::new();
+	let (mut i, mut j) = (0, 0);
+	while i < old.len() || j < new.len() {
+		if i < old.len() && j < new.len() && old[i] == new[j] {
+			lines.push((' ', old[i]));
+			i += 1;
+			j += 1;
+		} else if i < old.len() && (j == new.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+			lines.push(('-', old[i]));
+			i += 1;
+		} else {
+			lines.push(('+', new[j]));
+			j += 1;
+		}
+	}
+	lines
+}
+
+/// the lines 'old' and 'new' start with alike, followed by the first
+/// line where they differ
+fn first_difference<'a>(old: &[&'a str], new: &[&'a str]) -> Vec<(char, &'a str)> {
+	let same = old.iter().zip(new).take_while(|(old, new)| old == new).count();
+	let mut lines = old[..same].iter().map(|&line| (' ', line)).collect::<Vec<_>>();
+	lines.extend(old.get(same).map(|&line| ('-', line)));
+	lines.extend(new.get(same).map(|&line| ('+', line)));
+	lines
+}
+
+impl Outcome {
+	pub fn passed(&self) -> bool {
+		*self == Self::Passed
+	}
+
+	/// label of the outcome in text reports
+	fn label(&self) -> &'static str {
+		match self {
+			Self::Passed => "PASS",
+			Self::Failed { .. } => "FAIL",
+			Self::InstructionLimit(_) | Self::Timeout(_) => "TIME",
+			Self::Error(_) => "ERROR",
+		}
+	}
+
+	/// one line explaining why the case didn't pass
+	fn message(&self) -> String {
+		match self {
+			Self::Passed => String::from("passed"),
+			Self::Failed { .. } => String::from("output differs from the expected one"),
+			Self::InstructionLimit(max) => format!("didn't halt within {} instructions", max),
+			Self::Timeout(timeout) => {
+				format!("didn't halt within {:.3} seconds", timeout.as_secs_f64())
+			}
+			Self::Error(fault) => fault.clone(),
+		}
+	}
+}
+
+impl Report {
+	pub fn passed(&self) -> usize {
+		self.results.iter().filter(|result| result.outcome.passed()).count()
+	}
+
+	pub fn all_passed(&self) -> bool {
+		self.passed() == self.results.len()
+	}
+
+	fn errors(&self) -> usize {
+		self.results
+			.iter()
+			.filter(|result| matches!(result.outcome, Outcome::Error(_)))
+			.count()
+	}
+
+	fn time(&self) -> Duration {
+		self.results.iter().map(|result| result.time).sum()
+	}
+
+	/// a line per case, with the differences of failed ones, and a
+	/// closing count
+	pub fn text(&self) -> String {
+		let mut text = String::new();
+		for result in &self.results {
+			let _ = writeln!(
+				text, "{:<5} {}  ({} instructions, {} ms)",
+				result.outcome.label(), result.name,
+				result.instructions, result.time.as_millis()
+			);
+			match &result.outcome {
+				Outcome::Passed => {}
+				Outcome::Failed { diff } => {
+					for line in diff.lines() {
+						let _ = writeln!(text, "      {}", line);
+					}
+				}
+				outcome => {
+					let _ = writeln!(text, "      {}", outcome.message());
+				}
+			}
+		}
+
+		let _ = writeln!(
+			text, "{}: {} passed, {} failed of {} cases",
+			self.name, self.passed(), self.results.len() - self.passed(), self.results.len()
+		);
+		text
+	}
+
+	/// the report as a JUnit XML document; faults are errors, wrong
+	/// output and exceeded limits are failures
+	pub fn junit(&self) -> String {
+		let tests = self.results.len();
+		let errors = self.errors();
+		let failures = tests - self.passed() - errors;
+		let time = self.time().as_secs_f64();
+
+		let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+		let _ = writeln!(
+			xml, "<testsuites tests=\"{}\" failures=\"{}\" errors=\"{}\" time=\"{:.3}\">",
+			tests, failures, errors, time
+		);
+		let _ = writeln!(
+			xml, "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" errors=\"{}\" time=\"{:.3}\">",
+			escape_xml(&self.name), tests, failures, errors, time
+		);
+		for result in &self.results {
+			let _ = writeln!(
+				xml, "    <testcase name=\"{}\" classname=\"{}\" time=\"{:.3}\">",
+				escape_xml(&result.name), escape_xml(&self.name), result.time.as_secs_f64()
+			);
+			let _ = writeln!(xml, "      <properties>");
+			let _ = writeln!(
+				xml, "        <property name=\"instructions\" value=\"{}\"/>",
+				result.instructions
+			);
+			let _ = writeln!(xml, "      </properties>");
+
+			let message = escape_xml(&result.outcome.message());
+			match &result.outcome {
+				Outcome::Passed => {}
+				Outcome::Failed { diff } => {
+					let _ = writeln!(
+						xml, "      <failure message=\"{}\">{}</failure>",
+						message, escape_xml(diff)
+					);
+				}
+				Outcome::InstructionLimit(_) | Outcome::Timeout(_) => {
+					let _ = writeln!(xml, "      <failure message=\"{}\"/>", message);
+				}
+				Outcome::Error(_) => {
+					let _ = writeln!(xml, "      <error message=\"{}\"/>", message);
+				}
+			}
+			let _ = writeln!(
+				xml, "      <system-out>{}</system-out>",
+				escape_xml(&String::from_utf8_lossy(&result.output))
+			);
+			let _ = writeln!(xml, "    </testcase>");
+		}
+		xml.push_str("  </testsuite>\n</testsuites>\n");
+		xml
+	}
+}
+
+/// 'text' as XML character data or attribute value; characters XML
+/// can't hold are replaced
+fn escape_xml(text: &str) -> String {
+	let mut escaped = String::with_capacity(text.len());
+	for ch in text.chars() {
+		match ch {
+			'&' => escaped.push_str("&amp;"),
+			'<' => escaped.push_str("&lt;"),
+			'>' => escaped.push_str("&gt;"),
+			'"' => escaped.push_str("&quot;"),
+			'\n' | '\r' | '\t' => escaped.push(ch),
+			ch if ch.is_control() => escaped.push(char::REPLACEMENT_CHARACTER),
+			ch => escaped.push(ch),
+		}
+	}
+	escaped
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::asm;
+
+	/// echoes its input a line at a time until an empty line
+	const ECHO: &str = "
+        .ORIG x3000
+LOOP    GETC
+        OUT
+        ADD R1, R0, #-10
+        BRnp LOOP
+        GETC
+        OUT
+        ADD R1, R0, #-10
+        BRnp LOOP
+        HALT
+        .END
+";
+
+	fn program(source: &str) -> Vec<u8> {
+		asm::assemble(source).unwrap().to_object()
+	}
+
+	fn case(name: &str, input: &str, expected: &str) -> Case {
+		Case {
+			name: String::from(name),
+			input: input.into(),
+			expected: expected.into(),
+		}
+	}
+
+	#[test]
+	fn matching_output_passes() {
+		let result = run_case(&program(ECHO), &case("echo", "hi\n\n", "hi\n\nHALT\n"), &Config::default());
+		assert_eq!(result.outcome, Outcome::Passed);
+		assert_eq!(result.output, b"hi\n\nHALT\n");
+		assert!(result.instructions > 0);
+	}
+
+	#[test]
+	fn differing_output_fails_with_a_diff() {
+		let result = run_case(
+			&program(ECHO),
+			&case("echo", "one\ntwo\nthree\n\n", "one\n2\nthree\n\nHALT\n"),
+			&Config::default(),
+		);
+		let expected = "  one\n- 2\n+ two\n  three\n  \n  ...\n";
+		assert_eq!(result.outcome, Outcome::Failed { diff: String::from(expected) });
+	}
+
+	#[test]
+	fn diff_shows_context_around_changes() {
+		let old = (1..=20).map(|line| format!("{}\n", line)).collect::<String>();
+		let new = old.replace("10\n", "ten\n").replace("20\n", "20");
+		assert_eq!(
+			diff(old.as_bytes(), new.as_bytes()),
+			"  ...\n  8\n  9\n- 10\n+ ten\n  11\n  12\n  ...\n  18\n  19\n- 20\n+ 20  (no newline at end)\n"
+		);
+	}
+
+	#[test]
+	fn long_outputs_only_show_their_first_difference() {
+		let old = (0..5000).map(|line| format!("{}\n", line)).collect::<String>();
+		let new = old.replace("\n1234\n", "\nx\n").replace("\n4000\n", "\ny\n");
+		assert_eq!(
+			diff(old.as_bytes(), new.as_bytes()),
+			"  ...\n  1232\n  1233\n- 1234\n+ x\n  ... (too long to compare any further)\n"
+		);
+	}
+
+	#[test]
+	fn programs_that_run_too_long_time_out() {
+		let spin = program(".ORIG x3000\nSPIN BR SPIN\n.END");
+		let limited = Config {
+			limits: Limits { max_instructions: Some(1000), timeout: None },
+			..Config::default()
+		};
+		let result = run_case(&spin, &case("spin", "", ""), &limited);
+		assert_eq!(result.outcome, Outcome::InstructionLimit(1000));
+		assert_eq!(result.instructions, 1000);
+
+		let timeout = Duration::from_millis(20);
+		let timed = Config {
+			limits: Limits { max_instructions: None, timeout: Some(timeout) },
+			..Config::default()
+		};
+		let result = run_case(&spin, &case("spin", "", ""), &timed);
+		assert_eq!(result.outcome, Outcome::Timeout(timeout));
+		assert!(result.time >= timeout);
+	}
+
+	#[test]
+	fn faults_are_errors() {
+		let illegal = program(".ORIG x3000\nADD R0, R0, #1\n.FILL xD000\n.END");
+		let result = run_case(&illegal, &case("illegal", "", ""), &Config::default());
+		assert_eq!(result.outcome, Outcome::Error(String::from("illegal opcode xD000 at x3001")));
+	}
+
+	#[test]
+	fn reports_count_and_escape_their_cases() {
+		let cases = [
+			case("<pass & \"quote\">", "a\n\n", "a\n\nHALT\n"),
+			case("fail", "b\n\n", "<b>\n\nHALT\n"),
+		];
+		let report = run("echo&co", &program(ECHO), &cases, &Config::default());
+		assert_eq!(report.passed(), 1);
+		assert!(!report.all_passed());
+		assert!(report.text().ends_with("echo&co: 1 passed, 1 failed of 2 cases\n"));
+
+		let xml = report.junit();
+		assert!(xml.contains("<testsuite name=\"echo&amp;co\" tests=\"2\" failures=\"1\" errors=\"0\""));
+		assert!(xml.contains("<testcase name=\"&lt;pass &amp; &quot;quote&quot;&gt;\" classname=\"echo&amp;co\""));
+		assert!(xml.contains("<failure message=\"output differs from the expected one\">- &lt;b&gt;\n+ b\n"));
+		assert_eq!(escape_xml("a&<>\"\u{1}\t"), "a&amp;&lt;&gt;&quot;\u{fffd}\t");
+	}
+
+	#[test]
+	fn cases_are_loaded_by_name() {
+		let dir = std::env::temp_dir().join(format!("vlc3-cases-{}", std::process::id()));
+		fs::create_dir_all(&dir).unwrap();
+		fs::write(dir.join("b.out"), "B").unwrap();
+		fs::write(dir.join("a.out"), "A").unwrap();
+		fs::write(dir.join("a.in"), "typed").unwrap();
+		fs::write(dir.join("c.in"), "no output").unwrap();
+		fs::write(dir.join("notes.txt"), "").unwrap();
+
+		let cases = load_cases(&dir).unwrap();
+		fs::remove_dir_all(&dir).unwrap();
+		assert_eq!(cases, [case("a", "typed", "A"), case("b", "", "B")]);
+	}
+}
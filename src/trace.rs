@@ -0,0 +1,514 @@
+//! Execution traces, as written by `--trace FILE`.
+//!
+//! A trace holds a [`Record`] per executed instruction and per interrupt
+//! taken: the address and word of the instruction, the registers it
+//! changed, the memory it read and wrote and the condition codes it
+//! left. Records are written as lines of text or, for long runs, in a
+//! compact binary format which [`Records`] reads back.
+//!
+//! The binary format starts with [`MAGIC`], then every record is
+//!
+//! | field                 | encoding                                |
+//! |-----------------------|-----------------------------------------|
+//! | kind                  | u8, 0 instruction, 1 interrupt          |
+//! | index                 | varint, distance to the previous record |
+//! | pc                    | u16                                     |
+//! | instruction or vector | u16                                     |
+//! | psr                   | u16                                     |
+//! | number of events      | varint                                  |
+//! | events                | see below                               |
+//!
+//! where an event is a tag followed by two u16: the register number
+//! (0-11) with the old and the new value, or `0x10` and `0x11` for a
+//! memory read and write with the address and the value. Integers are
+//! little endian, varints LEB128.
+
+use crate::asm::SymbolTable;
+use crate::cpu::{psr, register::Register};
+use crate::disasm;
+use std::{
+	fmt,
+	io::{self, Read, Write},
+	ops::RangeInclusive,
+	str::FromStr,
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		Arc, Mutex,
+	},
+};
+
+/// first bytes of a binary trace, the last one is the format's version
+pub const MAGIC: &[u8; 8] = b"VLC3TRC\x01";
+
+const KIND_INSTRUCTION: u8 = 0;
+const KIND_INTERRUPT: u8 = 1;
+const TAG_READ: u8 = 0x10;
+const TAG_WRITE: u8 = 0x11;
+
+/// How a trace is written.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Format {
+	#[default]
+	Text,
+	Binary,
+}
+
+impl FromStr for Format {
+	type Err = String;
+
+	fn from_str(name: &str) -> Result<Self, Self::Err> {
+		match name {
+			"text" => Ok(Self::Text),
+			"binary" => Ok(Self::Binary),
+			_ => Err(format!("unknown trace format '{}'", name)),
+		}
+	}
+}
+
+/// An effect of an instruction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event {
+	/// a register changed from 'old' to 'new'
+	Register { register: Register, old: u16, new: u16 },
+	Read { addr: u16, value: u16 },
+	Write { addr: u16, value: u16 },
+}
+
+/// An executed instruction, or an interrupt taken, and its effects.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Record {
+	/// number of instructions executed before this one
+	pub index: u64,
+	pub pc: u16,
+	/// raw word of the instruction, 0 for interrupts
+	pub instr: u16,
+	/// vector of the interrupt taken instead of an instruction
+	pub interrupt: Option<u8>,
+	/// PSR afterwards, holding the resulting condition codes
+	pub psr: u16,
+	pub events: Vec<Event>,
+}
+
+/// The records of a binary trace.
+pub struct Records<R: Read> {
+	reader: R,
+	index: u64,
+}
+
+/// Writes the trace of one machine, as its CPU and memory report
+/// their accesses.
+#[derive(Clone, Debug, Default)]
+pub struct Tracer {
+	inner: Arc<Mutex<TracerInner>>,
+	/// whether a trace is being written, so that accesses needn't lock
+	/// 'inner' when none is
+	enabled: Arc<AtomicBool>,
+}
+
+#[derive(Default)]
+struct TracerInner {
+	sink: Option<Box<dyn Write + Send>>,
+	format: Format,
+	symbols: SymbolTable,
+	/// addresses records must touch to be written; all are if empty
+	ranges: Vec<RangeInclusive<u16>>,
+	/// events of the instruction being executed
+	events: Vec<Event>,
+	/// index of the last record written
+	last_index: u64,
+	/// the first write that failed, which ended the trace
+	error: Option<io::Error>,
+}
+
+impl Record {
+	/// whether the instruction is at or accessed an address in 'range'
+	fn touches(&self, range: &RangeInclusive<u16>) -> bool {
+		range.contains(&self.pc) || self.events.iter().any(|event| match *event {
+			Event::Read { addr, .. } | Event::Write { addr, .. } => range.contains(&addr),
+			Event::Register { .. } => false,
+		})
+	}
+
+	/// the record as a line of text, using 'symbols' in disassembly
+	pub fn to_text(&self, symbols: &SymbolTable) -> String {
+		let what = match self.interrupt {
+			Some(vector) => format!("-----  interrupt x{:02X}", vector),
+			None => format!(
+				"x{:04X}  {}",
+				self.instr, disasm::disassemble(self.pc, self.instr, symbols)
+			),
+		};
+		let mut line = format!("{:>8}  x{:04X}  {:<30}", self.index, self.pc, what);
+		for event in &self.events {
+			line.push_str("  ");
+			line.push_str(&event.to_string());
+		}
+		line.push_str(&format!("  cc={}", psr::flags(self.psr)));
+		line
+	}
+
+	/// append the binary encoding of the record following the one at
+	/// 'last_index' to 'out'
+	fn encode(&self, last_index: u64, out: &mut Vec<u8>) {
+		out.push(match self.interrupt {
+			Some(_) => KIND_INTERRUPT,
+			None => KIND_INSTRUCTION,
+		});
+		write_varint(out, self.index.wrapping_sub(last_index));
+		out.extend_from_slice(&self.pc.to_le_bytes());
+		let instr = self.interrupt.map_or(self.instr, u16::from);
+		out.extend_from_slice(&instr.to_le_bytes());
+		out.extend_from_slice(&self.psr.to_le_bytes());
+
+		write_varint(out, self.events.len() as u64);
+		for event in &self.events {
+			let (tag, first, second) = match *event {
+				Event::Register { register, old, new } => (register as u8, old, new),
+				Event::Read { addr, value } => (TAG_READ, addr, value),
+				Event::Write { addr, value } => (TAG_WRITE, addr, value),
+			};
+			out.push(tag);
+			out.extend_from_slice(&first.to_le_bytes());
+			out.extend_from_slice(&second.to_le_bytes());
+		}
+	}
+}
+
+impl fmt::Display for Event {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Register { register, old, new } => {
+				write!(f, "{} x{:04X}->x{:04X}", register, old, new)
+			}
+			Self::Read { addr, value } => write!(f, "rd x{:04X}=x{:04X}", addr, value),
+			Self::Write { addr, value } => write!(f, "wr x{:04X}=x{:04X}", addr, value),
+		}
+	}
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+	while value >= 0x80 {
+		out.push(value as u8 | 0x80);
+		value >>= 7;
+	}
+	out.push(value as u8);
+}
+
+fn invalid(message: &str) -> io::Error {
+	io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+impl<R: Read> Records<R> {
+	/// the records of the binary trace 'reader', checking its header
+	pub fn new(mut reader: R) -> io::Result<Self> {
+		let mut magic = [0; MAGIC.len()];
+		reader.read_exact(&mut magic)?;
+		if &magic != MAGIC {
+			return Err(invalid("not a binary vlc3 trace"));
+		}
+		Ok(Self { reader, index: 0 })
+	}
+
+	fn read_u8(&mut self) -> io::Result<u8> {
+		let mut byte = [0];
+		self.reader.read_exact(&mut byte)?;
+		Ok(byte[0])
+	}
+
+	fn read_u16(&mut self) -> io::Result<u16> {
+		let mut bytes = [0; 2];
+		self.reader.read_exact(&mut bytes)?;
+		Ok(u16::from_le_bytes(bytes))
+	}
+
+	fn read_varint(&mut self) -> io::Result<u64> {
+		let mut value = 0u64;
+		for shift in (0..64).step_by(7) {
+			let byte = self.read_u8()?;
+			value |= ((byte & 0x7f) as u64) << shift;
+			if byte & 0x80 == 0 {
+				return Ok(value);
+			}
+		}
+		Err(invalid("varint too long"))
+	}
+
+	/// the next record, None at the end of the trace
+	fn read_record(&mut self) -> io::Result<Option<Record>> {
+		let mut kind = [0];
+		if self.reader.read(&mut kind)? == 0 {
+			return Ok(None);
+		}
+
+		self.index = self.index.wrapping_add(self.read_varint()?);
+		let pc = self.read_u16()?;
+		let instr = self.read_u16()?;
+		let psr = self.read_u16()?;
+		let interrupt = match kind[0] {
+			KIND_INSTRUCTION => None,
+			KIND_INTERRUPT => Some(instr as u8),
+			_ => return Err(invalid("unknown record kind")),
+		};
+
+		let count = self.read_varint()?;
+		let mut events = Vec::new();
+		for _ in 0..count {
+			let tag = self.read_u8()?;
+			let first = self.read_u16()?;
+			let second = self.read_u16()?;
+			events.push(match tag {
+				TAG_READ => Event::Read { addr: first, value: second },
+				TAG_WRITE => Event::Write { addr: first, value: second },
+				_ => match Register::try_from(tag as u16) {
+					Ok(register) if register != Register::Count => Event::Register {
+						register,
+						old: first,
+						new: second,
+					},
+					_ => return Err(invalid("unknown event")),
+				},
+			});
+		}
+
+		Ok(Some(Record {
+			index: self.index,
+			pc,
+			instr: if interrupt.is_some() { 0 } else { instr },
+			interrupt,
+			psr,
+			events,
+		}))
+	}
+}
+
+impl<R: Read> Iterator for Records<R> {
+	type Item = io::Result<Record>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		self.read_record().transpose()
+	}
+}
+
+impl Tracer {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// write the records of instructions executed from now on to
+	/// 'writer', in 'format'; text disassembles with 'symbols'
+	pub fn start<W: Write + Send + 'static>(
+		&self,
+		writer: W,
+		format: Format,
+		symbols: SymbolTable,
+	) -> io::Result<()> {
+		let mut writer: Box<dyn Write + Send> = Box::new(io::BufWriter::new(writer));
+		if format == Format::Binary {
+			writer.write_all(MAGIC)?;
+		}
+
+		let mut inner = self.inner.lock().unwrap();
+		inner.sink = Some(writer);
+		inner.format = format;
+		inner.symbols = symbols;
+		inner.last_index = 0;
+		inner.error = None;
+		self.enabled.store(true, Ordering::SeqCst);
+		Ok(())
+	}
+
+	/// only write records of instructions at, or accessing memory in,
+	/// one of 'ranges'; all are written if it's empty
+	pub fn set_ranges(&self, ranges: Vec<RangeInclusive<u16>>) {
+		self.inner.lock().unwrap().ranges = ranges;
+	}
+
+	/// stop tracing and flush the trace, returning the first error
+	/// writing it met
+	pub fn finish(&self) -> io::Result<()> {
+		self.enabled.store(false, Ordering::SeqCst);
+		let mut inner = self.inner.lock().unwrap();
+		let flushed = match inner.sink.take() {
+			Some(mut sink) => sink.flush(),
+			None => Ok(()),
+		};
+		match inner.error.take() {
+			Some(e) => Err(e),
+			None => flushed,
+		}
+	}
+
+	pub(crate) fn is_enabled(&self) -> bool {
+		self.enabled.load(Ordering::Relaxed)
+	}
+
+	pub(crate) fn register_write(&self, register: Register, old: u16, new: u16) {
+		// PC changes with every instruction, as the next record shows
+		if register != Register::PC && old != new {
+			self.record(Event::Register { register, old, new });
+		}
+	}
+
+	pub(crate) fn memory_read(&self, addr: u16, value: u16) {
+		self.record(Event::Read { addr, value });
+	}
+
+	pub(crate) fn memory_write(&self, addr: u16, value: u16) {
+		self.record(Event::Write { addr, value });
+	}
+
+	fn record(&self, event: Event) {
+		if self.enabled.load(Ordering::Relaxed) {
+			self.inner.lock().unwrap().events.push(event);
+		}
+	}
+
+	/// forget events caused by anything but an instruction, e.g. by
+	/// loading a program
+	pub(crate) fn begin_instruction(&self) {
+		if self.enabled.load(Ordering::Relaxed) {
+			self.inner.lock().unwrap().events.clear();
+		}
+	}
+
+	/// write 'record' with the events since begin_instruction
+	pub(crate) fn end_instruction(&self, mut record: Record) {
+		if !self.enabled.load(Ordering::Relaxed) {
+			return;
+		}
+
+		let inner = &mut *self.inner.lock().unwrap();
+		record.events = std::mem::take(&mut inner.events);
+		if !inner.ranges.is_empty() && !inner.ranges.iter().any(|range| record.touches(range)) {
+			return;
+		}
+
+		let bytes = match inner.format {
+			Format::Text => format!("{}\n", record.to_text(&inner.symbols)).into_bytes(),
+			Format::Binary => {
+				let mut bytes = Vec::new();
+				record.encode(inner.last_index, &mut bytes);
+				bytes
+			}
+		};
+		inner.last_index = record.index;
+
+		let written = match &mut inner.sink {
+			Some(sink) => sink.write_all(&bytes),
+			None => Ok(()),
+		};
+		if let Err(e) = written {
+			inner.error = Some(e);
+			inner.sink = None;
+			self.enabled.store(false, Ordering::SeqCst);
+		}
+	}
+}
+
+impl fmt::Debug for TracerInner {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("TracerInner")
+			.field("format", &self.format)
+			.field("ranges", &self.ranges)
+			.field("events", &self.events)
+			.field("last_index", &self.last_index)
+			.field("error", &self.error)
+			.finish_non_exhaustive()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::asm;
+	use crate::console::{Capture, Headless};
+	use crate::machine::Machine;
+
+	const PROGRAM: &str = "
+        .ORIG x3000
+        LD R1, VALUE
+        ADD R1, R1, #1
+        ST R1, VALUE
+        LEA R2, VALUE
+        LDR R3, R2, #0
+        HALT
+VALUE   .FILL #41
+        .END
+";
+
+	/// the trace of PROGRAM in 'format', keeping records touching
+	/// 'ranges'
+	fn trace(format: Format, ranges: Vec<RangeInclusive<u16>>) -> Vec<u8> {
+		let assembly = asm::assemble(PROGRAM).unwrap();
+		let mut machine = Machine::new();
+		machine.set_console(Headless::new(Vec::new(), Capture::new()));
+		machine.load(&assembly.to_object()).unwrap();
+
+		let trace = Capture::new();
+		machine.tracer().start(trace.clone(), format, assembly.symbols().clone()).unwrap();
+		machine.tracer().set_ranges(ranges);
+		machine.run().unwrap();
+		machine.tracer().finish().unwrap();
+		trace.contents()
+	}
+
+	fn records(binary: &[u8]) -> Vec<Record> {
+		Records::new(binary).unwrap().collect::<io::Result<Vec<_>>>().unwrap()
+	}
+
+	#[test]
+	fn binary_traces_read_back_as_written() {
+		let records = records(&trace(Format::Binary, Vec::new()));
+		assert_eq!(records.len(), 6);
+		assert_eq!(
+			records[2],
+			Record {
+				index: 2,
+				pc: 0x3002,
+				instr: 0x3203,
+				interrupt: None,
+				psr: 0x8001,
+				events: vec![Event::Write { addr: 0x3006, value: 42 }],
+			}
+		);
+
+		let symbols = asm::assemble(PROGRAM).unwrap().symbols().clone();
+		let text = records
+			.iter()
+			.map(|record| format!("{}\n", record.to_text(&symbols)))
+			.collect::<String>();
+		assert_eq!(text.into_bytes(), trace(Format::Text, Vec::new()));
+	}
+
+	#[test]
+	fn ranges_keep_records_touching_them() {
+		let indices = |ranges| {
+			records(&trace(Format::Binary, ranges))
+				.iter()
+				.map(|record| record.index)
+				.collect::<Vec<_>>()
+		};
+		assert_eq!(indices(vec![0x3006..=0x3006]), [0, 2, 4]);
+		assert_eq!(indices(vec![0x3001..=0x3001, 0x3005..=0x3005]), [1, 5]);
+		assert!(indices(vec![0x4000..=0xffff]).is_empty());
+
+		let text = String::from_utf8(trace(Format::Text, vec![0x3003..=0x3003])).unwrap();
+		assert_eq!(text.lines().count(), 1);
+		assert!(text.contains("LEA R2, VALUE"), "{}", text);
+	}
+
+	#[test]
+	fn damaged_traces_are_errors() {
+		assert!(Records::new(&b"VLC3TRC\x02"[..]).is_err());
+		assert!(Records::new(&b"VLC3"[..]).is_err());
+
+		let mut binary = trace(Format::Binary, Vec::new());
+		binary[MAGIC.len()] = 7;
+		let first = Records::new(binary.as_slice()).unwrap().next().unwrap();
+		assert_eq!(first.unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+		let binary = trace(Format::Binary, Vec::new());
+		let cut = Records::new(&binary[..binary.len() - 1]).unwrap().last().unwrap();
+		assert_eq!(cut.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+	}
+}
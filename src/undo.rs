@@ -0,0 +1,246 @@
+//! Undo logs of executed instructions, which let the debugger run a
+//! program backwards.
+//!
+//! While an [`UndoLog`] is enabled, every step of the machine leaves a
+//! [`Step`] holding what it changed: the registers and memory it wrote
+//! with their old values, the state devices had before it and the keys
+//! it read. [`Machine::step_back`] takes the last step back. Output
+//! already written stays written.
+//!
+//! [`Machine::step_back`]: crate::machine::Machine::step_back
+
+use crate::cpu::register::Register;
+use std::{
+	collections::VecDeque,
+	ops::RangeInclusive,
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		Arc, Mutex,
+	},
+};
+
+/// An effect of an instruction, with what it replaced.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Change {
+	Register { register: Register, old: u16, new: u16 },
+	Read { addr: u16, value: u16 },
+	Write { addr: u16, old: u16, new: u16 },
+}
+
+/// An executed instruction, or an interrupt taken, and how to take it
+/// back.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Step {
+	/// number of instructions executed before this one
+	pub index: u64,
+	pub pc: u16,
+	/// the instruction's word, 0 for an interrupt
+	pub instr: u16,
+	/// vector of the interrupt taken, if this is one
+	pub interrupt: Option<u8>,
+	/// whether the machine was running before the step
+	pub running: bool,
+	/// the step's effects, in order
+	pub changes: Vec<Change>,
+	/// range and state before the step of every device it changed
+	pub devices: Vec<(RangeInclusive<u16>, Vec<u16>)>,
+	/// keys the step read from the console
+	pub keys: Vec<u8>,
+	/// address the machine's recent instructions forgot for this one
+	pub(crate) forgotten_pc: u16,
+}
+
+#[derive(Debug, Default)]
+struct UndoLogInner {
+	steps: VecDeque<Step>,
+	/// most steps kept, the oldest are forgotten first
+	limit: usize,
+	/// changes of the step being executed
+	changes: Vec<Change>,
+}
+
+/// The steps a machine executed last, shared by its CPU and memory.
+#[derive(Clone, Debug, Default)]
+pub struct UndoLog {
+	inner: Arc<Mutex<UndoLogInner>>,
+	enabled: Arc<AtomicBool>,
+}
+
+impl UndoLog {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// log the steps executed from now on, keeping the last 'limit'
+	pub fn enable(&self, limit: usize) {
+		let mut inner = self.inner.lock().unwrap();
+		inner.limit = limit;
+		while inner.steps.len() > limit {
+			inner.steps.pop_front();
+		}
+		self.enabled.store(limit > 0, Ordering::SeqCst);
+	}
+
+	/// stop logging and forget every step logged
+	pub fn disable(&self) {
+		self.enabled.store(false, Ordering::SeqCst);
+		self.clear();
+	}
+
+	pub fn is_enabled(&self) -> bool {
+		self.enabled.load(Ordering::Relaxed)
+	}
+
+	/// forget every step logged, e.g. when the machine's state is
+	/// replaced
+	pub fn clear(&self) {
+		self.inner.lock().unwrap().steps.clear();
+	}
+
+	/// number of steps that can be taken back
+	pub fn len(&self) -> usize {
+		self.inner.lock().unwrap().steps.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+
+	pub(crate) fn register_write(&self, register: Register, old: u16, new: u16) {
+		self.record(Change::Register { register, old, new });
+	}
+
+	pub(crate) fn memory_read(&self, addr: u16, value: u16) {
+		self.record(Change::Read { addr, value });
+	}
+
+	pub(crate) fn memory_write(&self, addr: u16, old: u16, new: u16) {
+		self.record(Change::Write { addr, old, new });
+	}
+
+	fn record(&self, change: Change) {
+		if self.enabled.load(Ordering::Relaxed) {
+			self.inner.lock().unwrap().changes.push(change);
+		}
+	}
+
+	/// forget changes made by anything but an instruction, e.g. by
+	/// the debugger
+	pub(crate) fn begin_instruction(&self) {
+		if self.enabled.load(Ordering::Relaxed) {
+			self.inner.lock().unwrap().changes.clear();
+		}
+	}
+
+	/// log 'step' with the changes since begin_instruction
+	pub(crate) fn end_instruction(&self, mut step: Step) {
+		if !self.enabled.load(Ordering::Relaxed) {
+			return;
+		}
+
+		let inner = &mut *self.inner.lock().unwrap();
+		step.changes = std::mem::take(&mut inner.changes);
+		if inner.steps.len() == inner.limit {
+			inner.steps.pop_front();
+		}
+		inner.steps.push_back(step);
+	}
+
+	/// the last step logged, removed from the log
+	pub(crate) fn pop(&self) -> Option<Step> {
+		self.inner.lock().unwrap().steps.pop_back()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::asm;
+	use crate::console::{Capture, Headless, PendingInput};
+	use crate::machine::Machine;
+	use crate::snapshot::Snapshot;
+
+	/// reads keys through the keyboard registers and stores them
+	/// through a subroutine, a pointer and a base register
+	const PROGRAM: &str = "
+        .ORIG x3000
+        LEA R4, BUFFER
+        AND R3, R3, #0
+        ADD R3, R3, #2
+WAIT    LDI R1, KBSRP
+        BRzp WAIT
+        LDI R0, KBDRP
+        JSR STORE
+        OUT
+        ADD R3, R3, #-1
+        BRp WAIT
+        STI R4, LAST
+        NOT R5, R4
+        HALT
+
+STORE   STR R0, R4, #0
+        ADD R4, R4, #1
+        RET
+
+KBSRP   .FILL xFE00
+KBDRP   .FILL xFE02
+LAST    .FILL x4000
+BUFFER  .BLKW #2
+        .END
+";
+
+	/// the machine without its console's keys, which reverse steps
+	/// give back rather than unread
+	fn state(machine: &Machine) -> Snapshot {
+		Snapshot { input: PendingInput::default(), ..machine.snapshot() }
+	}
+
+	fn machine(undo_limit: usize) -> (Machine, Capture) {
+		let output = Capture::new();
+		let mut machine = Machine::new();
+		machine.set_console(Headless::new("ok", output.clone()).with_delay(10));
+		machine.load(&asm::assemble(PROGRAM).unwrap().to_object()).unwrap();
+		machine.enable_undo(undo_limit);
+		(machine, output)
+	}
+
+	#[test]
+	fn steps_back_to_every_earlier_state() {
+		let (mut machine, output) = machine(1000);
+		let mut states = vec![state(&machine)];
+		while machine.is_running() {
+			machine.step().unwrap();
+			states.push(state(&machine));
+		}
+		assert_eq!(machine.peek(0x4000), 0x3015);
+		assert_eq!(output.contents(), b"okHALT\n");
+
+		let last = states.pop().unwrap();
+		while let Some(expected) = states.pop() {
+			let step = machine.step_back().unwrap();
+			assert_eq!(state(&machine), expected, "back over x{:04X}", step.pc);
+		}
+		assert_eq!(machine.step_back(), None);
+		assert_eq!(machine.peek(0x4000), 0);
+
+		// the keys read are read again, as output is written again, only
+		// without waiting as long for them
+		machine.run().unwrap();
+		let finished = state(&machine);
+		assert_eq!(finished.registers, last.registers);
+		assert_eq!(finished.memory, last.memory);
+		assert_eq!(output.contents(), b"okHALT\nokHALT\n");
+	}
+
+	#[test]
+	fn steps_back_as_far_as_the_limit() {
+		let (mut machine, _) = machine(3);
+		machine.run().unwrap();
+		let instructions = machine.instructions();
+		for _ in 0..3 {
+			assert!(machine.step_back().is_some());
+		}
+		assert_eq!(machine.step_back(), None);
+		assert_eq!(machine.instructions(), instructions - 3);
+		assert!(machine.is_running());
+	}
+}
@@ -1,128 +1,217 @@
-use crate::cpu::CPU;
-use crate::memory::MEMORY;
-use ctrlc;
-use lazy_static::*;
+use crate::debugger::{self, Action, Debugger};
+use crate::exit_status;
+use crate::fault::Fault;
+use crate::gdb::{Connection, Detach, GdbStub};
+use crate::machine::{Limits, Machine, Stop};
 use std::{
-	io,
+	io::{self, Write},
 	process::exit,
 	os::fd::AsRawFd,
-	sync::{Arc, Mutex},
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		Arc, Mutex, OnceLock,
+	},
+	thread,
+	time::Duration,
 };
 use termios::*;
 
-#[derive(Debug)]
-struct VmInner {
-	old_tio: Termios,
-	new_tio: Termios,
+/// how long a run may take to stop after Ctrl-C before vlc3 exits
+/// anyway, e.g. because the program blocks on a key
+const INTERRUPT_GRACE: Duration = Duration::from_millis(500);
+
+/// what Ctrl-C does for the vm running; a process has one handler for
+/// the signal, which every vm shares
+static ON_INTERRUPT: Mutex<Option<Box<dyn FnMut() + Send>>> = Mutex::new(None);
+
+/// make Ctrl-C call 'action' instead of what it did before
+fn on_interrupt<F: FnMut() + Send + 'static>(action: F) -> io::Result<()> {
+	static INSTALLED: OnceLock<Result<(), String>> = OnceLock::new();
+
+	*ON_INTERRUPT.lock().unwrap() = Some(Box::new(action));
+	INSTALLED
+		.get_or_init(|| {
+			ctrlc::set_handler(|| {
+				if let Some(action) = ON_INTERRUPT.lock().unwrap().as_mut() {
+					action();
+				}
+			}).map_err(|e| format!("failed to set Ctrl-C handler: {}", e))
+		})
+		.clone()
+		.map_err(io::Error::other)
 }
 
+/// Runs a [`Machine`] attached to the controlling terminal, if there is
+/// one.
 #[derive(Debug)]
 pub struct Vm {
-	inner: Arc<Mutex<VmInner>>,
+	machine: Machine,
+	/// the terminal's settings and those the program runs with; None
+	/// when standard input isn't a terminal or the machine is headless
+	tio: Option<(Termios, Termios)>,
 }
 
-lazy_static! {
-	pub static ref VM: Vm = Vm::new();
-}
+impl Vm {
+	pub fn new(machine: Machine) -> Self {
+		let tio = Termios::from_fd(io::stdin().as_raw_fd())
+			.ok()
+			.map(|old_tio| {
+				let mut new_tio = old_tio;
+				new_tio.c_lflag &= !ICANON & !ECHO;
+				(old_tio, new_tio)
+			});
 
-impl VmInner {
-	fn new() -> Self {
-		let old_tio = Termios::from_fd(io::stdin().as_raw_fd())
-				.expect("failed to get terminal I/O structure");
-		let mut new_tio = old_tio;
-		new_tio.c_lflag &= !ICANON & !ECHO;
+		Self { machine, tio }
+	}
+
+	/// a vm leaving the terminal alone, for machines whose console
+	/// isn't the terminal, see [`Headless`]
+	///
+	/// [`Headless`]: crate::console::Headless
+	pub fn headless(machine: Machine) -> Self {
+		Self { machine, tio: None }
+	}
 
-		Self {
-			old_tio,
-			new_tio
+	pub fn machine(&self) -> &Machine {
+		&self.machine
+	}
+
+	fn disable_input_buffering(&self) {
+		if let Some((_, new_tio)) = &self.tio {
+			let _ = tcsetattr(io::stdin().as_raw_fd(), TCSANOW, new_tio);
 		}
 	}
-}
 
-impl Vm {
-	fn new() -> Self {
-		Self {
-			inner: Arc::new(Mutex::new(VmInner::new())),
+	fn restore_input_buffering(tio: Option<&Termios>) {
+		if let Some(tio) = tio {
+			let _ = tcsetattr(io::stdin().as_raw_fd(), TCSANOW, tio);
 		}
 	}
 
-	fn load(&self, byte_stream: Vec<u8>) {
-		// to little endian
-		let stream_u16 = byte_stream
-			.chunks(2)
-			.map(|two_bytes| {
-				let big_endian_low = two_bytes[1] as u16;
-				let big_endian_high = two_bytes[0] as u16;
-				(big_endian_high << 8) | big_endian_low
-			})
-			.collect::<Vec<_>>();
-
-		// copy u16s from [start, end) into MEMORY
-		let start = stream_u16[0];
-		stream_u16
-			.iter()
-			.enumerate()
-			.skip(1)
-			.for_each(|(idx, &data)| {
-				MEMORY.write(start + idx as u16 - 1, data);
-			});
+	fn handle_interrupt(tio: Option<&Termios>) {
+		Vm::restore_input_buffering(tio);
+		println!();
+		exit(exit_status::INTERRUPTED);
+	}
+
+	fn old_tio(&self) -> Option<Termios> {
+		self.tio.map(|(old_tio, _)| old_tio)
 	}
 
-	pub fn init(&self, byte_stream: Vec<u8>) {
-		// load byte stream into memory
-		self.load(byte_stream);
+	/// run the program until it halts, faults, exceeds 'limits' or the
+	/// user presses Ctrl-C
+	pub fn run(&mut self, limits: Limits) -> Result<Stop, Fault> {
+		// stop the run when SIGINT toggled, or exit if it doesn't stop
+		// in time; a run that did stop finishes on its own
+		let old_tio = self.old_tio();
+		let stop = self.machine.stop_flag();
+		let stopped = Arc::new(AtomicBool::new(false));
+		let run_stopped = Arc::clone(&stopped);
+		on_interrupt(move || {
+			stop.store(true, Ordering::SeqCst);
+			thread::sleep(INTERRUPT_GRACE);
+			if !run_stopped.load(Ordering::SeqCst) {
+				Vm::handle_interrupt(old_tio.as_ref());
+			}
+		})?;
 
 		// initialize terminal
-		Vm::disable_input_buffering();
-	}
+		self.disable_input_buffering();
+		let result = self.machine.run_limited(limits);
+		stopped.store(true, Ordering::SeqCst);
 
-	fn disable_input_buffering() {
-		let _ = tcsetattr(
-			io::stdin().as_raw_fd(),
-			TCSANOW,
-			&VM
-				.inner
-				.lock()
-				.unwrap()
-				.new_tio,
-		);
-	}
-	
-	fn restore_input_buffering() {
-		let _ = tcsetattr(
-			io::stdin().as_raw_fd(),
-			TCSANOW,
-			&VM
-				.inner
-				.lock()
-				.unwrap()
-				.old_tio,
-		);
+		// shutdown, even if the program faulted
+		self.deinit();
+		result
 	}
 
-	fn handle_interrupt() {
-		Vm::restore_input_buffering();
-		println!();
-		exit(-2);
-	}
+	/// run the machine under 'debugger', reading commands from the
+	/// terminal until the user quits
+	pub fn debug(&mut self, debugger: &mut Debugger) -> Result<(), Fault> {
+		// Ctrl-C pauses the program instead of killing vlc3
+		let interrupted = debugger.interrupt_flag();
+		on_interrupt(move || {
+			interrupted.store(true, Ordering::SeqCst);
+		})?;
+		// so that the program can be run backwards
+		self.machine.enable_undo(debugger::UNDO_LIMIT);
+
+		let stdin = io::stdin();
+		let mut stdout = io::stdout();
+		writeln!(stdout, "vlc3 debugger, type 'help' for a list of commands")?;
+		debugger.command(&mut self.machine, "list", &mut stdout)?;
+
+		loop {
+			write!(stdout, "(vlc3) ")?;
+			stdout.flush()?;
+
+			let mut line = String::new();
+			if stdin.read_line(&mut line)? == 0 {
+				writeln!(stdout)?;
+				break;
+			}
+
+			match debugger.command(&mut self.machine, &line, &mut stdout)? {
+				Action::Prompt => {}
+				Action::Resume(mode) => {
+					// the program gets the terminal while it runs
+					self.disable_input_buffering();
+					let result = debugger.resume(&mut self.machine, mode, &mut stdout);
+					self.deinit();
+					result?;
+				}
+				Action::Quit => break,
+			}
+		}
 
-	pub fn run(&self) {
-		// restore input buffering when SIGINT toggled
-		ctrlc::set_handler(move || {
-			Vm::handle_interrupt();
-		}).expect("failed to set Ctrl-C handler");
+		Ok(())
+	}
 
-		while CPU.is_running() {
-			let raw_instr = CPU.fetch();
-			let instr = CPU.decode(raw_instr);
-			CPU.execute(instr);
-		}
+	/// let a GDB front end on 'conn' drive the machine; if it detaches,
+	/// the program runs on to completion
+	pub fn serve_gdb<C: Connection>(&mut self, conn: C) -> Result<Stop, Fault> {
+		let old_tio = self.old_tio();
+		on_interrupt(move || {
+			Vm::handle_interrupt(old_tio.as_ref());
+		})?;
+		self.disable_input_buffering();
+
+		let result = GdbStub::new(&mut self.machine, conn)
+			.serve()
+			.map_err(Fault::from)
+			.and_then(|detach| match detach {
+				Detach::Detached => self.machine.run(),
+				Detach::Killed => Ok(Stop::Halted),
+			});
 
-		// shutdown
 		self.deinit();
+		result
 	}
 
 	fn deinit(&self) {
-		Vm::restore_input_buffering();
+		Vm::restore_input_buffering(self.old_tio().as_ref());
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::asm;
+	use crate::console::{Capture, Headless};
+
+	fn halting_machine() -> Machine {
+		let object = asm::assemble(".ORIG x3000\nHALT\n.END").unwrap().to_object();
+		let mut machine = Machine::new();
+		machine.set_console(Headless::new(Vec::new(), Capture::new()));
+		machine.load(&object).unwrap();
+		machine
+	}
+
+	#[test]
+	fn vms_run_one_after_another() {
+		for _ in 0..3 {
+			let mut vm = Vm::headless(halting_machine());
+			assert!(matches!(vm.run(Limits::default()), Ok(Stop::Halted)));
+		}
 	}
 }
@@ -0,0 +1,298 @@
+use crate::cpu::register::Register;
+use std::sync::{
+	atomic::{AtomicBool, Ordering},
+	Arc, Mutex,
+};
+
+/// Which memory accesses a watchpoint reacts to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+	Read,
+	Write,
+	ReadWrite,
+}
+
+/// A condition that stops execution when the program meets it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Watchpoint {
+	/// an access to an address in [start, end], optionally only when
+	/// 'value' is read or written
+	Memory {
+		start: u16,
+		end: u16,
+		access: Access,
+		value: Option<u16>,
+	},
+
+	/// a write to 'register', optionally only of 'value'
+	Register {
+		register: Register,
+		value: Option<u16>,
+	},
+}
+
+/// The access that triggered a watchpoint.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WatchEvent {
+	Read { addr: u16, value: u16 },
+	Write { addr: u16, value: u16 },
+	Register { register: Register, value: u16 },
+}
+
+/// A triggered watchpoint together with the instruction that did it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WatchHit {
+	/// id returned when the watchpoint was added
+	pub id: usize,
+	/// address of the triggering instruction
+	pub pc: u16,
+	/// raw word of the triggering instruction
+	pub instr: u16,
+	pub event: WatchEvent,
+}
+
+#[derive(Debug, Default)]
+struct WatchpointsInner {
+	next_id: usize,
+	watchpoints: Vec<(usize, Watchpoint)>,
+	/// events of the instruction being executed
+	events: Vec<(usize, WatchEvent)>,
+	/// hits not yet taken by the machine's user
+	hits: Vec<WatchHit>,
+}
+
+/// Watchpoints shared by the CPU and memory of one machine.
+#[derive(Clone, Debug, Default)]
+pub struct Watchpoints {
+	inner: Arc<Mutex<WatchpointsInner>>,
+	/// whether any watchpoint exists, so that accesses needn't lock
+	/// 'inner' when none does
+	armed: Arc<AtomicBool>,
+}
+
+impl Watchpoint {
+	fn matches(&self, event: &WatchEvent) -> bool {
+		let value_matches = |expected: &Option<u16>, value: u16| {
+			expected.is_none_or(|expected| expected == value)
+		};
+
+		match (*self, *event) {
+			(
+				Watchpoint::Memory { start, end, access, value },
+				WatchEvent::Read { addr, value: read },
+			) => {
+				access != Access::Write
+					&& (start..=end).contains(&addr)
+					&& value_matches(&value, read)
+			}
+			(
+				Watchpoint::Memory { start, end, access, value },
+				WatchEvent::Write { addr, value: written },
+			) => {
+				access != Access::Read
+					&& (start..=end).contains(&addr)
+					&& value_matches(&value, written)
+			}
+			(
+				Watchpoint::Register { register, value },
+				WatchEvent::Register { register: written, value: data },
+			) => register == written && value_matches(&value, data),
+			_ => false,
+		}
+	}
+}
+
+impl Watchpoints {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// add 'watchpoint', returning the id hits will carry
+	pub fn add(&self, watchpoint: Watchpoint) -> usize {
+		let mut inner = self.inner.lock().unwrap();
+		inner.next_id += 1;
+		let id = inner.next_id;
+		inner.watchpoints.push((id, watchpoint));
+		self.armed.store(true, Ordering::SeqCst);
+		id
+	}
+
+	/// remove the watchpoint 'id', returning false if there is none
+	pub fn remove(&self, id: usize) -> bool {
+		let mut inner = self.inner.lock().unwrap();
+		let count = inner.watchpoints.len();
+		inner.watchpoints.retain(|&(watch_id, _)| watch_id != id);
+		self.armed.store(!inner.watchpoints.is_empty(), Ordering::SeqCst);
+		inner.watchpoints.len() != count
+	}
+
+	pub fn list(&self) -> Vec<(usize, Watchpoint)> {
+		self.inner.lock().unwrap().watchpoints.clone()
+	}
+
+	/// whether any watchpoint is set
+	pub(crate) fn is_armed(&self) -> bool {
+		self.armed.load(Ordering::Relaxed)
+	}
+
+	pub(crate) fn memory_read(&self, addr: u16, value: u16) {
+		self.record(WatchEvent::Read { addr, value });
+	}
+
+	pub(crate) fn memory_write(&self, addr: u16, value: u16) {
+		self.record(WatchEvent::Write { addr, value });
+	}
+
+	pub(crate) fn register_write(&self, register: Register, value: u16) {
+		self.record(WatchEvent::Register { register, value });
+	}
+
+	/// the PC moving on to the next instruction when one is fetched,
+	/// which isn't a control transfer; only watchpoints on the value of
+	/// the PC see it
+	pub(crate) fn pc_advance(&self, value: u16) {
+		self.record_where(
+			WatchEvent::Register { register: Register::PC, value },
+			|watchpoint| matches!(watchpoint, Watchpoint::Register { value: Some(_), .. }),
+		);
+	}
+
+	fn record(&self, event: WatchEvent) {
+		self.record_where(event, |_| true);
+	}
+
+	/// record 'event' for the watchpoints it matches that 'reacts'
+	/// accepts
+	fn record_where<F: Fn(&Watchpoint) -> bool>(&self, event: WatchEvent, reacts: F) {
+		if !self.armed.load(Ordering::Relaxed) {
+			return;
+		}
+
+		let inner = &mut *self.inner.lock().unwrap();
+		for &(id, watchpoint) in &inner.watchpoints {
+			if watchpoint.matches(&event) && reacts(&watchpoint) {
+				inner.events.push((id, event));
+			}
+		}
+	}
+
+	/// forget events caused by anything but an instruction, e.g. by
+	/// loading a program
+	pub(crate) fn begin_instruction(&self) {
+		if self.armed.load(Ordering::Relaxed) {
+			self.inner.lock().unwrap().events.clear();
+		}
+	}
+
+	/// turn the events of the instruction at 'pc' into hits
+	pub(crate) fn end_instruction(&self, pc: u16, instr: u16) {
+		if !self.armed.load(Ordering::Relaxed) {
+			return;
+		}
+
+		let inner = &mut *self.inner.lock().unwrap();
+		let hits = inner.events
+			.drain(..)
+			.map(|(id, event)| WatchHit { id, pc, instr, event });
+		inner.hits.extend(hits);
+	}
+
+	pub(crate) fn has_hits(&self) -> bool {
+		self.armed.load(Ordering::Relaxed) && !self.inner.lock().unwrap().hits.is_empty()
+	}
+
+	pub fn take_hits(&self) -> Vec<WatchHit> {
+		std::mem::take(&mut self.inner.lock().unwrap().hits)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::asm;
+	use crate::console::{Capture, Headless};
+	use crate::machine::{Machine, Stop};
+
+	/// loads and stores x3010, then loops once through a branch
+	const PROGRAM: &str = "
+        .ORIG x3000
+        LD R0, DATA
+        ADD R0, R0, #1
+        ST R0, DATA
+        AND R1, R1, #0
+        ADD R1, R1, #2
+LOOP    ADD R1, R1, #-1
+        BRp LOOP
+        HALT
+        .BLKW #8
+DATA    .FILL #41
+        .END
+";
+
+	/// the events of every hit of 'watchpoint' in a run of PROGRAM
+	fn hits(watchpoint: Watchpoint) -> Vec<WatchEvent> {
+		let mut machine = Machine::new();
+		machine.set_console(Headless::new(Vec::new(), Capture::new()));
+		machine.load(&asm::assemble(PROGRAM).unwrap().to_object()).unwrap();
+		machine.add_watchpoint(watchpoint);
+
+		let mut events = Vec::new();
+		while machine.run().unwrap() == Stop::Watchpoint {
+			events.extend(machine.take_watch_hits().iter().map(|hit| hit.event));
+		}
+		events
+	}
+
+	fn memory(access: Access, value: Option<u16>) -> Watchpoint {
+		Watchpoint::Memory { start: 0x3010, end: 0x3010, access, value }
+	}
+
+	#[test]
+	fn memory_watchpoints_see_their_accesses() {
+		let read = WatchEvent::Read { addr: 0x3010, value: 41 };
+		let write = WatchEvent::Write { addr: 0x3010, value: 42 };
+		assert_eq!(hits(memory(Access::Read, None)), [read]);
+		assert_eq!(hits(memory(Access::Write, None)), [write]);
+		assert_eq!(hits(memory(Access::ReadWrite, None)), [read, write]);
+
+		// outside the range nothing is seen
+		let code = Watchpoint::Memory {
+			start: 0x3000,
+			end: 0x300f,
+			access: Access::ReadWrite,
+			value: None,
+		};
+		assert_eq!(hits(code), []);
+	}
+
+	#[test]
+	fn value_conditions_filter_accesses() {
+		assert_eq!(hits(memory(Access::ReadWrite, Some(42))), [
+			WatchEvent::Write { addr: 0x3010, value: 42 },
+		]);
+		assert_eq!(hits(memory(Access::ReadWrite, Some(7))), []);
+	}
+
+	#[test]
+	fn register_watchpoints_see_writes() {
+		let r1 = |value| WatchEvent::Register { register: Register::R1, value };
+		assert_eq!(hits(Watchpoint::Register { register: Register::R1, value: None }), [
+			r1(0), r1(2), r1(1), r1(0),
+		]);
+		assert_eq!(hits(Watchpoint::Register { register: Register::R1, value: Some(1) }), [r1(1)]);
+	}
+
+	#[test]
+	fn the_pc_is_only_seen_on_control_transfers() {
+		let pc = |value| WatchEvent::Register { register: Register::PC, value };
+		// only the branch taken back to LOOP, not every fetch
+		assert_eq!(hits(Watchpoint::Register { register: Register::PC, value: None }), [
+			pc(0x3005),
+		]);
+
+		// a value is seen however the PC gets there
+		assert_eq!(hits(Watchpoint::Register { register: Register::PC, value: Some(0x3001) }), [
+			pc(0x3001),
+		]);
+	}
+}
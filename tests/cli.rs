@@ -0,0 +1,216 @@
+//! Tests of the vlc3 binary: its command line, exit statuses and the
+//! files it writes.
+
+use std::{
+	fs,
+	path::PathBuf,
+	process::{Command, Output, Stdio},
+	time::{Duration, Instant},
+};
+use vlc3::{asm, exit_status};
+
+/// a directory of its own for 'test'
+fn scratch(test: &str) -> PathBuf {
+	let dir = std::env::temp_dir().join(format!("vlc3-cli-{}-{}", std::process::id(), test));
+	fs::create_dir_all(&dir).unwrap();
+	dir
+}
+
+fn vlc3(args: &[&str]) -> Output {
+	Command::new(env!("CARGO_BIN_EXE_vlc3"))
+		.args(args)
+		.output()
+		.unwrap()
+}
+
+#[test]
+fn a_program_is_required_unless_a_snapshot_is_loaded() {
+	let output = vlc3(&[]);
+	assert_eq!(output.status.code(), Some(exit_status::USAGE));
+	assert_eq!(
+		String::from_utf8_lossy(&output.stderr),
+		"vlc3: PROGRAM is required unless --load-snapshot is given\n"
+	);
+
+	let dir = scratch("snapshot");
+	let program = dir.join("halt.obj");
+	let snapshot = dir.join("halt.snapshot");
+	let object = asm::assemble(".ORIG x3000\nADD R0, R0, #1\nHALT\n.END").unwrap().to_object();
+	fs::write(&program, object).unwrap();
+
+	let saved = vlc3(&[
+		"--headless", "--max-instructions", "1",
+		"--save-snapshot", snapshot.to_str().unwrap(),
+		program.to_str().unwrap(),
+	]);
+	assert_eq!(saved.status.code(), Some(exit_status::TIMEOUT));
+	let resumed = vlc3(&["--headless", "--load-snapshot", snapshot.to_str().unwrap()]);
+	assert_eq!(resumed.status.code(), Some(exit_status::HALTED));
+	assert_eq!(resumed.stdout, b"HALT\n");
+
+	// keys left to type are saved too
+	let object = asm::assemble(".ORIG x3000\nGETC\nOUT\nGETC\nOUT\nHALT\n.END").unwrap().to_object();
+	fs::write(&program, object).unwrap();
+	let saved = vlc3(&[
+		"--input-text", "ok", "--max-instructions", "2",
+		"--save-snapshot", snapshot.to_str().unwrap(),
+		program.to_str().unwrap(),
+	]);
+	assert_eq!(saved.stdout, b"o");
+	let resumed = vlc3(&["--headless", "--load-snapshot", snapshot.to_str().unwrap()]);
+	assert_eq!(resumed.status.code(), Some(exit_status::HALTED));
+	assert_eq!(resumed.stdout, b"kHALT\n");
+
+	// a snapshot holds its operating system already
+	for os in [&["--os"][..], &["--os-image", program.to_str().unwrap()]] {
+		let mut args = vec!["--headless", "--load-snapshot", snapshot.to_str().unwrap()];
+		args.extend_from_slice(os);
+		let output = vlc3(&args);
+		assert_eq!(output.status.code(), Some(exit_status::USAGE));
+		assert_eq!(
+			String::from_utf8_lossy(&output.stderr),
+			"vlc3: --load-snapshot can't be combined with --os or --os-image\n"
+		);
+	}
+	fs::remove_dir_all(&dir).unwrap();
+}
+
+/// run 'source' with 'args', returning its exit status and the result
+/// it wrote with --result-json
+fn run_with_result(test: &str, source: &str, args: &[&str]) -> (Option<i32>, serde_json::Value) {
+	let dir = scratch(test);
+	let program = dir.join("program.obj");
+	let result = dir.join("result.json");
+	fs::write(&program, asm::assemble(source).unwrap().to_object()).unwrap();
+
+	let mut all_args = vec!["--headless", "--result-json", result.to_str().unwrap()];
+	all_args.extend_from_slice(args);
+	all_args.push(program.to_str().unwrap());
+	let output = vlc3(&all_args);
+	let json = serde_json::from_str(&fs::read_to_string(&result).unwrap()).unwrap();
+	fs::remove_dir_all(&dir).unwrap();
+	(output.status.code(), json)
+}
+
+#[test]
+fn halts_are_reported_with_the_final_registers() {
+	let (status, result) = run_with_result("halt", "
+        .ORIG x3000
+        AND R1, R1, #0
+        ADD R1, R1, #-2
+        HALT
+        .END
+", &[]);
+	assert_eq!(status, Some(exit_status::HALTED));
+	assert_eq!(result["reason"], "halted");
+	assert_eq!(result["exit_code"], exit_status::HALTED);
+	assert_eq!(result["instructions"], 3);
+	assert_eq!(result["registers"]["R1"], 0xfffe);
+	assert_eq!(result["condition"], "N");
+	assert_eq!(result["fault"], serde_json::Value::Null);
+}
+
+#[test]
+fn faults_are_reported_with_their_pc() {
+	let (status, result) = run_with_result("fault", "
+        .ORIG x3000
+        ADD R0, R0, #1
+        .FILL xD000
+        .END
+", &[]);
+	assert_eq!(status, Some(exit_status::FAULT));
+	assert_eq!(result["reason"], "fault");
+	assert_eq!(result["exit_code"], exit_status::FAULT);
+	assert_eq!(result["fault"], "illegal opcode xD000 at x3001");
+	assert_eq!(result["fault_pc"], 0x3001);
+}
+
+#[test]
+fn limits_are_reported_as_timeouts() {
+	let (status, result) = run_with_result(
+		"limit",
+		".ORIG x3000\nSPIN BR SPIN\n.END",
+		&["--max-instructions", "100"],
+	);
+	assert_eq!(status, Some(exit_status::TIMEOUT));
+	assert_eq!(result["reason"], "instruction_limit");
+	assert_eq!(result["instructions"], 100);
+
+	let (status, result) = run_with_result(
+		"timeout",
+		".ORIG x3000\nSPIN BR SPIN\n.END",
+		&["--timeout", "0.05"],
+	);
+	assert_eq!(status, Some(exit_status::TIMEOUT));
+	assert_eq!(result["reason"], "timeout");
+}
+
+#[test]
+fn interrupted_runs_are_reported() {
+	let dir = scratch("sigint");
+	let program = dir.join("program.obj");
+	let result = dir.join("result.json");
+	fs::write(&program, asm::assemble(".ORIG x3000\nSPIN BR SPIN\n.END").unwrap().to_object()).unwrap();
+
+	let child = Command::new(env!("CARGO_BIN_EXE_vlc3"))
+		.args(["--headless", "--result-json", result.to_str().unwrap(), program.to_str().unwrap()])
+		.stdout(Stdio::null())
+		.stderr(Stdio::null())
+		.spawn()
+		.unwrap();
+	// let it start spinning
+	std::thread::sleep(Duration::from_millis(300));
+	unsafe {
+		libc::kill(child.id() as libc::pid_t, libc::SIGINT);
+	}
+	let output = child.wait_with_output().unwrap();
+	assert_eq!(output.status.code(), Some(exit_status::INTERRUPTED));
+
+	let result: serde_json::Value = serde_json::from_str(&fs::read_to_string(&result).unwrap()).unwrap();
+	assert_eq!(result["reason"], "interrupted");
+	assert_eq!(result["exit_code"], exit_status::INTERRUPTED);
+	assert_eq!(result["registers"]["PC"], 0x3000);
+	fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn programs_waiting_for_a_key_time_out() {
+	let dir = scratch("getc-timeout");
+	let program = dir.join("program.obj");
+	fs::write(&program, asm::assemble(".ORIG x3000\nGETC\nHALT\n.END").unwrap().to_object()).unwrap();
+
+	// standard input stays open, but never has a key
+	let mut child = Command::new(env!("CARGO_BIN_EXE_vlc3"))
+		.args(["--timeout", "0.2", program.to_str().unwrap()])
+		.stdin(Stdio::piped())
+		.stdout(Stdio::null())
+		.spawn()
+		.unwrap();
+	let _stdin = child.stdin.take();
+	let begin = Instant::now();
+	while child.try_wait().unwrap().is_none() && begin.elapsed() < Duration::from_secs(10) {
+		std::thread::sleep(Duration::from_millis(10));
+	}
+	let status = match child.try_wait().unwrap() {
+		Some(status) => status,
+		None => {
+			child.kill().unwrap();
+			panic!("vlc3 kept waiting for a key past its timeout");
+		}
+	};
+	assert_eq!(status.code(), Some(exit_status::TIMEOUT));
+	fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn reading_past_the_end_of_the_input_is_a_fault() {
+	let (status, result) = run_with_result(
+		"eof",
+		".ORIG x3000\nGETC\nGETC\nHALT\n.END",
+		&["--input-text", "a"],
+	);
+	assert_eq!(status, Some(exit_status::FAULT));
+	assert_eq!(result["reason"], "fault");
+	assert_eq!(result["registers"]["R0"], 'a' as u32);
+	assert_eq!(result["fault"], "I/O error: no more input to read");
+}
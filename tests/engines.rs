@@ -0,0 +1,449 @@
+//! Differential tests of the execution engines: every program of the
+//! corpus has to leave the same machine, output and stop behind when
+//! run with [`Engine::Blocks`] as when interpreted, also when cut short
+//! by an instruction limit and resumed.
+
+use vlc3::{
+	asm,
+	console::{Capture, Headless},
+	machine::{Engine, Limits},
+	os,
+	snapshot::Snapshot,
+	Machine,
+};
+
+/// A program of the corpus and how it runs.
+struct Program {
+	name: &'static str,
+	source: &'static str,
+	input: &'static [u8],
+	/// instructions each key waits
+	input_delay: u64,
+	/// boot the bundled operating system instead of native traps
+	os: bool,
+}
+
+/// how a run ended, the machine it left and what it wrote
+type Outcome = (String, Snapshot, Vec<u8>);
+
+/// most instructions any program of the corpus may execute
+const MAX_INSTRUCTIONS: u64 = 2_000_000;
+
+const CORPUS: &[Program] = &[
+	Program {
+		name: "arithmetic",
+		source: "
+        .ORIG x3000
+        AND R0, R0, #0
+        ADD R1, R0, #12
+        ADD R2, R0, #-7
+LOOP    ADD R0, R0, R2      ; multiply by repeated addition
+        NOT R3, R0
+        AND R4, R3, R1
+        ADD R5, R4, R3
+        LEA R6, LOOP
+        ADD R1, R1, #-1
+        BRp LOOP
+        BRz DONE
+        ADD R0, R0, #15     ; never reached
+DONE    LD R7, BIG
+        ADD R7, R7, R7      ; overflows
+        HALT
+BIG     .FILL x7FFF
+        .END
+",
+		input: b"",
+		input_delay: 0,
+		os: false,
+	},
+	Program {
+		name: "sieve",
+		source: "
+        .ORIG x3000
+        LD R1, TABLE
+        LD R2, SIZE
+        AND R3, R3, #0
+CLEAR   STR R3, R1, #0
+        ADD R1, R1, #1
+        ADD R2, R2, #-1
+        BRp CLEAR
+        AND R0, R0, #0
+        ADD R2, R0, #2
+OUTER   LD R1, TABLE
+        ADD R1, R1, R2
+        LDR R3, R1, #0
+        BRnp NEXT
+        ADD R0, R0, #1
+        ADD R4, R2, R2
+INNER   LD R5, NSIZE
+        ADD R5, R4, R5
+        BRzp NEXT
+        LD R1, TABLE
+        ADD R1, R1, R4
+        STR R2, R1, #0
+        ADD R4, R4, R2
+        BR INNER
+NEXT    ADD R2, R2, #1
+        LD R5, NSIZE
+        ADD R5, R2, R5
+        BRn OUTER
+        HALT
+SIZE    .FILL #300
+NSIZE   .FILL #-300
+TABLE   .FILL x4000
+        .END
+",
+		input: b"",
+		input_delay: 0,
+		os: false,
+	},
+	Program {
+		name: "subroutines",
+		source: "
+        .ORIG x3000
+        LD R6, STACK
+        AND R0, R0, #0
+        ADD R0, R0, #10
+        JSR FIB             ; R1 = fib(10), recursively
+        LEA R2, DOUBLE
+        JSRR R2
+        LDI R3, PTR         ; through a pointer
+        STI R1, PTR
+        LD R4, RESULT
+        HALT
+
+FIB     ADD R6, R6, #-3
+        STR R7, R6, #0
+        STR R0, R6, #1
+        STR R2, R6, #2
+        ADD R1, R0, #-2
+        BRp RECURSE
+        AND R1, R1, #0
+        ADD R1, R1, #1
+        BR RETURN
+RECURSE ADD R0, R0, #-1
+        JSR FIB
+        ADD R2, R1, #0
+        ADD R0, R0, #-1
+        JSR FIB
+        ADD R1, R1, R2
+RETURN  LDR R7, R6, #0
+        LDR R0, R6, #1
+        LDR R2, R6, #2
+        ADD R6, R6, #3
+        RET
+
+DOUBLE  ADD R1, R1, R1
+        JMP R7
+
+STACK   .FILL x5000
+PTR     .FILL RESULT
+RESULT  .FILL #0
+        .END
+",
+		input: b"",
+		input_delay: 0,
+		os: false,
+	},
+	Program {
+		name: "self-modifying",
+		source: "
+        .ORIG x3000
+        LD R0, PATCH
+        ST R0, TARGET       ; rewrites the next instruction
+TARGET  ADD R1, R1, #1
+        AND R3, R3, #0
+        ADD R3, R3, #15
+LOOP    LD R0, STEP
+        ADD R0, R0, #1
+        ST R0, STEP         ; bumps its own immediate
+STEP    ADD R2, R2, #0
+        LEA R4, JUMP
+        LD R5, HALTOP
+        ADD R3, R3, #-1
+        BRp LOOP
+        STR R5, R4, #0      ; replaces the jump below with a HALT
+JUMP    BR LOOP
+        .FILL xD000
+PATCH   ADD R1, R1, #5
+HALTOP  HALT
+        .END
+",
+		input: b"",
+		input_delay: 0,
+		os: false,
+	},
+	Program {
+		name: "generated",
+		source: "
+        .ORIG x3000
+        LD R1, CODE         ; writes N increments and a RET
+        LD R2, COUNT
+        LD R3, INC
+EMIT    STR R3, R1, #0
+        ADD R1, R1, #1
+        ADD R2, R2, #-1
+        BRp EMIT
+        LD R3, RETOP
+        STR R3, R1, #0
+        AND R0, R0, #0
+        LD R4, CODE
+        JSRR R4
+        JSRR R4
+        LD R3, INC2         ; patches the routine between calls
+        STR R3, R4, #5
+        JSRR R4
+        HALT
+CODE    .FILL x4000
+COUNT   .FILL #100
+INC     ADD R0, R0, #1
+INC2    ADD R0, R0, #-7
+RETOP   RET
+        .END
+",
+		input: b"",
+		input_delay: 0,
+		os: false,
+	},
+	Program {
+		name: "polling",
+		source: "
+        .ORIG x3000
+LOOP    LDI R1, KBSRP
+        BRzp LOOP
+        LDI R0, KBDRP
+        LD R3, IOBASE       ; the display through a base register
+WAIT    LDR R1, R3, #4
+        BRzp WAIT
+        STR R0, R3, #6
+        ADD R2, R0, #-10
+        BRnp LOOP
+        HALT
+KBSRP   .FILL xFE00
+KBDRP   .FILL xFE02
+IOBASE  .FILL xFE00
+        .END
+",
+		input: b"hello, blocks\n",
+		input_delay: 25,
+		os: false,
+	},
+	Program {
+		name: "os traps",
+		source: "
+        .ORIG x3000
+        LEA R0, PROMPT
+        PUTS
+        AND R5, R5, #0
+        ADD R5, R5, #3
+READ    GETC
+        ADD R0, R0, #1
+        OUT
+        ADD R5, R5, #-1
+        BRp READ
+        IN
+        HALT
+PROMPT  .STRINGZ \"type: \"
+        .END
+",
+		input: b"abcz",
+		input_delay: 40,
+		os: true,
+	},
+	Program {
+		name: "keyboard interrupt",
+		source: "
+        .ORIG x3000
+        LD R0, HANDLER
+        STI R0, KBVEC
+        LD R0, KBIE
+        STI R0, KBSRP
+SPIN    ADD R1, R1, #1
+        LD R2, KEYS
+        ADD R2, R2, #-3
+        BRn SPIN
+        AND R0, R0, #0
+        STI R0, KBSRP
+        HALT
+
+ISR     ST R0, SAVED
+        LDI R0, KBDRP
+        OUT
+        LD R0, KEYS
+        ADD R0, R0, #1
+        ST R0, KEYS
+        LD R0, SAVED
+        RTI
+
+HANDLER .FILL ISR
+KBVEC   .FILL x0180
+KBIE    .FILL x4000
+KBSRP   .FILL xFE00
+KBDRP   .FILL xFE02
+KEYS    .FILL #0
+SAVED   .FILL #0
+        .END
+",
+		input: b"xyz",
+		input_delay: 100,
+		os: false,
+	},
+	Program {
+		name: "timer interrupt",
+		source: "
+        .ORIG x3000
+        LD R0, HANDLER
+        STI R0, TMVEC
+        LD R0, INTERVAL
+        STI R0, TMIRP
+        LD R0, START
+        STI R0, TMCRP
+SPIN    ADD R1, R1, #1
+        LD R2, TICKS
+        ADD R2, R2, #-5
+        BRn SPIN
+        AND R0, R0, #0
+        STI R0, TMCRP
+        ADD R3, R1, #0      ; a few after the timer stopped
+        ADD R3, R3, R3
+        HALT
+
+ISR     ST R0, SAVED
+        LDI R0, TMCRP       ; acknowledges the interval
+        LD R0, TICKS
+        ADD R0, R0, #1
+        ST R0, TICKS
+        LD R0, SAVED
+        RTI
+
+HANDLER .FILL ISR
+TMVEC   .FILL x0181
+INTERVAL .FILL #37
+START   .FILL x4001
+TMCRP   .FILL xFE08
+TMIRP   .FILL xFE0A
+TICKS   .FILL #0
+SAVED   .FILL #0
+        .END
+",
+		input: b"",
+		input_delay: 0,
+		os: false,
+	},
+	Program {
+		name: "illegal opcode",
+		source: "
+        .ORIG x3000
+        ADD R0, R0, #3
+LOOP    ADD R0, R0, #-1
+        BRp LOOP
+        ADD R1, R1, #2
+        .FILL xD000
+        .END
+",
+		input: b"",
+		input_delay: 0,
+		os: false,
+	},
+	Program {
+		name: "privileged rti",
+		source: "
+        .ORIG x3000
+        ADD R0, R0, #1
+        ADD R1, R0, R0
+        RTI
+        .END
+",
+		input: b"",
+		input_delay: 0,
+		os: false,
+	},
+	Program {
+		name: "machine control",
+		source: "
+        .ORIG x3000
+        ADD R0, R0, #7
+        AND R1, R1, #0
+        STI R1, MCRP        ; stops the clock
+        ADD R0, R0, #1
+MCRP    .FILL xFFFE
+        .END
+",
+		input: b"",
+		input_delay: 0,
+		os: false,
+	},
+];
+
+/// a machine with 'program' loaded, executing with 'engine'
+fn machine(program: &Program, engine: Engine) -> (Machine, Capture) {
+	let object = asm::assemble(program.source)
+		.unwrap_or_else(|e| panic!("{} doesn't assemble: {:?}", program.name, e))
+		.to_object();
+	let output = Capture::new();
+	let mut machine = Machine::new();
+	machine.set_engine(engine);
+	machine.set_console(
+		Headless::new(program.input, output.clone()).with_delay(program.input_delay)
+	);
+	machine.load(&object).unwrap();
+	if program.os {
+		machine.boot(&os::image()).unwrap();
+	}
+	(machine, output)
+}
+
+/// run 'program' with 'engine', first stopping after each of 'limits'
+/// instructions in turn, then to the end
+fn run(program: &Program, engine: Engine, limits: &[u64]) -> Vec<Outcome> {
+	let (mut machine, output) = machine(program, engine);
+	let mut outcomes = Vec::new();
+	for &max in limits.iter().chain(&[MAX_INSTRUCTIONS]) {
+		let result = machine.run_limited(Limits {
+			max_instructions: Some(max),
+			timeout: None,
+		});
+		outcomes.push((format!("{:?}", result), machine.snapshot(), output.contents()));
+	}
+	outcomes
+}
+
+fn assert_same(program: &Program, limits: &[u64]) {
+	let interpreted = run(program, Engine::Interpreter, limits);
+	let compiled = run(program, Engine::Blocks, limits);
+	for (i, (expected, actual)) in interpreted.iter().zip(&compiled).enumerate() {
+		let context = format!("{}, run {} with limits {:?}", program.name, i, limits);
+		assert_eq!(expected.0, actual.0, "{}: the stops differ", context);
+		assert_eq!(expected.2, actual.2, "{}: the outputs differ", context);
+		let differs = expected.1.memory.iter().zip(&actual.1.memory).position(|(a, b)| a != b);
+		assert_eq!(differs, None, "{}: memory differs from this address on", context);
+		let without_memory = |snapshot: &Snapshot| Snapshot { memory: Vec::new(), ..snapshot.clone() };
+		assert_eq!(
+			without_memory(&expected.1), without_memory(&actual.1),
+			"{}: the machines differ", context
+		);
+	}
+}
+
+#[test]
+fn engines_agree_on_whole_runs() {
+	for program in CORPUS {
+		assert_same(program, &[]);
+		let outcome = &run(program, Engine::Interpreter, &[])[0];
+		assert!(
+			!outcome.0.contains("InstructionLimit"),
+			"{} doesn't finish: {}", program.name, outcome.0
+		);
+	}
+}
+
+#[test]
+fn engines_agree_when_limited() {
+	for program in CORPUS {
+		assert_same(program, &[1; 200]);
+		assert_same(program, &[1, 2, 3, 5, 8, 13, 21, 34, 55, 89, 144, 233, 377]);
+		for max in [0, 7, 17, 33, 63, 64, 65, 100, 129, 250, 1000] {
+			assert_same(program, &[max]);
+		}
+	}
+}